@@ -0,0 +1,138 @@
+//! Detects the hardware acceleration of the host for the default QEMU run command.
+//!
+//! Without acceleration, QEMU emulates the CPU with its TCG, which is an order of
+//! magnitude slower and makes timing-sensitive kernels and tests flaky. KVM is used
+//! on Linux if `/dev/kvm` is accessible, HVF on macOS if the CPU supports the
+//! Hypervisor framework, and WHPX on Windows, where QEMU itself falls back to TCG if
+//! the Windows Hypervisor Platform isn't enabled. The accelerator is only used if
+//! QEMU was built with it.
+
+use std::fs::OpenOptions;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::sync::OnceLock;
+
+/// The device through which QEMU accesses KVM.
+const KVM_DEVICE: &str = "/dev/kvm";
+
+/// The accelerator of the host, which is only detected once per invocation of
+/// bootimage (e.g. for all test kernels).
+static DETECTED: OnceLock<Option<Accelerator>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Accelerator {
+    Kvm,
+    Hvf,
+    Whpx,
+}
+
+impl Accelerator {
+    /// The name of the accelerator in `-accel`.
+    fn name(self) -> &'static str {
+        match self {
+            Accelerator::Kvm => "kvm",
+            Accelerator::Hvf => "hvf",
+            Accelerator::Whpx => "whpx",
+        }
+    }
+}
+
+/// The arguments that enable the hardware acceleration of the host in the QEMU at
+/// `qemu`, which are empty if there is none.
+///
+/// The chosen accelerator (or the reason why there is none) is printed the first
+/// time.
+pub(crate) fn qemu_args(qemu: &Path) -> Vec<String> {
+    let accelerator = *DETECTED.get_or_init(|| match detect(qemu) {
+        Ok(Accelerator::Whpx) => {
+            println!(
+                "Using WHPX acceleration (QEMU falls back to TCG if the Windows Hypervisor \
+                 Platform is disabled)"
+            );
+            Some(Accelerator::Whpx)
+        }
+        Ok(accelerator) => {
+            println!("Using {} acceleration", accelerator.name().to_uppercase());
+            Some(accelerator)
+        }
+        Err(reason) => {
+            println!("Running QEMU without acceleration: {}", reason);
+            None
+        }
+    });
+    match accelerator {
+        Some(Accelerator::Whpx) => {
+            // the interrupt controller of WHPX doesn't work with all QEMU versions, and
+            // QEMU uses the next accelerator if WHPX isn't available
+            vec![
+                "-accel".into(),
+                "whpx,kernel-irqchip=off".into(),
+                "-accel".into(),
+                "tcg".into(),
+            ]
+        }
+        Some(accelerator) => vec!["-accel".into(), accelerator.name().into()],
+        None => Vec::new(),
+    }
+}
+
+/// Whether the arguments already select an accelerator, which isn't overridden.
+pub(crate) fn selects_accelerator<'a, I: IntoIterator<Item = &'a String>>(args: I) -> bool {
+    args.into_iter().any(|arg| {
+        let arg = arg.trim_start_matches('-');
+        arg == "accel" || arg == "enable-kvm" || arg.contains("accel=")
+    })
+}
+
+/// The accelerator of the host, or the reason why it has none.
+fn detect(qemu: &Path) -> Result<Accelerator, String> {
+    let accelerator = host_accelerator()?;
+    // e.g. `Accelerators supported in QEMU binary:` followed by one name per line
+    let output = Command::new(qemu)
+        .args(["-accel", "help"])
+        .output()
+        .map_err(|err| format!("failed to run `{} -accel help`: {}", qemu.display(), err))?;
+    let supported = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|line| line.trim() == accelerator.name());
+    if !supported {
+        return Err(format!(
+            "{} doesn't support {}",
+            qemu.display(),
+            accelerator.name().to_uppercase()
+        ));
+    }
+    Ok(accelerator)
+}
+
+fn host_accelerator() -> Result<Accelerator, String> {
+    if cfg!(target_os = "linux") {
+        match OpenOptions::new().read(true).write(true).open(KVM_DEVICE) {
+            Ok(_) => Ok(Accelerator::Kvm),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Err(format!(
+                "{} doesn't exist (enable hardware virtualization or load the `kvm` kernel \
+                 module)",
+                KVM_DEVICE
+            )),
+            Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => Err(format!(
+                "no permission to access {} (add the user to the `kvm` group)",
+                KVM_DEVICE
+            )),
+            Err(err) => Err(format!("failed to open {}: {}", KVM_DEVICE, err)),
+        }
+    } else if cfg!(target_os = "macos") {
+        let output = Command::new("sysctl")
+            .args(["-n", "kern.hv_support"])
+            .output()
+            .map_err(|err| format!("failed to run `sysctl`: {}", err))?;
+        match String::from_utf8_lossy(&output.stdout).trim() {
+            "1" => Ok(Accelerator::Hvf),
+            _ => Err("the CPU doesn't support the Hypervisor framework".into()),
+        }
+    } else if cfg!(windows) {
+        Ok(Accelerator::Whpx)
+    } else {
+        Err("hardware acceleration is only detected on Linux, macOS, and Windows".into())
+    }
+}
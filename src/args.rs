@@ -1,24 +1,252 @@
 use std::{env, mem};
+use std::net::{IpAddr, Ipv4Addr};
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use config::{Display, ImageFormat};
+use debug::DebuggerKind;
+use disasm::Mode;
+use objdump::Selection;
+use report::ReportFormat;
 use Command;
+use Error;
 
-pub(crate) fn parse_args() -> Command {
+pub(crate) fn parse_args() -> Result<Command, Error> {
     let mut args = env::args().skip(1);
     let first = args.next();
-    match first.as_ref().map(|s| s.as_str()) {
-        Some("build") => parse_build_args(args),
-        Some("run") => match parse_build_args(args) {
+    let command = match first.as_deref() {
+        Some("build") => parse_build_args(args)?,
+        Some("run") => match parse_build_args(args)? {
             Command::Build(args) => Command::Run(args),
             Command::BuildHelp => Command::RunHelp,
             cmd => cmd,
         },
+        Some("test") => match parse_build_args(args)? {
+            Command::Build(args) => Command::Test(args),
+            Command::BuildHelp => Command::TestHelp,
+            cmd => cmd,
+        },
+        Some("debug") => match parse_build_args(args)? {
+            Command::Build(args) => Command::Debug(args),
+            Command::BuildHelp => Command::DebugHelp,
+            cmd => cmd,
+        },
+        Some("addr2line") => match parse_build_args(args)? {
+            // the positional arguments are the addresses instead of test filters
+            Command::Build(mut args) => {
+                for address in mem::take(&mut args.test_filters) {
+                    args.addresses.push(parse_address(&address)?);
+                }
+                Command::Addr2line(args)
+            }
+            Command::BuildHelp => Command::Addr2lineHelp,
+            cmd => cmd,
+        },
+        Some("objdump") => match parse_build_args(args)? {
+            // the positional arguments select the disassembled symbols and address ranges
+            Command::Build(mut args) => {
+                for selection in mem::take(&mut args.test_filters) {
+                    args.selections.push(selection.parse().map_err(Error::Args)?);
+                }
+                Command::Objdump(args)
+            }
+            Command::BuildHelp => Command::ObjdumpHelp,
+            cmd => cmd,
+        },
+        Some("replay") => match parse_build_args(args)? {
+            // the positional argument is the test kernel whose recording is replayed
+            Command::Build(mut args) => {
+                let mut tests = mem::take(&mut args.test_filters);
+                if tests.len() > 1 {
+                    Err(Error::Args(format!(
+                        "unexpected argument `{}` (`bootimage replay` replays one recording)",
+                        tests[1]
+                    )))?
+                }
+                args.replay_test = tests.pop();
+                Command::Replay(args)
+            }
+            Command::BuildHelp => Command::ReplayHelp,
+            cmd => cmd,
+        },
+        Some("size") => match parse_build_args(args)? {
+            Command::Build(args) => Command::Size(args),
+            Command::BuildHelp => Command::SizeHelp,
+            cmd => cmd,
+        },
+        Some("runner") => parse_runner_args(args)?,
+        Some("inspect") => parse_inspect_args(args)?,
+        Some("extract") => parse_extract_args(args)?,
+        Some("verify") => parse_verify_args(args)?,
+        Some("serve") => parse_serve_args(args)?,
+        Some("flash") => parse_flash_args(args)?,
+        Some("attach") => parse_attach_args(args)?,
+        Some("watch") => parse_watch_args(args)?,
+        Some("clean") => parse_clean_args(args)?,
+        Some("check") => match parse_build_args(args)? {
+            Command::Build(args) => {
+                if let Some(arg) = args.run_args.first() {
+                    Err(Error::Args(format!(
+                        "unexpected argument `{}` (only `bootimage clippy` takes arguments \
+                         after `--`)",
+                        arg
+                    )))?
+                }
+                Command::Check(args)
+            }
+            Command::BuildHelp => Command::CheckHelp,
+            cmd => cmd,
+        },
+        Some("clippy") => match parse_build_args(args)? {
+            Command::Build(args) => Command::Clippy(args),
+            Command::BuildHelp => Command::ClippyHelp,
+            cmd => cmd,
+        },
         Some("--help") | Some("-h") => Command::Help,
         Some("--version") => Command::Version,
         _ => Command::NoSubcommand,
+    };
+    if let Command::Build(ref args)
+    | Command::Run(ref args)
+    | Command::Debug(ref args)
+    | Command::Addr2line(ref args)
+    | Command::Objdump(ref args)
+    | Command::Size(ref args)
+    | Command::Replay(ref args)
+    | Command::Serve(ServeArgs { ref args, .. })
+    | Command::Flash(FlashArgs { ref args, .. })
+    | Command::Attach(AttachArgs { ref args, .. })
+    | Command::Watch(WatchArgs { ref args, .. })
+    | Command::Clean(CleanArgs { ref args, .. })
+    | Command::Check(ref args)
+    | Command::Clippy(ref args) = command
+    {
+        if let Some(filter) = args.test_filters.first() {
+            Err(Error::Args(format!(
+                "unexpected argument `{}` (test filters are only supported by `bootimage test`)",
+                filter
+            )))?
+        }
+        if args.exact {
+            Err(Error::Args(
+                "`--exact` is only supported by `bootimage test`".into(),
+            ))?
+        }
+        if args.report_format.is_some() || args.report_path.is_some() {
+            Err(Error::Args(
+                "test reports are only supported by `bootimage test`".into(),
+            ))?
+        }
+        if args.no_run {
+            Err(Error::Args(
+                "`--no-run` is only supported by `bootimage test`".into(),
+            ))?
+        }
+        if args.failed {
+            Err(Error::Args(
+                "`--failed` is only supported by `bootimage test`".into(),
+            ))?
+        }
+        if args.list.is_some() {
+            Err(Error::Args(
+                "`--list` is only supported by `bootimage test`".into(),
+            ))?
+        }
+    }
+    if let Command::Build(ref args)
+    | Command::Run(ref args)
+    | Command::Test(ref args)
+    | Command::Addr2line(ref args)
+    | Command::Objdump(ref args)
+    | Command::Size(ref args)
+    | Command::Replay(ref args)
+    | Command::Serve(ServeArgs { ref args, .. })
+    | Command::Flash(FlashArgs { ref args, .. })
+    | Command::Attach(AttachArgs { ref args, .. })
+    | Command::Watch(WatchArgs { ref args, .. })
+    | Command::Clean(CleanArgs { ref args, .. })
+    | Command::Check(ref args)
+    | Command::Clippy(ref args) = command
+    {
+        if args.debugger.is_some() {
+            Err(Error::Args(
+                "`--debugger` is only supported by `bootimage debug`".into(),
+            ))?
+        }
+    }
+    if let Command::Build(ref args)
+    | Command::Run(ref args)
+    | Command::Test(ref args)
+    | Command::Debug(ref args)
+    | Command::Addr2line(ref args)
+    | Command::Size(ref args)
+    | Command::Replay(ref args)
+    | Command::Serve(ServeArgs { ref args, .. })
+    | Command::Flash(FlashArgs { ref args, .. })
+    | Command::Attach(AttachArgs { ref args, .. })
+    | Command::Watch(WatchArgs { ref args, .. })
+    | Command::Clean(CleanArgs { ref args, .. })
+    | Command::Check(ref args)
+    | Command::Clippy(ref args) = command
+    {
+        if args.mode.is_some() {
+            Err(Error::Args(
+                "`--mode` is only supported by `bootimage objdump`".into(),
+            ))?
+        }
+    }
+    if let Command::Build(ref args)
+    | Command::Test(ref args)
+    | Command::Debug(ref args)
+    | Command::Addr2line(ref args)
+    | Command::Objdump(ref args)
+    | Command::Size(ref args)
+    | Command::Replay(ref args)
+    | Command::Serve(ServeArgs { ref args, .. })
+    | Command::Flash(FlashArgs { ref args, .. })
+    | Command::Attach(AttachArgs { ref args, .. })
+    | Command::Watch(WatchArgs { ref args, .. })
+    | Command::Clean(CleanArgs { ref args, .. })
+    | Command::Check(ref args)
+    | Command::Clippy(ref args) = command
+    {
+        if args.remote.is_some() {
+            Err(Error::Args(
+                "`--remote` is only supported by `bootimage run`".into(),
+            ))?
+        }
+    }
+    if let Command::Build(ref args)
+    | Command::Debug(ref args)
+    | Command::Addr2line(ref args)
+    | Command::Objdump(ref args)
+    | Command::Size(ref args)
+    | Command::Replay(ref args)
+    | Command::Serve(ServeArgs { ref args, .. })
+    | Command::Flash(FlashArgs { ref args, .. })
+    | Command::Clean(CleanArgs { ref args, .. })
+    | Command::Check(ref args)
+    | Command::Clippy(ref args) = command
+    {
+        if args.serial_port.is_some() {
+            Err(Error::Args(
+                "`--serial-port` is only supported by `bootimage run`, `bootimage test`, and \
+                 `bootimage attach`"
+                    .into(),
+            ))?
+        }
+    }
+    if let Command::Test(ref args) = command {
+        if args.no_run && (args.report_format.is_some() || args.report_path.is_some()) {
+            Err(Error::Args(
+                "`--no-run` can't be used together with a test report".into(),
+            ))?
+        }
     }
+    Ok(command)
 }
 
-fn parse_build_args<A>(args: A) -> Command
+fn parse_build_args<A>(args: A) -> Result<Command, Error>
 where
     A: Iterator<Item = String>,
 {
@@ -26,18 +254,46 @@ where
     let mut target: Option<String> = None;
     let mut release: Option<bool> = None;
     let mut update_bootloader: Option<bool> = None;
+    let mut format: Option<ImageFormat> = None;
+    let mut bin: Option<String> = None;
+    let mut example: Option<String> = None;
+    let mut package: Option<String> = None;
+    let mut features = Vec::new();
+    let mut all_features: Option<bool> = None;
+    let mut no_default_features: Option<bool> = None;
+    let mut bootloader_features: Option<Vec<String>> = None;
+    let mut profile: Option<String> = None;
+    let mut target_dir: Option<PathBuf> = None;
+    let mut offline: Option<bool> = None;
+    let mut config: Option<PathBuf> = None;
+    let mut run_profile: Option<String> = None;
+    let mut timeout: Option<Duration> = None;
+    let mut capture_serial: Option<bool> = None;
+    let mut no_accel: Option<bool> = None;
+    let mut deterministic: Option<bool> = None;
+    let mut display: Option<Display> = None;
+    let mut update_screenshots: Option<bool> = None;
+    let mut remote: Option<String> = None;
+    let mut serial_port: Option<u16> = None;
+    let mut reproducible: Option<bool> = None;
+    let mut allow_unverified_downloads: Option<bool> = None;
+    let mut no_sparse: Option<bool> = None;
+    let mut cmdline: Option<String> = None;
+    let mut exact: Option<bool> = None;
+    let mut no_run: Option<bool> = None;
+    let mut failed: Option<bool> = None;
+    let mut debugger: Option<DebuggerKind> = None;
+    let mut mode: Option<Mode> = None;
+    let mut list: Option<bool> = None;
+    let mut list_format: Option<ListFormat> = None;
+    let mut jobs: Option<usize> = None;
+    let mut report_format: Option<ReportFormat> = None;
+    let mut report_path: Option<PathBuf> = None;
+    let mut test_filters = Vec::new();
     let mut cargo_args = Vec::new();
     let mut run_args = Vec::new();
     let mut run_args_started = false;
     {
-        fn set<T>(arg: &mut Option<T>, value: Option<T>) {
-            let previous = mem::replace(arg, value);
-            assert!(
-                previous.is_none(),
-                "multiple arguments of same type provided"
-            )
-        };
-
         let mut arg_iter = args.into_iter();
         while let Some(arg) = arg_iter.next() {
             if run_args_started {
@@ -46,49 +302,331 @@ where
             }
             match arg.as_ref() {
                 "--help" | "-h" => {
-                    return Command::BuildHelp;
+                    return Ok(Command::BuildHelp);
                 }
                 "--version" => {
-                    return Command::Version;
+                    return Ok(Command::Version);
                 }
                 "--target" => {
                     let next = arg_iter.next();
-                    set(&mut target, next.clone());
+                    set_once(&mut target, next.clone(), &arg)?;
                     cargo_args.push(arg);
                     if let Some(next) = next {
                         cargo_args.push(next);
                     }
                 }
                 _ if arg.starts_with("--target=") => {
-                    set(
+                    set_once(
                         &mut target,
-                        Some(String::from(arg.trim_left_matches("--target="))),
-                    );
+                        Some(String::from(arg.trim_start_matches("--target="))),
+                        &arg,
+                    )?;
                     cargo_args.push(arg);
                 }
                 "--manifest-path" => {
                     let next = arg_iter.next();
-                    set(&mut manifest_path, next.as_ref().map(|p| PathBuf::from(&p)));
+                    set_once(&mut manifest_path, next.as_ref().map(|p| PathBuf::from(&p)), &arg)?;
                     cargo_args.push(arg);
                     if let Some(next) = next {
                         cargo_args.push(next);
                     }
                 }
                 _ if arg.starts_with("--manifest-path=") => {
-                    let path = PathBuf::from(arg.trim_left_matches("--manifest-path="));
-                    set(&mut manifest_path, Some(path));
+                    let path = PathBuf::from(arg.trim_start_matches("--manifest-path="));
+                    set_once(&mut manifest_path, Some(path), &arg)?;
+                    cargo_args.push(arg);
+                }
+                "--package" | "-p" => {
+                    let next = arg_iter.next();
+                    set_once(&mut package, next.clone(), &arg)?;
+                    cargo_args.push(arg);
+                    if let Some(next) = next {
+                        cargo_args.push(next);
+                    }
+                }
+                _ if arg.starts_with("--package=") => {
+                    let value = arg.trim_start_matches("--package=");
+                    set_once(&mut package, Some(String::from(value)), &arg)?;
+                    cargo_args.push(arg);
+                }
+                "--bin" => {
+                    let next = arg_iter.next();
+                    set_once(&mut bin, next.clone(), &arg)?;
+                    cargo_args.push(arg);
+                    if let Some(next) = next {
+                        cargo_args.push(next);
+                    }
+                }
+                _ if arg.starts_with("--bin=") => {
+                    set_once(&mut bin, Some(String::from(arg.trim_start_matches("--bin="))), &arg)?;
+                    cargo_args.push(arg);
+                }
+                "--example" => {
+                    let next = arg_iter.next();
+                    set_once(&mut example, next.clone(), &arg)?;
+                    cargo_args.push(arg);
+                    if let Some(next) = next {
+                        cargo_args.push(next);
+                    }
+                }
+                _ if arg.starts_with("--example=") => {
+                    let value = arg.trim_start_matches("--example=");
+                    set_once(&mut example, Some(String::from(value)), &arg)?;
+                    cargo_args.push(arg);
+                }
+                // cargo accumulates the features of repeated `--features` flags
+                "--features" | "-F" => {
+                    let next = arg_iter.next();
+                    if let Some(ref next) = next {
+                        features.extend(split_features(next));
+                    }
+                    cargo_args.push(arg);
+                    if let Some(next) = next {
+                        cargo_args.push(next);
+                    }
+                }
+                _ if arg.starts_with("--features=") => {
+                    features.extend(split_features(arg.trim_start_matches("--features=")));
+                    cargo_args.push(arg);
+                }
+                "--all-features" => {
+                    all_features = Some(true);
+                    cargo_args.push(arg);
+                }
+                "--no-default-features" => {
+                    no_default_features = Some(true);
+                    cargo_args.push(arg);
+                }
+                "--bootloader-features" => {
+                    let next = arg_iter.next().ok_or_else(|| {
+                        Error::Args("`--bootloader-features` requires a value".into())
+                    })?;
+                    set_once(&mut bootloader_features, Some(split_features(&next)), &arg)?;
+                }
+                _ if arg.starts_with("--bootloader-features=") => {
+                    let value = arg.trim_start_matches("--bootloader-features=");
+                    set_once(&mut bootloader_features, Some(split_features(value)), &arg)?;
+                }
+                "--config" => {
+                    let next = arg_iter
+                        .next()
+                        .ok_or_else(|| Error::Args("`--config` requires a path".into()))?;
+                    set_once(&mut config, Some(PathBuf::from(next)), &arg)?;
+                }
+                _ if arg.starts_with("--config=") => {
+                    let path = PathBuf::from(arg.trim_start_matches("--config="));
+                    set_once(&mut config, Some(path), &arg)?;
+                }
+                "--run-profile" => {
+                    let next = arg_iter
+                        .next()
+                        .ok_or_else(|| Error::Args("`--run-profile` requires a name".into()))?;
+                    set_once(&mut run_profile, Some(next), &arg)?;
+                }
+                _ if arg.starts_with("--run-profile=") => {
+                    let name = arg.trim_start_matches("--run-profile=");
+                    set_once(&mut run_profile, Some(String::from(name)), &arg)?;
+                }
+                "--timeout" => {
+                    let next = arg_iter
+                        .next()
+                        .ok_or_else(|| Error::Args("`--timeout` requires a value".into()))?;
+                    set_once(&mut timeout, Some(parse_timeout(&next)?), &arg)?;
+                }
+                _ if arg.starts_with("--timeout=") => {
+                    let value = arg.trim_start_matches("--timeout=");
+                    set_once(&mut timeout, Some(parse_timeout(value)?), &arg)?;
+                }
+                "--target-dir" => {
+                    let next = arg_iter.next();
+                    set_once(&mut target_dir, next.as_ref().map(PathBuf::from), &arg)?;
+                    cargo_args.push(arg);
+                    if let Some(next) = next {
+                        cargo_args.push(next);
+                    }
+                }
+                _ if arg.starts_with("--target-dir=") => {
+                    let path = PathBuf::from(arg.trim_start_matches("--target-dir="));
+                    set_once(&mut target_dir, Some(path), &arg)?;
                     cargo_args.push(arg);
                 }
                 "--release" => {
-                    set(&mut release, Some(true));
+                    set_once(&mut release, Some(true), &arg)?;
+                    cargo_args.push(arg);
+                }
+                "--profile" => {
+                    let next = arg_iter.next();
+                    set_once(&mut profile, next.clone(), &arg)?;
+                    cargo_args.push(arg);
+                    if let Some(next) = next {
+                        cargo_args.push(next);
+                    }
+                }
+                _ if arg.starts_with("--profile=") => {
+                    let value = arg.trim_start_matches("--profile=");
+                    set_once(&mut profile, Some(String::from(value)), &arg)?;
                     cargo_args.push(arg);
                 }
+                "--offline" => {
+                    set_once(&mut offline, Some(true), &arg)?;
+                    cargo_args.push(arg);
+                }
+                "--capture-serial" => {
+                    set_once(&mut capture_serial, Some(true), &arg)?;
+                }
+                "--no-accel" => {
+                    set_once(&mut no_accel, Some(true), &arg)?;
+                }
+                "--deterministic" => {
+                    set_once(&mut deterministic, Some(true), &arg)?;
+                }
+                "--display" => {
+                    let next = arg_iter.next().ok_or_else(|| {
+                        Error::Args("`--display` requires a value".into())
+                    })?;
+                    set_once(&mut display, Some(next.parse().map_err(Error::Args)?), &arg)?;
+                }
+                "--update-screenshots" => {
+                    set_once(&mut update_screenshots, Some(true), &arg)?;
+                }
+                "--remote" => {
+                    let next = arg_iter.next().ok_or_else(|| {
+                        Error::Args("`--remote` requires a value".into())
+                    })?;
+                    set_once(&mut remote, Some(next), &arg)?;
+                }
+                _ if arg.starts_with("--remote=") => {
+                    let value = arg.trim_start_matches("--remote=").to_owned();
+                    set_once(&mut remote, Some(value), &arg)?;
+                }
+                "--serial-port" => {
+                    let next = arg_iter.next().ok_or_else(|| {
+                        Error::Args("`--serial-port` requires a port".into())
+                    })?;
+                    set_once(&mut serial_port, Some(parse_serial_port(&next)?), &arg)?;
+                }
+                _ if arg.starts_with("--serial-port=") => {
+                    let port = arg.trim_start_matches("--serial-port=");
+                    set_once(&mut serial_port, Some(parse_serial_port(port)?), &arg)?;
+                }
+                _ if arg.starts_with("--display=") => {
+                    let value = arg.trim_start_matches("--display=");
+                    set_once(&mut display, Some(value.parse().map_err(Error::Args)?), &arg)?;
+                }
+                "--reproducible" => {
+                    set_once(&mut reproducible, Some(true), &arg)?;
+                }
+                "--allow-unverified-downloads" => {
+                    set_once(&mut allow_unverified_downloads, Some(true), &arg)?;
+                }
+                "--no-sparse" => {
+                    set_once(&mut no_sparse, Some(true), &arg)?;
+                }
+                "--cmdline" => {
+                    let next = arg_iter.next().ok_or_else(|| {
+                        Error::Args("`--cmdline` requires a value".into())
+                    })?;
+                    set_once(&mut cmdline, Some(next), &arg)?;
+                }
+                _ if arg.starts_with("--cmdline=") => {
+                    let value = arg.trim_start_matches("--cmdline=");
+                    set_once(&mut cmdline, Some(String::from(value)), &arg)?;
+                }
                 "--update-bootloader" => {
-                    set(&mut update_bootloader, Some(true));
+                    set_once(&mut update_bootloader, Some(true), &arg)?;
+                }
+                "--format" => {
+                    let next = arg_iter.next().ok_or_else(|| {
+                        Error::Args("`--format` requires a value".into())
+                    })?;
+                    set_once(&mut format, Some(next.parse().map_err(Error::Args)?), &arg)?;
+                }
+                _ if arg.starts_with("--format=") => {
+                    let value = arg.trim_start_matches("--format=");
+                    set_once(&mut format, Some(value.parse().map_err(Error::Args)?), &arg)?;
+                }
+                "--jobs" | "-j" => {
+                    let next = arg_iter.next();
+                    // cargo also accepts values like `default`, which keep the default
+                    set_once(&mut jobs, next.as_ref().and_then(|n| n.parse().ok()), &arg)?;
+                    cargo_args.push(arg);
+                    if let Some(next) = next {
+                        cargo_args.push(next);
+                    }
+                }
+                _ if arg.starts_with("--jobs=") => {
+                    set_once(&mut jobs, arg.trim_start_matches("--jobs=").parse().ok(), &arg)?;
+                    cargo_args.push(arg);
+                }
+                "--exact" => {
+                    set_once(&mut exact, Some(true), &arg)?;
+                }
+                "--no-run" => {
+                    set_once(&mut no_run, Some(true), &arg)?;
+                }
+                "--failed" => {
+                    set_once(&mut failed, Some(true), &arg)?;
+                }
+                "--debugger" => {
+                    let next = arg_iter.next().ok_or_else(|| {
+                        Error::Args("`--debugger` requires a value".into())
+                    })?;
+                    set_once(&mut debugger, Some(next.parse().map_err(Error::Args)?), &arg)?;
+                }
+                _ if arg.starts_with("--debugger=") => {
+                    let value = arg.trim_start_matches("--debugger=");
+                    set_once(&mut debugger, Some(value.parse().map_err(Error::Args)?), &arg)?;
+                }
+                "--mode" => {
+                    let next = arg_iter
+                        .next()
+                        .ok_or_else(|| Error::Args("`--mode` requires a value".into()))?;
+                    set_once(&mut mode, Some(next.parse().map_err(Error::Args)?), &arg)?;
+                }
+                _ if arg.starts_with("--mode=") => {
+                    let value = arg.trim_start_matches("--mode=");
+                    set_once(&mut mode, Some(value.parse().map_err(Error::Args)?), &arg)?;
+                }
+                "--list" => {
+                    set_once(&mut list, Some(true), &arg)?;
+                }
+                "--list-format" => {
+                    let next = arg_iter.next().ok_or_else(|| {
+                        Error::Args("`--list-format` requires a value".into())
+                    })?;
+                    set_once(&mut list_format, Some(next.parse().map_err(Error::Args)?), &arg)?;
+                }
+                _ if arg.starts_with("--list-format=") => {
+                    let value = arg.trim_start_matches("--list-format=");
+                    set_once(&mut list_format, Some(value.parse().map_err(Error::Args)?), &arg)?;
+                }
+                "--report-format" => {
+                    let next = arg_iter.next().ok_or_else(|| {
+                        Error::Args("`--report-format` requires a value".into())
+                    })?;
+                    set_once(&mut report_format, Some(next.parse().map_err(Error::Args)?), &arg)?;
+                }
+                _ if arg.starts_with("--report-format=") => {
+                    let value = arg.trim_start_matches("--report-format=");
+                    set_once(&mut report_format, Some(value.parse().map_err(Error::Args)?), &arg)?;
+                }
+                "--report-path" => {
+                    let next = arg_iter
+                        .next()
+                        .ok_or_else(|| Error::Args("`--report-path` requires a path".into()))?;
+                    set_once(&mut report_path, Some(PathBuf::from(next)), &arg)?;
+                }
+                _ if arg.starts_with("--report-path=") => {
+                    let path = arg.trim_start_matches("--report-path=");
+                    set_once(&mut report_path, Some(PathBuf::from(path)), &arg)?;
                 }
                 "--" => {
                     run_args_started = true;
                 }
+                // cargo doesn't accept positional arguments, so they are test filters
+                _ if !arg.starts_with('-') && !takes_value(cargo_args.last()) => {
+                    test_filters.push(arg);
+                }
                 _ => {
                     cargo_args.push(arg);
                 }
@@ -96,16 +634,556 @@ where
         }
     }
 
-    Command::Build(Args {
+    if bin.is_some() && example.is_some() {
+        Err(Error::Args(
+            "`--bin` and `--example` can't be used together".into(),
+        ))?;
+    }
+
+    if release.is_some() && profile.is_some() {
+        Err(Error::Args(
+            "`--release` and `--profile` can't be used together".into(),
+        ))?;
+    }
+
+    Ok(Command::Build(Args {
         cargo_args,
         run_args,
         target,
         manifest_path,
         release: release.unwrap_or(false),
         update_bootloader: update_bootloader.unwrap_or(false),
+        format,
+        bin,
+        example,
+        package,
+        features,
+        all_features: all_features.unwrap_or(false),
+        no_default_features: no_default_features.unwrap_or(false),
+        bootloader_features,
+        profile,
+        target_dir,
+        offline: offline.unwrap_or(false),
+        config,
+        run_profile,
+        timeout,
+        capture_serial: capture_serial.unwrap_or(false),
+        no_accel: no_accel.unwrap_or(false),
+        deterministic: deterministic.unwrap_or(false),
+        display,
+        update_screenshots: update_screenshots.unwrap_or(false),
+        remote,
+        serial_port,
+        reproducible: reproducible.unwrap_or(false),
+        allow_unverified_downloads: allow_unverified_downloads.unwrap_or(false),
+        no_sparse: no_sparse.unwrap_or(false),
+        cmdline,
+        test_filters,
+        exact: exact.unwrap_or(false),
+        no_run: no_run.unwrap_or(false),
+        failed: failed.unwrap_or(false),
+        debugger,
+        addresses: Vec::new(),
+        replay_test: None,
+        selections: Vec::new(),
+        mode,
+        // a list format implies `--list`
+        list: match (list, list_format) {
+            (None, None) => None,
+            (_, format) => Some(format.unwrap_or(ListFormat::Human)),
+        },
+        jobs: jobs.filter(|&jobs| jobs > 0),
+        report_format,
+        report_path,
+    }))
+}
+
+/// Whether `arg` is a cargo option whose value is passed as a separate argument.
+fn takes_value(arg: Option<&String>) -> bool {
+    const OPTIONS: &[&str] = &[
+        "--features",
+        "-F",
+        "--color",
+        "--message-format",
+        "-Z",
+        "--exclude",
+        "--test",
+        "--bench",
+        "--artifact-dir",
+        "--out-dir",
+        "--lockfile-path",
+    ];
+    arg.is_some_and(|arg| OPTIONS.contains(&arg.as_str()))
+}
+
+/// Stores the value of a flag that may only be given once.
+///
+/// The flag is named in the error without its `=value` part, if any.
+fn set_once<T>(slot: &mut Option<T>, value: Option<T>, arg: &str) -> Result<(), Error> {
+    if slot.is_some() {
+        let flag = arg.split('=').next().unwrap_or(arg);
+        return Err(Error::Args(format!("`{}` can only be given once", flag)));
+    }
+    *slot = value;
+    Ok(())
+}
+
+/// Parses a timeout in seconds.
+fn parse_timeout(seconds: &str) -> Result<Duration, Error> {
+    match seconds.parse() {
+        Ok(seconds) if seconds > 0 => Ok(Duration::from_secs(seconds)),
+        _ => Err(Error::Args(format!(
+            "`--timeout` requires a positive number of seconds, found `{}`",
+            seconds
+        ))),
+    }
+}
+
+/// Parses the port of the serial console.
+fn parse_serial_port(port: &str) -> Result<u16, Error> {
+    match port.parse() {
+        Ok(port) if port > 0 => Ok(port),
+        _ => Err(Error::Args(format!(
+            "`--serial-port` requires a port number, found `{}`",
+            port
+        ))),
+    }
+}
+
+/// Parses a hexadecimal address, with or without a `0x` prefix.
+pub(crate) fn parse_address(address: &str) -> Result<u64, Error> {
+    let digits = address
+        .strip_prefix("0x")
+        .or_else(|| address.strip_prefix("0X"))
+        .unwrap_or(address);
+    u64::from_str_radix(digits, 16)
+        .map_err(|_| Error::Args(format!("`{}` is not a hexadecimal address", address)))
+}
+
+/// Splits a list of features that is separated by spaces or commas, like cargo's `--features`.
+fn split_features(features: &str) -> Vec<String> {
+    features
+        .split([' ', ','])
+        .filter(|f| !f.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn parse_runner_args<A>(mut args: A) -> Result<Command, Error>
+where
+    A: Iterator<Item = String>,
+{
+    let executable = match args.next() {
+        Some(ref arg) if arg == "--help" || arg == "-h" => return Ok(Command::RunnerHelp),
+        Some(ref arg) if arg == "--version" => return Ok(Command::Version),
+        Some(arg) => PathBuf::from(arg),
+        None => Err(Error::Args(
+            "`bootimage runner` requires the path to a kernel executable".into(),
+        ))?,
+    };
+
+    Ok(Command::Runner(RunnerArgs {
+        executable,
+        run_args: args.collect(),
+    }))
+}
+
+fn parse_inspect_args<A>(args: A) -> Result<Command, Error>
+where
+    A: Iterator<Item = String>,
+{
+    let mut image = None;
+    let mut json = false;
+    for arg in args {
+        match arg.as_ref() {
+            "--help" | "-h" => return Ok(Command::InspectHelp),
+            "--version" => return Ok(Command::Version),
+            "--json" => json = true,
+            _ if arg.starts_with('-') => Err(Error::Args(format!(
+                "unexpected argument `{}` for `bootimage inspect`",
+                arg
+            )))?,
+            _ if image.is_some() => Err(Error::Args(format!(
+                "unexpected argument `{}` (`bootimage inspect` takes a single image)",
+                arg
+            )))?,
+            _ => image = Some(PathBuf::from(arg)),
+        }
+    }
+    let image = image.ok_or_else(|| {
+        Error::Args("`bootimage inspect` requires the path to a disk image".into())
+    })?;
+
+    Ok(Command::Inspect(InspectArgs { image, json }))
+}
+
+fn parse_extract_args<A>(args: A) -> Result<Command, Error>
+where
+    A: Iterator<Item = String>,
+{
+    let mut image = None;
+    let mut kernel = None;
+    let mut bootloader = None;
+    let mut arg_iter = args.into_iter();
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_ref() {
+            "--help" | "-h" => return Ok(Command::ExtractHelp),
+            "--version" => return Ok(Command::Version),
+            "--kernel" => {
+                let path = arg_iter
+                    .next()
+                    .ok_or_else(|| Error::Args("`--kernel` requires a path".into()))?;
+                set_once(&mut kernel, Some(PathBuf::from(path)), &arg)?;
+            }
+            _ if arg.starts_with("--kernel=") => {
+                let path = arg.trim_start_matches("--kernel=");
+                set_once(&mut kernel, Some(PathBuf::from(path)), &arg)?;
+            }
+            "--bootloader" => {
+                let path = arg_iter
+                    .next()
+                    .ok_or_else(|| Error::Args("`--bootloader` requires a path".into()))?;
+                set_once(&mut bootloader, Some(PathBuf::from(path)), &arg)?;
+            }
+            _ if arg.starts_with("--bootloader=") => {
+                let path = arg.trim_start_matches("--bootloader=");
+                set_once(&mut bootloader, Some(PathBuf::from(path)), &arg)?;
+            }
+            _ if arg.starts_with('-') => Err(Error::Args(format!(
+                "unexpected argument `{}` for `bootimage extract`",
+                arg
+            )))?,
+            _ if image.is_some() => Err(Error::Args(format!(
+                "unexpected argument `{}` (`bootimage extract` takes a single image)",
+                arg
+            )))?,
+            _ => image = Some(PathBuf::from(arg)),
+        }
+    }
+    let image = image.ok_or_else(|| {
+        Error::Args("`bootimage extract` requires the path to a disk image".into())
+    })?;
+    if kernel.is_none() && bootloader.is_none() {
+        Err(Error::Args(
+            "`bootimage extract` requires `--kernel PATH` or `--bootloader PATH`".into(),
+        ))?
+    }
+
+    Ok(Command::Extract(ExtractArgs {
+        image,
+        kernel,
+        bootloader,
+    }))
+}
+
+fn parse_verify_args<A>(args: A) -> Result<Command, Error>
+where
+    A: Iterator<Item = String>,
+{
+    let mut image = None;
+    let mut public_key = None;
+    let mut arg_iter = args.into_iter();
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_ref() {
+            "--help" | "-h" => return Ok(Command::VerifyHelp),
+            "--version" => return Ok(Command::Version),
+            "--signature" => {
+                let path = arg_iter.next().ok_or_else(|| {
+                    Error::Args("`--signature` requires the path to a public key".into())
+                })?;
+                public_key = Some(PathBuf::from(path));
+            }
+            _ if arg.starts_with("--signature=") => {
+                public_key = Some(PathBuf::from(arg.trim_start_matches("--signature=")));
+            }
+            _ if arg.starts_with('-') => Err(Error::Args(format!(
+                "unexpected argument `{}` for `bootimage verify`",
+                arg
+            )))?,
+            _ if image.is_some() => Err(Error::Args(format!(
+                "unexpected argument `{}` (`bootimage verify` takes a single image)",
+                arg
+            )))?,
+            _ => image = Some(PathBuf::from(arg)),
+        }
+    }
+    let image = image.ok_or_else(|| {
+        Error::Args("`bootimage verify` requires the path to a disk image".into())
+    })?;
+
+    Ok(Command::Verify(VerifyArgs { image, public_key }))
+}
+
+fn parse_serve_args<A>(args: A) -> Result<Command, Error>
+where
+    A: Iterator<Item = String>,
+{
+    fn parse_port(option: &str, port: Option<&str>) -> Result<u16, Error> {
+        let port = port.ok_or_else(|| Error::Args(format!("`{}` requires a port", option)))?;
+        port.parse().map_err(|_| {
+            Error::Args(format!("`{}` requires a port number, found `{}`", option, port))
+        })
+    }
+
+    let mut address = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+    let mut http_port = 8080;
+    let mut tftp_port = 69;
+    let mut watch = false;
+    let mut base_url = None;
+    // the remaining arguments are passed to `bootimage build` on every rebuild
+    let mut build_args = Vec::new();
+    let mut arg_iter = args.into_iter();
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_ref() {
+            "--address" => {
+                let next = arg_iter
+                    .next()
+                    .ok_or_else(|| Error::Args("`--address` requires an IP address".into()))?;
+                address = parse_ip_address(&next)?;
+            }
+            _ if arg.starts_with("--address=") => {
+                address = parse_ip_address(arg.trim_start_matches("--address="))?;
+            }
+            "--http-port" => http_port = parse_port("--http-port", arg_iter.next().as_deref())?,
+            _ if arg.starts_with("--http-port=") => {
+                let port = arg.trim_start_matches("--http-port=");
+                http_port = parse_port("--http-port", Some(port))?;
+            }
+            "--tftp-port" => tftp_port = parse_port("--tftp-port", arg_iter.next().as_deref())?,
+            _ if arg.starts_with("--tftp-port=") => {
+                let port = arg.trim_start_matches("--tftp-port=");
+                tftp_port = parse_port("--tftp-port", Some(port))?;
+            }
+            "--watch" => watch = true,
+            "--base-url" => {
+                let next = arg_iter
+                    .next()
+                    .ok_or_else(|| Error::Args("`--base-url` requires a URL".into()))?;
+                base_url = Some(next);
+            }
+            _ if arg.starts_with("--base-url=") => {
+                base_url = Some(String::from(arg.trim_start_matches("--base-url=")));
+            }
+            "--" => Err(Error::Args(
+                "`bootimage serve` doesn't run the image, so it takes no run arguments".into(),
+            ))?,
+            _ => build_args.push(arg),
+        }
+    }
+
+    Ok(match parse_build_args(build_args.clone().into_iter())? {
+        Command::Build(args) => Command::Serve(ServeArgs {
+            args,
+            build_args,
+            address,
+            http_port,
+            tftp_port,
+            watch,
+            base_url: base_url.map(|url| url.trim_end_matches('/').to_owned()),
+        }),
+        Command::BuildHelp => Command::ServeHelp,
+        cmd => cmd,
+    })
+}
+
+fn parse_flash_args<A>(args: A) -> Result<Command, Error>
+where
+    A: Iterator<Item = String>,
+{
+    let mut yes = false;
+    let mut verify = false;
+    let mut build_args = Vec::new();
+    for arg in args {
+        match arg.as_ref() {
+            "--yes" | "-y" => yes = true,
+            "--verify" => verify = true,
+            "--" => Err(Error::Args(
+                "`bootimage flash` doesn't run the image, so it takes no run arguments".into(),
+            ))?,
+            _ => build_args.push(arg),
+        }
+    }
+
+    Ok(match parse_build_args(build_args.into_iter())? {
+        // the positional argument is the device instead of a test filter
+        Command::Build(mut args) => {
+            let mut positional = mem::take(&mut args.test_filters).into_iter();
+            let device = positional.next().ok_or_else(|| {
+                Error::Args("`bootimage flash` requires the path to a device, e.g. /dev/sdb".into())
+            })?;
+            if let Some(arg) = positional.next() {
+                Err(Error::Args(format!(
+                    "unexpected argument `{}` (`bootimage flash` takes a single device)",
+                    arg
+                )))?
+            }
+            Command::Flash(FlashArgs {
+                args,
+                device: PathBuf::from(device),
+                yes,
+                verify,
+            })
+        }
+        Command::BuildHelp => Command::FlashHelp,
+        cmd => cmd,
+    })
+}
+
+fn parse_attach_args<A>(args: A) -> Result<Command, Error>
+where
+    A: Iterator<Item = String>,
+{
+    let mut log = None;
+    let mut build_args = Vec::new();
+    let mut arg_iter = args.into_iter();
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_ref() {
+            "--log" => {
+                let path = arg_iter
+                    .next()
+                    .ok_or_else(|| Error::Args("`--log` requires a path".into()))?;
+                log = Some(PathBuf::from(path));
+            }
+            _ if arg.starts_with("--log=") => {
+                log = Some(PathBuf::from(arg.trim_start_matches("--log=")));
+            }
+            "--" => Err(Error::Args(
+                "`bootimage attach` doesn't run the image, so it takes no run arguments".into(),
+            ))?,
+            _ => build_args.push(arg),
+        }
+    }
+
+    Ok(match parse_build_args(build_args.into_iter())? {
+        // the positional argument is the address of the console instead of a test filter
+        Command::Build(mut args) => {
+            let mut positional = mem::take(&mut args.test_filters).into_iter();
+            let address = positional.next();
+            if let Some(arg) = positional.next() {
+                Err(Error::Args(format!(
+                    "unexpected argument `{}` (`bootimage attach` takes a single address)",
+                    arg
+                )))?
+            }
+            Command::Attach(AttachArgs { args, address, log })
+        }
+        Command::BuildHelp => Command::AttachHelp,
+        cmd => cmd,
+    })
+}
+
+fn parse_watch_args<A>(args: A) -> Result<Command, Error>
+where
+    A: Iterator<Item = String>,
+{
+    let mut run = false;
+    let mut all_args = Vec::new();
+    // the build arguments are passed to `bootimage build` on every rebuild, without
+    // the options of the run
+    let mut build_args = Vec::new();
+    let mut arg_iter = args.into_iter();
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_ref() {
+            "--run" => run = true,
+            "--serial-port" => {
+                all_args.push(arg);
+                all_args.extend(arg_iter.next());
+            }
+            _ if arg.starts_with("--serial-port=") => all_args.push(arg),
+            "--" => {
+                all_args.push(arg);
+                all_args.extend(&mut arg_iter);
+            }
+            _ => {
+                build_args.push(arg.clone());
+                all_args.push(arg);
+            }
+        }
+    }
+
+    Ok(match parse_build_args(all_args.into_iter())? {
+        Command::Build(args) => {
+            if !run && !args.run_args.is_empty() {
+                Err(Error::Args(
+                    "run arguments require `--run`, which reruns the image after every build"
+                        .into(),
+                ))?
+            }
+            Command::Watch(WatchArgs {
+                args,
+                build_args,
+                run,
+            })
+        }
+        Command::BuildHelp => Command::WatchHelp,
+        cmd => cmd,
     })
 }
 
+fn parse_clean_args<A>(args: A) -> Result<Command, Error>
+where
+    A: Iterator<Item = String>,
+{
+    let mut all = false;
+    let mut cache = false;
+    let mut dry_run = false;
+    let mut build_args = Vec::new();
+    for arg in args {
+        match arg.as_ref() {
+            "--all" => all = true,
+            "--cache" => cache = true,
+            "--dry-run" => dry_run = true,
+            "--" => Err(Error::Args(
+                "`bootimage clean` doesn't run the image, so it takes no run arguments".into(),
+            ))?,
+            _ => build_args.push(arg),
+        }
+    }
+
+    Ok(match parse_build_args(build_args.into_iter())? {
+        Command::Build(args) => Command::Clean(CleanArgs {
+            args,
+            all,
+            cache,
+            dry_run,
+        }),
+        Command::BuildHelp => Command::CleanHelp,
+        cmd => cmd,
+    })
+}
+
+/// Parses the IP address that `bootimage serve` listens on.
+fn parse_ip_address(address: &str) -> Result<IpAddr, Error> {
+    address
+        .parse()
+        .map_err(|_| Error::Args(format!("`{}` is not an IP address", address)))
+}
+
+/// The output format of `bootimage test --list`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListFormat {
+    /// A human-readable list of the test kernels.
+    Human,
+    /// A JSON document for tools, e.g. for sharding the tests across machines.
+    Json,
+}
+
+impl FromStr for ListFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(ListFormat::Human),
+            "json" => Ok(ListFormat::Json),
+            other => Err(format!(
+                "unknown list format `{}` (expected `human` or `json`)",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Args {
     /// All arguments that are passed to cargo.
     pub cargo_args: Vec<String>,
@@ -119,6 +1197,90 @@ pub struct Args {
     release: bool,
     /// Whether the bootloader should be updated (not present in `cargo_args`).
     update_bootloader: bool,
+    /// The image format, overriding the configured one (not present in `cargo_args`).
+    format: Option<ImageFormat>,
+    /// The binary target that contains the kernel (also present in `cargo_args`).
+    bin: Option<String>,
+    /// The example target that contains the kernel (also present in `cargo_args`).
+    example: Option<String>,
+    /// The workspace member that contains the kernel (also present in `cargo_args`).
+    package: Option<String>,
+    /// The features of the kernel (also present in `cargo_args`).
+    features: Vec<String>,
+    /// Whether all features of the kernel are enabled (also present in `cargo_args`).
+    all_features: bool,
+    /// Whether the default features of the kernel are disabled (also present in `cargo_args`).
+    no_default_features: bool,
+    /// The features of the bootloader, overriding the configured ones (not present in `cargo_args`).
+    bootloader_features: Option<Vec<String>>,
+    /// The cargo profile (also present in `cargo_args`).
+    profile: Option<String>,
+    /// The target directory (also present in `cargo_args`).
+    target_dir: Option<PathBuf>,
+    /// Whether network access is forbidden (also present in `cargo_args`).
+    offline: bool,
+    /// The bootimage configuration file, overriding `bootimage.toml` (not present in `cargo_args`).
+    config: Option<PathBuf>,
+    /// The run profile that selects the run command (not present in `cargo_args`).
+    run_profile: Option<String>,
+    /// The timeout of the run command, overriding the configured one (not present in `cargo_args`).
+    timeout: Option<Duration>,
+    /// Whether the serial output is captured (not present in `cargo_args`).
+    capture_serial: bool,
+    /// Whether QEMU runs without hardware acceleration (not present in `cargo_args`).
+    no_accel: bool,
+    /// Whether QEMU runs deterministically and records the run (not present in
+    /// `cargo_args`).
+    deterministic: bool,
+    /// The display of QEMU, overriding the configured one (not present in `cargo_args`).
+    display: Option<Display>,
+    /// Whether the screenshots replace their golden images (not present in `cargo_args`).
+    update_screenshots: bool,
+    /// The SSH destination on which `bootimage run` runs the image (not present in
+    /// `cargo_args`).
+    remote: Option<String>,
+    /// The port of the serial console, overriding the configured one (not present in
+    /// `cargo_args`).
+    serial_port: Option<u16>,
+    /// Whether the disk image is built reproducibly (not present in `cargo_args`).
+    reproducible: bool,
+    /// Whether downloads without a pinned hash are used (not present in `cargo_args`).
+    allow_unverified_downloads: bool,
+    /// Whether the images are written fully allocated (not present in `cargo_args`).
+    no_sparse: bool,
+    /// The kernel command line, overriding the configured one (not present in `cargo_args`).
+    cmdline: Option<String>,
+    /// The filters that select the test kernels of `bootimage test` (not present in `cargo_args`).
+    test_filters: Vec<String>,
+    /// Whether the test filters must match the test names exactly (not present in `cargo_args`).
+    exact: bool,
+    /// Whether `bootimage test` only builds the test kernels (not present in `cargo_args`).
+    no_run: bool,
+    /// Whether `bootimage test` only runs the tests that failed in the last run (not present
+    /// in `cargo_args`).
+    failed: bool,
+    /// The debugger launched by `bootimage debug` (not present in `cargo_args`).
+    debugger: Option<DebuggerKind>,
+    /// The addresses that `bootimage addr2line` resolves (not present in `cargo_args`).
+    addresses: Vec<u64>,
+    /// The test kernel whose recording `bootimage replay` replays (not present in
+    /// `cargo_args`).
+    replay_test: Option<String>,
+    /// The symbols and address ranges that `bootimage objdump` disassembles (not present in
+    /// `cargo_args`).
+    selections: Vec<Selection>,
+    /// The mode in which `bootimage objdump` disassembles all code (not present in
+    /// `cargo_args`).
+    mode: Option<Mode>,
+    /// The format in which `bootimage test` lists the test kernels instead of running them
+    /// (not present in `cargo_args`).
+    list: Option<ListFormat>,
+    /// The number of parallel jobs (also present in `cargo_args`).
+    jobs: Option<usize>,
+    /// The format of the test report of `bootimage test` (not present in `cargo_args`).
+    report_format: Option<ReportFormat>,
+    /// The path of the test report of `bootimage test` (not present in `cargo_args`).
+    report_path: Option<PathBuf>,
 }
 
 impl Args {
@@ -130,18 +1292,271 @@ impl Args {
         &self.target
     }
 
+    pub fn target_dir(&self) -> &Option<PathBuf> {
+        &self.target_dir
+    }
+
     pub fn release(&self) -> bool {
         self.release
     }
 
+    pub fn profile(&self) -> &Option<String> {
+        &self.profile
+    }
+
+    pub fn offline(&self) -> bool {
+        self.offline
+    }
+
     pub fn update_bootloader(&self) -> bool {
         self.update_bootloader
     }
 
+    pub fn format(&self) -> Option<ImageFormat> {
+        self.format
+    }
+
+    pub fn bin(&self) -> &Option<String> {
+        &self.bin
+    }
+
+    pub fn example(&self) -> &Option<String> {
+        &self.example
+    }
+
+    pub fn package(&self) -> &Option<String> {
+        &self.package
+    }
+
+    pub fn features(&self) -> &[String] {
+        &self.features
+    }
+
+    pub fn all_features(&self) -> bool {
+        self.all_features
+    }
+
+    pub fn no_default_features(&self) -> bool {
+        self.no_default_features
+    }
+
+    pub fn bootloader_features(&self) -> &Option<Vec<String>> {
+        &self.bootloader_features
+    }
+
+    pub fn config(&self) -> &Option<PathBuf> {
+        &self.config
+    }
+
+    pub fn run_profile(&self) -> &Option<String> {
+        &self.run_profile
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn capture_serial(&self) -> bool {
+        self.capture_serial
+    }
+
+    pub fn no_accel(&self) -> bool {
+        self.no_accel
+    }
+
+    pub fn deterministic(&self) -> bool {
+        self.deterministic
+    }
+
+    pub fn display(&self) -> &Option<Display> {
+        &self.display
+    }
+
+    pub fn update_screenshots(&self) -> bool {
+        self.update_screenshots
+    }
+
+    pub fn remote(&self) -> Option<&str> {
+        self.remote.as_deref()
+    }
+
+    pub fn serial_port(&self) -> Option<u16> {
+        self.serial_port
+    }
+
+    pub fn reproducible(&self) -> bool {
+        self.reproducible
+    }
+
+    pub fn allow_unverified_downloads(&self) -> bool {
+        self.allow_unverified_downloads
+    }
+
+    pub fn no_sparse(&self) -> bool {
+        self.no_sparse
+    }
+
+    pub fn cmdline(&self) -> &Option<String> {
+        &self.cmdline
+    }
+
+    pub fn test_filters(&self) -> &[String] {
+        &self.test_filters
+    }
+
+    pub fn exact(&self) -> bool {
+        self.exact
+    }
+
+    pub fn no_run(&self) -> bool {
+        self.no_run
+    }
+
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
+    pub fn debugger(&self) -> Option<DebuggerKind> {
+        self.debugger
+    }
+
+    pub fn addresses(&self) -> &[u64] {
+        &self.addresses
+    }
+
+    pub fn replay_test(&self) -> Option<&str> {
+        self.replay_test.as_deref()
+    }
+
+    pub fn selections(&self) -> &[Selection] {
+        &self.selections
+    }
+
+    pub fn mode(&self) -> Option<Mode> {
+        self.mode
+    }
+
+    pub fn list(&self) -> Option<ListFormat> {
+        self.list
+    }
+
+    pub fn jobs(&self) -> Option<usize> {
+        self.jobs
+    }
+
+    pub fn report_format(&self) -> Option<ReportFormat> {
+        self.report_format
+    }
+
+    pub fn report_path(&self) -> &Option<PathBuf> {
+        &self.report_path
+    }
+
     pub fn set_target(&mut self, target: String) {
         assert!(self.target.is_none());
         self.target = Some(target.clone());
         self.cargo_args.push("--target".into());
         self.cargo_args.push(target);
     }
+
+    /// Sets the binary target, replacing a binary target that was set through this method.
+    pub fn set_bin(&mut self, bin: String) {
+        if let Some(previous) = self.bin.replace(bin.clone()) {
+            let index = self
+                .cargo_args
+                .windows(2)
+                .position(|args| args[0] == "--bin" && args[1] == previous)
+                .expect("binary target must be set through `set_bin`");
+            self.cargo_args.drain(index..index + 2);
+        }
+        self.cargo_args.push("--bin".into());
+        self.cargo_args.push(bin);
+    }
+}
+
+pub struct RunnerArgs {
+    /// The kernel executable built by cargo.
+    pub executable: PathBuf,
+    /// All arguments that are passed to the runner.
+    pub run_args: Vec<String>,
+}
+
+pub struct InspectArgs {
+    /// The disk image that is inspected.
+    pub image: PathBuf,
+    /// Whether the layout is printed as JSON instead of text.
+    pub json: bool,
+}
+
+pub struct ExtractArgs {
+    /// The disk image that contains the kernel.
+    pub image: PathBuf,
+    /// The path that the kernel ELF file is written to.
+    pub kernel: Option<PathBuf>,
+    /// The path that the bootloader is written to.
+    pub bootloader: Option<PathBuf>,
+}
+
+pub struct ServeArgs {
+    /// The build arguments.
+    pub args: Args,
+    /// The unparsed build arguments, which are passed to `bootimage build`.
+    pub build_args: Vec<String>,
+    /// The IP address that the servers listen on.
+    pub address: IpAddr,
+    /// The port of the HTTP server.
+    pub http_port: u16,
+    /// The port of the TFTP server.
+    pub tftp_port: u16,
+    /// Whether the image is rebuilt as soon as the sources change.
+    pub watch: bool,
+    /// The URL of the HTTP server in the iPXE script, e.g. behind a proxy.
+    pub base_url: Option<String>,
+}
+
+pub struct FlashArgs {
+    /// The build arguments.
+    pub args: Args,
+    /// The block device that the image is written to.
+    pub device: PathBuf,
+    /// Whether the device is overwritten without asking for confirmation.
+    pub yes: bool,
+    /// Whether the device is read back and compared with the image.
+    pub verify: bool,
+}
+
+pub struct AttachArgs {
+    /// The build arguments, which select the configuration of the console.
+    pub args: Args,
+    /// The address of the console, e.g. `4555` or `host:4555`.
+    pub address: Option<String>,
+    /// The file that the output of the console is appended to.
+    pub log: Option<PathBuf>,
+}
+
+pub struct WatchArgs {
+    /// The build and run arguments.
+    pub args: Args,
+    /// The unparsed build arguments, which are passed to `bootimage build`.
+    pub build_args: Vec<String>,
+    /// Whether the image is run after every successful build.
+    pub run: bool,
+}
+
+pub struct CleanArgs {
+    /// The build arguments, which select the artifacts.
+    pub args: Args,
+    /// Whether `cargo clean` is run as well.
+    pub all: bool,
+    /// Whether the user-level cache is removed as well.
+    pub cache: bool,
+    /// Whether the artifacts are only listed instead of removed.
+    pub dry_run: bool,
+}
+
+pub struct VerifyArgs {
+    /// The disk image that is verified.
+    pub image: PathBuf,
+    /// The minisign public key that the signature of the image is verified with.
+    pub public_key: Option<PathBuf>,
 }
@@ -0,0 +1,120 @@
+//! Runs the disk image in the Bochs emulator.
+//!
+//! Bochs emulates the PC instruction by instruction, which is slow, but its
+//! debugger can single-step the boot sector and the switches to protected and long
+//! mode, where the GDB server of QEMU is of little help. The `bochsrc` that
+//! configures the emulated machine is generated into the `bochs` directory of the
+//! output directory on every run, so that Bochs can also be started by hand (e.g.
+//! `bochs -f target/x86_64-os/debug/bochs/bochsrc`). The first serial port writes
+//! to `serial.log` and the log of Bochs is written to `bochs.log` in that
+//! directory, while the terminal belongs to the debugger.
+
+use build::find_executable;
+use config::{Config, Firmware, ImageFormat};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use Error;
+
+/// The file that the first serial port writes to, relative to the Bochs directory.
+const SERIAL_FILE: &str = "serial.log";
+/// The file that Bochs logs to, relative to the Bochs directory.
+const LOG_FILE: &str = "bochs.log";
+
+/// The directory that the `bochsrc` of the image is written to.
+pub(crate) fn bochs_dir(out_dir: &Path) -> PathBuf {
+    out_dir.join("bochs")
+}
+
+/// The file that the serial output of the emulated machine is written to, which
+/// Bochs creates when it starts.
+pub(crate) fn serial_path(bochs_dir: &Path) -> PathBuf {
+    bochs_dir.join(SERIAL_FILE)
+}
+
+/// The file that Bochs logs to, e.g. the reason of a panic.
+pub(crate) fn log_path(bochs_dir: &Path) -> PathBuf {
+    bochs_dir.join(LOG_FILE)
+}
+
+/// Writes the `bochsrc` for the image to `bochs_dir` and prepares the invocation of
+/// Bochs with it.
+pub(crate) fn command(config: &Config, bochs_dir: &Path) -> Result<Command, Error> {
+    if config.format == ImageFormat::Multiboot2 {
+        return Err(Error::Config(
+            "Bochs can't boot Multiboot2 kernels directly, use the `grub-iso` or `limine` \
+             format to boot them"
+                .into(),
+        ));
+    }
+    if config.firmware == Firmware::Uefi {
+        return Err(Error::Config(
+            "Bochs only has BIOS firmware, use `firmware = \"bios\"` with the `bochs` run \
+             backend"
+                .into(),
+        ));
+    }
+    // the Windows builds with the debugger are called `bochsdbg`
+    let program = Some("bochsdbg")
+        .filter(|_| config.bochs.magic_break)
+        .and_then(|name| find_executable(name, &[]))
+        .or_else(|| find_executable("bochs", &[]))
+        .ok_or_else(|| {
+            Error::Run(
+                "`bochs` was not found in `PATH`, install it or use the `qemu` run backend".into(),
+            )
+        })?;
+
+    fs::create_dir_all(bochs_dir)?;
+    // Bochs resolves relative paths against its own working directory
+    let bochs_dir = bochs_dir.canonicalize()?;
+    let serial = serial_path(&bochs_dir);
+    if serial.exists() {
+        fs::remove_file(&serial)?;
+    }
+    let memory_mib = config.bochs.memory / (1024 * 1024);
+    let mut lines = vec![
+        "# generated by bootimage, changes are overwritten by the next run".to_owned(),
+        format!("memory: guest={}, host={}", memory_mib, memory_mib),
+        "ata0: enabled=1, ioaddr1=0x1f0, ioaddr2=0x3f0, irq=14".into(),
+    ];
+    match config.format {
+        ImageFormat::GrubIso | ImageFormat::Isolinux => {
+            let iso = config.image_path().canonicalize()?;
+            lines.push(format!(
+                "ata0-master: type=cdrom, path={}, status=inserted",
+                quote(&iso)
+            ));
+            lines.push("boot: cdrom".into());
+        }
+        _ => {
+            let raw_image = config.raw_image_path().canonicalize()?;
+            lines.push(format!("ata0-master: type=disk, path={}, mode=flat", quote(&raw_image)));
+            lines.push("boot: disk".into());
+        }
+    }
+    lines.push(format!("com1: enabled=1, mode=file, dev={}", quote(&serial)));
+    lines.push(format!("log: {}", quote(&log_path(&bochs_dir))));
+    // a panic of the emulated hardware ends the run instead of asking how to continue
+    lines.push("panic: action=fatal".into());
+    lines.push("error: action=report".into());
+    lines.push("info: action=ignore".into());
+    if config.bochs.headless {
+        lines.push("display_library: nogui".into());
+    }
+    if config.bochs.magic_break {
+        lines.push("magic_break: enabled=1".into());
+    }
+    let bochsrc = bochs_dir.join("bochsrc");
+    fs::write(&bochsrc, lines.join("\n") + "\n")?;
+
+    let mut command = Command::new(program);
+    // `-q` skips the start menu
+    command.arg("-q").arg("-f").arg(bochsrc);
+    Ok(command)
+}
+
+/// Quotes a path for the `bochsrc`.
+fn quote(path: &Path) -> String {
+    format!("\"{}\"", path.display())
+}
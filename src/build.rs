@@ -1,164 +1,3622 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::{self, File};
-use std::{env, io, process};
+use std::{env, io, iter, process, slice, thread};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use std::io::{BufRead, BufReader};
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
-use byteorder::{ByteOrder, LittleEndian};
-use args::{self, Args};
-use config::{self, Config};
-use cargo_metadata::{self, Metadata as CargoMetadata, Package as CrateMetadata};
+use accel;
+use bochs;
+use args::{
+    self, Args, AttachArgs, CleanArgs, ExtractArgs, FlashArgs, InspectArgs, ListFormat, RunnerArgs,
+    ServeArgs, VerifyArgs, WatchArgs,
+};
+use config::{
+    self, BuildCommand, Config, Display, Firmware, ImageFormat, KernelCompression, LimineProtocol,
+    Linking, MenuEntry, MenuKernel, QemuConfig, RunBackend,
+};
+use console;
+use crc32;
+use debug::{self, DebuggerKind};
+use cargo_metadata::{self, Metadata as CargoMetadata, Package as CrateMetadata, Target};
+use export;
+use flash;
+use grub;
+use hyperv;
+use image::{self, BootEntry};
+use inspect;
+use ipxe;
+use iso;
+use limine;
+use map;
+use microvm;
+use multiboot2;
+use network::{self, Network};
+use objdump;
+use ovmf;
+use qcow2;
+use qmp;
+use remote::Remote;
+use replay;
+use report::{self, ReportFormat, TestCase};
+use screenshot;
+use serve;
+use serde_json::{self, Value as JsonValue};
+use sha256;
+use size::{self, Bytes, Composition};
+use sparse;
+use strip;
+use symbolize::{BacktraceScanner, Symbolizer};
+use syslinux;
+use validate;
+use verify::{self, Outcome};
+use virtualbox;
+use vmware;
+use watch;
 use Error;
 use xmas_elf;
 use tempdir::TempDir;
+use toml::Value;
 
-const BLOCK_SIZE: usize = 512;
-type KernelInfoBlock = [u8; BLOCK_SIZE];
+/// Arguments appended to the run command by `bootimage test`. The device allows
+/// the kernel to exit QEMU by writing its exit code to I/O port `0xf4`.
+const TEST_RUN_ARGS: &[&str] = &["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"];
+
+/// Arguments appended to the run command by `bootimage debug`. QEMU starts its GDB
+/// server on port 1234 and halts the CPU until the debugger continues it.
+const DEBUG_RUN_ARGS: &[&str] = &["-s", "-S"];
+
+/// QEMU exits with status `(value << 1) | 1` when the kernel writes `value` to
+/// the `isa-debug-exit` port, so a kernel reports success by writing `0`.
+const TEST_SUCCESS_EXIT_CODE: i32 = 1;
+
+/// The file in the output directory that stores the outcome of the last test run.
+const TEST_RESULTS_FILE: &str = "bootimage-test-results.json";
+
+/// The files and directories that bootimage writes next to the disk image, besides
+/// the ones that are named after it.
+const IMAGE_DIR_ARTIFACTS: &[&str] = &[
+    "kernel.elf",
+    "kernel.elf.lz4",
+    "kernel.elf.gz",
+    "kernel.debug",
+    "bootloader.elf",
+    "boot.ipxe",
+    "OVMF_VARS.fd",
+    "limine.tar.gz",
+    "virtualbox.vdi",
+    "virtualbox.serial",
+    "hyperv.vhd",
+    "firecracker.json",
+    "firecracker.log",
+    "grub-iso",
+    "isolinux",
+    "kernels",
+    "reproducible-check",
+    "replay",
+    "screenshots",
+    "ovmf",
+    "limine",
+    "bochs",
+    "vmware",
+];
+
+/// The number of symbols that `bootimage size` lists.
+const LARGEST_SYMBOLS: usize = 20;
 
 pub(crate) fn build(args: Args) -> Result<(), Error> {
     let (args, config, metadata, out_dir) = common_setup(args)?;
+    let variables = build_variables(&args, &config, &metadata, &out_dir);
+
+    build_impl(&args, &config, &metadata, &out_dir, &variables)
+}
+
+pub(crate) fn check(args: Args) -> Result<(), Error> {
+    check_kernel(args, "check")
+}
+
+pub(crate) fn clippy(args: Args) -> Result<(), Error> {
+    check_kernel(args, "clippy")
+}
+
+/// Runs the cargo `subcommand` (`check` or `clippy`) on the kernel like its build,
+/// i.e. with the build command, the default target, and the `RUST_TARGET_PATH` of
+/// `bootimage build`, but without creating a disk image.
+fn check_kernel(args: Args, subcommand: &str) -> Result<(), Error> {
+    let (args, config, _, _) = common_setup(args)?;
+    let mut command = cargo_command(&config, subcommand, &env::current_dir()?, &args.cargo_args);
+    // e.g. the lint levels of clippy
+    if !args.run_args.is_empty() {
+        command.arg("--").args(&args.run_args);
+    }
+    let status = command.status()?;
+    if !status.success() {
+        process::exit(status.code().unwrap_or(1));
+    }
+    Ok(())
+}
+
+pub(crate) fn run(args: Args) -> Result<(), Error> {
+    let (args, config, metadata, out_dir) = common_setup(args)?;
+    let variables = build_variables(&args, &config, &metadata, &out_dir);
+
+    if config.deterministic && config.run_backend != RunBackend::Qemu {
+        return Err(Error::Config(
+            "deterministic runs require the `qemu` run backend, whose record/replay mode \
+             records them"
+                .into(),
+        ));
+    }
+
+    build_impl(&args, &config, &metadata, &out_dir, &variables)?;
+    if let Some(port) = config.serial_port {
+        console::start(port)?;
+    }
+    if let Some(host) = args.remote() {
+        if config.run_backend != RunBackend::Qemu {
+            return Err(Error::Config(
+                "`--remote` requires the `qemu` run backend, whose run command is started on \
+                 the remote machine"
+                    .into(),
+            ));
+        }
+        run_remote(
+            host,
+            &args.run_args,
+            &config,
+            args.run_profile().as_deref(),
+            &variables,
+        )?;
+        return Ok(());
+    }
+    match config.run_backend {
+        RunBackend::Qemu => {
+            let result = run_impl(
+                &args.run_args,
+                &config,
+                args.run_profile().as_deref(),
+                &variables,
+                &[],
+                None,
+            );
+            // a hung kernel is worth replaying, too
+            if config.deterministic {
+                println!(
+                    "Recorded the run in {}, replay it with `bootimage replay`",
+                    replay::recording_dir(&config, &outdir(&config)).display()
+                );
+            }
+            result?;
+        }
+        RunBackend::VirtualBox | RunBackend::Vmware | RunBackend::HyperV => run_vm(
+            &args.run_args,
+            &config,
+            args.run_profile().as_deref(),
+            &variables,
+        )?,
+        RunBackend::Firecracker | RunBackend::CloudHypervisor => {
+            run_microvm(
+                &args.run_args,
+                &config,
+                args.run_profile().as_deref(),
+                &variables,
+            )?;
+        }
+        RunBackend::Bochs => run_bochs(
+            &args.run_args,
+            &config,
+            args.run_profile().as_deref(),
+            &variables,
+        )?,
+    }
+    Ok(())
+}
+
+pub(crate) fn debug(args: Args) -> Result<(), Error> {
+    let (args, mut config, metadata, out_dir) = common_setup(args)?;
+    if config.run_backend != RunBackend::Qemu {
+        return Err(Error::Config(
+            "`bootimage debug` requires the `qemu` run backend, whose GDB server it connects to"
+                .into(),
+        ));
+    }
+    // bootimage is replaced by the debugger, so nobody would read the serial output
+    config.capture_serial = false;
+    // the GDB server of QEMU doesn't support HVF and WHPX, and breakpoints and single
+    // steps are only reliable with TCG
+    config.accel = false;
+    // a debugging session would replace the recording of the last run
+    config.deterministic = false;
+    let variables = build_variables(&args, &config, &metadata, &out_dir);
+
+    build_impl(&args, &config, &metadata, &out_dir, &variables)?;
+    let (mut command, _qmp) = run_command(
+        &args.run_args,
+        &config,
+        args.run_profile().as_deref(),
+        &variables,
+        DEBUG_RUN_ARGS,
+        None,
+    )?;
+    // the debugger owns the terminal, so Ctrl-C must only interrupt the kernel
+    command.stdin(process::Stdio::null());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+    let mut child = spawn_run(&mut command)?;
+    println!("Waiting for a debugger on port {}", debug::GDB_PORT);
+    let debugger = args.debugger().unwrap_or(DebuggerKind::Gdb);
+    // a stripped kernel has no symbols, so the debugger loads the full one
+    let kernel = if config.strip_kernel { "kernel.debug" } else { "kernel.elf" };
+    let result = debug::launch(debugger, &outdir(&config).join(kernel));
+    // only reached if the debugger couldn't replace bootimage or has exited
+    let _ = child.kill();
+    child.wait()?;
+    result
+}
+
+pub(crate) fn replay(args: Args) -> Result<(), Error> {
+    let (args, config, metadata, out_dir) = common_setup(args)?;
+    let mut config = match args.replay_test() {
+        Some(test) if test.starts_with(&config.test_prefix) => test_config(&config, test),
+        Some(test) => test_config(&config, &format!("{}{}", config.test_prefix, test)),
+        None => config,
+    };
+    // the replay shows the recorded run instead of checking it again
+    config.screenshots = Default::default();
+    let variables = build_variables(&args, &config, &metadata, &out_dir);
+
+    let qmp = qmp::Socket::new()?;
+    let command = replay::command(&config, &outdir(&config), &qmp)?;
+    println!(
+        "Replaying the run recorded in {}",
+        replay::recording_dir(&config, &outdir(&config)).display()
+    );
+    let status = run_process(command, Some(&qmp), &config, &variables, None, None)?;
+    println!("Replay finished with {}", status);
+    Ok(())
+}
+
+pub(crate) fn addr2line(args: Args) -> Result<(), Error> {
+    use std::io::Read;
+
+    let (args, config, metadata, _) = common_setup(args)?;
+    let mut elf = Vec::new();
+    build_kernel(&args, &config, &metadata)?.read_to_end(&mut elf)?;
+    let symbolizer = Symbolizer::new(&elf).map_err(Error::Kernel)?;
+    if !symbolizer.has_line_info() {
+        eprintln!("Warning: the kernel has no line number information (`debug = true` is missing)");
+    }
+
+    let mut addresses = args.addresses().to_vec();
+    if addresses.is_empty() {
+        // like `addr2line`, the addresses are read from stdin if none are given
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            for address in line?.split_whitespace() {
+                addresses.push(args::parse_address(address)?);
+            }
+        }
+    }
+    for address in addresses {
+        println!("{}", symbolizer.locate(address));
+    }
+    Ok(())
+}
+
+pub(crate) fn objdump(args: Args) -> Result<(), Error> {
+    use std::io::Read;
+
+    let (args, config, metadata, _) = common_setup(args)?;
+    let mut elf = Vec::new();
+    build_kernel(&args, &config, &metadata)?.read_to_end(&mut elf)?;
+    let disassembly =
+        objdump::disassemble(&elf, args.selections(), args.mode()).map_err(Error::Kernel)?;
+    print!("{}", disassembly);
+    Ok(())
+}
+
+pub(crate) fn size(args: Args) -> Result<(), Error> {
+    let (args, config, metadata, out_dir) = common_setup(args)?;
+    let variables = build_variables(&args, &config, &metadata, &out_dir);
+    build_impl(&args, &config, &metadata, &out_dir, &variables)?;
+
+    let kernel = fs::read(outdir(&config).join("kernel.elf"))?;
+    let sections = size::sections(&kernel).map_err(Error::Kernel)?;
+    let loaded: u64 = sections
+        .iter()
+        .filter(|section| section.loaded)
+        .map(|section| section.size)
+        .sum();
+    println!();
+    println!(
+        "Kernel sections ({} loaded, {} file):",
+        Bytes(loaded),
+        Bytes(kernel.len() as u64)
+    );
+    for section in &sections {
+        if section.loaded {
+            println!(
+                "    {:<24} {:#018x} {:>10} {:>6.1}%",
+                section.name,
+                section.address,
+                Bytes(section.size),
+                size::percent(section.size, loaded)
+            );
+        } else {
+            println!("    {:<24} {:>18} {:>10}", section.name, "-", Bytes(section.size));
+        }
+    }
+
+    // a stripped kernel has no symbols, so they are read from the full one
+    let symbols = if config.strip_kernel {
+        fs::read(outdir(&config).join("kernel.debug"))?
+    } else {
+        kernel
+    };
+    let symbols = size::largest_symbols(&symbols, LARGEST_SYMBOLS).map_err(Error::Kernel)?;
+    println!();
+    if symbols.is_empty() {
+        println!("The kernel has no symbols");
+    } else {
+        println!("Largest symbols:");
+        for symbol in &symbols {
+            println!(
+                "    {:>10} {:>6.1}%  {}",
+                Bytes(symbol.size),
+                size::percent(symbol.size, loaded),
+                symbol.name
+            );
+        }
+    }
+
+    println!();
+    match image_composition(&config)? {
+        Some(composition) => {
+            let total = composition.total();
+            println!("Disk image {} ({}):", config.output.display(), Bytes(total));
+            for &(part, size) in &[
+                ("bootloader", composition.bootloader),
+                ("info block", composition.info_block),
+                ("kernel", composition.kernel),
+                ("padding", composition.padding),
+            ] {
+                let share = size::percent(size, total);
+                println!("    {:<24} {:>10} {:>6.1}%", part, Bytes(size), share);
+            }
+        }
+        None => {
+            let total = fs::metadata(&config.output)?.len();
+            println!("Disk image {} ({})", config.output.display(), Bytes(total));
+            println!("    (only images with an appended kernel are broken down)");
+        }
+    }
+    Ok(())
+}
+
+/// Prints the layout of an existing disk image without building anything.
+pub(crate) fn inspect(args: InspectArgs) -> Result<(), Error> {
+    let layout = inspect::layout(&args.image).map_err(Error::Image)?;
+    let block_size = image::BLOCK_SIZE as u64;
+    let metadata = layout.metadata.as_ref().map(|metadata| {
+        metadata
+            .iter()
+            .map(|(key, value)| (key.clone(), json!(value)))
+            .collect::<serde_json::Map<_, _>>()
+    });
+    if args.json {
+        println!(
+            "{}",
+            json!({
+                "image": args.image,
+                "container": layout.container,
+                "disk_offset": layout.disk_offset,
+                "total_size": layout.total,
+                "boot_signature": layout.boot_signature,
+                "gpt": layout.gpt,
+                "mbr_partitions": layout.mbr_partitions.iter().map(|partition| json!({
+                    "active": partition.active,
+                    "type": partition.partition_type,
+                    "offset": layout.disk_offset + partition.first_lba * block_size,
+                    "size": partition.sectors * block_size,
+                })).collect::<Vec<_>>(),
+                "bootloader": { "offset": layout.disk_offset, "size": layout.bootloader },
+                "info_block": {
+                    "offset": layout.info_block_offset(),
+                    "size": block_size,
+                    "version": layout.info_block_version,
+                    "flags": layout.info_block_flags,
+                    "kernel_size": layout.kernel_size,
+                    "kernel_crc": layout.kernel_crc,
+                },
+                "kernel": {
+                    "offset": layout.kernel_offset(),
+                    "size": layout.kernel_size,
+                    "class": layout.kernel_class,
+                    "entry": layout.kernel_entry,
+                    "compression": layout.compression.map(|(compression, size)| json!({
+                        "algorithm": compression.to_string(),
+                        "uncompressed_size": size,
+                    })),
+                },
+                "initrd": layout.initrd.map(|(offset, size)| json!({
+                    "offset": offset,
+                    "size": size,
+                })),
+                "data_partition": layout.data_partition.map(|(offset, size)| json!({
+                    "offset": offset,
+                    "size": size,
+                })),
+                "padding": { "offset": layout.padding_offset(), "size": layout.padding() },
+                "cmdline": layout.cmdline,
+                "metadata": metadata,
+            })
+        );
+        return Ok(());
+    }
+
+    println!(
+        "Disk image {} ({}, {}):",
+        args.image.display(),
+        layout.container,
+        Bytes(layout.total)
+    );
+    let boot_signature = if layout.boot_signature {
+        "present"
+    } else {
+        "missing"
+    };
+    let partition_table = if layout.gpt {
+        "GPT (hybrid image)".to_owned()
+    } else if layout.mbr_partitions.is_empty() {
+        "none".to_owned()
+    } else {
+        let partitions: Vec<String> = layout
+            .mbr_partitions
+            .iter()
+            .map(|partition| {
+                let active = if partition.active { ", active" } else { "" };
+                format!(
+                    "type {:#04x} at {:#x}{}",
+                    partition.partition_type,
+                    layout.disk_offset + partition.first_lba * block_size,
+                    active
+                )
+            })
+            .collect();
+        format!("MBR ({})", partitions.join("; "))
+    };
+    println!("    boot signature: {}", boot_signature);
+    println!("    partition table: {}", partition_table);
+    println!();
+    println!("    {:<18} {:>10}  Part", "Offset", "Size");
+    if layout.disk_offset > 0 {
+        let headers = Bytes(layout.disk_offset);
+        println!("    {:#018x} {:>10}  ISO 9660 headers", 0, headers);
+    }
+    println!(
+        "    {:#018x} {:>10}  bootloader",
+        layout.disk_offset,
+        Bytes(layout.bootloader)
+    );
+    let kernel_crc = match layout.kernel_crc {
+        Some(crc) => format!(", CRC-32: {:#010x}", crc),
+        None => String::new(),
+    };
+    println!(
+        "    {:#018x} {:>10}  kernel info block (v{}, kernel size: {} bytes{})",
+        layout.info_block_offset(),
+        Bytes(block_size),
+        layout.info_block_version,
+        layout.kernel_size,
+        kernel_crc
+    );
+    let compression = match layout.compression {
+        Some((compression, size)) => format!(", {} from {}", compression, Bytes(u64::from(size))),
+        None => String::new(),
+    };
+    println!(
+        "    {:#018x} {:>10}  kernel (ELF{}, entry point {:#x}{})",
+        layout.kernel_offset(),
+        Bytes(u64::from(layout.kernel_size)),
+        layout.kernel_class,
+        layout.kernel_entry,
+        compression
+    );
+    // the gaps between the parts behind the kernel are padding
+    let mut end = layout.padding_offset();
+    let parts = [
+        (layout.initrd, "initrd"),
+        (layout.data_partition, "data partition"),
+    ];
+    for &(part, name) in &parts {
+        if let Some((offset, size)) = part {
+            if offset > end {
+                println!("    {:#018x} {:>10}  padding", end, Bytes(offset - end));
+            }
+            println!("    {:#018x} {:>10}  {}", offset, Bytes(size), name);
+            end = offset + size;
+        }
+    }
+    if layout.total > end || end == layout.padding_offset() {
+        println!(
+            "    {:#018x} {:>10}  padding",
+            end,
+            Bytes(layout.total - end)
+        );
+    }
+    if let Some(ref cmdline) = layout.cmdline {
+        println!();
+        println!("    Kernel command line: {}", cmdline);
+    }
+    if let Some(ref metadata) = layout.metadata {
+        println!();
+        println!("    Build metadata:");
+        for (key, value) in metadata {
+            println!("    {:<10} {}", key, value);
+        }
+    }
+    Ok(())
+}
+
+/// Builds the image and serves it (with the netboot artifacts) over TFTP and HTTP.
+pub(crate) fn serve(args: ServeArgs) -> Result<(), Error> {
+    let build_args = &args.build_args;
+    let (cargo_args, config, metadata, out_dir) = common_setup(args.args)?;
+    // a failed build (which exits bootimage) or a changed configuration doesn't
+    // affect the servers
+    let build = || rebuild(build_args);
+
+    // the disk images of these formats have a kernel info block at the same place
+    let replaces_cmdline = match config.format {
+        ImageFormat::Raw | ImageFormat::Iso | ImageFormat::Hybrid | ImageFormat::Qcow2 => {
+            config.bootloader.linking == Linking::Append
+        }
+        _ => false,
+    };
+    let mut files = BTreeMap::new();
+    for path in &[config.image_path(), config.output.clone()] {
+        let name = path.file_name().expect("output is a file").to_string_lossy();
+        // the layout of qcow2 images can't be read, but the raw disk image is served too
+        let qcow2 = config.format == ImageFormat::Qcow2 && *path == config.image_path();
+        let served = serve::ServedFile {
+            path: path.clone(),
+            replaces_cmdline: replaces_cmdline && !qcow2,
+        };
+        files.insert(name.into_owned(), served);
+    }
+    let mut serve_file = |name: String, path: PathBuf| {
+        let served = serve::ServedFile {
+            path,
+            replaces_cmdline: false,
+        };
+        files.insert(name, served);
+    };
+    serve_file("kernel.elf".into(), outdir(&config).join("kernel.elf"));
+    if let Some(ref initrd) = config.initrd {
+        serve_file("initrd".into(), initrd.clone());
+    }
+    for module in &config.multiboot_modules {
+        if let Some(name) = module.file_name() {
+            serve_file(format!("modules/{}", name.to_string_lossy()), module.clone());
+        }
+    }
+
+    let netboot_image = match config.format {
+        ImageFormat::Multiboot2 => None,
+        _ => Some(config.raw_image_path()),
+    };
+    match netboot_image {
+        Some(image) => {
+            let (address, http_port) = (args.address, args.http_port);
+            let base_url = args.base_url.clone().unwrap_or_else(|| {
+                // iPXE sets `next-server` to the TFTP server that it was loaded from
+                let host = serve::host_address(address)
+                    .map(|address| match address {
+                        IpAddr::V6(address) => format!("[{}]", address),
+                        address => address.to_string(),
+                    })
+                    .unwrap_or_else(|| "${next-server}".into());
+                format!("http://{}:{}", host, http_port)
+            });
+            let name = image.file_name().expect("output is a file").to_string_lossy();
+            let script = ipxe::script(
+                &default_menu_title(&config),
+                &base_url,
+                &name,
+                replaces_cmdline,
+                config.kernel_cmdline.as_deref(),
+            );
+            let script_path = outdir(&config).join("boot.ipxe");
+            fs::create_dir_all(outdir(&config))?;
+            fs::write(&script_path, script)?;
+            serve_file("boot.ipxe".into(), script_path);
+        }
+        None => eprintln!(
+            "Warning: no iPXE script is generated, since iPXE can't boot Multiboot2 kernels \
+             (use the `grub-iso` format to boot them over the network)"
+        ),
+    }
+    let target_dir = target_dir(&cargo_args, &metadata);
+    let sources = watched_sources(&config, &metadata, &out_dir, &target_dir)?;
+    let options = serve::Options {
+        address: args.address,
+        http_port: args.http_port,
+        tftp_port: args.tftp_port,
+        watch: args.watch,
+    };
+    serve::serve(files, sources, build, &options)
+}
+
+pub(crate) fn flash(args: FlashArgs) -> Result<(), Error> {
+    let (cargo_args, config, metadata, out_dir) = common_setup(args.args)?;
+    let image = match config.format {
+        ImageFormat::Multiboot2 => Err(Error::Config(
+            "`multiboot2` images are kernels for other bootloaders and can't be flashed (use \
+             the `grub-iso` or `limine` format to boot them from a USB stick)"
+                .into(),
+        ))?,
+        ImageFormat::Isolinux => Err(Error::Config(
+            "`isolinux` images only boot from CDs, use the `syslinux` format to flash them to \
+             a USB stick"
+                .into(),
+        ))?,
+        _ => config.raw_image_path(),
+    };
+    let variables = build_variables(&cargo_args, &config, &metadata, &out_dir);
+    build_impl(&cargo_args, &config, &metadata, &out_dir, &variables)?;
+    flash::flash(&image, &args.device, args.yes, args.verify)
+}
+
+pub(crate) fn attach(args: AttachArgs) -> Result<(), Error> {
+    let address = match args.address {
+        Some(address) => address,
+        None => {
+            let (_, config, _, _) = common_setup(args.args)?;
+            let port = config.serial_port.ok_or_else(|| {
+                Error::Config(
+                    "`bootimage attach` requires the address of the serial console or a \
+                     `serial-port` in the configuration"
+                        .into(),
+                )
+            })?;
+            port.to_string()
+        }
+    };
+    // a bare port is a console on localhost
+    let address = match address.parse::<u16>() {
+        Ok(port) => format!("localhost:{}", port),
+        Err(_) => address,
+    };
+    console::attach(&address, args.log.as_deref())
+}
+
+/// Rebuilds the image whenever the sources change, and reruns it after every
+/// successful build with `--run`.
+pub(crate) fn watch(args: WatchArgs) -> Result<(), Error> {
+    let (cargo_args, config, metadata, out_dir) = common_setup(args.args.clone())?;
+    if args.run && config.run_backend != RunBackend::Qemu {
+        return Err(Error::Config(
+            "`bootimage watch --run` requires the `qemu` run backend, whose run command it \
+             restarts"
+                .into(),
+        ));
+    }
+    let target_dir = target_dir(&cargo_args, &metadata);
+    let sources = watched_sources(&config, &metadata, &out_dir, &target_dir)?;
+    println!("Watching {} for changes (press Ctrl-C to stop)", sources.dir.display());
+
+    loop {
+        // changes during the build trigger the next one
+        let fingerprint = watch::fingerprint(&sources);
+        let start = Instant::now();
+        let built = rebuild(&args.build_args);
+        if built {
+            let next = if args.run { "running the image" } else { "waiting for changes" };
+            println!("Build finished in {:.1}s, {}", start.elapsed().as_secs_f64(), next);
+        } else {
+            println!("Build failed, waiting for changes");
+        }
+        let stop = AtomicBool::new(false);
+        thread::scope(|scope| {
+            if built && args.run {
+                scope.spawn(|| {
+                    let result = run_watched(args.args.clone(), &stop);
+                    if stop.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    match result {
+                        Ok(status) => println!(
+                            "The run command exited ({}), waiting for changes",
+                            status
+                        ),
+                        Err(err) => eprintln!("Error: {:?}, waiting for changes", err),
+                    }
+                });
+            }
+            watch::wait_for_change(&sources, fingerprint);
+            stop.store(true, Ordering::SeqCst);
+        });
+        println!("The sources have changed, rebuilding");
+    }
+}
+
+/// Runs the image that `bootimage watch` has built until it exits or `stop` is set.
+///
+/// The configuration is read again, since the change that triggered the build may
+/// have changed it.
+fn run_watched(args: Args, stop: &AtomicBool) -> Result<process::ExitStatus, Error> {
+    let (args, config, metadata, out_dir) = common_setup(args)?;
+    let variables = build_variables(&args, &config, &metadata, &out_dir);
+    if let Some(port) = config.serial_port {
+        console::start(port)?;
+    }
+    let (command, qmp) = run_command(
+        &args.run_args,
+        &config,
+        args.run_profile().as_deref(),
+        &variables,
+        &[],
+        None,
+    )?;
+    run_process(command, qmp.as_ref(), &config, &variables, None, Some(stop))
+}
+
+/// Removes the files that bootimage has written for the crate (and with `--all` the
+/// build output of cargo), so that the next build starts from scratch.
+///
+/// Directories with generic names (like `kernels`) are only removed from the target
+/// directory, since an output outside of it may be placed next to directories of the
+/// user.
+pub(crate) fn clean(args: CleanArgs) -> Result<(), Error> {
+    let (cargo_args, config, metadata, out_dir) = common_setup(args.args)?;
+    let target_dir = target_dir(&cargo_args, &metadata);
+
+    let cwd = env::current_dir()?;
+    let mut removed = 0;
+    for path in artifact_paths(&config, &metadata, &out_dir, &target_dir) {
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        if metadata.is_dir() && !cwd.join(&path).starts_with(&target_dir) {
+            println!(
+                "Skipping {}, which is outside of the target directory (remove it by hand if \
+                 bootimage created it)",
+                path.display()
+            );
+            continue;
+        }
+        if args.dry_run {
+            println!("Would remove {}", path.display());
+        } else {
+            println!("Removing {}", path.display());
+            if metadata.is_dir() {
+                fs::remove_dir_all(&path)?;
+            } else {
+                fs::remove_file(&path)?;
+            }
+        }
+        removed += 1;
+    }
+    if removed == 0 {
+        println!("Nothing to remove");
+    }
+
+    if let Some(cache_dir) = cache_dir().filter(|dir| dir.exists()) {
+        if !args.cache {
+            println!(
+                "The user-level cache in {} is shared by all crates and is kept (remove it \
+                 with `--cache`)",
+                cache_dir.display()
+            );
+        } else if args.dry_run {
+            println!("Would remove the user-level cache in {}", cache_dir.display());
+        } else {
+            println!("Removing the user-level cache in {}", cache_dir.display());
+            fs::remove_dir_all(&cache_dir)?;
+        }
+    }
+    if args.all {
+        if args.dry_run {
+            println!("Would run `cargo clean` for {}", target_dir.display());
+            return Ok(());
+        }
+        let status = process::Command::new("cargo")
+            .arg("clean")
+            .arg("--manifest-path")
+            .arg(&config.manifest_path)
+            .arg("--target-dir")
+            .arg(&target_dir)
+            .status()?;
+        if !status.success() {
+            process::exit(status.code().unwrap_or(1));
+        }
+    }
+    Ok(())
+}
+
+/// The files and directories that bootimage writes for the crate: the outputs of the
+/// kernel and the test kernels with the files named after them, the files next to
+/// the output, the bootloader, and the test results.
+fn artifact_paths(
+    config: &Config,
+    metadata: &CargoMetadata,
+    out_dir: &Path,
+    target_dir: &Path,
+) -> BTreeSet<PathBuf> {
+    let mut paths = BTreeSet::new();
+    let tests = test_kernels(config, metadata);
+    let configs = iter::once(config.clone())
+        .chain(tests.iter().map(|test| test_config(config, &test.name)));
+    for config in configs {
+        paths.extend(vec![
+            config.output.clone(),
+            config.image_path(),
+            config.map_path(),
+            config.symbols_path(),
+            config.checksums_path(),
+            config.manifest_path(),
+        ]);
+        paths.extend(config.signature_path());
+        paths.extend(config.export.iter().map(|&format| config.export_path(format)));
+        paths.extend(serial_log_path(&config, out_dir));
+    }
+    let image_dir = outdir(config);
+    paths.extend(IMAGE_DIR_ARTIFACTS.iter().map(|name| image_dir.join(name)));
+    // the bootloader of the last build, its build directory, and the test results
+    paths.insert(out_dir.join("bootloader"));
+    paths.insert(target_dir.join("bootloader"));
+    paths.insert(out_dir.join(TEST_RESULTS_FILE));
+    paths.insert(out_dir.join("report.xml"));
+    paths
+}
+
+/// The sources of the crate whose changes trigger a rebuild: the workspace without
+/// the target directory and the files that the build writes, which are placed in
+/// the workspace with an output like the default `bootimage.bin`.
+fn watched_sources(
+    config: &Config,
+    metadata: &CargoMetadata,
+    out_dir: &Path,
+    target_dir: &Path,
+) -> Result<watch::Sources, Error> {
+    let cwd = env::current_dir()?;
+    let mut excluded = vec![target_dir.to_owned()];
+    excluded.extend(
+        artifact_paths(config, metadata, out_dir, target_dir)
+            .iter()
+            .map(|path| cwd.join(path)),
+    );
+    Ok(watch::Sources {
+        dir: PathBuf::from(&metadata.workspace_root),
+        excluded,
+    })
+}
+
+/// Builds the image with `bootimage build` and returns whether it succeeded.
+///
+/// A failed build exits bootimage, so the commands that keep running across
+/// rebuilds build in a child process.
+fn rebuild(build_args: &[String]) -> bool {
+    let status = env::current_exe().and_then(|executable| {
+        process::Command::new(executable)
+            .arg("build")
+            .args(build_args)
+            .status()
+    });
+    match status {
+        Ok(status) => status.success(),
+        Err(err) => {
+            eprintln!("Error: failed to run `bootimage build`: {}", err);
+            false
+        }
+    }
+}
+
+/// Checks the structure of an existing disk image and fails if a check fails.
+pub(crate) fn verify(args: VerifyArgs) -> Result<(), Error> {
+    let public_key = args.public_key.as_deref();
+    let checks = verify::verify(&args.image, public_key).map_err(Error::Image)?;
+    println!("Verifying disk image {}", args.image.display());
+    let mut failed = 0;
+    for check in &checks {
+        let (status, message) = match check.outcome {
+            Outcome::Passed(ref message) => ("ok", message),
+            Outcome::Failed(ref message) => {
+                failed += 1;
+                ("FAILED", message)
+            }
+            Outcome::Skipped(ref message) => ("skipped", message),
+        };
+        println!("    {:<8} {}: {}", status, check.name, message);
+    }
+    if failed > 0 {
+        return Err(Error::Image(format!(
+            "{} of {} checks failed for {}",
+            failed,
+            checks.len(),
+            args.image.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Writes the kernel and/or the bootloader of an existing disk image to the given paths.
+pub(crate) fn extract(args: ExtractArgs) -> Result<(), Error> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let layout = inspect::layout(&args.image).map_err(Error::Image)?;
+    let mut image = File::open(&args.image)?;
+    let mut read_part = |offset: u64, size: u64| -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        image.seek(SeekFrom::Start(offset))?;
+        (&mut image).take(size).read_to_end(&mut data)?;
+        Ok(data)
+    };
+
+    if let Some(ref path) = args.kernel {
+        let mut kernel = read_part(layout.kernel_offset(), u64::from(layout.kernel_size))?;
+        if let Some((compression, _)) = layout.compression {
+            kernel = image::decompress_kernel(&kernel, compression).map_err(Error::Image)?;
+        }
+        fs::write(path, &kernel)?;
+        println!(
+            "Extracted the kernel ({}) to {}",
+            Bytes(kernel.len() as u64),
+            path.display()
+        );
+    }
+    if let Some(ref path) = args.bootloader {
+        // the BIOS part of a hybrid image is split by the partition table
+        if layout.gpt {
+            return Err(Error::Image(
+                "the bootloader can't be extracted from hybrid images".into(),
+            ));
+        }
+        let bootloader = read_part(layout.disk_offset, layout.bootloader)?;
+        fs::write(path, &bootloader)?;
+        println!(
+            "Extracted the bootloader ({}) to {}",
+            Bytes(bootloader.len() as u64),
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// The parts of the disk image, or `None` if the kernel isn't appended to the
+/// bootloader (e.g. for `gpt` images).
+fn image_composition(config: &Config) -> Result<Option<Composition>, Error> {
+    if config.bootloader.linking != Linking::Append
+        || config.format == ImageFormat::Gpt
+        || config.format == ImageFormat::Hybrid
+        || config.format == ImageFormat::Multiboot2
+        || config.format == ImageFormat::GrubIso
+        || config.format == ImageFormat::Limine
+    {
+        return Ok(None);
+    }
+    let bootloader_elf = fs::read(outdir(config).join("bootloader.elf"))?;
+    let elf_file = xmas_elf::ElfFile::new(&bootloader_elf)
+        .map_err(|err| Error::Image(format!("invalid bootloader ELF file: {}", err)))?;
+    let bootloader = match elf_file.find_section_by_name(".bootloader") {
+        Some(section) => section.size(),
+        None => return Ok(None),
+    };
+    let info_block = image::BLOCK_SIZE as u64;
+    let kernel = fs::metadata(kernel_payload_path(config))?.len();
+    let total = fs::metadata(&config.output)?.len();
+    Ok(Some(Composition {
+        bootloader,
+        info_block,
+        kernel,
+        padding: total.saturating_sub(bootloader + info_block + kernel),
+    }))
+}
+
+pub(crate) fn runner(args: RunnerArgs) -> Result<(), Error> {
+    // cargo sets `CARGO_MANIFEST_DIR` when it invokes a target runner
+    let manifest_path =
+        env::var_os("CARGO_MANIFEST_DIR").map(|dir| PathBuf::from(dir).join("Cargo.toml"));
+    let metadata = cargo_metadata::metadata(manifest_path.as_deref())?;
+    let workspace_manifest_path = PathBuf::from(&metadata.workspace_root).join("Cargo.toml");
+    let manifest_path = manifest_path.unwrap_or_else(|| workspace_manifest_path.clone());
+    let out_dir = runner_out_dir(&args.executable);
+    // the `dev` profile is the only one whose directory is named differently
+    let profile = match out_dir.file_name().and_then(|name| name.to_str()) {
+        Some("debug") | None => "dev",
+        Some(profile) => profile,
+    };
+    let mut config = config::read_config(manifest_path, &workspace_manifest_path, None, profile)?;
+    config.output = runner_output_path(&args.executable);
+
+    // the target directory is the parent of the output directory for custom targets
+    let target = out_dir
+        .parent()
+        .filter(|dir| *dir != Path::new(&metadata.target_directory))
+        .and_then(|dir| dir.file_name())
+        .and_then(|name| name.to_str());
+    let variables = Variables::new(&config, &metadata, &out_dir, target, profile);
+
+    let kernel = File::open(&args.executable)?;
+    let target_dir = Path::new(&metadata.target_directory);
+    create_image(&config, kernel, target_dir, &out_dir)?;
+    if config.reproducible {
+        check_reproducible(&config, target_dir, &out_dir)?;
+    }
+    sign_image(&config, &variables)?;
+    if config.run_backend == RunBackend::Vmware {
+        vmware::write_vm(&config, &vmware::vm_dir(&outdir(&config)))?;
+    }
+    run_hook("post-build", config.post_build.as_ref(), &variables)?;
+
+    let exit_status = match config.run_backend {
+        RunBackend::Qemu => run_impl(&args.run_args, &config, None, &variables, &[], None)?,
+        RunBackend::Firecracker | RunBackend::CloudHypervisor => {
+            run_microvm(&args.run_args, &config, None, &variables)?
+        }
+        RunBackend::Bochs => return run_bochs(&args.run_args, &config, None, &variables),
+        _ => return run_vm(&args.run_args, &config, None, &variables),
+    };
+    match exit_status.code() {
+        // e.g. the kernel of a `cargo test` run reported success through `isa-debug-exit`
+        Some(code) if Some(code) == config.test_success_exit_code => Ok(()),
+        _ if exit_status.success() => Ok(()),
+        code => process::exit(code.unwrap_or(1)),
+    }
+}
+
+pub(crate) fn test(args: Args) -> Result<(), Error> {
+    let explicit_kernel = args.bin().is_some() || args.example().is_some();
+    let (args, mut config, metadata, out_dir) = common_setup(args)?;
+    let report = test_report(&args, &out_dir);
+    if report.is_some() {
+        // the report contains the serial output of every test kernel
+        config.capture_serial = true;
+    }
+
+    let all_tests = if explicit_kernel {
+        Vec::new()
+    } else {
+        test_kernels(&config, &metadata)
+    };
+    let mut outcomes = read_test_results(&out_dir)?;
+    if args.failed() && outcomes.is_empty() {
+        return Err(Error::Args(
+            "`--failed` requires the results of a previous `bootimage test` run".into(),
+        ));
+    }
+    let tests: Vec<&Target> = all_tests
+        .iter()
+        .cloned()
+        .filter(|test| {
+            test_matches(&test.name, &config.test_prefix, args.test_filters(), args.exact())
+        })
+        .filter(|test| !args.failed() || outcomes.get(&test.name) == Some(&false))
+        .collect();
+    if let Some(format) = args.list() {
+        return list_tests(&tests, &config, format);
+    }
+    if let (Some(port), false) = (config.serial_port, args.no_run()) {
+        // the console serves the test kernels one after another
+        console::start(port)?;
+    }
+    if all_tests.is_empty() {
+        if let Some(filter) = args.test_filters().first() {
+            return Err(Error::Args(format!(
+                "test filter `{}` requires test kernels, but the crate has no binaries \
+                 starting with `{}`",
+                filter, config.test_prefix
+            )));
+        }
+        let variables = build_variables(&args, &config, &metadata, &out_dir);
+        build_impl(&args, &config, &metadata, &out_dir, &variables)?;
+        if args.no_run() {
+            println!("Created test image {}", config.image_path().display());
+            return Ok(());
+        }
+
+        println!("Running test");
+        let name = match variables.get("crate") {
+            Some(Some(name)) => name.clone(),
+            _ => "test".into(),
+        };
+        let test = run_test_case(&args, &config, &variables, None, name, None)?;
+        if let Some((ref path, format)) = report {
+            write_test_report(path, format, &variables, slice::from_ref(&test))?;
+        }
+        return match test.failure {
+            None => {
+                println!("Test passed");
+                Ok(())
+            }
+            Some(reason) => {
+                eprintln!("Test failed: {}", reason);
+                process::exit(1)
+            }
+        };
+    }
+
+    let mut kernels = Vec::new();
+    for test in tests.iter().map(|test| &test.name) {
+        let overrides = config.test_overrides(test);
+        let mut test_args = args.clone();
+        test_args.set_bin(test.to_string());
+        test_args.run_args.extend(overrides.run_args);
+        let test_config = test_config(&config, test);
+        let variables = build_variables(&test_args, &test_config, &metadata, &out_dir);
+        build_impl(&test_args, &test_config, &metadata, &out_dir, &variables)?;
 
-    build_impl(&args, &config, &metadata, &out_dir)
+        let mut peers: Vec<Peer> = Vec::new();
+        for name in &overrides.peers {
+            // several instances of a kernel (or of the test kernel itself) are only built once
+            let built = peers.iter().find(|peer| peer.name == *name).cloned();
+            let peer = match built {
+                Some(peer) => peer,
+                None if name == test => Peer {
+                    name: name.clone(),
+                    args: test_args.clone(),
+                    config: test_config.clone(),
+                    variables: variables.clone(),
+                },
+                None => {
+                    let mut peer_args = args.clone();
+                    peer_args.set_bin(name.clone());
+                    peer_args.run_args.extend(config.test_overrides(name).run_args);
+                    let peer_config = self::test_config(&config, name);
+                    let variables = build_variables(&peer_args, &peer_config, &metadata, &out_dir);
+                    build_impl(&peer_args, &peer_config, &metadata, &out_dir, &variables)?;
+                    Peer {
+                        name: name.clone(),
+                        args: peer_args,
+                        config: peer_config,
+                        variables,
+                    }
+                }
+            };
+            peers.push(peer);
+        }
+        let network = if peers.is_empty() {
+            None
+        } else {
+            Some(NetworkTest {
+                nic_model: overrides
+                    .nic_model
+                    .unwrap_or_else(|| network::DEFAULT_NIC_MODEL.into()),
+                peers,
+            })
+        };
+        kernels.push((test, test_args, test_config, variables, network));
+    }
+    if args.no_run() {
+        for (test, _, test_config, _, _) in &kernels {
+            println!("Created test image {} ({})", test_config.image_path().display(), test);
+        }
+        return Ok(());
+    }
+
+    // the test kernels are booted by `jobs` threads, which take the next test in order
+    let jobs = args.jobs().or(config.test_jobs).unwrap_or(1).min(kernels.len());
+    let next = AtomicUsize::new(0);
+    let finished = Mutex::new(Vec::new());
+    let results: Vec<Result<(), Error>> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                scope.spawn(|| loop {
+                    let (test, test_args, test_config, variables, network) =
+                        match kernels.get(next.fetch_add(1, Ordering::SeqCst)) {
+                            Some(kernel) => kernel,
+                            None => return Ok(()),
+                        };
+                    println!("Running test {}", test);
+                    let prefix = if jobs > 1 { Some(test.as_str()) } else { None };
+                    let test = run_test_case(
+                        test_args,
+                        test_config,
+                        variables,
+                        prefix,
+                        test.to_string(),
+                        network.as_ref(),
+                    )?;
+                    match test.failure {
+                        None => println!("test {} ... ok", test.name),
+                        Some(ref reason) => println!("test {} ... FAILED ({})", test.name, reason),
+                    }
+                    finished.lock().unwrap().push(test);
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("test thread panicked"))
+            .collect()
+    });
+    for result in results {
+        result?;
+    }
+    let mut finished = finished.into_inner().unwrap();
+    finished.sort_by(|a, b| a.name.cmp(&b.name));
+    for test in &finished {
+        outcomes.insert(test.name.clone(), test.failure.is_none());
+    }
+    write_test_results(&out_dir, &outcomes)?;
+    if let Some((ref path, format)) = report {
+        let variables = build_variables(&args, &config, &metadata, &out_dir);
+        write_test_report(path, format, &variables, &finished)?;
+    }
+    let failures: Vec<_> = finished
+        .iter()
+        .filter(|test| test.failure.is_some())
+        .map(|test| &test.name)
+        .collect();
+
+    println!();
+    if !failures.is_empty() {
+        println!("failures:");
+        for test in &failures {
+            println!("    {}", test);
+        }
+        println!();
+    }
+    println!(
+        "test result: {}. {} passed; {} failed; {} filtered out",
+        if failures.is_empty() { "ok" } else { "FAILED" },
+        tests.len() - failures.len(),
+        failures.len(),
+        all_tests.len() - tests.len()
+    );
+    if !failures.is_empty() {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Reads whether each test kernel passed in the last `bootimage test` run.
+///
+/// The outcomes are only updated for the test kernels that ran, so the file also
+/// remembers the tests that were not selected by later runs.
+fn read_test_results(out_dir: &Path) -> Result<BTreeMap<String, bool>, Error> {
+    let content = match fs::read(out_dir.join(TEST_RESULTS_FILE)) {
+        Ok(content) => content,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let results: JsonValue = serde_json::from_slice(&content).map_err(|err| {
+        Error::Config(format!("invalid test results in {}: {}", TEST_RESULTS_FILE, err))
+    })?;
+    Ok(results["tests"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(name, result)| match result.as_str() {
+            Some("passed") => Some((name.clone(), true)),
+            Some("failed") => Some((name.clone(), false)),
+            _ => None,
+        })
+        .collect())
+}
+
+fn write_test_results(out_dir: &Path, results: &BTreeMap<String, bool>) -> Result<(), Error> {
+    let tests: serde_json::Map<_, _> = results
+        .iter()
+        .map(|(name, &passed)| {
+            let result = if passed { "passed" } else { "failed" };
+            (name.clone(), JsonValue::from(result))
+        })
+        .collect();
+    fs::create_dir_all(out_dir)?;
+    fs::write(out_dir.join(TEST_RESULTS_FILE), json!({ "tests": tests }).to_string())?;
+    Ok(())
+}
+
+/// The path and format of the test report, if one was requested.
+///
+/// Passing only one of `--report-format` and `--report-path` selects a JUnit report
+/// or a `report.xml` in the output directory for the other.
+fn test_report(args: &Args, out_dir: &Path) -> Option<(PathBuf, ReportFormat)> {
+    if args.report_format().is_none() && args.report_path().is_none() {
+        return None;
+    }
+    let path = args
+        .report_path()
+        .clone()
+        .unwrap_or_else(|| out_dir.join("report.xml"));
+    Some((path, args.report_format().unwrap_or(ReportFormat::Junit)))
+}
+
+fn write_test_report(
+    path: &Path,
+    format: ReportFormat,
+    variables: &Variables,
+    tests: &[TestCase],
+) -> Result<(), Error> {
+    let suite = match variables.get("crate") {
+        Some(Some(name)) => name.as_str(),
+        _ => "bootimage",
+    };
+    report::write_report(path, format, suite, tests)?;
+    println!("Wrote test report to {}", path.display());
+    Ok(())
+}
+
+/// Prints the selected test kernels and their configuration without building them.
+fn list_tests(tests: &[&Target], config: &Config, format: ListFormat) -> Result<(), Error> {
+    match format {
+        ListFormat::Human => {
+            for test in tests {
+                let test_config = test_config(config, &test.name);
+                println!("{}: {}", test.name, test.src_path);
+                println!("    image: {}", test_config.image_path().display());
+                println!(
+                    "    success exit code: {}",
+                    test_config
+                        .test_success_exit_code
+                        .unwrap_or(TEST_SUCCESS_EXIT_CODE)
+                );
+                if let Some(timeout) = test_config.run_timeout {
+                    println!("    timeout: {} seconds", timeout.as_secs());
+                }
+                let overrides = config.test_overrides(&test.name);
+                if !overrides.run_args.is_empty() {
+                    println!("    run args: {}", overrides.run_args.join(" "));
+                }
+                if !overrides.peers.is_empty() {
+                    println!("    peers: {}", overrides.peers.join(", "));
+                }
+            }
+            println!();
+            println!("{} test kernels", tests.len());
+        }
+        ListFormat::Json => {
+            let tests: Vec<_> = tests
+                .iter()
+                .map(|test| {
+                    let test_config = test_config(config, &test.name);
+                    json!({
+                        "name": test.name,
+                        "src_path": test.src_path,
+                        "image": test_config.image_path(),
+                        "success_exit_code": test_config
+                            .test_success_exit_code
+                            .unwrap_or(TEST_SUCCESS_EXIT_CODE),
+                        "timeout": test_config.run_timeout.map(|timeout| timeout.as_secs()),
+                        "run_args": config.test_overrides(&test.name).run_args,
+                        "peers": config.test_overrides(&test.name).peers,
+                    })
+                })
+                .collect();
+            println!("{}", json!({ "tests": tests }));
+        }
+    }
+    Ok(())
+}
+
+/// The configuration of the test kernel `test`, which gets its own disk image next
+/// to the configured output. The run arguments of its overrides are not included.
+fn test_config(config: &Config, test: &str) -> Config {
+    let stem = config
+        .output
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "bootimage".into());
+    let file_name = match config.output.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, test, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, test),
+    };
+    let overrides = config.test_overrides(test);
+    let mut test_config = config.clone();
+    test_config.output = config.output.with_file_name(file_name);
+    if overrides.timeout.is_some() {
+        test_config.run_timeout = overrides.timeout;
+    }
+    if overrides.success_exit_code.is_some() {
+        test_config.test_success_exit_code = overrides.success_exit_code;
+    }
+    if let Some(ref at) = overrides.screenshot_at {
+        test_config.screenshots.at = at.clone();
+    }
+    if let Some(at_end) = overrides.screenshot_at_end {
+        test_config.screenshots.at_end = at_end;
+    }
+    test_config
+}
+
+/// The binary targets of the kernel crate whose name starts with the configured
+/// `test-prefix`, sorted by name.
+fn test_kernels<'a>(config: &Config, metadata: &'a CargoMetadata) -> Vec<&'a Target> {
+    let crate_ = metadata
+        .packages
+        .iter()
+        .find(|p| Path::new(&p.manifest_path) == config.manifest_path);
+    let mut tests: Vec<&Target> = crate_
+        .into_iter()
+        .flat_map(|crate_| &crate_.targets)
+        .filter(|target| target.kind.iter().any(|kind| kind == "bin"))
+        .filter(|target| target.name.starts_with(&config.test_prefix))
+        .collect();
+    tests.sort_by(|a, b| a.name.cmp(&b.name));
+    tests
+}
+
+/// Whether the test kernel `test` is selected by the test filters.
+///
+/// Without `--exact`, a filter matches if it is contained in the test name or, if it
+/// contains `*` or `?`, if it matches the test name as a glob pattern. The test names
+/// can be given with or without the test prefix.
+fn test_matches(test: &str, prefix: &str, filters: &[String], exact: bool) -> bool {
+    let short_name = test.strip_prefix(prefix).unwrap_or(test);
+    filters.is_empty()
+        || filters.iter().any(|filter| {
+            [test, short_name].iter().any(|name| {
+                if exact {
+                    name == filter
+                } else if filter.contains(['*', '?']) {
+                    glob_match(filter.as_bytes(), name.as_bytes())
+                } else {
+                    name.contains(filter.as_str())
+                }
+            })
+        })
+}
+
+/// Matches `name` against a glob pattern with the wildcards `*` and `?`.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], name) || (!name.is_empty() && glob_match(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => glob_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Runs a test kernel and records its outcome, duration, and captured serial output.
+fn run_test_case(
+    args: &Args,
+    config: &Config,
+    variables: &Variables,
+    output_prefix: Option<&str>,
+    name: String,
+    network: Option<&NetworkTest>,
+) -> Result<TestCase, Error> {
+    let start = Instant::now();
+    let failure = run_test(args, config, variables, output_prefix, network)?.err();
+    let duration = start.elapsed();
+    // only the recordings of failed tests are kept
+    if config.deterministic {
+        if failure.is_none() {
+            replay::discard(config, &outdir(config))?;
+        } else {
+            let test = if name.starts_with(&config.test_prefix) {
+                format!(" {}", name)
+            } else {
+                String::new()
+            };
+            println!(
+                "Recorded the failing run in {}, replay it with `bootimage replay{}`",
+                replay::recording_dir(config, &outdir(config)).display(),
+                test
+            );
+        }
+    }
+    let serial_log = variables.get("serial_log").and_then(|log| log.map(PathBuf::from));
+    let output = match serial_log {
+        Some(ref log) => String::from_utf8_lossy(&fs::read(log)?).into_owned(),
+        None => String::new(),
+    };
+    Ok(TestCase {
+        name,
+        duration,
+        failure,
+        output,
+        serial_log,
+    })
+}
+
+/// Runs a built test kernel and returns the reason if the test failed.
+///
+/// The peers of a network test are booted before the test kernel and killed after
+/// it exited, so only the test kernel reports the result.
+fn run_test(
+    args: &Args,
+    config: &Config,
+    variables: &Variables,
+    output_prefix: Option<&str>,
+    network: Option<&NetworkTest>,
+) -> Result<Result<(), String>, Error> {
+    if config.run_backend != RunBackend::Qemu {
+        return Err(Error::Config(
+            "`bootimage test` requires the `qemu` run backend, since the VMs of the other \
+             backends have no `isa-debug-exit` device through which the test kernels report \
+             their result"
+                .into(),
+        ));
+    }
+    let mut run_args: Vec<String> = TEST_RUN_ARGS.iter().map(|&arg| arg.into()).collect();
+    let mut peers = Vec::new();
+    if let Some(network_test) = network {
+        let network = Network::new()?;
+        run_args.extend(network.qemu_args(&network_test.nic_model, 0));
+        for (index, peer) in network_test.peers.iter().enumerate() {
+            match start_peer(peer, &network, &network_test.nic_model, index + 1) {
+                Ok(running) => peers.push(running),
+                Err(err) => {
+                    stop_peers(peers);
+                    return Err(err);
+                }
+            }
+        }
+    }
+    let run_args: Vec<&str> = run_args.iter().map(String::as_str).collect();
+    let result = run_impl(
+        &args.run_args,
+        config,
+        args.run_profile().as_deref(),
+        variables,
+        &run_args,
+        output_prefix,
+    );
+    stop_peers(peers);
+    let exit_status = match result {
+        Ok(exit_status) => exit_status,
+        // a hung kernel only fails this test
+        Err(Error::Timeout(timeout)) => {
+            return Ok(Err(format!(
+                "timed out after {} seconds",
+                timeout.as_secs()
+            )))
+        }
+        Err(Error::Screenshot(failures)) => return Ok(Err(failures)),
+        Err(err) => return Err(err),
+    };
+    let success_exit_code = config
+        .test_success_exit_code
+        .unwrap_or(TEST_SUCCESS_EXIT_CODE);
+    Ok(match exit_status.code() {
+        Some(code) if code == success_exit_code => Ok(()),
+        Some(code) if code & 1 == 1 => Err(format!("kernel exited with code {}", code >> 1)),
+        Some(code) => Err(format!("run command exited with status {}", code)),
+        None => Err("run command was terminated by a signal".into()),
+    })
+}
+
+/// The peers of a network test.
+struct NetworkTest {
+    /// The QEMU model of the network cards.
+    nic_model: String,
+    peers: Vec<Peer>,
+}
+
+/// A built kernel that is booted alongside a test kernel.
+#[derive(Clone)]
+struct Peer {
+    name: String,
+    args: Args,
+    config: Config,
+    variables: Variables,
+}
+
+/// A running peer, whose output is echoed with its name and number (e.g.
+/// `[echo-server#2]`) as prefix.
+struct RunningPeer {
+    child: process::Child,
+    /// Removes the QMP socket of the peer when it is dropped.
+    _qmp: Option<qmp::Socket>,
+    tee: Option<thread::JoinHandle<io::Result<Vec<u64>>>>,
+}
+
+/// Boots the peer with the number `index` (1 for the first peer) on `network`.
+fn start_peer(
+    peer: &Peer,
+    network: &Network,
+    nic_model: &str,
+    index: usize,
+) -> Result<RunningPeer, Error> {
+    let prefix = format!("{}#{}", peer.name, index + 1);
+    let network_args = network.qemu_args(nic_model, index);
+    let network_args: Vec<&str> = network_args.iter().map(String::as_str).collect();
+    let (mut command, qmp) = run_command(
+        &peer.args.run_args,
+        &peer.config,
+        peer.args.run_profile().as_deref(),
+        &peer.variables,
+        &network_args,
+        Some(&prefix),
+    )?;
+    // the terminal belongs to the test kernel
+    command.stdin(process::Stdio::null());
+    let mut child = spawn_run(&mut command)?;
+    let tee = child
+        .stdout
+        .take()
+        .map(|output| thread::spawn(move || tee_output(output, None, Some(&prefix), None)));
+    Ok(RunningPeer {
+        child,
+        _qmp: qmp,
+        tee,
+    })
+}
+
+/// Kills the peers of a network test, which run until the test kernel exited.
+fn stop_peers(peers: Vec<RunningPeer>) {
+    for mut peer in peers {
+        let _ = peer.child.kill();
+        let _ = peer.child.wait();
+        if let Some(tee) = peer.tee {
+            let _ = tee.join();
+        }
+    }
+}
+
+fn common_setup(mut args: Args) -> Result<(Args, Config, CargoMetadata, PathBuf), Error> {
+    fn out_dir(args: &Args, metadata: &CargoMetadata) -> PathBuf {
+        let mut out_dir = target_dir(args, metadata);
+        if let Some(ref target) = *args.target() {
+            out_dir.push(target);
+        }
+        // the built-in profiles use the `debug` and `release` directories,
+        // custom profiles a directory named after the profile
+        let profile_dir = match args.profile().as_deref() {
+            Some("dev") | Some("test") => "debug",
+            Some("release") | Some("bench") => "release",
+            Some(profile) => profile,
+            None if args.release() => "release",
+            None => "debug",
+        };
+        out_dir.push(profile_dir);
+        out_dir
+    }
+
+    let metadata = read_cargo_metadata(&args)?;
+    let crate_root = PathBuf::from(&metadata.workspace_root);
+    let manifest_path = match *args.package() {
+        Some(ref package) => metadata
+            .packages
+            .iter()
+            .find(|p| p.name == *package)
+            .map(|p| PathBuf::from(&p.manifest_path))
+            .ok_or_else(|| {
+                Error::Args(format!("package `{}` is not a workspace member", package))
+            })?,
+        None => args.manifest_path().clone().unwrap_or({
+            let mut path = crate_root.clone();
+            path.push("Cargo.toml");
+            path
+        }),
+    };
+    let mut config = config::read_config(
+        manifest_path,
+        &crate_root.join("Cargo.toml"),
+        args.config().as_deref(),
+        profile(&args),
+    )?;
+
+    if args.offline() {
+        // also applies to the cargo invocations for the bootloader and the metadata
+        env::set_var("CARGO_NET_OFFLINE", "true");
+    }
+    if let Some(format) = args.format() {
+        config.format = format;
+    }
+    if let Some(timeout) = args.timeout() {
+        config.run_timeout = Some(timeout);
+    }
+    if args.capture_serial() {
+        config.capture_serial = true;
+    }
+    if let Some(port) = args.serial_port() {
+        config.serial_port = Some(port);
+    }
+    if args.no_accel() {
+        config.accel = false;
+    }
+    if args.deterministic() {
+        config.deterministic = true;
+    }
+    if let Some(ref display) = *args.display() {
+        config.display = Some(display.clone());
+    }
+    if args.update_screenshots() {
+        config.screenshots.update_golden = true;
+    }
+    if args.reproducible() {
+        config.reproducible = true;
+    }
+    if args.allow_unverified_downloads() {
+        config.allow_unverified_downloads = true;
+    }
+    if args.no_sparse() {
+        config.sparse = false;
+    }
+    if let Some(ref cmdline) = *args.cmdline() {
+        config.kernel_cmdline = Some(cmdline.clone());
+    }
+    if args.target().is_none() {
+        if let Some(ref target) = config.default_target {
+            args.set_target(target.clone());
+        }
+    }
+    if let Some(ref features) = *args.bootloader_features() {
+        config.bootloader.features = features.clone();
+    }
+    config.output = feature_output(&config.output, &args);
+    if args.bin().is_none() && args.example().is_none() {
+        if let Some(ref bin) = config.default_run {
+            args.set_bin(bin.clone());
+        }
+    }
+
+    let out_dir = out_dir(&args, &metadata);
+
+    Ok((args, config, metadata, out_dir))
+}
+
+/// The output path of a kernel built with the feature flags of `args`: the file stem
+/// of `output` gets a suffix for the feature set (e.g. `bootimage-log+smp.bin`), so
+/// that the images of different feature sets don't overwrite each other.
+fn feature_output(output: &Path, args: &Args) -> PathBuf {
+    let mut parts = Vec::new();
+    if args.all_features() {
+        parts.push(String::from("all-features"));
+    } else {
+        if args.no_default_features() {
+            parts.push(String::from("no-default-features"));
+        }
+        // the order of the features doesn't matter, and `dep/feature` isn't a file name
+        let features: BTreeSet<String> =
+            args.features().iter().map(|f| f.replace('/', "-")).collect();
+        parts.extend(features);
+    }
+    if parts.is_empty() {
+        return output.to_path_buf();
+    }
+    let mut file_name = output.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(format!("-{}", parts.join("+")));
+    if let Some(extension) = output.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    output.with_file_name(file_name)
+}
+
+/// The name of the cargo profile that the kernel is built with.
+fn profile(args: &Args) -> &str {
+    match *args.profile() {
+        Some(ref profile) => profile.as_str(),
+        None if args.release() => "release",
+        None => "dev",
+    }
+}
+
+fn build_impl(
+    args: &Args,
+    config: &Config,
+    metadata: &CargoMetadata,
+    out_dir: &Path,
+    variables: &Variables,
+) -> Result<(), Error> {
+    run_hook("pre-build", config.pre_build.as_ref(), variables)?;
+    let kernel = build_kernel(args, config, metadata)?;
+
+    if args.update_bootloader() {
+        // forces a fresh download and build of the bootloader
+        for file in &["fingerprint", "Cargo.lock"] {
+            let mut path = PathBuf::from(out_dir);
+            path.push("bootloader");
+            path.push(file);
+
+            match fs::remove_file(path) {
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+    }
+
+    let target_dir = target_dir(args, metadata);
+    create_image(config, kernel, &target_dir, out_dir)?;
+    if config.reproducible {
+        check_reproducible(config, &target_dir, out_dir)?;
+    }
+    sign_image(config, variables)?;
+    if config.run_backend == RunBackend::Vmware {
+        vmware::write_vm(config, &vmware::vm_dir(&outdir(config)))?;
+    }
+    run_hook("post-build", config.post_build.as_ref(), variables)
+}
+
+/// The `--target-dir` argument or the target directory reported by cargo,
+/// which respects `CARGO_TARGET_DIR`.
+fn target_dir(args: &Args, metadata: &CargoMetadata) -> PathBuf {
+    match *args.target_dir() {
+        // the bootloader is built from a different working directory
+        Some(ref dir) => env::current_dir()
+            .map(|cwd| cwd.join(dir))
+            .unwrap_or_else(|_| dir.clone()),
+        None => PathBuf::from(&metadata.target_directory),
+    }
+}
+
+/// Combines the given kernel with the bootloader into a disk image at `config.output`.
+///
+/// The bootloader is built in the `bootloader` subdirectory of `target_dir` and
+/// the last built bootloader is kept in the `bootloader` subdirectory of `out_dir`.
+fn create_image(
+    config: &Config,
+    mut kernel: File,
+    target_dir: &Path,
+    out_dir: &Path,
+) -> Result<(), Error> {
+    use std::io::Read;
+
+    let kernel_path = outdir(config).join("kernel.elf");
+    let mut elf = Vec::new();
+    kernel.read_to_end(&mut elf)?;
+    validate::kernel(&elf).map_err(Error::Kernel)?;
+    let multiboot2 =
+        config.format == ImageFormat::Multiboot2 || config.format == ImageFormat::GrubIso;
+    let loads_modules =
+        config.format == ImageFormat::GrubIso || config.format == ImageFormat::Limine;
+    if config.format != ImageFormat::Gpt && config.format != ImageFormat::Limine && !multiboot2 {
+        for warning in validate::bios_load_warnings(&elf) {
+            eprintln!("Warning: {}", warning);
+        }
+    }
+    if config.strip_kernel {
+        // the full kernel is kept for debuggers and the backtrace symbolizer
+        fs::write(outdir(config).join("kernel.debug"), &elf)?;
+        fs::write(&kernel_path, strip::strip(&elf).map_err(Error::Kernel)?)?;
+    } else {
+        fs::write(&kernel_path, &elf)?;
+    }
+    kernel = File::open(&kernel_path)?;
+    let uncompressed_size = kernel.metadata()?.len();
+    // the uncompressed kernel is kept for debuggers and `bootimage extract`
+    let payload_path = kernel_payload_path(config);
+    if config.kernel_compression != KernelCompression::None {
+        let elf = fs::read(&kernel_path)?;
+        fs::write(&payload_path, image::compress_kernel(&elf, config.kernel_compression))?;
+        kernel = File::open(&payload_path)?;
+    }
+    let kernel_size = kernel.metadata()?.len();
+    check_budget("the kernel", kernel_size, "max-kernel-size", config.max_kernel_size)
+        .map_err(Error::Kernel)?;
+
+    let info_block_key = if config.embed_metadata {
+        Some("embed-metadata")
+    } else if config.info_block_version != 1 {
+        Some("info-block-version")
+    // GRUB and Limine pass the command line and the initrd to the kernel themselves
+    } else if config.kernel_cmdline.is_some() && !loads_modules {
+        Some("kernel-cmdline")
+    } else if config.initrd.is_some() && !loads_modules {
+        Some("initrd")
+    } else if !config.info_block_fields.is_empty() {
+        Some("info-block")
+    } else if config.kernel_alignment != image::BLOCK_SIZE as u64 {
+        Some("kernel-alignment")
+    } else if config.kernel_compression != KernelCompression::None {
+        Some("kernel-compression")
+    } else {
+        None
+    };
+    match info_block_key {
+        Some(key)
+            if config.format == ImageFormat::Gpt
+                || config.format == ImageFormat::Multiboot2
+                || config.format == ImageFormat::GrubIso
+                || config.format == ImageFormat::Limine
+                || config.bootloader.linking == Linking::Embed =>
+        {
+            return Err(Error::Config(format!(
+                "`{}` requires a kernel info block, i.e. a BIOS image with the `append` \
+                 bootloader linking mode",
+                key
+            )));
+        }
+        _ => {}
+    }
+    if config.sector_size != image::BLOCK_SIZE as u64 && config.format != ImageFormat::Gpt {
+        return Err(Error::Config(format!(
+            "`sector-size = {}` requires the `gpt` image format, since BIOS bootloaders are \
+             loaded in {}-byte sectors",
+            config.sector_size,
+            image::BLOCK_SIZE
+        )));
+    }
+    for &(key, alignment) in &[
+        ("kernel-alignment", config.kernel_alignment),
+        ("image-alignment", config.image_alignment),
+    ] {
+        if !alignment.is_multiple_of(config.sector_size) {
+            return Err(Error::Config(format!(
+                "`{}` must be a multiple of the sector size ({} bytes), found {}",
+                key, config.sector_size, alignment
+            )));
+        }
+    }
+    if config.mbr_partition_table
+        && (config.format == ImageFormat::Gpt || config.format == ImageFormat::Hybrid)
+    {
+        return Err(Error::Config(
+            "`mbr-partition-table` can't be combined with the GPT of the `gpt` and `hybrid` \
+             image formats"
+                .into(),
+        ));
+    }
+    if config.kernel_compression != KernelCompression::None && config.format == ImageFormat::Hybrid
+    {
+        return Err(Error::Config(
+            "`kernel-compression` can't be combined with the `hybrid` image format, whose EFI \
+             system partition contains the uncompressed kernel"
+                .into(),
+        ));
+    }
+    if !config.multiboot_modules.is_empty() && !loads_modules {
+        return Err(Error::Config(
+            "`multiboot-modules` requires the `grub-iso` or `limine` image format".into(),
+        ));
+    }
+    if config.mbr_partition_table && config.format == ImageFormat::Limine {
+        return Err(Error::Config(
+            "`mbr-partition-table` can't be combined with the `limine` image format, whose \
+             MBR partition table contains the FAT partition of Limine"
+                .into(),
+        ));
+    }
+    let memdisk = config.format == ImageFormat::Syslinux || config.format == ImageFormat::Isolinux;
+    if !config.kernels.is_empty() && !loads_modules && !memdisk {
+        return Err(Error::Config(
+            "`kernels` requires a boot menu, i.e. the `grub-iso`, `limine`, `syslinux`, or \
+             `isolinux` image format"
+                .into(),
+        ));
+    }
+    if multiboot2 {
+        let disk_key = if !config.files.is_empty() {
+            Some("files")
+        } else if config.data_directory.is_some() {
+            Some("data-directory")
+        } else if config.mbr_partition_table {
+            Some("mbr-partition-table")
+        } else if !config.export.is_empty() {
+            Some("export")
+        } else {
+            None
+        };
+        if let Some(key) = disk_key {
+            return Err(Error::Config(format!(
+                "`{}` requires a disk image with the builtin bootloader, which the `{}` \
+                 format doesn't create",
+                key, config.format
+            )));
+        }
+        println!("Creating Multiboot2 kernel at {}", config.output.display());
+        let kernel = multiboot2::kernel(&fs::read(&kernel_path)?).map_err(Error::Kernel)?;
+        fs::write(&config.output, &kernel)?;
+        if config.format == ImageFormat::GrubIso {
+            println!("Creating GRUB ISO image at {}", config.image_path().display());
+            let entries = boot_entries(config, kernel, out_dir)?;
+            let staging_dir = outdir(config).join("grub-iso");
+            grub::create_grub_iso(config, &entries, &staging_dir, &config.image_path())?;
+        }
+    } else if config.format == ImageFormat::Limine {
+        let kernel = match config.limine.protocol {
+            LimineProtocol::Limine => fs::read(&kernel_path)?,
+            LimineProtocol::Multiboot2 => {
+                multiboot2::kernel(&fs::read(&kernel_path)?).map_err(Error::Kernel)?
+            }
+        };
+        let entries = boot_entries(config, kernel, out_dir)?;
+        limine::create_limine_image(config, &entries, &outdir(config))?;
+    } else if config.format == ImageFormat::Gpt {
+        image::create_uefi_disk_image(config, kernel)?;
+    } else if config.bootloader.linking == Linking::Embed {
+        if config.format == ImageFormat::Hybrid {
+            return Err(Error::Config(
+                "the `hybrid` image format requires the `append` bootloader linking mode".into(),
+            ));
+        }
+        // the bootloader is built from a different working directory
+        let kernel_path = env::current_dir()?.join(&kernel_path);
+        let tmp_dir = HelperDir::new(config, &out_dir.join("bootloader"))?;
+        let lockfile = out_dir.join("bootloader").join("Cargo.lock");
+        let bootloader = build_bootloader(
+            tmp_dir.path(),
+            config,
+            &target_dir.join("bootloader"),
+            &lockfile,
+            Some(&kernel_path),
+        )?;
+        tmp_dir.close()?;
+        fs::write(outdir(config).join("bootloader.elf"), &bootloader)?;
+        validate::load_region(&elf, &bootloader).map_err(Error::Kernel)?;
+        image::create_flat_disk_image(config, &bootloader)?;
+    } else {
+        let mut kernel_info_block =
+            image::create_kernel_info_block(&fs::read(&payload_path)?, config.info_block_version)
+                .map_err(Error::Kernel)?;
+        if config.kernel_compression != KernelCompression::None {
+            image::write_compression_info(
+                &mut kernel_info_block,
+                config.kernel_compression,
+                uncompressed_size,
+            )
+            .map_err(Error::Config)?;
+        }
+        if config.embed_metadata {
+            image::write_metadata(&mut kernel_info_block, &build_metadata(config))
+                .map_err(Error::Image)?;
+        }
+        if let Some(ref cmdline) = config.kernel_cmdline {
+            image::write_cmdline(&mut kernel_info_block, cmdline).map_err(Error::Config)?;
+        }
+        if let Some(ref initrd) = config.initrd {
+            let initrd_size = fs::metadata(initrd)
+                .map_err(|err| {
+                    Error::Config(format!(
+                        "failed to read the initrd at {}: {}",
+                        initrd.display(),
+                        err
+                    ))
+                })?
+                .len();
+            image::write_initrd_info(&mut kernel_info_block, kernel_size, initrd_size)
+                .map_err(Error::Config)?;
+        }
+        image::write_info_block_fields(&mut kernel_info_block, &config.info_block_fields)
+            .map_err(Error::Config)?;
+
+        let bootloader = load_bootloader(
+            config,
+            &out_dir.join("bootloader"),
+            &target_dir.join("bootloader"),
+        )?;
+        let bootloader_elf = fs::read(outdir(config).join("bootloader.elf"))?;
+        validate::load_region(&elf, &bootloader_elf).map_err(Error::Kernel)?;
+
+        if config.format == ImageFormat::Hybrid {
+            image::create_hybrid_disk_image(config, kernel, kernel_info_block, &bootloader)?;
+        } else {
+            image::create_disk_image(config, kernel, kernel_info_block, &bootloader)?;
+        }
+    }
+
+    match config.format {
+        ImageFormat::Iso => {
+            println!("Creating ISO image at {}", config.image_path().display());
+            iso::create_iso_image(&config.output, &config.image_path())?;
+        }
+        ImageFormat::Qcow2 => {
+            println!("Creating qcow2 image at {}", config.image_path().display());
+            qcow2::create_qcow2_image(&config.output, &config.image_path())?;
+        }
+        ImageFormat::Syslinux => {
+            let entries = memdisk_entries(config, target_dir, out_dir)?;
+            println!("Creating SYSLINUX image at {}", config.image_path().display());
+            syslinux::create_syslinux_image(config, &entries, &config.image_path())?;
+        }
+        ImageFormat::Isolinux => {
+            let entries = memdisk_entries(config, target_dir, out_dir)?;
+            println!("Creating ISOLINUX image at {}", config.image_path().display());
+            let staging_dir = outdir(config).join("isolinux");
+            syslinux::create_isolinux_image(config, &entries, &staging_dir, &config.image_path())?;
+        }
+        _ => {}
+    }
+    let image_size = fs::metadata(config.image_path())?.len();
+    check_budget("the disk image", image_size, "max-image-size", config.max_image_size)
+        .map_err(Error::Image)?;
+
+    if config.emit_map {
+        // the symbols of a stripped kernel are only in the full one
+        let elf = if config.strip_kernel {
+            fs::read(outdir(config).join("kernel.debug"))?
+        } else {
+            fs::read(&kernel_path)?
+        };
+        println!("Writing kernel map to {}", config.map_path().display());
+        fs::write(config.map_path(), map::map(&elf).map_err(Error::Kernel)?)?;
+        fs::write(config.symbols_path(), map::symbol_table(&elf).map_err(Error::Kernel)?)?;
+    }
+
+    if config.emit_checksums {
+        write_checksums(config, out_dir)?;
+    }
+
+    for &format in &config.export {
+        let path = config.export_path(format);
+        println!("Exporting {} image to {}", format, path.display());
+        export::export(&config.output, format, &path)?;
+    }
+
+    if !config.sparse {
+        // the images are written with holes, which are filled with zeros now
+        let mut paths = vec![config.output.clone(), config.image_path()];
+        paths.extend(config.export.iter().map(|&format| config.export_path(format)));
+        paths.dedup();
+        for path in paths {
+            sparse::allocate(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The title of the menu entry of the kernel itself, the name of the output file.
+fn default_menu_title(config: &Config) -> String {
+    config
+        .output
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "kernel".into())
+}
+
+/// The path of the kernel of a menu entry, where binary targets are built into
+/// `out_dir` together with the kernel.
+fn menu_kernel_path(title: &str, entry: &MenuEntry, out_dir: &Path) -> Result<PathBuf, Error> {
+    let path = match entry.kernel {
+        MenuKernel::Bin(ref name) => out_dir.join(name),
+        MenuKernel::Path(ref path) => path.clone(),
+    };
+    if !path.is_file() {
+        return Err(Error::Config(format!(
+            "the kernel of the menu entry `{}` wasn't found at {}",
+            title,
+            path.display()
+        )));
+    }
+    Ok(path)
+}
+
+/// The boot menu entries of the bootloaders that load the kernels themselves:
+/// the prepared `kernel` first and then the `kernels` in the order of their titles.
+///
+/// The additional kernels are validated and prepared like the kernel, i.e. they
+/// are stripped and get a Multiboot2 header if the bootloader needs one.
+fn boot_entries(config: &Config, kernel: Vec<u8>, out_dir: &Path) -> Result<Vec<BootEntry>, Error> {
+    let multiboot2 = config.format == ImageFormat::GrubIso
+        || config.limine.protocol == LimineProtocol::Multiboot2;
+    let mut entries = vec![BootEntry {
+        title: default_menu_title(config),
+        data: kernel,
+        cmdline: config.kernel_cmdline.clone(),
+    }];
+    for (title, entry) in &config.kernels {
+        let in_entry = |err: String| Error::Kernel(format!("menu entry `{}`: {}", title, err));
+        let mut data = fs::read(menu_kernel_path(title, entry, out_dir)?)?;
+        validate::kernel(&data).map_err(in_entry)?;
+        if config.strip_kernel {
+            data = strip::strip(&data).map_err(in_entry)?;
+        }
+        if multiboot2 {
+            data = multiboot2::kernel(&data).map_err(in_entry)?;
+        }
+        entries.push(BootEntry {
+            title: title.clone(),
+            data,
+            cmdline: entry.cmdline.clone().or_else(|| config.kernel_cmdline.clone()),
+        });
+    }
+    Ok(entries)
+}
+
+/// The boot menu entries of the images that boot raw disk images with `memdisk`:
+/// the disk image at the output first and then a disk image for each of the
+/// `kernels` in the order of their titles.
+///
+/// The builtin bootloader has no menu, so each additional kernel gets its own
+/// raw disk image, which is created in the `kernels` subdirectory of the output
+/// directory with the configuration of the kernel.
+fn memdisk_entries(
+    config: &Config,
+    target_dir: &Path,
+    out_dir: &Path,
+) -> Result<Vec<BootEntry>, Error> {
+    let mut entries = vec![BootEntry {
+        title: default_menu_title(config),
+        data: fs::read(&config.output)?,
+        cmdline: None,
+    }];
+    for (index, (title, entry)) in config.kernels.iter().enumerate() {
+        let kernel = File::open(menu_kernel_path(title, entry, out_dir)?)?;
+        let dir = outdir(config).join("kernels").join((index + 1).to_string());
+        fs::create_dir_all(&dir)?;
+        let mut entry_config = config.clone();
+        entry_config.format = ImageFormat::Raw;
+        entry_config.output = dir.join(config.output.file_name().expect("output is a file"));
+        entry_config.kernels = BTreeMap::new();
+        entry_config.emit_map = false;
+        entry_config.emit_checksums = false;
+        entry_config.export = Vec::new();
+        if entry.cmdline.is_some() {
+            entry_config.kernel_cmdline = entry.cmdline.clone();
+        }
+        println!("Creating disk image of the menu entry `{}`", title);
+        create_image(&entry_config, kernel, target_dir, out_dir)?;
+        entries.push(BootEntry {
+            title: title.clone(),
+            data: fs::read(&entry_config.output)?,
+            cmdline: None,
+        });
+    }
+    Ok(entries)
+}
+
+/// Writes the SHA-256 hashes of the disk image, the kernel, and the bootloader to a
+/// checksum file (in `sha256sum` format) and a JSON manifest with their sizes and the
+/// path of the serial log (`null` without `capture-serial`).
+fn write_checksums(config: &Config, out_dir: &Path) -> Result<(), Error> {
+    let image_path = config.image_path();
+    let mut paths = vec![image_path.clone(), outdir(config).join("kernel.elf")];
+    // UEFI images don't use a BIOS bootloader
+    let bootloader_path = outdir(config).join("bootloader.elf");
+    if bootloader_path.is_file() {
+        paths.push(bootloader_path);
+    }
+
+    println!("Writing checksums to {}", config.checksums_path().display());
+    let mut checksums = String::new();
+    let mut artifacts = Vec::new();
+    for path in &paths {
+        let data = fs::read(path)?;
+        let hash = sha256::to_hex(&sha256::sha256(&data));
+        // the artifacts are next to the checksum file, so `sha256sum -c` works from there
+        let name = path
+            .file_name()
+            .expect("artifact paths have a file name")
+            .to_string_lossy();
+        checksums.push_str(&format!("{}  {}\n", hash, name));
+        artifacts.push(json!({
+            "name": name,
+            "size": data.len(),
+            "sha256": hash,
+        }));
+    }
+    fs::write(config.checksums_path(), checksums)?;
+    // the log is written by the runs, which come after the build
+    let serial_log = serial_log_path(config, out_dir)
+        .filter(|_| config.capture_serial)
+        .map(|log| log.to_string_lossy().into_owned());
+    fs::write(
+        config.manifest_path(),
+        json!({ "artifacts": artifacts, "serial_log": serial_log }).to_string(),
+    )?;
+    Ok(())
+}
+
+/// The build metadata that is embedded into the kernel info block with `embed-metadata`.
+///
+/// Values that aren't available, like the commit outside of a git repository, are
+/// left out. The build time is taken from `SOURCE_DATE_EPOCH` if it is set, and
+/// reproducible builds leave it out otherwise.
+fn build_metadata(config: &Config) -> Vec<(&'static str, String)> {
+    use std::time::SystemTime;
+
+    let output = |command: &mut process::Command| {
+        command
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    };
+    let crate_dir = config.manifest_path.parent().unwrap_or_else(|| Path::new("."));
+    let git = |args: &[&str]| output(process::Command::new("git").arg("-C").arg(crate_dir).args(args));
+    let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+
+    let mut metadata = Vec::new();
+    if let Some(commit) = git(&["rev-parse", "HEAD"]) {
+        let dirty = git(&["status", "--porcelain", "--untracked-files=no"])
+            .is_some_and(|status| !status.is_empty());
+        let suffix = if dirty { "-dirty" } else { "" };
+        metadata.push(("git", format!("{}{}", commit, suffix)));
+    }
+    if let Some(version) = output(process::Command::new(rustc).arg("--version")) {
+        metadata.push(("rustc", version));
+    }
+    metadata.push(("bootimage", env!("CARGO_PKG_VERSION").to_owned()));
+    let timestamp = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => Some(epoch),
+        Err(_) if config.reproducible => None,
+        Err(_) => SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .ok()
+            .map(|time| time.as_secs().to_string()),
+    };
+    if let Some(timestamp) = timestamp {
+        metadata.push(("timestamp", timestamp));
+    }
+    metadata
+}
+
+/// Creates the disk image a second time in a scratch directory and fails if it
+/// isn't byte-identical to the first one.
+fn check_reproducible(config: &Config, target_dir: &Path, out_dir: &Path) -> Result<(), Error> {
+    let check_dir = outdir(config).join("reproducible-check");
+    if check_dir.exists() {
+        fs::remove_dir_all(&check_dir)?;
+    }
+    fs::create_dir_all(&check_dir)?;
+    let mut check_config = config.clone();
+    check_config.output = check_dir.join(config.output.file_name().expect("output is a file"));
+    check_config.emit_map = false;
+    check_config.emit_checksums = false;
+    check_config.export = Vec::new();
+    // the kernel as built by cargo, before it was stripped
+    let kernel = if config.strip_kernel {
+        File::open(outdir(config).join("kernel.debug"))?
+    } else {
+        File::open(outdir(config).join("kernel.elf"))?
+    };
+
+    println!("Checking that the disk image is reproducible");
+    create_image(&check_config, kernel, target_dir, out_dir)?;
+    let image = fs::read(config.image_path())?;
+    let check_image = fs::read(check_config.image_path())?;
+    fs::remove_dir_all(&check_dir)?;
+    if image != check_image {
+        let offset = image
+            .iter()
+            .zip(&check_image)
+            .position(|(a, b)| a != b)
+            .unwrap_or_else(|| image.len().min(check_image.len()));
+        return Err(Error::Image(format!(
+            "the disk image is not reproducible: a second build differs at offset {:#x}",
+            offset
+        )));
+    }
+    Ok(())
+}
+
+/// Fails with the difference to the budget if `size` exceeds the `budget` that is
+/// configured through `key`.
+fn check_budget(what: &str, size: u64, key: &str, budget: Option<u64>) -> Result<(), String> {
+    match budget {
+        Some(budget) if size > budget => Err(format!(
+            "{} is {} ({} bytes), which exceeds `{}` of {} ({} bytes) by {} ({} bytes)",
+            what,
+            Bytes(size),
+            size,
+            key,
+            Bytes(budget),
+            budget,
+            Bytes(size - budget),
+            size - budget
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the disk image path for a kernel executable passed to `bootimage runner`.
+///
+/// The image is placed next to the executable so that different binaries don't
+/// overwrite each other's images.
+fn runner_output_path(executable: &Path) -> PathBuf {
+    let file_name = executable
+        .file_stem()
+        .expect("kernel executable must have a file name")
+        .to_string_lossy();
+    executable.with_file_name(format!("bootimage-{}.bin", file_name))
+}
+
+/// Returns the output directory of the profile that a kernel executable passed
+/// to `bootimage runner` was built with.
+fn runner_out_dir(executable: &Path) -> PathBuf {
+    let dir = executable.parent().unwrap_or_else(|| Path::new("."));
+    // test and example executables are placed in subdirectories
+    match dir.file_name().and_then(|name| name.to_str()) {
+        Some("deps") | Some("examples") => dir.parent().unwrap_or(dir).to_path_buf(),
+        _ => dir.to_path_buf(),
+    }
+}
+
+/// The log of the serial output of the run command of the image of `config`, which
+/// is named after the image, since it differs between kernels.
+fn serial_log_path(config: &Config, out_dir: &Path) -> Option<PathBuf> {
+    config
+        .output
+        .file_stem()
+        .map(|stem| out_dir.join(format!("{}.serial.log", stem.to_string_lossy())))
+}
+
+/// The values of the named placeholders in the run command and the hooks, `None` if a
+/// value is not available.
+#[derive(Clone)]
+struct Variables(Vec<(&'static str, Option<String>)>);
+
+impl Variables {
+    fn new(
+        config: &Config,
+        metadata: &CargoMetadata,
+        out_dir: &Path,
+        target: Option<&str>,
+        profile: &str,
+    ) -> Variables {
+        let path = |path: &Path| Some(path.to_string_lossy().into_owned());
+        let crate_name = metadata
+            .packages
+            .iter()
+            .find(|p| Path::new(&p.manifest_path) == config.manifest_path)
+            .map(|p| p.name.clone());
+        let serial_log = serial_log_path(config, out_dir);
+        Variables(vec![
+            ("bootimage", path(&config.image_path())),
+            ("kernel_elf", path(&outdir(config).join("kernel.elf"))),
+            (
+                "kernel_debug",
+                Some(outdir(config).join("kernel.debug"))
+                    .filter(|_| config.strip_kernel)
+                    .and_then(|debug| path(&debug)),
+            ),
+            ("bootloader_elf", path(&outdir(config).join("bootloader.elf"))),
+            ("signature", config.signature_path().and_then(|sig| path(&sig))),
+            ("out_dir", path(out_dir)),
+            ("target", target.map(String::from)),
+            ("profile", Some(profile.to_owned())),
+            ("crate", crate_name),
+            (
+                "serial_log",
+                serial_log
+                    .filter(|_| config.capture_serial)
+                    .and_then(|log| path(&log)),
+            ),
+        ])
+    }
+
+    fn get(&self, name: &str) -> Option<Option<&String>> {
+        self.0
+            .iter()
+            .find(|variable| variable.0 == name)
+            .map(|variable| variable.1.as_ref())
+    }
+}
+
+fn build_variables(
+    args: &Args,
+    config: &Config,
+    metadata: &CargoMetadata,
+    out_dir: &Path,
+) -> Variables {
+    let target = args.target().as_deref();
+    Variables::new(config, metadata, out_dir, target, profile(args))
+}
+
+/// Runs the hook command of the configuration key `name`.
+///
+/// The values of the placeholders are passed to the hook in `BOOTIMAGE_*`
+/// environment variables, e.g. `BOOTIMAGE_KERNEL_ELF`.
+fn run_hook(name: &str, hook: Option<&Vec<String>>, variables: &Variables) -> Result<(), Error> {
+    let hook = match hook {
+        Some(hook) => hook,
+        None => return Ok(()),
+    };
+    let mut args = Vec::new();
+    for arg in hook {
+        args.push(expand_placeholders(arg, variables)?);
+    }
+
+    println!("Running {} hook", name);
+    let mut command = process::Command::new(&args[0]);
+    command.args(&args[1..]);
+    for variable in &variables.0 {
+        if let Some(ref value) = variable.1 {
+            let name = match variable.0 {
+                "bootimage" => "image",
+                name => name,
+            };
+            command.env(format!("BOOTIMAGE_{}", name.to_uppercase()), value);
+        }
+    }
+    let status = command.status().map_err(|err| {
+        Error::Hook(format!("failed to run the `{}` hook `{}`: {}", name, args[0], err))
+    })?;
+    if !status.success() {
+        Err(Error::Hook(format!("the `{}` hook failed ({})", name, status)))?
+    }
+    Ok(())
+}
+
+/// Writes a detached signature of the disk image with the configured signing method.
+///
+/// A `signing-key` is passed to `minisign`, while a `signing-command` is run with
+/// the `{bootimage}` and `{signature}` placeholders.
+fn sign_image(config: &Config, variables: &Variables) -> Result<(), Error> {
+    let signature_path = match config.signature_path() {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let args = match (config.signing_key.as_ref(), config.signing_command.as_ref()) {
+        (Some(key), _) => vec![
+            "minisign".into(),
+            "-S".into(),
+            "-s".into(),
+            key.to_string_lossy().into_owned(),
+            "-m".into(),
+            config.image_path().to_string_lossy().into_owned(),
+            "-x".into(),
+            signature_path.to_string_lossy().into_owned(),
+        ],
+        (None, Some(command)) => {
+            let mut args = Vec::new();
+            for arg in command {
+                args.push(expand_placeholders(arg, variables)?);
+            }
+            args
+        }
+        (None, None) => unreachable!("the signature path requires a signing method"),
+    };
+
+    println!("Signing disk image to {}", signature_path.display());
+    let status = process::Command::new(&args[0])
+        .args(&args[1..])
+        .status()
+        .map_err(|err| Error::Sign(format!("failed to run `{}`: {}", args[0], err)))?;
+    if !status.success() {
+        return Err(Error::Sign(format!("`{}` failed ({})", args[0], status)));
+    }
+    if !signature_path.is_file() {
+        return Err(Error::Sign(format!(
+            "`{}` didn't write the signature to {}",
+            args[0],
+            signature_path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Replaces the placeholders in an argument of the run command.
+///
+/// `{}` is a shorthand for `{bootimage}`, and `{{` and `}}` are literal braces.
+fn expand_placeholders(arg: &str, variables: &Variables) -> Result<String, Error> {
+    let mut expanded = String::new();
+    let mut chars = arg.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                expanded.push('{');
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                expanded.push('}');
+            }
+            '{' => {
+                let rest = chars.as_str();
+                let end = rest.find('}').ok_or_else(|| {
+                    Error::Config(format!(
+                        "unclosed placeholder in run command argument `{}` \
+                         (use `{{{{` for a literal `{{`)",
+                        arg
+                    ))
+                })?;
+                let name = match &rest[..end] {
+                    "" => "bootimage",
+                    name => name,
+                };
+                let value = match variables.get(name) {
+                    Some(Some(value)) => value,
+                    Some(None) => Err(Error::Config(format!(
+                        "the value of placeholder `{{{}}}` in the run command is not available",
+                        name
+                    )))?,
+                    None => Err(Error::Config(format!(
+                        "unknown placeholder `{{{}}}` in run command argument `{}` \
+                         (use `{{{{` and `}}}}` for literal braces)",
+                        name, arg
+                    )))?,
+                };
+                expanded.push_str(value);
+                chars = rest[end + 1..].chars();
+            }
+            c => expanded.push(c),
+        }
+    }
+    Ok(expanded)
+}
+
+/// Runs the `pre-run` hook and prepares the command that runs the disk image with the
+/// run profile `run_profile`, the `run_args`, and the `extra_args`, together with the
+/// QMP socket that controls the default QEMU run command. The output of the command is
+/// piped when it is processed (for the serial log, the backtrace marker, the serial
+/// port, or an `output_prefix`), and inherited otherwise.
+fn run_command(
+    run_args: &[String],
+    config: &Config,
+    run_profile: Option<&str>,
+    variables: &Variables,
+    extra_args: &[&str],
+    output_prefix: Option<&str>,
+) -> Result<(process::Command, Option<qmp::Socket>), Error> {
+    let run_profile = config.run_profile(run_profile)?;
+    run_hook("pre-run", config.pre_run.as_ref(), variables)?;
+    let is_default = run_profile.command.is_none() && config.run_command.is_none();
+    check_default_run_command(config, is_default)?;
+    let run_command = run_profile
+        .command
+        .unwrap_or_else(|| config.run_command_or_default());
+    let program = if is_default {
+        find_executable(&run_command[0], QEMU_INSTALL_DIRS).ok_or_else(|| {
+            Error::Run(format!(
+                "no run command is configured and `{}` was not found, install QEMU \
+                 or configure a `run-command`",
+                run_command[0]
+            ))
+        })?
+    } else {
+        PathBuf::from(expand_placeholders(&run_command[0], variables)?)
+    };
+    if config.deterministic && !is_default {
+        return Err(Error::Run(
+            "deterministic runs require the default QEMU run command, which attaches the disk \
+             image for record/replay"
+                .into(),
+        ));
+    }
+    let mut command = process::Command::new(&program);
+    // only QEMU is known to understand `-qmp`
+    let qmp = if is_default {
+        Some(qmp::Socket::new()?)
+    } else {
+        None
+    };
+    if let Some(ref qmp) = qmp {
+        command.args(qmp.qemu_args());
+    }
+    let recording = if config.deterministic {
+        Some(replay::Recording::create(config, &outdir(config))?)
+    } else {
+        None
+    };
+    // custom run commands configure the firmware themselves
+    if is_default && config.firmware == Firmware::Uefi {
+        // the replay starts from the variable store of the recorded run
+        let vars_dir = recording.as_ref().map_or_else(|| outdir(config), |r| r.dir().into());
+        command.args(ovmf::qemu_args(config, &program, &vars_dir)?);
+    }
+    // `-icount` requires the emulated CPU
+    if is_default
+        && config.accel
+        && !config.deterministic
+        && !accel::selects_accelerator(run_profile.args.iter().chain(run_args))
+    {
+        command.args(accel::qemu_args(&program));
+    }
+    if is_default {
+        command.args(hardware_args(&config.qemu, run_profile.args.iter().chain(run_args)));
+    }
+    match recording {
+        Some(ref recording) => {
+            command.args(recording.qemu_args(config)?);
+        }
+        None => {
+            for arg in &run_command[1..] {
+                command.arg(expand_placeholders(arg, variables)?);
+            }
+        }
+    }
+    for arg in &run_profile.args {
+        command.arg(expand_placeholders(arg, variables)?);
+    }
+    let serial_log = variables.get("serial_log").and_then(|log| log.map(PathBuf::from));
+    let piped = serial_log.is_some()
+        || output_prefix.is_some()
+        || config.backtrace_marker.is_some()
+        || config.serial_port.is_some();
+    if piped {
+        if is_default {
+            command.args(["-serial", "stdio"]);
+        }
+        command.stdout(process::Stdio::piped());
+    }
+    if is_default && !selects_display(run_profile.args.iter().chain(run_args)) {
+        if let Some(display) = config.display.clone().or_else(default_display) {
+            command.args(display_args(&display, piped)?);
+        }
+    }
+    for (name, value) in &run_profile.env {
+        command.env(name, expand_placeholders(value, variables)?);
+    }
+    command.args(extra_args);
+    command.args(run_args);
+    if let Some(recording) = recording {
+        recording.save(&command)?;
+    }
+    Ok((command, qmp))
+}
+
+fn check_default_run_command(config: &Config, is_default: bool) -> Result<(), Error> {
+    if is_default && config.format == ImageFormat::Multiboot2 {
+        return Err(Error::Run(
+            "QEMU can't boot Multiboot2 kernels directly, configure a `run-command` that boots \
+             the kernel through a Multiboot2 bootloader like GRUB"
+                .into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Runs the disk image with the run command on the remote machine `host`, whose
+/// output is echoed (and captured) like the output of a local run command.
+///
+/// The accelerator of the remote machine isn't detected, so the default QEMU run
+/// command uses KVM if it is available and TCG otherwise. Only the disk image and
+/// the firmware are copied, so the placeholders of other local files aren't
+/// available in the run command.
+fn run_remote(
+    host: &str,
+    run_args: &[String],
+    config: &Config,
+    run_profile: Option<&str>,
+    variables: &Variables,
+) -> Result<process::ExitStatus, Error> {
+    let run_profile = config.run_profile(run_profile)?;
+    run_hook("pre-run", config.pre_run.as_ref(), variables)?;
+    if config.deterministic {
+        return Err(Error::Run(
+            "deterministic runs aren't supported on remote machines".into(),
+        ));
+    }
+    let is_default = run_profile.command.is_none() && config.run_command.is_none();
+    check_default_run_command(config, is_default)?;
+    let run_command = run_profile
+        .command
+        .unwrap_or_else(|| config.run_command_or_default());
+
+    let file_name = |path: &Path| -> String {
+        path.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+    let image = config.image_path();
+    // the run command is started in the directory of the uploaded files
+    let remote_variables = Variables(
+        variables
+            .0
+            .iter()
+            .map(|&(name, ref value)| {
+                let value = match name {
+                    "bootimage" => Some(file_name(&image)),
+                    "out_dir" => Some(".".into()),
+                    "target" | "profile" | "crate" => value.clone(),
+                    _ => None,
+                };
+                (name, value)
+            })
+            .collect(),
+    );
+    let mut files = vec![image.clone()];
+    let mut remote_command = Vec::new();
+    for (name, value) in &run_profile.env {
+        remote_command.push(format!("{}={}", name, expand_placeholders(value, &remote_variables)?));
+    }
+    if !remote_command.is_empty() {
+        remote_command.insert(0, "env".into());
+    }
+    remote_command.push(expand_placeholders(&run_command[0], &remote_variables)?);
+    if is_default && config.firmware == Firmware::Uefi {
+        let (code, vars) = ovmf::firmware(config, Path::new(&run_command[0]), &outdir(config))?;
+        remote_command.extend(ovmf::drive_args(
+            Path::new(&file_name(&code)),
+            Path::new(&file_name(&vars)),
+        ));
+        files.extend([code, vars]);
+    }
+    if is_default
+        && config.accel
+        && !accel::selects_accelerator(run_profile.args.iter().chain(run_args))
+    {
+        // QEMU uses the first accelerator that works
+        remote_command.extend(["-accel", "kvm", "-accel", "tcg"].map(String::from));
+    }
+    if is_default {
+        let args = run_profile.args.iter().chain(run_args);
+        remote_command.extend(hardware_args(&config.qemu, args));
+    }
+    for arg in run_command[1..].iter().chain(&run_profile.args) {
+        remote_command.push(expand_placeholders(arg, &remote_variables)?);
+    }
+    let serial_log = variables.get("serial_log").and_then(|log| log.map(PathBuf::from));
+    let piped = serial_log.is_some()
+        || config.backtrace_marker.is_some()
+        || config.serial_port.is_some();
+    if piped && is_default {
+        remote_command.extend(["-serial", "stdio"].map(String::from));
+    }
+    if is_default && !selects_display(run_profile.args.iter().chain(run_args)) {
+        // a window would open on the remote machine
+        let display = config.display.clone().unwrap_or(Display::None);
+        remote_command.extend(display_args(&display, piped)?);
+    }
+    remote_command.extend(run_args.iter().cloned());
+
+    let stem = config
+        .output
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "bootimage".into());
+    let remote = Remote::new(host, &stem);
+    remote.upload(&files)?;
+    println!("Running on {}", host);
+    let mut command = process::Command::new("ssh");
+    // without a terminal, the serial output can be read
+    command.args(remote.command(&remote_command, !piped));
+    if piped {
+        command.stdout(process::Stdio::piped());
+    }
+    run_process(command, None, config, variables, None, None)
+}
+
+/// Escapes a path for a QEMU option (e.g. `-drive` or `-qmp`), in which commas
+/// separate the parameters.
+pub(crate) fn qemu_escape(path: &Path) -> String {
+    path.to_string_lossy().replace(',', ",,")
+}
+
+/// Whether the arguments select the display of QEMU, which isn't overridden.
+fn selects_display<'a, I: IntoIterator<Item = &'a String>>(args: I) -> bool {
+    args.into_iter().any(|arg| {
+        let arg = arg.trim_start_matches('-');
+        arg == "display" || arg == "nographic" || arg == "vnc" || arg == "curses"
+    })
+}
+
+/// The QEMU arguments for the configured virtual hardware, except for the options
+/// that the run profile or the run arguments set themselves.
+fn hardware_args<'a, I>(qemu: &QemuConfig, args: I) -> Vec<String>
+where
+    I: Iterator<Item = &'a String> + Clone,
+{
+    let sets = |options: &[&str]| {
+        args.clone()
+            .any(|arg| options.contains(&arg.trim_start_matches('-')))
+    };
+    let mut hardware = Vec::new();
+    if let Some(memory) = qemu.memory.filter(|_| !sets(&["m"])) {
+        hardware.extend(["-m".into(), format!("{}M", memory / (1024 * 1024))]);
+    }
+    if let Some(smp) = qemu.smp.filter(|_| !sets(&["smp"])) {
+        hardware.extend(["-smp".into(), smp.to_string()]);
+    }
+    if let Some(cpu) = qemu.cpu.as_ref().filter(|_| !sets(&["cpu"])) {
+        hardware.extend(["-cpu".into(), cpu.clone()]);
+    }
+    if let Some(machine) = qemu.machine.filter(|_| !sets(&["machine", "M"])) {
+        hardware.extend(["-machine".into(), machine.qemu_arg().into()]);
+    }
+    hardware
+}
+
+/// The display of QEMU if none is configured: none in terminal sessions without a
+/// graphical session (e.g. over SSH or in CI), in which QEMU would fail to open its
+/// window, and QEMU's default otherwise.
+fn default_display() -> Option<Display> {
+    let graphical = env::var_os("DISPLAY").is_some() || env::var_os("WAYLAND_DISPLAY").is_some();
+    // Windows and macOS always have a graphical session
+    if cfg!(unix) && !cfg!(target_os = "macos") && !graphical {
+        Some(Display::None)
+    } else {
+        None
+    }
+}
+
+/// The QEMU arguments for the display, which show the serial output in the terminal
+/// if there is no local window and the output isn't `piped` already.
+fn display_args(display: &Display, piped: bool) -> Result<Vec<String>, Error> {
+    let display_arg = vec!["-display".to_owned(), display.qemu_arg()];
+    Ok(match *display {
+        // like `-display none`, but with the serial port and the monitor on the terminal
+        Display::None if !piped => vec!["-nographic".into()],
+        Display::None | Display::Gtk => display_arg,
+        Display::Curses if piped => {
+            return Err(Error::Run(
+                "the `curses` display draws the screen in the terminal, which doesn't work when \
+                 the serial output is read (with `capture-serial`, a `backtrace-marker`, a \
+                 `serial-port`, or parallel tests)"
+                    .into(),
+            ))
+        }
+        Display::Curses => display_arg,
+        Display::Vnc(ref vnc_display) => {
+            println!("The screen of the VM is shown by the VNC server on {}", vnc_display);
+            let mut args = display_arg;
+            if !piped {
+                // `mon:` multiplexes the monitor of QEMU, as with `-nographic`
+                args.extend(["-serial".to_owned(), "mon:stdio".to_owned()]);
+            }
+            args
+        }
+    })
+}
+
+fn spawn_run(command: &mut process::Command) -> Result<process::Child, Error> {
+    command.spawn().map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => Error::Run(format!(
+            "run command `{}` was not found",
+            Path::new(command.get_program()).display()
+        )),
+        _ => Error::Io(err),
+    })
+}
+
+fn run_impl(
+    run_args: &[String],
+    config: &Config,
+    run_profile: Option<&str>,
+    variables: &Variables,
+    extra_args: &[&str],
+    output_prefix: Option<&str>,
+) -> Result<process::ExitStatus, Error> {
+    let (command, qmp) = run_command(
+        run_args,
+        config,
+        run_profile,
+        variables,
+        extra_args,
+        output_prefix,
+    )?;
+    run_process(command, qmp.as_ref(), config, variables, output_prefix, None)
+}
+
+/// Boots the kernel in the microVM monitor of the run backend, whose output is the
+/// serial output of the kernel. The run arguments are passed to the monitor.
+fn run_microvm(
+    run_args: &[String],
+    config: &Config,
+    run_profile: Option<&str>,
+    variables: &Variables,
+) -> Result<process::ExitStatus, Error> {
+    if run_profile.is_some() {
+        return Err(Error::Run(
+            "run profiles are only supported by the `qemu` run backend".into(),
+        ));
+    }
+    run_hook("pre-run", config.pre_run.as_ref(), variables)?;
+    let kernel = outdir(config).join("kernel.elf");
+    let mut command = microvm::command(config, &kernel, &outdir(config))?;
+    let serial_log = variables.get("serial_log").and_then(|log| log.map(PathBuf::from));
+    if serial_log.is_some() || config.backtrace_marker.is_some() || config.serial_port.is_some() {
+        command.stdout(process::Stdio::piped());
+    }
+    command.args(run_args);
+    run_process(command, None, config, variables, None, None)
+}
+
+/// Runs the disk image in Bochs, whose serial output is echoed (and captured) like
+/// the output of the run command. The run arguments are passed to Bochs, which
+/// reads them as additional lines of the `bochsrc`.
+fn run_bochs(
+    run_args: &[String],
+    config: &Config,
+    run_profile: Option<&str>,
+    variables: &Variables,
+) -> Result<(), Error> {
+    if run_profile.is_some() {
+        return Err(Error::Run(
+            "run profiles are only supported by the `qemu` run backend".into(),
+        ));
+    }
+    run_hook("pre-run", config.pre_run.as_ref(), variables)?;
+    let bochs_dir = bochs::bochs_dir(&outdir(config));
+    let mut command = bochs::command(config, &bochs_dir)?;
+    command.args(run_args);
+    let mut child = spawn_run(&mut command)?;
+    follow_serial(config, variables, &bochs::serial_path(&bochs_dir), || {
+        let status = wait_with_timeout(&mut child, config.run_timeout, None, None)?;
+        if !status.success() {
+            return Err(Error::Run(format!(
+                "Bochs exited with {} (see {} for details)",
+                status,
+                bochs::log_path(&bochs_dir).display()
+            )));
+        }
+        Ok(())
+    })
 }
 
-pub(crate) fn run(args: Args) -> Result<(), Error> {
-    let (args, config, metadata, out_dir) = common_setup(args)?;
-
-    build_impl(&args, &config, &metadata, &out_dir)?;
-    run_impl(&args, &config)
-}
+/// Runs the prepared run command, echoing (and capturing) its output if it is
+/// piped, until it exits, `run-timeout` passes, or `stop` is set.
+///
+/// With a QMP socket, the configured screenshots are taken while it runs and
+/// compared with their golden images after it exited, and QEMU is shut down
+/// through the socket after a timeout.
+fn run_process(
+    mut command: process::Command,
+    qmp: Option<&qmp::Socket>,
+    config: &Config,
+    variables: &Variables,
+    output_prefix: Option<&str>,
+    stop: Option<&AtomicBool>,
+) -> Result<process::ExitStatus, Error> {
+    // the input of the serial console goes to the only VM, instead of the terminal
+    let takes_input = console::is_running() && output_prefix.is_none();
+    if takes_input {
+        command.stdin(process::Stdio::piped());
+    }
+    let mut child = spawn_run(&mut command)?;
+    if takes_input {
+        console::set_input(child.stdin.take());
+    }
+    let serial_log = variables.get("serial_log").and_then(|log| log.map(PathBuf::from));
+    let log = match serial_log {
+        Some(log) => {
+            println!("Capturing serial output in {}", log.display());
+            Some(File::create(log)?)
+        }
+        None => None,
+    };
+    let output = child.stdout.take();
 
-fn common_setup(mut args: Args) -> Result<(Args, Config, CargoMetadata, PathBuf), Error> {
-    fn out_dir(args: &Args, metadata: &CargoMetadata) -> PathBuf {
-        let target_dir = PathBuf::from(&metadata.target_directory);
-        let mut out_dir = target_dir;
-        if let &Some(ref target) = args.target() {
-            out_dir.push(target);
+    let marker = config.backtrace_marker.as_deref();
+    let takes_screenshots = !config.screenshots.at.is_empty() || config.screenshots.at_end;
+    let out_dir = outdir(config);
+    let stopped = AtomicBool::new(false);
+    let (status, screenshots) = thread::scope(|scope| {
+        let tee = output
+            .map(|output| scope.spawn(move || tee_output(output, log, output_prefix, marker)));
+        let capture = qmp
+            .filter(|_| takes_screenshots)
+            .map(|qmp| {
+                let (out_dir, stopped) = (&out_dir, &stopped);
+                scope.spawn(move || screenshot::capture(config, qmp, out_dir, stopped))
+            });
+        let status = wait_with_timeout(&mut child, config.run_timeout, qmp, stop);
+        if takes_input {
+            console::set_input(None);
         }
-        if args.release() {
-            out_dir.push("release");
-        } else {
-            out_dir.push("debug");
+        stopped.store(true, Ordering::SeqCst);
+        if let Some(tee) = tee {
+            let backtrace = tee.join().expect("serial output thread panicked")?;
+            // a kernel that hangs after printing its backtrace still gets it symbolized
+            if !backtrace.is_empty() {
+                print_backtrace(&backtrace, variables, output_prefix);
+            }
         }
-        out_dir
+        let screenshots = match capture {
+            Some(capture) => Some(capture.join().expect("screenshot thread panicked")?),
+            None => None,
+        };
+        Ok::<_, Error>((status, screenshots))
+    })?;
+    let status = status?;
+    if let Some(screenshots) = screenshots {
+        screenshot::check(config, &screenshots)?;
     }
+    Ok(status)
+}
 
-    let metadata = read_cargo_metadata(&args)?;
-    let crate_root = PathBuf::from(&metadata.workspace_root);
-    let manifest_path = args.manifest_path().as_ref().map(Clone::clone).unwrap_or({
-        let mut path = crate_root.clone();
-        path.push("Cargo.toml");
-        path
-    });
-    let config = config::read_config(manifest_path)?;
-
-    if args.target().is_none() {
-        if let Some(ref target) = config.default_target {
-            args.set_target(target.clone());
+/// Runs the disk image in the VM of the VirtualBox, VMware, or Hyper-V run
+/// backend, whose serial output is echoed (and captured) like the output of the
+/// run command.
+fn run_vm(
+    run_args: &[String],
+    config: &Config,
+    run_profile: Option<&str>,
+    variables: &Variables,
+) -> Result<(), Error> {
+    if !run_args.is_empty() || run_profile.is_some() {
+        return Err(Error::Run(
+            "run arguments and run profiles are only supported by the `qemu` run backend".into(),
+        ));
+    }
+    run_hook("pre-run", config.pre_run.as_ref(), variables)?;
+    match config.run_backend {
+        RunBackend::VirtualBox => {
+            let vm = virtualbox::start(config, &outdir(config))?;
+            follow_serial(config, variables, vm.serial(), || vm.wait(config.run_timeout))
+        }
+        RunBackend::Vmware => {
+            let vm = vmware::start(config, &vmware::vm_dir(&outdir(config)))?;
+            follow_serial(config, variables, vm.serial(), || vm.wait(config.run_timeout))
+        }
+        RunBackend::HyperV => {
+            let vm = hyperv::start(config, &outdir(config))?;
+            follow_serial(config, variables, vm.serial(), || vm.wait(config.run_timeout))
+        }
+        RunBackend::Qemu
+        | RunBackend::Firecracker
+        | RunBackend::CloudHypervisor
+        | RunBackend::Bochs => {
+            unreachable!("the run backend has no VM")
         }
     }
+}
 
-    let out_dir = out_dir(&args, &metadata);
+/// Echoes (and captures) the serial output that a VM writes to `serial` until
+/// `wait` returns.
+fn follow_serial<F>(
+    config: &Config,
+    variables: &Variables,
+    serial: &Path,
+    wait: F,
+) -> Result<(), Error>
+where
+    F: FnOnce() -> Result<(), Error>,
+{
+    let serial_log = variables.get("serial_log").and_then(|log| log.map(PathBuf::from));
+    let log = match serial_log {
+        Some(log) => {
+            println!("Capturing serial output in {}", log.display());
+            Some(File::create(log)?)
+        }
+        None => None,
+    };
 
-    Ok((args, config, metadata, out_dir))
+    let marker = config.backtrace_marker.as_deref();
+    let stopped = AtomicBool::new(false);
+    thread::scope(|scope| {
+        let output = FollowedFile {
+            path: serial,
+            file: None,
+            stopped: &stopped,
+        };
+        let tee = scope.spawn(move || tee_output(output, log, None, marker));
+        let result = wait();
+        stopped.store(true, Ordering::SeqCst);
+        let backtrace = tee.join().expect("serial output thread panicked")?;
+        if !backtrace.is_empty() {
+            print_backtrace(&backtrace, variables, None);
+        }
+        result
+    })
 }
 
-fn build_impl(
-    args: &Args,
-    config: &Config,
-    metadata: &CargoMetadata,
-    out_dir: &Path,
-) -> Result<(), Error> {
-    let kernel = build_kernel(&out_dir, &args, &config, &metadata)?;
+/// Reads a file (or a named pipe) while another process writes to it, like a pipe
+/// that is closed once `stopped` is set.
+struct FollowedFile<'a> {
+    path: &'a Path,
+    /// The file, which is opened once it has been created.
+    file: Option<File>,
+    stopped: &'a AtomicBool,
+}
 
-    let kernel_size = kernel.metadata()?.len();
-    let kernel_info_block = create_kernel_info_block(kernel_size);
+impl<'a> io::Read for FollowedFile<'a> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        loop {
+            // the flag is checked first, so that everything that was written before
+            // it was set is read
+            let stopped = self.stopped.load(Ordering::SeqCst);
+            if self.file.is_none() {
+                match File::open(self.path) {
+                    Ok(file) => self.file = Some(file),
+                    Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            if let Some(ref mut file) = self.file {
+                match file.read(buffer) {
+                    Ok(0) => {}
+                    Ok(len) => return Ok(len),
+                    // the writer of a named pipe has closed it
+                    Err(ref err) if err.kind() == io::ErrorKind::BrokenPipe => return Ok(0),
+                    Err(err) => return Err(err),
+                }
+            }
+            if stopped {
+                return Ok(0);
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
 
-    if args.update_bootloader() {
-        let mut bootloader_cargo_lock = PathBuf::from(out_dir);
-        bootloader_cargo_lock.push("bootloader");
-        bootloader_cargo_lock.push("Cargo.lock");
+/// Prints the backtrace addresses from the serial output with their functions and
+/// source lines.
+fn print_backtrace(addresses: &[u64], variables: &Variables, prefix: Option<&str>) {
+    let kernel = match variables.get("kernel_debug") {
+        Some(Some(path)) => Some(path),
+        _ => variables.get("kernel_elf").and_then(|path| path),
+    };
+    let symbolizer = match kernel {
+        Some(path) => fs::read(path)
+            .map_err(|err| err.to_string())
+            .and_then(|elf| Symbolizer::new(&elf)),
+        None => Err("the kernel ELF file is not available".into()),
+    };
+    let prefix = prefix.map(|prefix| format!("[{}] ", prefix)).unwrap_or_default();
+    println!("{}Backtrace:", prefix);
+    for (index, &address) in addresses.iter().enumerate() {
+        match symbolizer {
+            Ok(ref symbolizer) => {
+                println!("{}{:>4}: {}", prefix, index, symbolizer.locate(address))
+            }
+            Err(_) => println!("{}{:>4}: {:#018x}", prefix, index, address),
+        }
+    }
+    if let Err(err) = symbolizer {
+        eprintln!("Warning: the backtrace could not be symbolized: {}", err);
+    }
+}
 
-        fs::remove_file(bootloader_cargo_lock)?;
+/// Waits for the run command to exit and kills it after `timeout` or once `stop`
+/// is set.
+fn wait_with_timeout(
+    child: &mut process::Child,
+    timeout: Option<Duration>,
+    qmp: Option<&qmp::Socket>,
+    stop: Option<&AtomicBool>,
+) -> Result<process::ExitStatus, Error> {
+    if timeout.is_none() && stop.is_none() {
+        return Ok(child.wait()?);
+    }
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if stop.is_some_and(|stop| stop.load(Ordering::SeqCst)) {
+            // the kernel is replaced anyway, so it doesn't get the chance to shut down,
+            // but QEMU quits through QMP, which restores the terminal unlike killing it
+            if let Some(qmp) = qmp {
+                let _ = qmp.with(Duration::from_secs(1), |qmp| {
+                    // QEMU may exit before it answers
+                    let _ = qmp.quit();
+                    Ok(wait_for_exit(child, Duration::from_secs(2))?)
+                });
+            }
+            if child.try_wait()?.is_none() {
+                child.kill()?;
+            }
+            return Ok(child.wait()?);
+        }
+        if let Some(timeout) = timeout.filter(|&timeout| start.elapsed() >= timeout) {
+            // a hung kernel would otherwise block forever, and QEMU is only killed if
+            // it doesn't quit through QMP
+            if let Some(qmp) = qmp {
+                if let Err(err) = shut_down(child, qmp) {
+                    eprintln!("Warning: could not shut down QEMU through QMP: {:?}", err);
+                }
+            }
+            if child.try_wait()?.is_none() {
+                child.kill()?;
+            }
+            child.wait()?;
+            return Err(Error::Timeout(timeout));
+        }
+        thread::sleep(Duration::from_millis(100));
     }
+}
 
-    let tmp_dir = TempDir::new("bootloader")?;
-    let bootloader = build_bootloader(tmp_dir.path(), &config)?;
-    tmp_dir.close()?;
+/// Shuts QEMU down through QMP: a running kernel gets the chance to shut down on
+/// an ACPI power button press, then QEMU quits, which flushes its disk images and
+/// logs unlike killing it.
+fn shut_down(child: &mut process::Child, qmp: &qmp::Socket) -> Result<(), Error> {
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+    const POWERDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+    const QUIT_TIMEOUT: Duration = Duration::from_secs(2);
 
-    create_disk_image(&config, kernel, kernel_info_block, &bootloader)?;
+    qmp.with(CONNECT_TIMEOUT, |qmp| {
+        let status = qmp.status()?;
+        if status == "running" {
+            qmp.system_powerdown()?;
+            if wait_for_exit(child, POWERDOWN_TIMEOUT)? {
+                return Ok(());
+            }
+        } else {
+            // e.g. `guest-panicked` or `internal-error`, which explain the hang
+            eprintln!("The VM was in the state `{}` when the run timed out", status);
+        }
+        // QEMU may exit before it answers
+        let _ = qmp.quit();
+        wait_for_exit(child, QUIT_TIMEOUT)?;
+        Ok(())
+    })
+}
 
-    Ok(())
+/// Waits up to `timeout` for the child to exit and returns whether it did.
+fn wait_for_exit(child: &mut process::Child, timeout: Duration) -> io::Result<bool> {
+    let start = Instant::now();
+    while child.try_wait()?.is_none() {
+        if start.elapsed() >= timeout {
+            return Ok(false);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    Ok(true)
 }
 
-fn run_impl(args: &Args, config: &Config) -> Result<(), Error> {
-    let command = &config.run_command[0];
-    let mut command = process::Command::new(command);
-    for arg in &config.run_command[1..] {
-        command.arg(
-            arg.replace(
-                "{}",
-                config
-                    .output
-                    .to_str()
-                    .expect("output must be valid unicode"),
-            ),
-        );
+/// Copies the output of the run command to stdout and to `log`.
+///
+/// With a `prefix`, every line is prefixed with it on stdout, so that the output of
+/// concurrently running test kernels can be told apart. The addresses of the lines
+/// that contain the backtrace `marker` are returned.
+fn tee_output<R: io::Read>(
+    output: R,
+    mut log: Option<File>,
+    prefix: Option<&str>,
+    marker: Option<&str>,
+) -> Result<Vec<u64>, io::Error> {
+    use std::io::{Read, Write};
+
+    let mut output = BufReader::new(output);
+    let mut buffer = Vec::new();
+    let mut backtrace = marker.map(BacktraceScanner::new);
+    let stdout = io::stdout();
+    loop {
+        buffer.clear();
+        let len = match prefix {
+            Some(_) => output.read_until(b'\n', &mut buffer)?,
+            None => {
+                buffer.resize(4096, 0);
+                let len = match output.read(&mut buffer) {
+                    Ok(len) => len,
+                    Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err),
+                };
+                buffer.truncate(len);
+                len
+            }
+        };
+        if len == 0 {
+            if let Some(ref mut log) = log {
+                log.flush()?;
+            }
+            return Ok(backtrace.map(BacktraceScanner::finish).unwrap_or_default());
+        }
+        if let Some(ref mut backtrace) = backtrace {
+            backtrace.feed(&buffer);
+        }
+        let echoed = match prefix {
+            Some(prefix) => {
+                let mut line = format!("[{}] ", prefix).into_bytes();
+                line.extend_from_slice(&buffer);
+                if !buffer.ends_with(b"\n") {
+                    line.push(b'\n');
+                }
+                Cow::Owned(line)
+            }
+            None => Cow::Borrowed(&buffer[..]),
+        };
+        // the output is echoed unbuffered, so that it appears as the kernel writes it
+        let mut stdout = stdout.lock();
+        stdout.write_all(&echoed)?;
+        stdout.flush()?;
+        console::broadcast(&echoed);
+        if let Some(ref mut log) = log {
+            log.write_all(&buffer)?;
+        }
     }
-    command.args(&args.run_args);
-    command.status()?;
-    Ok(())
 }
 
+/// The default installation directories of QEMU that aren't always in `PATH`.
+const QEMU_INSTALL_DIRS: &[&str] = &[
+    "/usr/local/bin",
+    "/opt/homebrew/bin",
+    "/opt/local/bin",
+    "C:\\Program Files\\qemu",
+];
+
 fn read_cargo_metadata(args: &Args) -> Result<CargoMetadata, cargo_metadata::Error> {
     cargo_metadata::metadata(args.manifest_path().as_ref().map(PathBuf::as_path))
 }
 
-fn build_kernel(
-    out_dir: &Path,
-    args: &args::Args,
-    config: &Config,
-    metadata: &CargoMetadata,
-) -> Result<File, Error> {
+fn build_kernel(args: &args::Args, config: &Config, metadata: &CargoMetadata) -> Result<File, Error> {
     let crate_ = metadata
         .packages
         .iter()
         .find(|p| Path::new(&p.manifest_path) == config.manifest_path)
         .expect("Could not read crate name from cargo metadata");
-    let crate_name = &crate_.name;
 
     // compile kernel
+    let (kind, name) = match *args.example() {
+        Some(ref example) => ("example", Some(example)),
+        None => ("bin", args.bin().as_ref()),
+    };
+
     println!("Building kernel");
-    let exit_status = run_xargo_build(&env::current_dir()?, &args.cargo_args)?;
-    if !exit_status.success() {
+    let mut command = cargo_command(config, "build", &env::current_dir()?, &args.cargo_args);
+    // without a selected target, cargo builds all binaries, including the menu kernels
+    if name.is_some() {
+        for entry in config.kernels.values() {
+            if let MenuKernel::Bin(ref bin) = entry.kernel {
+                command.arg("--bin").arg(bin);
+            }
+        }
+    }
+    command.arg("--message-format=json");
+    command.stdout(process::Stdio::piped());
+    let mut child = command.spawn()?;
+    let mut executables = Vec::new();
+    let stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+    for line in stdout.lines() {
+        if let Some(executable) = handle_build_message(&line?, &crate_.id, kind) {
+            executables.push(executable);
+        }
+    }
+    if !child.wait()?.success() {
         process::exit(1)
     }
 
-    let mut kernel_path = out_dir.to_owned();
-    kernel_path.push(crate_name);
+    let kernel_path = select_kernel(crate_, kind, name, executables)?;
     let kernel = File::open(kernel_path)?;
     Ok(kernel)
 }
 
-fn run_xargo_build(target_path: &Path, args: &[String]) -> io::Result<process::ExitStatus> {
-    let mut command = process::Command::new("xargo");
-    command.arg("build");
-    command.env("RUST_TARGET_PATH", target_path);
-    command.args(args);
-    command.status()
+/// Handles a line of `--message-format=json` build output.
+///
+/// Compiler messages are printed and the target name and path of executables
+/// of the target kind `kind` (`bin` or `example`) built for the package
+/// `package_id` are returned. Lines that aren't JSON messages are passed through.
+fn handle_build_message(line: &str, package_id: &str, kind: &str) -> Option<(String, PathBuf)> {
+    let message: JsonValue = match serde_json::from_str(line) {
+        Ok(message) => message,
+        Err(_) => {
+            println!("{}", line);
+            return None;
+        }
+    };
+    match message["reason"].as_str() {
+        Some("compiler-message") => {
+            if let Some(rendered) = message["message"]["rendered"].as_str() {
+                eprint!("{}", rendered);
+            }
+            None
+        }
+        Some("compiler-artifact") if message["package_id"] == package_id => {
+            let target = &message["target"];
+            let has_kind = target["kind"]
+                .as_array()
+                .is_some_and(|kinds| kinds.iter().any(|k| k == kind));
+            if !has_kind {
+                return None;
+            }
+            // older cargo versions don't report `executable`, but list it as first file
+            let executable = message["executable"]
+                .as_str()
+                .or_else(|| message["filenames"][0].as_str())?;
+            let name = target["name"].as_str()?;
+            Some((name.to_owned(), PathBuf::from(executable)))
+        }
+        _ => None,
+    }
 }
 
-fn create_kernel_info_block(kernel_size: u64) -> KernelInfoBlock {
-    let kernel_size = if kernel_size <= u64::from(u32::max_value()) {
-        kernel_size as u32
+/// Selects the kernel from the executables built for `crate_`, which is the
+/// `kind` target `name` if given.
+fn select_kernel(
+    crate_: &CrateMetadata,
+    kind: &str,
+    name: Option<&String>,
+    mut executables: Vec<(String, PathBuf)>,
+) -> Result<PathBuf, Error> {
+    executables.dedup();
+    if let Some(name) = name {
+        return match executables.into_iter().find(|e| e.0 == *name) {
+            Some(executable) => Ok(executable.1),
+            None => Err(Error::Kernel(format!(
+                "the build of `{}` did not produce the {} `{}`",
+                crate_.name, kind, name
+            ))),
+        };
+    }
+    if executables.len() == 1 {
+        return Ok(executables.remove(0).1);
+    }
+    if let Some(executable) = executables.iter().find(|e| e.0 == crate_.name) {
+        return Ok(executable.1.clone());
+    }
+    if executables.is_empty() {
+        Err(Error::Kernel(format!(
+            "the build of `{}` did not produce an executable",
+            crate_.name
+        )))
     } else {
-        panic!("Kernel can't be loaded by BIOS bootloader because is too big")
+        let names: Vec<_> = executables.iter().map(|e| e.0.as_str()).collect();
+        Err(Error::Kernel(format!(
+            "the build of `{}` produced multiple executables ({}), \
+             so the kernel can't be determined (use `--bin` to select one)",
+            crate_.name,
+            names.join(", ")
+        )))
+    }
+}
+
+/// Creates the invocation of the cargo `subcommand` (e.g. `build`) through the
+/// configured build command, which builds `core` and `alloc` for the target, with
+/// the given arguments.
+fn cargo_command(
+    config: &Config,
+    subcommand: &str,
+    target_path: &Path,
+    args: &[String],
+) -> process::Command {
+    let mut command = match build_command(config) {
+        BuildCommand::Xargo => {
+            let mut command = process::Command::new("xargo");
+            command.arg(subcommand);
+            command
+        }
+        BuildCommand::BuildStd => {
+            let mut command = process::Command::new("cargo");
+            command.arg(subcommand).arg("-Z").arg("build-std=core,alloc");
+            command
+        }
     };
+    command.env("RUST_TARGET_PATH", target_path);
+    command.args(args);
+    command
+}
+
+/// Returns the configured build command, defaulting to xargo if it is installed.
+fn build_command(config: &Config) -> BuildCommand {
+    config.build_command.unwrap_or_else(|| {
+        if find_executable("xargo", &[]).is_some() {
+            BuildCommand::Xargo
+        } else {
+            BuildCommand::BuildStd
+        }
+    })
+}
+
+/// Searches the directories in `PATH`, and then the `install_dirs` of programs that
+/// don't add themselves to `PATH`, for an executable with the given name.
+pub(crate) fn find_executable(name: &str, install_dirs: &[&str]) -> Option<PathBuf> {
+    let file_name = format!("{}{}", name, env::consts::EXE_SUFFIX);
+    let paths = env::var_os("PATH")
+        .map(|paths| env::split_paths(&paths).collect::<Vec<_>>())
+        .unwrap_or_default();
+    paths
+        .into_iter()
+        .chain(install_dirs.iter().map(PathBuf::from))
+        .map(|dir| dir.join(&file_name))
+        .find(|path| path.is_file())
+}
+
+/// The directory of the helper crate that downloads the bootloader.
+///
+/// It is a fresh temporary directory, except for reproducible builds, which use
+/// the fixed `helper` subdirectory of the bootloader directory instead so that
+/// its path is the same in every build.
+enum HelperDir {
+    Temp(TempDir),
+    Fixed(PathBuf),
+}
+
+impl HelperDir {
+    fn new(config: &Config, bootloader_dir: &Path) -> io::Result<HelperDir> {
+        if !config.reproducible {
+            return TempDir::new("bootloader").map(HelperDir::Temp);
+        }
+        let dir = bootloader_dir.join("helper");
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(&dir)?;
+        Ok(HelperDir::Fixed(dir))
+    }
 
-    let mut kernel_info_block = [0u8; BLOCK_SIZE];
-    LittleEndian::write_u32(&mut kernel_info_block[0..4], kernel_size);
+    fn path(&self) -> &Path {
+        match *self {
+            HelperDir::Temp(ref dir) => dir.path(),
+            HelperDir::Fixed(ref dir) => dir,
+        }
+    }
 
-    kernel_info_block
+    fn close(self) -> io::Result<()> {
+        match self {
+            HelperDir::Temp(dir) => dir.close(),
+            HelperDir::Fixed(dir) => fs::remove_dir_all(dir),
+        }
+    }
 }
 
-fn download_bootloader(bootloader_dir: &Path, config: &Config) -> Result<CrateMetadata, Error> {
+/// Downloads the bootloader using a helper crate in `bootloader_dir`.
+///
+/// The lockfile of the helper crate is kept at `lockfile`, so that the same
+/// bootloader version is used until `--update-bootloader` is passed and the
+/// bootloader can be resolved without network access.
+fn download_bootloader(
+    bootloader_dir: &Path,
+    config: &Config,
+    lockfile: &Path,
+) -> Result<CrateMetadata, Error> {
     use std::io::Write;
 
     let cargo_toml = {
@@ -193,7 +3651,14 @@ fn download_bootloader(bootloader_dir: &Path, config: &Config) -> Result<CrateMe
                 config.bootloader.name
             ).as_bytes(),
         )?;
-        if let &Some(ref version) = &config.bootloader.version {
+        let bootloader = &config.bootloader;
+        // cargo requires a version for dependencies without another source
+        let version = match bootloader.version {
+            Some(ref version) => Some(version.as_str()),
+            None if bootloader.git.is_none() && bootloader.path.is_none() => Some("*"),
+            None => None,
+        };
+        if let Some(version) = version {
             cargo_toml_file.write_all(
                 format!(
                     r#"
@@ -203,7 +3668,7 @@ fn download_bootloader(bootloader_dir: &Path, config: &Config) -> Result<CrateMe
                 ).as_bytes(),
             )?;
         }
-        if let &Some(ref git) = &config.bootloader.git {
+        if let Some(ref git) = config.bootloader.git {
             cargo_toml_file.write_all(
                 format!(
                     r#"
@@ -213,7 +3678,7 @@ fn download_bootloader(bootloader_dir: &Path, config: &Config) -> Result<CrateMe
                 ).as_bytes(),
             )?;
         }
-        if let &Some(ref branch) = &config.bootloader.branch {
+        if let Some(ref branch) = config.bootloader.branch {
             cargo_toml_file.write_all(
                 format!(
                     r#"
@@ -223,7 +3688,7 @@ fn download_bootloader(bootloader_dir: &Path, config: &Config) -> Result<CrateMe
                 ).as_bytes(),
             )?;
         }
-        if let &Some(ref path) = &config.bootloader.path {
+        if let Some(ref path) = config.bootloader.path {
             cargo_toml_file.write_all(
                 format!(
                     r#"
@@ -233,6 +3698,17 @@ fn download_bootloader(bootloader_dir: &Path, config: &Config) -> Result<CrateMe
                 ).as_bytes(),
             )?;
         }
+        if !bootloader.features.is_empty() {
+            let features = bootloader.features.iter().cloned().map(Value::String).collect();
+            cargo_toml_file.write_all(
+                format!(
+                    r#"
+                    features = {}
+            "#,
+                    Value::Array(features)
+                ).as_bytes(),
+            )?;
+        }
 
         File::create(src_lib)?.write_all(
             r#"
@@ -241,52 +3717,353 @@ fn download_bootloader(bootloader_dir: &Path, config: &Config) -> Result<CrateMe
         )?;
     }
 
+    if let Some(ref vendor) = config.bootloader.vendor {
+        let vendor_dir = vendor_directory(vendor, bootloader_dir)?;
+        let directory = Value::String(vendor_dir.to_string_lossy().into_owned());
+        fs::create_dir_all(bootloader_dir.join(".cargo"))?;
+        fs::write(
+            bootloader_dir.join(".cargo").join("config.toml"),
+            format!(
+                "[source.crates-io]\n\
+                 replace-with = \"vendored-sources\"\n\n\
+                 [source.vendored-sources]\n\
+                 directory = {}\n",
+                directory
+            ),
+        )?;
+    }
+    if lockfile.is_file() {
+        fs::copy(lockfile, bootloader_dir.join("Cargo.lock"))?;
+    }
+
     let mut command = process::Command::new("cargo");
     command.arg("fetch");
     command.current_dir(bootloader_dir);
-    assert!(command.status()?.success(), "Bootloader download failed.");
+    if !command.status()?.success() {
+        return Err(Error::Download(if is_offline() {
+            format!(
+                "the bootloader `{}` is not available offline: build once without \
+                 `--offline` to download it, or use a local bootloader through `path`",
+                config.bootloader.name
+            )
+        } else {
+            format!("failed to download the bootloader `{}`", config.bootloader.name)
+        }));
+    }
+    if let Some(parent) = lockfile.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(bootloader_dir.join("Cargo.lock"), lockfile)?;
 
-    let metadata = cargo_metadata::metadata_deps(Some(&cargo_toml), true)?;
+    let metadata = read_helper_metadata(bootloader_dir)?;
     let bootloader = metadata
         .packages
         .iter()
         .find(|p| p.name == config.bootloader.name)
-        .expect(&format!(
-            "Could not find crate named “{}”",
-            config.bootloader.name
-        ));
+        .unwrap_or_else(|| {
+            panic!(
+                "Could not find crate named “{}”",
+                config.bootloader.name
+            )
+        });
 
     Ok(bootloader.clone())
 }
 
-fn build_bootloader(out_dir: &Path, config: &Config) -> Result<Box<[u8]>, Error> {
-    use std::io::{Read, Write};
+/// Returns the `.bootloader` section of the bootloader ELF file.
+///
+/// The last built bootloader is kept in `bootloader_dir` together with a
+/// fingerprint of its configuration. If the configuration didn't change since,
+/// the bootloader isn't downloaded and built again. Otherwise, it is built in
+/// `target_dir`.
+fn load_bootloader(
+    config: &Config,
+    bootloader_dir: &Path,
+    target_dir: &Path,
+) -> Result<Box<[u8]>, Error> {
+    let elf_path = bootloader_dir.join("bootloader.elf");
+    let fingerprint_path = bootloader_dir.join("fingerprint");
+    let fingerprint = bootloader_fingerprint(config);
+
+    let up_to_date = match fingerprint {
+        Some(ref fingerprint) => {
+            let previous = fs::read_to_string(&fingerprint_path).ok();
+            elf_path.is_file() && previous.as_ref() == Some(fingerprint)
+        }
+        None => false,
+    };
+    let bootloader_elf_bytes = if up_to_date {
+        println!("Bootloader is up to date");
+        fs::read(&elf_path)?
+    } else {
+        if fingerprint_path.exists() {
+            fs::remove_file(&fingerprint_path)?;
+        }
+        let bytes = match config.bootloader.url {
+            Some(ref url) => download_prebuilt_bootloader(config, url, bootloader_dir)?,
+            None => {
+                let tmp_dir = HelperDir::new(config, bootloader_dir)?;
+                let lockfile = bootloader_dir.join("Cargo.lock");
+                let bytes = build_bootloader(tmp_dir.path(), config, target_dir, &lockfile, None)?;
+                tmp_dir.close()?;
+                bytes
+            }
+        };
+
+        fs::create_dir_all(bootloader_dir)?;
+        fs::write(&elf_path, &bytes)?;
+        if let Some(fingerprint) = fingerprint {
+            fs::write(&fingerprint_path, fingerprint)?;
+        }
+        bytes
+    };
+
+    fs::write(outdir(config).join("bootloader.elf"), &bootloader_elf_bytes)?;
+
+    // copy bootloader section of ELF file to bootloader_path
+    let elf_file = xmas_elf::ElfFile::new(&bootloader_elf_bytes).unwrap();
+    xmas_elf::header::sanity_check(&elf_file).unwrap();
+    let bootloader_section = elf_file
+        .find_section_by_name(".bootloader")
+        .expect("bootloader must have a .bootloader section");
+
+    Ok(Vec::from(bootloader_section.raw_data(&elf_file)).into_boxed_slice())
+}
+
+/// Describes the bootloader configuration that the last built bootloader is
+/// compared against.
+///
+/// Bootloaders from local paths can change without a configuration change, so
+/// they have no fingerprint and are always rebuilt.
+fn bootloader_fingerprint(config: &Config) -> Option<String> {
+    let bootloader = &config.bootloader;
+    if bootloader.path.is_some() {
+        return None;
+    }
+    Some(format!(
+        "bootimage {}\nname = {}\nversion = {:?}\ngit = {:?}\nbranch = {:?}\n\
+         vendor = {:?}\nurl = {:?}\nsha256 = {:?}\n\
+         precompiled = {}\ntarget = {}\nfeatures = {:?}\nreproducible = {}\n",
+        env!("CARGO_PKG_VERSION"),
+        bootloader.name,
+        bootloader.version,
+        bootloader.git,
+        bootloader.branch,
+        bootloader.vendor,
+        bootloader.url,
+        bootloader.sha256,
+        bootloader.precompiled,
+        bootloader.target,
+        bootloader.features,
+        config.reproducible,
+    ))
+}
+
+/// Whether network access is forbidden through `--offline` or cargo's `CARGO_NET_OFFLINE`.
+pub(crate) fn is_offline() -> bool {
+    env::var_os("CARGO_NET_OFFLINE").is_some_and(|v| v == "true")
+}
+
+/// Downloads the file at `url` to `dest` using `curl`, which follows redirects
+/// (e.g. of GitHub release assets) and fails on HTTP errors.
+pub(crate) fn download(url: &str, dest: &Path) -> Result<(), String> {
+    let mut command = process::Command::new("curl");
+    command.arg("--fail").arg("--location").arg("--silent").arg("--show-error");
+    command.arg("--output").arg(dest).arg(url);
+    match command.status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("failed to download {} ({})", url, status)),
+        Err(err) => Err(format!("failed to run `curl` to download {}: {}", url, err)),
+    }
+}
+
+/// Extracts the (possibly compressed) tarball `archive` into `dir` using `tar`,
+/// without the first `strip_components` components of the paths.
+pub(crate) fn extract_tar(
+    archive: &Path,
+    dir: &Path,
+    strip_components: usize,
+) -> Result<(), String> {
+    let mut command = process::Command::new("tar");
+    command.arg("-xf").arg(archive).arg("-C").arg(dir);
+    if strip_components > 0 {
+        command.arg(format!("--strip-components={}", strip_components));
+    }
+    match command.status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("failed to extract {} ({})", archive.display(), status)),
+        Err(err) => Err(format!("failed to run `tar` to extract {}: {}", archive.display(), err)),
+    }
+}
+
+/// Downloads the prebuilt bootloader ELF file at `url` using `curl` and
+/// verifies its SHA-256 hash.
+///
+/// Verified downloads are kept in the user-level cache, keyed by their hash.
+fn download_prebuilt_bootloader(
+    config: &Config,
+    url: &str,
+    bootloader_dir: &Path,
+) -> Result<Vec<u8>, Error> {
+    let expected = config
+        .bootloader
+        .sha256
+        .as_ref()
+        .expect("prebuilt bootloaders have a sha256 hash");
+    let cache_path = cache_dir().map(|dir| dir.join("prebuilt").join(expected).join("bootloader.elf"));
+    if let Some(ref path) = cache_path {
+        if path.is_file() {
+            let bytes = fs::read(path)?;
+            if sha256::to_hex(&sha256::sha256(&bytes)) == *expected {
+                println!("Using cached bootloader at {}", path.display());
+                return Ok(bytes);
+            }
+        }
+    }
+    if is_offline() {
+        return Err(Error::Download(format!(
+            "the prebuilt bootloader from {} is not available offline: \
+             build once without `--offline` to download it",
+            url
+        )));
+    }
+
+    println!("Downloading bootloader from {}", url);
+    fs::create_dir_all(bootloader_dir)?;
+    let download_path = bootloader_dir.join("bootloader.download");
+    download(url, &download_path).map_err(Error::Download)?;
+    let bytes = fs::read(&download_path)?;
+    let actual = sha256::to_hex(&sha256::sha256(&bytes));
+    if actual != *expected {
+        fs::remove_file(&download_path)?;
+        return Err(Error::Download(format!(
+            "the bootloader downloaded from {} has the SHA-256 hash {}, expected {}",
+            url, actual, expected
+        )));
+    }
+
+    if let Some(ref cache_path) = cache_path {
+        if let Err(err) = cache_bootloader(&download_path, cache_path) {
+            eprintln!("Warning: could not cache the bootloader: {}", err);
+        }
+    }
+    fs::remove_file(&download_path)?;
+    Ok(bytes)
+}
+
+/// Returns the directory of the `cargo vendor` output at `vendor`, which is
+/// either a directory or a tarball that is extracted into `bootloader_dir`.
+fn vendor_directory(vendor: &Path, bootloader_dir: &Path) -> Result<PathBuf, Error> {
+    if vendor.is_dir() {
+        return Ok(vendor.to_path_buf());
+    }
+
+    let extract_dir = bootloader_dir.join("vendor");
+    fs::create_dir_all(&extract_dir)?;
+    extract_tar(vendor, &extract_dir, 0).map_err(Error::Download)?;
+
+    // the tarball might contain the vendor directory itself instead of its contents,
+    // which is recognizable since vendored crates have a `.cargo-checksum.json`
+    let entries = fs::read_dir(&extract_dir)?.collect::<Result<Vec<_>, _>>()?;
+    if let [ref entry] = *entries.as_slice() {
+        let path = entry.path();
+        if path.is_dir() && !path.join(".cargo-checksum.json").exists() {
+            return Ok(path);
+        }
+    }
+    Ok(extract_dir)
+}
+
+/// Runs `cargo metadata` for the download helper crate in `bootloader_dir`.
+///
+/// `cargo metadata` runs inside of `bootloader_dir` so that it uses the
+/// vendored sources configured there.
+fn read_helper_metadata(bootloader_dir: &Path) -> Result<CargoMetadata, Error> {
+    let mut command = process::Command::new("cargo");
+    command.args(["metadata", "--format-version", "1"]);
+    command.current_dir(bootloader_dir);
+    let output = command.output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        return Err(Error::CargoMetadata(
+            cargo_metadata::ErrorKind::CargoMetadata(stderr).into(),
+        ));
+    }
+    serde_json::from_slice(&output.stdout).map_err(|err| Error::CargoMetadata(err.into()))
+}
+
+/// Downloads and builds the bootloader and returns the bootloader ELF file.
+///
+/// If `kernel` is given, it is passed to the bootloader build through the
+/// `KERNEL` environment variable and the bootloader is not cached.
+fn build_bootloader(
+    out_dir: &Path,
+    config: &Config,
+    target_dir: &Path,
+    lockfile: &Path,
+    kernel: Option<&Path>,
+) -> Result<Vec<u8>, Error> {
+    use std::io::Read;
+
+    if kernel.is_some() && config.bootloader.precompiled {
+        return Err(Error::Config(
+            "the `embed` bootloader linking mode requires a bootloader that is not precompiled"
+                .into(),
+        ));
+    }
 
-    let bootloader_metadata = download_bootloader(out_dir, config)?;
+    let bootloader_metadata = download_bootloader(out_dir, config, lockfile)?;
     let bootloader_dir = Path::new(&bootloader_metadata.manifest_path)
         .parent()
         .unwrap();
 
-    let bootloader_elf_path = if !config.bootloader.precompiled {
-        let args = &[
+    let cache_path = match kernel {
+        Some(_) => None,
+        None => bootloader_cache_path(config, &bootloader_metadata),
+    };
+    let bootloader_elf_path = if let Some(path) = cache_path.as_ref().filter(|p| p.is_file()) {
+        println!("Using cached bootloader at {}", path.display());
+        path.clone()
+    } else if !config.bootloader.precompiled {
+        let mut args = vec![
             String::from("--manifest-path"),
             bootloader_metadata.manifest_path.clone(),
             String::from("--target"),
             config.bootloader.target.clone(),
             String::from("--release"),
+            String::from("--target-dir"),
+            target_dir.to_string_lossy().into_owned(),
         ];
+        if !config.bootloader.features.is_empty() {
+            args.push(String::from("--features"));
+            args.push(config.bootloader.features.join(" "));
+        }
 
         println!("Building bootloader");
-        let exit_status = run_xargo_build(bootloader_dir, args)?;
+        let mut command = cargo_command(config, "build", bootloader_dir, &args);
+        // use the source configuration of the download helper
+        command.current_dir(out_dir);
+        if let Some(kernel) = kernel {
+            command.env("KERNEL", kernel);
+            command.env("KERNEL_MANIFEST", &config.manifest_path);
+        }
+        if config.reproducible {
+            command.env("RUSTFLAGS", reproducible_rustflags(bootloader_dir));
+        }
+        let exit_status = command.status()?;
         if !exit_status.success() {
             process::exit(1)
         }
 
-        let mut bootloader_elf_path = bootloader_dir.to_path_buf();
-        bootloader_elf_path.push("target");
+        let mut bootloader_elf_path = target_dir.to_path_buf();
         bootloader_elf_path.push(&config.bootloader.target);
         bootloader_elf_path.push("release");
         bootloader_elf_path.push("bootloader");
+        if let Some(ref cache_path) = cache_path {
+            if let Err(err) = cache_bootloader(&bootloader_elf_path, cache_path) {
+                eprintln!("Warning: could not cache the bootloader: {}", err);
+            }
+        }
         bootloader_elf_path
     } else {
         let mut bootloader_elf_path = bootloader_dir.to_path_buf();
@@ -306,16 +4083,88 @@ fn build_bootloader(out_dir: &Path, config: &Config) -> Result<Box<[u8]>, Error>
     })?;
     bootloader.read_to_end(&mut bootloader_elf_bytes)?;
 
-    File::create(outdir(config).join("bootloader.elf"))?.write_all(&bootloader_elf_bytes)?;
+    Ok(bootloader_elf_bytes)
+}
 
-    // copy bootloader section of ELF file to bootloader_path
-    let elf_file = xmas_elf::ElfFile::new(&bootloader_elf_bytes).unwrap();
-    xmas_elf::header::sanity_check(&elf_file).unwrap();
-    let bootloader_section = elf_file
-        .find_section_by_name(".bootloader")
-        .expect("bootloader must have a .bootloader section");
+/// The `RUSTFLAGS` for a reproducible bootloader build, which replace the
+/// machine-specific paths of the bootloader sources, the cargo home, and the
+/// Rust sysroot (with the sources of `core`) in panic messages and debug info.
+fn reproducible_rustflags(bootloader_dir: &Path) -> String {
+    let mut remaps = Vec::new();
+    let cargo_home = env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cargo")));
+    if let Some(cargo_home) = cargo_home {
+        remaps.push((cargo_home, "/cargo"));
+    }
+    let sysroot = process::Command::new("rustc")
+        .arg("--print")
+        .arg("sysroot")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned());
+    if let Some(sysroot) = sysroot {
+        remaps.push((PathBuf::from(sysroot), "/rustc"));
+    }
+    // the last matching prefix wins, and the bootloader may be in the cargo home
+    remaps.push((bootloader_dir.to_path_buf(), "/bootloader"));
 
-    Ok(Vec::from(bootloader_section.raw_data(&elf_file)).into_boxed_slice())
+    // the flags are appended to the ones from the environment
+    let mut flags = env::var("RUSTFLAGS").unwrap_or_default();
+    for (from, to) in remaps {
+        if !flags.is_empty() {
+            flags.push(' ');
+        }
+        flags.push_str(&format!("--remap-path-prefix={}={}", from.display(), to));
+    }
+    flags
+}
+
+/// Returns the path of the compiled bootloader in the user-level bootloader cache.
+///
+/// The cache entry is keyed by the bootloader's package ID (which contains the
+/// version and source, including the git revision), the target, and the enabled
+/// features. Precompiled bootloaders and bootloaders from local paths, whose
+/// contents can change without a new package ID, are not cached.
+fn bootloader_cache_path(config: &Config, bootloader: &CrateMetadata) -> Option<PathBuf> {
+    if config.bootloader.precompiled || config.bootloader.path.is_some() {
+        return None;
+    }
+    let key = format!(
+        "{}\n{}\n{}{}",
+        bootloader.id,
+        config.bootloader.target,
+        config.bootloader.features.join(","),
+        // reproducible bootloaders are built with different flags
+        if config.reproducible { "\nreproducible" } else { "" }
+    );
+    let dir_name = format!(
+        "{}-{}-{}-{:08x}",
+        bootloader.name,
+        bootloader.version,
+        config.bootloader.target,
+        crc32::crc32(key.as_bytes())
+    );
+    cache_dir().map(|dir| dir.join(dir_name).join("bootloader.elf"))
+}
+
+/// The user-level cache directory of bootimage.
+pub(crate) fn cache_dir() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(base.join("bootimage"))
+}
+
+/// Copies the compiled bootloader at `path` into the cache.
+fn cache_bootloader(path: &Path, cache_path: &Path) -> io::Result<()> {
+    fs::create_dir_all(cache_path.parent().expect("cache path has a parent"))?;
+    // copy to a temporary file first so that a concurrent build never sees a partial file
+    let tmp_path = cache_path.with_extension("elf.tmp");
+    fs::copy(path, &tmp_path)?;
+    fs::rename(tmp_path, cache_path)
 }
 
 #[inline]
@@ -325,49 +4174,12 @@ fn outdir(config: &Config) -> PathBuf {
     out
 }
 
-fn create_disk_image(
-    config: &Config,
-    mut kernel: File,
-    kernel_info_block: KernelInfoBlock,
-    bootloader_data: &[u8],
-) -> Result<(), Error> {
-    use std::io::{Read, Write, Seek};
-
-    println!("Creating disk image at {}", config.output.display());
-
-    let _ = ::std::io::copy(&mut kernel, &mut File::create(outdir(config).join("kernel.elf"))?)?;
-    let _ = kernel.seek(::std::io::SeekFrom::Start(0))?;
-
-    let mut output = File::create(&config.output)?;
-    output.write_all(&bootloader_data)?;
-    output.write_all(&kernel_info_block)?;
-
-    // write out kernel elf file
-    let kernel_size = kernel.metadata()?.len();
-    let mut buffer = [0u8; 1024];
-    loop {
-        let (n, interrupted) = match kernel.read(&mut buffer) {
-            Ok(0) => break,
-            Ok(n) => (n, false),
-            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (0, true),
-            Err(e) => Err(e)?,
-        };
-        if !interrupted {
-            output.write_all(&buffer[..n])?
-        }
-    }
-
-    let padding_size = ((512 - (kernel_size % 512)) % 512) as usize;
-    let padding = [0u8; 512];
-    output.write_all(&padding[..padding_size])?;
-
-    if let Some(min_size) = config.minimum_image_size {
-        // we already wrote to output successfully,
-        // both metadata and set_len should succeed.
-        if output.metadata()?.len() < min_size {
-            output.set_len(min_size)?;
-        }
-    }
-
-    Ok(())
+/// The kernel as it is stored in the image, i.e. `kernel.elf` or its compressed
+/// version for `kernel-compression`.
+fn kernel_payload_path(config: &Config) -> PathBuf {
+    outdir(config).join(match config.kernel_compression {
+        KernelCompression::None => "kernel.elf",
+        KernelCompression::Lz4 => "kernel.elf.lz4",
+        KernelCompression::Gzip => "kernel.elf.gz",
+    })
 }
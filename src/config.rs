@@ -1,16 +1,609 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use image::BLOCK_SIZE;
 use Error;
-use toml::Value;
+use toml;
+use toml::value::{Table, Value};
 
+#[derive(Clone)]
 pub struct Config {
     pub manifest_path: PathBuf,
+    /// The `default-run` binary of the package.
+    pub default_run: Option<String>,
     pub default_target: Option<String>,
     pub output: PathBuf,
+    pub format: ImageFormat,
     pub bootloader: BootloaderConfig,
     pub minimum_image_size: Option<u64>,
-    pub run_command: Vec<String>,
+    /// The logical sector size of the disk (512 or 4096 bytes), in which the
+    /// partition tables and file systems of the image are laid out.
+    pub sector_size: u64,
+    /// The alignment in bytes that the start of the appended kernel must have.
+    pub kernel_alignment: u64,
+    /// The image is padded to a multiple of this many bytes.
+    pub image_alignment: u64,
+    /// The value of the padding bytes behind the kernel and up to the end of the image.
+    pub pad_byte: u8,
+    /// Whether the kernel is stripped in the image, with its symbols kept in `kernel.debug`.
+    pub strip_kernel: bool,
+    /// The compression of the kernel in the image.
+    pub kernel_compression: KernelCompression,
+    /// The size in bytes that the kernel in the image must not exceed.
+    pub max_kernel_size: Option<u64>,
+    /// The size in bytes that the disk image must not exceed.
+    pub max_image_size: Option<u64>,
+    /// Whether a linker map and a symbol table of the kernel are written next to the image.
+    pub emit_map: bool,
+    /// Whether a SHA-256 checksum file and a manifest of the build artifacts are written.
+    pub emit_checksums: bool,
+    /// Whether build metadata (like the git commit) is written into the kernel info block.
+    pub embed_metadata: bool,
+    /// The version of the kernel info block, `1` (only the kernel size) or `2`.
+    pub info_block_version: u16,
+    /// The command line that is written into the kernel info block for the kernel.
+    pub kernel_cmdline: Option<String>,
+    /// A file that is appended to the kernel as an initial ramdisk.
+    pub initrd: Option<PathBuf>,
+    /// Additional files that the bootloader loads as modules for `grub-iso` and
+    /// `limine` images.
+    pub multiboot_modules: Vec<PathBuf>,
+    /// The Limine release and boot protocol of `limine` images.
+    pub limine: LimineConfig,
+    /// The additional kernels of the boot menu, by the title of their menu entry.
+    pub kernels: BTreeMap<String, MenuEntry>,
+    /// The seconds after which the boot menu boots the first kernel.
+    pub menu_timeout: u64,
+    /// The directory with the SYSLINUX files of `syslinux` and `isolinux` images,
+    /// which are searched in the default installation directories otherwise.
+    pub syslinux_directory: Option<PathBuf>,
+    /// The files of the data partition, by their path in the partition.
+    pub files: BTreeMap<String, PathBuf>,
+    /// The file system of the data partition.
+    pub data_filesystem: Filesystem,
+    /// A host directory whose contents are copied to the data partition.
+    pub data_directory: Option<PathBuf>,
+    /// Whether the image gets an MBR partition table with an active kernel partition.
+    pub mbr_partition_table: bool,
+    /// Whether zero regions of the images are left as holes instead of being allocated.
+    pub sparse: bool,
+    /// Additional named values that are written into the kernel info block.
+    pub info_block_fields: BTreeMap<String, InfoBlockField>,
+    /// The minisign secret key that the image is signed with.
+    pub signing_key: Option<PathBuf>,
+    /// The command that signs the image, as an alternative to `signing-key`.
+    pub signing_command: Option<Vec<String>>,
+    pub run_command: Option<Vec<String>>,
+    /// The named run configurations, selected with `--run-profile`.
+    pub run_profiles: BTreeMap<String, RunProfile>,
+    /// The hypervisor that boots the image.
+    pub run_backend: RunBackend,
+    /// The firmware that the image is booted with, UEFI for `gpt` images and the
+    /// BIOS otherwise by default.
+    pub firmware: Firmware,
+    /// Where the OVMF firmware for UEFI runs in QEMU comes from.
+    pub ovmf: OvmfConfig,
+    /// The virtual machine of the `virtualbox` run backend.
+    pub virtualbox: VmConfig,
+    /// The virtual machine of the `vmware` run backend.
+    pub vmware: VmConfig,
+    /// The virtual machine of the `hyperv` run backend.
+    pub hyperv: VmConfig,
+    /// The microVM of the `firecracker` and `cloud-hypervisor` run backends.
+    pub microvm: MicrovmConfig,
+    /// The emulator of the `bochs` run backend.
+    pub bochs: BochsConfig,
+    /// The command that is run before the kernel is built.
+    pub pre_build: Option<Vec<String>>,
+    /// The command that is run after the disk image is created.
+    pub post_build: Option<Vec<String>>,
+    /// The command that is run before the run command.
+    pub pre_run: Option<Vec<String>>,
+    /// The exit status of the run command that means that the tests passed.
+    pub test_success_exit_code: Option<i32>,
+    /// The time after which the run command is killed.
+    pub run_timeout: Option<Duration>,
+    /// The name prefix of the binary targets that `bootimage test` runs as test kernels.
+    pub test_prefix: String,
+    /// The number of test kernels that `bootimage test` boots concurrently.
+    pub test_jobs: Option<usize>,
+    /// The overrides for individual test kernels, by test name.
+    pub tests: BTreeMap<String, TestConfig>,
+    /// Whether the serial output of the run command is written to a log file.
+    pub capture_serial: bool,
+    /// Whether the default QEMU run command uses the hardware acceleration of the host.
+    pub accel: bool,
+    /// Whether the default QEMU run command executes deterministically and records the
+    /// run, so that `bootimage replay` can replay it.
+    pub deterministic: bool,
+    /// The display of the default QEMU run command, which is chosen by QEMU if unset.
+    pub display: Option<Display>,
+    /// The virtual hardware of the default QEMU run command.
+    pub qemu: QemuConfig,
+    /// The screenshots that are taken of the screen of the VM.
+    pub screenshots: ScreenshotConfig,
+    /// Whether the disk image is built reproducibly and checked for it.
+    pub reproducible: bool,
+    /// Whether downloads without a pinned SHA-256 hash (of the Limine release and
+    /// OVMF) are used instead of rejected.
+    pub allow_unverified_downloads: bool,
+    /// The marker of the serial output lines that contain backtrace addresses.
+    pub backtrace_marker: Option<String>,
+    /// The port on localhost on which the serial console of the VM is served.
+    pub serial_port: Option<u16>,
+    pub efi_loader: Option<PathBuf>,
+    pub export: Vec<ExportFormat>,
+    /// The configured build command, or `None` to pick one automatically.
+    pub build_command: Option<BuildCommand>,
 }
 
+impl Config {
+    /// The path of the bootable image, which depends on the image format.
+    pub fn image_path(&self) -> PathBuf {
+        match self.format {
+            ImageFormat::Raw
+            | ImageFormat::Gpt
+            | ImageFormat::Hybrid
+            | ImageFormat::Multiboot2
+            | ImageFormat::Limine => self.output.clone(),
+            ImageFormat::Iso | ImageFormat::GrubIso | ImageFormat::Isolinux => {
+                self.output.with_extension("iso")
+            }
+            ImageFormat::Syslinux => self.output.with_extension("syslinux.img"),
+            ImageFormat::Qcow2 => self.output.with_extension("qcow2"),
+        }
+    }
+
+    /// The path of the disk image that boots from a hard disk or a USB stick: the raw
+    /// disk image that the `iso` and `qcow2` images wrap, and the bootable image of the
+    /// other formats.
+    pub fn raw_image_path(&self) -> PathBuf {
+        match self.format {
+            ImageFormat::Iso | ImageFormat::Qcow2 => self.output.clone(),
+            _ => self.image_path(),
+        }
+    }
+
+    /// The path of the linker map of the kernel, written with `emit-map`.
+    pub fn map_path(&self) -> PathBuf {
+        self.output.with_extension("map")
+    }
+
+    /// The path of the symbol table of the kernel, written with `emit-map`.
+    pub fn symbols_path(&self) -> PathBuf {
+        self.output.with_extension("sym")
+    }
+
+    /// The path of the SHA-256 checksum file of the artifacts, written with `emit-checksums`.
+    pub fn checksums_path(&self) -> PathBuf {
+        let mut path = self.image_path().into_os_string();
+        path.push(".sha256");
+        path.into()
+    }
+
+    /// The path of the JSON manifest of the artifacts, written with `emit-checksums`.
+    pub fn manifest_path(&self) -> PathBuf {
+        let mut path = self.image_path().into_os_string();
+        path.push(".manifest.json");
+        path.into()
+    }
+
+    /// The path of the detached signature of the image, `None` if it isn't signed.
+    pub fn signature_path(&self) -> Option<PathBuf> {
+        let extension = if self.signing_key.is_some() {
+            ".minisig"
+        } else if self.signing_command.is_some() {
+            ".sig"
+        } else {
+            return None;
+        };
+        let mut path = self.image_path().into_os_string();
+        path.push(extension);
+        Some(path.into())
+    }
+
+    /// The path of the image that is exported in the given format.
+    pub fn export_path(&self, format: ExportFormat) -> PathBuf {
+        self.output.with_extension(format.extension())
+    }
+
+    /// The configured run command or a QEMU invocation suitable for the image format,
+    /// which is empty for Multiboot2 kernels, since QEMU can't boot them directly.
+    pub fn run_command_or_default(&self) -> Vec<String> {
+        if let Some(ref run_command) = self.run_command {
+            return run_command.clone();
+        }
+        match self.format {
+            ImageFormat::Raw
+            | ImageFormat::Gpt
+            | ImageFormat::Hybrid
+            | ImageFormat::Limine
+            | ImageFormat::Syslinux => vec![
+                "qemu-system-x86_64".into(),
+                "-drive".into(),
+                "format=raw,file={}".into(),
+            ],
+            ImageFormat::Iso | ImageFormat::GrubIso | ImageFormat::Isolinux => {
+                vec!["qemu-system-x86_64".into(), "-cdrom".into(), "{}".into()]
+            }
+            ImageFormat::Qcow2 => vec![
+                "qemu-system-x86_64".into(),
+                "-drive".into(),
+                "format=qcow2,file={}".into(),
+            ],
+            ImageFormat::Multiboot2 => Vec::new(),
+        }
+    }
+
+    /// The run profile with the given name or, if `name` is `None`, the `default`
+    /// run profile if it is defined.
+    pub fn run_profile(&self, name: Option<&str>) -> Result<RunProfile, Error> {
+        match name {
+            Some(name) => self.run_profiles.get(name).cloned().ok_or_else(|| {
+                let names: Vec<_> = self.run_profiles.keys().map(String::as_str).collect();
+                Error::Config(format!(
+                    "run profile `{}` is not defined (defined run profiles: {})",
+                    name,
+                    if names.is_empty() {
+                        "none".into()
+                    } else {
+                        names.join(", ")
+                    }
+                ))
+            }),
+            None => Ok(self.run_profiles.get("default").cloned().unwrap_or_default()),
+        }
+    }
+
+    /// The overrides of the test kernel `test`, which are configured under its name
+    /// with or without the test prefix.
+    pub fn test_overrides(&self, test: &str) -> TestConfig {
+        let short_name = test.strip_prefix(self.test_prefix.as_str());
+        self.tests
+            .get(test)
+            .or_else(|| short_name.and_then(|name| self.tests.get(name)))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+/// The configuration of an individual test kernel, which overrides the configuration
+/// of the crate.
+#[derive(Debug, Clone, Default)]
+pub struct TestConfig {
+    /// The time after which the run command is killed.
+    pub timeout: Option<Duration>,
+    /// The exit status of the run command that means that the test passed.
+    pub success_exit_code: Option<i32>,
+    /// Additional arguments that are appended to the run command.
+    pub run_args: Vec<String>,
+    /// The times at which screenshots are taken.
+    pub screenshot_at: Option<Vec<Duration>>,
+    /// Whether the last screen before the run ends is captured.
+    pub screenshot_at_end: Option<bool>,
+    /// The binary targets that are booted alongside the test kernel and connected to
+    /// it by a virtual network.
+    pub peers: Vec<String>,
+    /// The QEMU model of the network cards of a network test.
+    pub nic_model: Option<String>,
+}
+
+/// The virtual hardware of the default QEMU run command, which uses the defaults of
+/// QEMU for the unset values.
+#[derive(Debug, Clone, Default)]
+pub struct QemuConfig {
+    /// The memory of the VM in bytes (a multiple of 1 MiB).
+    pub memory: Option<u64>,
+    /// The number of CPU cores.
+    pub smp: Option<u32>,
+    /// The CPU model, optionally with features (e.g. `max` or `qemu64,+ssse3`).
+    pub cpu: Option<String>,
+    pub machine: Option<Machine>,
+}
+
+/// The machine type of the default QEMU run command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Machine {
+    /// The i440FX chipset with an ISA bus and IDE disks, the default of QEMU.
+    Pc,
+    /// The Q35 chipset with PCI Express, AHCI disks, and an IOMMU.
+    Q35,
+}
+
+impl Machine {
+    /// The value of the `-machine` argument of QEMU.
+    pub fn qemu_arg(self) -> &'static str {
+        match self {
+            Machine::Pc => "pc",
+            Machine::Q35 => "q35",
+        }
+    }
+}
+
+impl FromStr for Machine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pc" => Ok(Machine::Pc),
+            "q35" => Ok(Machine::Q35),
+            _ => Err(format!("unknown machine type `{}` (expected `pc` or `q35`)", s)),
+        }
+    }
+}
+
+/// The screenshots that are taken of the screen of the VM through QMP, which are
+/// written to the `screenshots` directory in the output directory.
+#[derive(Debug, Clone, Default)]
+pub struct ScreenshotConfig {
+    /// The times after the start of QEMU at which screenshots are taken.
+    pub at: Vec<Duration>,
+    /// Whether the last screen before the run ends is captured.
+    pub at_end: bool,
+    /// The directory of the golden images that the screenshots are compared with.
+    pub golden_dir: Option<PathBuf>,
+    /// The fraction of the pixels that may differ from the golden image.
+    pub tolerance: f64,
+    /// Whether the golden images are replaced by the screenshots instead (set by
+    /// `--update-screenshots`).
+    pub update_golden: bool,
+}
+
+/// A named run configuration.
+#[derive(Debug, Clone, Default)]
+pub struct RunProfile {
+    /// The run command, or `None` to use the configured or default run command.
+    pub command: Option<Vec<String>>,
+    /// Additional arguments that are appended to the run command.
+    pub args: Vec<String>,
+    /// Environment variables that are set for the run command.
+    pub env: Vec<(String, String)>,
+}
+
+/// An additional kernel in the boot menu of the image.
+#[derive(Debug, Clone)]
+pub struct MenuEntry {
+    pub kernel: MenuKernel,
+    /// The command line of the kernel, which replaces the `kernel-cmdline`.
+    pub cmdline: Option<String>,
+}
+
+/// The source of an additional kernel in the boot menu.
+#[derive(Debug, Clone)]
+pub enum MenuKernel {
+    /// A binary target of the crate, which is built together with the kernel.
+    Bin(String),
+    /// A kernel ELF file that is built separately.
+    Path(PathBuf),
+}
+
+/// A value that is written into the kernel info block at a fixed offset, e.g. for
+/// passing build-time parameters to a custom bootloader.
+#[derive(Debug, Clone, Copy)]
+pub struct InfoBlockField {
+    /// The offset of the field in the kernel info block.
+    pub offset: usize,
+    /// The size of the field in bytes: 1, 2, 4, or 8.
+    pub size: usize,
+    /// The value, which is written little-endian.
+    pub value: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// A raw disk image consisting of the bootloader and the kernel.
+    Raw,
+    /// An ISO 9660 image that boots the raw disk image through El Torito.
+    Iso,
+    /// A GPT-partitioned disk image with an EFI system partition for UEFI firmware.
+    Gpt,
+    /// A GPT-partitioned disk image that boots on both BIOS and UEFI firmware.
+    Hybrid,
+    /// The raw disk image in QEMU's qcow2 format, which only stores non-zero clusters.
+    Qcow2,
+    /// No disk image, but the kernel with a Multiboot2 header for bootloaders like GRUB.
+    Multiboot2,
+    /// An ISO image created by `grub-mkrescue` that boots the Multiboot2 kernel with GRUB.
+    GrubIso,
+    /// A disk image with an MBR partition table and a FAT partition from which the
+    /// Limine bootloader boots the kernel.
+    Limine,
+    /// A FAT image without a partition table that boots the raw disk image with
+    /// SYSLINUX and `memdisk`.
+    Syslinux,
+    /// An ISO image that boots the raw disk image with ISOLINUX and `memdisk`.
+    Isolinux,
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(ImageFormat::Raw),
+            "iso" => Ok(ImageFormat::Iso),
+            "gpt" => Ok(ImageFormat::Gpt),
+            "hybrid" => Ok(ImageFormat::Hybrid),
+            "qcow2" => Ok(ImageFormat::Qcow2),
+            "multiboot2" => Ok(ImageFormat::Multiboot2),
+            "grub-iso" => Ok(ImageFormat::GrubIso),
+            "limine" => Ok(ImageFormat::Limine),
+            "syslinux" => Ok(ImageFormat::Syslinux),
+            "isolinux" => Ok(ImageFormat::Isolinux),
+            other => Err(format!(
+                "unknown image format `{}` (expected `raw`, `iso`, `gpt`, `hybrid`, `qcow2`, \
+                 `multiboot2`, `grub-iso`, `limine`, `syslinux`, or `isolinux`)",
+                other
+            )),
+        }
+    }
+}
+
+/// The file system of the data partition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filesystem {
+    /// FAT16 or FAT32, depending on the size of the partition.
+    Fat,
+    Ext2,
+}
+
+impl FromStr for Filesystem {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fat" => Ok(Filesystem::Fat),
+            "ext2" => Ok(Filesystem::Ext2),
+            other => Err(format!(
+                "unknown file system `{}` (expected `fat` or `ext2`)",
+                other
+            )),
+        }
+    }
+}
+
+/// The compression of the kernel in the image, which the bootloader has to undo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelCompression {
+    None,
+    /// The LZ4 frame format.
+    Lz4,
+    Gzip,
+}
+
+impl FromStr for KernelCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(KernelCompression::None),
+            "lz4" => Ok(KernelCompression::Lz4),
+            "gzip" => Ok(KernelCompression::Gzip),
+            other => Err(format!(
+                "unknown kernel compression `{}` (expected `none`, `lz4`, or `gzip`)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for KernelCompression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            KernelCompression::None => "none",
+            KernelCompression::Lz4 => "lz4",
+            KernelCompression::Gzip => "gzip",
+        })
+    }
+}
+
+/// The tool that cross-compiles the kernel and the bootloader together with `core` and `alloc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildCommand {
+    /// `xargo build`, which builds a sysroot for the target.
+    Xargo,
+    /// `cargo build -Z build-std=core,alloc`, which requires a nightly cargo.
+    BuildStd,
+}
+
+impl FromStr for BuildCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "xargo" => Ok(BuildCommand::Xargo),
+            "build-std" => Ok(BuildCommand::BuildStd),
+            other => Err(format!(
+                "unknown build command `{}` (expected `xargo` or `build-std`)",
+                other
+            )),
+        }
+    }
+}
+
+/// A hypervisor disk format that the raw disk image can be exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// VirtualBox disk image
+    Vdi,
+    /// VMware virtual disk
+    Vmdk,
+    /// Hyper-V and Virtual PC virtual hard disk
+    Vhd,
+    /// Google Compute Engine image, a gzip-compressed tar archive of the raw disk
+    Gce,
+    /// Amazon EC2 VM import image, a stream-optimized VMDK image
+    Aws,
+}
+
+impl ExportFormat {
+    /// The extension of the exported image, which replaces the one of the output.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Vdi => "vdi",
+            ExportFormat::Vmdk => "vmdk",
+            ExportFormat::Vhd => "vhd",
+            // GCE only imports archives whose name ends with `.tar.gz`
+            ExportFormat::Gce => "tar.gz",
+            ExportFormat::Aws => "stream.vmdk",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vdi" => Ok(ExportFormat::Vdi),
+            "vmdk" => Ok(ExportFormat::Vmdk),
+            "vhd" => Ok(ExportFormat::Vhd),
+            "gce" => Ok(ExportFormat::Gce),
+            "aws" => Ok(ExportFormat::Aws),
+            other => Err(format!(
+                "unknown export format `{}` (expected `vdi`, `vmdk`, `vhd`, `gce`, or `aws`)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            ExportFormat::Vdi => "vdi",
+            ExportFormat::Vmdk => "vmdk",
+            ExportFormat::Vhd => "vhd",
+            ExportFormat::Gce => "gce",
+            ExportFormat::Aws => "aws",
+        };
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            ImageFormat::Raw => "raw",
+            ImageFormat::Iso => "iso",
+            ImageFormat::Gpt => "gpt",
+            ImageFormat::Hybrid => "hybrid",
+            ImageFormat::Qcow2 => "qcow2",
+            ImageFormat::Multiboot2 => "multiboot2",
+            ImageFormat::GrubIso => "grub-iso",
+            ImageFormat::Limine => "limine",
+            ImageFormat::Syslinux => "syslinux",
+            ImageFormat::Isolinux => "isolinux",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Clone)]
 pub struct BootloaderConfig {
     pub name: String,
     pub precompiled: bool,
@@ -19,107 +612,1498 @@ pub struct BootloaderConfig {
     pub git: Option<String>,
     pub branch: Option<String>,
     pub path: Option<PathBuf>,
+    pub vendor: Option<PathBuf>,
+    pub features: Vec<String>,
+    /// The URL of a prebuilt bootloader ELF file that is used instead of building one.
+    pub url: Option<String>,
+    /// The SHA-256 hash of the prebuilt bootloader (in lowercase hex).
+    pub sha256: Option<String>,
+    pub linking: Linking,
+}
+
+/// How the kernel is combined with the bootloader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Linking {
+    /// The kernel is appended to the `.bootloader` section of the bootloader.
+    Append,
+    /// The kernel is passed to the bootloader build through the `KERNEL`
+    /// environment variable and the resulting bootloader is the disk image.
+    Embed,
+}
+
+impl FromStr for Linking {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "append" => Ok(Linking::Append),
+            "embed" => Ok(Linking::Embed),
+            other => Err(format!(
+                "unknown linking mode `{}` (expected `append` or `embed`)",
+                other
+            )),
+        }
+    }
+}
+
+/// The Limine release that `limine` images are created with.
+#[derive(Clone)]
+pub struct LimineConfig {
+    /// The version of the Limine binary release, e.g. `7.13.3`.
+    pub version: Option<String>,
+    /// The URL of the release archive, which defaults to the GitHub archive of the
+    /// `v<version>-binary` tag.
+    pub url: Option<String>,
+    /// The SHA-256 hash of the release archive (in lowercase hex).
+    pub sha256: Option<String>,
+    pub protocol: LimineProtocol,
+}
+
+/// The hypervisor that `bootimage run` boots the image with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunBackend {
+    /// The run command, which is a QEMU invocation by default.
+    Qemu,
+    /// A VirtualBox VM that is created and updated through `VBoxManage`.
+    VirtualBox,
+    /// A VMware VM that is generated as a `.vmx` file and started with `vmrun`.
+    Vmware,
+    /// A Hyper-V VM that is created and updated through PowerShell on Windows.
+    HyperV,
+    /// The kernel is booted directly by the Firecracker microVM monitor.
+    Firecracker,
+    /// The kernel is booted directly by the cloud-hypervisor microVM monitor.
+    CloudHypervisor,
+    /// The Bochs emulator, which is configured through a generated `bochsrc`.
+    Bochs,
+}
+
+impl FromStr for RunBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "qemu" => Ok(RunBackend::Qemu),
+            "virtualbox" => Ok(RunBackend::VirtualBox),
+            "vmware" => Ok(RunBackend::Vmware),
+            "hyperv" => Ok(RunBackend::HyperV),
+            "firecracker" => Ok(RunBackend::Firecracker),
+            "cloud-hypervisor" => Ok(RunBackend::CloudHypervisor),
+            "bochs" => Ok(RunBackend::Bochs),
+            other => Err(format!(
+                "unknown run backend `{}` (expected `qemu`, `virtualbox`, `vmware`, `hyperv`, \
+                 `firecracker`, `cloud-hypervisor`, or `bochs`)",
+                other
+            )),
+        }
+    }
+}
+
+/// The VM of the `virtualbox`, `vmware`, or `hyperv` run backend.
+#[derive(Clone)]
+pub struct VmConfig {
+    /// The name of the VM, which defaults to the file stem of the output.
+    pub vm_name: Option<String>,
+    /// The memory of the VM in bytes (a multiple of 1 MiB, 4 MiB for VMware, or
+    /// 2 MiB for Hyper-V).
+    pub memory: u64,
+    /// Whether the VM runs without a window.
+    pub headless: bool,
+}
+
+/// The firmware of the machine that boots the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Firmware {
+    Bios,
+    Uefi,
+}
+
+impl FromStr for Firmware {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bios" => Ok(Firmware::Bios),
+            "uefi" => Ok(Firmware::Uefi),
+            other => Err(format!("unknown firmware `{}` (expected `bios` or `uefi`)", other)),
+        }
+    }
+}
+
+/// The display on which QEMU shows the screen of the VM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Display {
+    /// No display, the serial output is shown in the terminal instead.
+    None,
+    Gtk,
+    /// The text mode screen in the terminal.
+    Curses,
+    /// A VNC server on the given display (e.g. `:1`, which listens on port 5901).
+    Vnc(String),
+}
+
+impl Display {
+    /// The value of the `-display` argument of QEMU.
+    pub fn qemu_arg(&self) -> String {
+        match self {
+            Display::None => "none".into(),
+            Display::Gtk => "gtk".into(),
+            Display::Curses => "curses".into(),
+            Display::Vnc(display) => format!("vnc={}", display),
+        }
+    }
+}
+
+impl FromStr for Display {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Display::None),
+            "gtk" => Ok(Display::Gtk),
+            "curses" => Ok(Display::Curses),
+            _ => match s.strip_prefix("vnc=") {
+                Some(display) if !display.is_empty() => Ok(Display::Vnc(display.into())),
+                _ => Err(format!(
+                    "unknown display `{}` (expected `none`, `gtk`, `vnc=:N`, or `curses`)",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// The OVMF firmware that QEMU boots UEFI images with.
+#[derive(Clone)]
+pub struct OvmfConfig {
+    /// The configured firmware code, which is searched in the usual installation
+    /// directories otherwise.
+    pub code: Option<PathBuf>,
+    /// The template of the variable store that belongs to `code`.
+    pub vars: Option<PathBuf>,
+    /// The URL from which OVMF is downloaded if it isn't installed, in which `{}`
+    /// is replaced by `CODE` or `VARS`.
+    pub url: String,
+    /// The SHA-256 hashes of the downloaded firmware code and variable store (in
+    /// lowercase hex).
+    pub code_sha256: Option<String>,
+    pub vars_sha256: Option<String>,
+}
+
+/// The microVM of the `firecracker` and `cloud-hypervisor` run backends.
+#[derive(Clone)]
+pub struct MicrovmConfig {
+    /// The memory of the microVM in bytes (a multiple of 1 MiB).
+    pub memory: u64,
+    /// The number of virtual CPUs.
+    pub vcpus: u32,
+}
+
+/// The emulator of the `bochs` run backend.
+#[derive(Clone)]
+pub struct BochsConfig {
+    /// The memory of the emulated machine in bytes (a multiple of 1 MiB).
+    pub memory: u64,
+    /// Whether `xchg bx, bx` stops the emulation in the Bochs debugger.
+    pub magic_break: bool,
+    /// Whether Bochs runs without a display window.
+    pub headless: bool,
+}
+
+/// The boot protocol through which Limine loads the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimineProtocol {
+    /// The native Limine boot protocol, which enters the kernel in 64-bit long mode.
+    Limine,
+    /// The Multiboot2 protocol, for which the kernel gets a Multiboot2 header.
+    Multiboot2,
+}
+
+impl FromStr for LimineProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "limine" => Ok(LimineProtocol::Limine),
+            "multiboot2" => Ok(LimineProtocol::Multiboot2),
+            other => Err(format!(
+                "unknown boot protocol `{}` (expected `limine` or `multiboot2`)",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LimineProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            LimineProtocol::Limine => "limine",
+            LimineProtocol::Multiboot2 => "multiboot2",
+        })
+    }
 }
 
-pub(crate) fn read_config(manifest_path: PathBuf) -> Result<Config, Error> {
+/// The table that holds the configuration.
+const METADATA_TABLE: &str = "package.metadata.bootimage";
+/// The table that holds the configuration shared by the members of a workspace.
+const WORKSPACE_TABLE: &str = "workspace.metadata.bootimage";
+/// The top-level table that was used for the configuration before.
+const LEGACY_TABLE: &str = "bootimage";
+/// The configuration file that is used instead of the table if it exists next to
+/// the manifest.
+const CONFIG_FILE: &str = "bootimage.toml";
+
+/// Reads the configuration of the manifest at `manifest_path`, with the overrides of
+/// the cargo profile `profile` merged over it.
+///
+/// The configuration is read from `config_path` or, if it is `None`, from a
+/// `bootimage.toml` next to the manifest if it exists. It is merged over the
+/// configuration of the workspace in `workspace_manifest_path`.
+pub(crate) fn read_config(
+    manifest_path: PathBuf,
+    workspace_manifest_path: &Path,
+    config_path: Option<&Path>,
+    profile: &str,
+) -> Result<Config, Error> {
     use std::{fs::File, io::Read};
-    let cargo_toml: Value = {
-        let mut content = String::new();
-        File::open(&manifest_path)?.read_to_string(&mut content)?;
-        content.parse()?
+    let mut content = String::new();
+    File::open(&manifest_path)?.read_to_string(&mut content)?;
+    let cargo_toml: Value = content.parse()?;
+    let source = Source {
+        path: &manifest_path,
+        content: &content,
     };
 
+    let default_run = cargo_toml
+        .get("package")
+        .and_then(|table| table.get("default-run"))
+        .and_then(Value::as_str)
+        .map(String::from);
     let metadata = cargo_toml
         .get("package")
         .and_then(|table| table.get("metadata"))
         .and_then(|table| table.get("bootimage"));
-    let metadata = match metadata {
-        None => {
-            return Ok(ConfigBuilder {
-                manifest_path: Some(manifest_path),
-                ..Default::default()
-            }.into())
-        }
-        Some(metadata) => metadata.as_table().ok_or(Error::Config(format!(
-            "Bootimage configuration invalid: {:?}",
-            metadata
-        )))?,
-    };
-
+    let legacy = cargo_toml.get("bootimage");
     let mut config = ConfigBuilder {
-        manifest_path: Some(manifest_path),
+        manifest_path: Some(manifest_path.clone()),
+        default_run,
         ..Default::default()
     };
+    read_workspace_config(workspace_manifest_path, profile, &mut config)?;
+
+    let config_path = match config_path {
+        Some(path) => Some(path.to_path_buf()),
+        None => Some(manifest_path.with_file_name(CONFIG_FILE)).filter(|path| path.is_file()),
+    };
+    if let Some(config_path) = config_path {
+        let message = format!(
+            "conflicts with the configuration file `{}`",
+            config_path.display()
+        );
+        if metadata.is_some() {
+            Err(source.error("package.metadata", "bootimage", &message))?
+        }
+        if legacy.is_some() {
+            Err(source.error("", LEGACY_TABLE, &message))?
+        }
+        let mut content = String::new();
+        File::open(&config_path)
+            .and_then(|mut file| file.read_to_string(&mut content))
+            .map_err(|err| {
+                Error::Config(format!(
+                    "failed to read the configuration file `{}`: {}",
+                    config_path.display(),
+                    err
+                ))
+            })?;
+        let table: Table = toml::from_str(&content)?;
+        let source = Source {
+            path: &config_path,
+            content: &content,
+        };
+        read_metadata(&source, "", &table, profile, &mut config)?;
+        read_env(&mut config)?;
+        return Ok(config.into());
+    }
+
+    let (table, metadata) = match (metadata, legacy) {
+        (None, None) => {
+            read_env(&mut config)?;
+            return Ok(config.into());
+        }
+        (Some(metadata), None) => (METADATA_TABLE, metadata),
+        (None, Some(legacy)) => {
+            eprintln!(
+                "Warning: the `[{}]` table is deprecated, move the configuration to `[{}]`",
+                LEGACY_TABLE, METADATA_TABLE
+            );
+            (LEGACY_TABLE, legacy)
+        }
+        (Some(_), Some(_)) => Err(source.error(
+            "",
+            LEGACY_TABLE,
+            &format!(
+                "conflicts with `[{}]`, the configuration must be in one table",
+                METADATA_TABLE
+            ),
+        ))?,
+    };
+    let (parent, name) = match table.rfind('.') {
+        Some(i) => (&table[..i], &table[i + 1..]),
+        None => ("", table),
+    };
+    let metadata = source.table(parent, name, metadata)?;
+
+    read_metadata(&source, table, metadata, profile, &mut config)?;
+    read_env(&mut config)?;
+    Ok(config.into())
+}
+
+/// Reads the `[workspace.metadata.bootimage]` table of the workspace root manifest,
+/// which is shared by all workspace members, into `config`.
+fn read_workspace_config(
+    manifest_path: &Path,
+    profile: &str,
+    config: &mut ConfigBuilder,
+) -> Result<(), Error> {
+    let content = fs::read_to_string(manifest_path)?;
+    let cargo_toml: Value = content.parse()?;
+    let source = Source {
+        path: manifest_path,
+        content: &content,
+    };
+    let metadata = cargo_toml
+        .get("workspace")
+        .and_then(|table| table.get("metadata"))
+        .and_then(|table| table.get("bootimage"));
+    if let Some(metadata) = metadata {
+        let metadata = source.table("workspace.metadata", "bootimage", metadata)?;
+        read_metadata(&source, WORKSPACE_TABLE, metadata, profile, config)?;
+    }
+    Ok(())
+}
+
+/// Reads the configuration table `table` and the overrides of the cargo profile
+/// `profile` into `config`.
+fn read_metadata(
+    source: &Source,
+    table: &str,
+    metadata: &Table,
+    profile: &str,
+    config: &mut ConfigBuilder,
+) -> Result<(), Error> {
+    read_table(source, table, metadata, config)?;
+    if let Some(profiles) = metadata.get("profile") {
+        let profiles = source.table(table, "profile", profiles)?;
+        let profiles_table = join(table, "profile");
+        for (name, overrides) in profiles {
+            let overrides = source.table(&profiles_table, name, overrides)?;
+            let profile_table = join(&profiles_table, name);
+            if name == profile {
+                read_table(source, &profile_table, overrides, config)?;
+            } else {
+                // the overrides of other profiles are only validated
+                read_table(
+                    source,
+                    &profile_table,
+                    overrides,
+                    &mut ConfigBuilder::default(),
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
 
+/// Reads the `BOOTIMAGE_*` environment variables, which take precedence over the
+/// configuration in the manifest.
+///
+/// The variable of a key is its name in upper case with `-` replaced by `_`, prefixed
+/// with `BOOTIMAGE_`. Lists are separated by whitespace.
+fn read_env(config: &mut ConfigBuilder) -> Result<(), Error> {
+    fn var(name: &str) -> Result<Option<String>, Error> {
+        match env::var(name) {
+            Ok(value) => Ok(Some(value)),
+            Err(env::VarError::NotPresent) => Ok(None),
+            Err(env::VarError::NotUnicode(_)) => Err(Error::Config(format!(
+                "environment variable `{}` is not valid unicode",
+                name
+            ))),
+        }
+    }
+    fn invalid(name: &str, err: &str) -> Error {
+        Error::Config(format!(
+            "environment variable `{}` is invalid: {}",
+            name, err
+        ))
+    }
+
+    if let Some(value) = var("BOOTIMAGE_DEFAULT_TARGET")? {
+        config.default_target = Some(value);
+    }
+    if let Some(value) = var("BOOTIMAGE_OUTPUT")? {
+        config.output = Some(PathBuf::from(value));
+    }
+    if let Some(value) = var("BOOTIMAGE_EFI_LOADER")? {
+        let path = Path::new(&value)
+            .canonicalize()
+            .map_err(|err| invalid("BOOTIMAGE_EFI_LOADER", &format!("`{}`: {}", value, err)))?;
+        config.efi_loader = Some(path);
+    }
+    if let Some(value) = var("BOOTIMAGE_FORMAT")? {
+        config.format = Some(
+            value
+                .parse()
+                .map_err(|err: String| invalid("BOOTIMAGE_FORMAT", &err))?,
+        );
+    }
+    if let Some(value) = var("BOOTIMAGE_BUILD_COMMAND")? {
+        let build_command = value
+            .parse()
+            .map_err(|err: String| invalid("BOOTIMAGE_BUILD_COMMAND", &err))?;
+        config.build_command = Some(build_command);
+    }
+    if let Some(value) = var("BOOTIMAGE_MINIMUM_IMAGE_SIZE")? {
+        let size: u64 = value.trim().parse().map_err(|_| {
+            invalid(
+                "BOOTIMAGE_MINIMUM_IMAGE_SIZE",
+                &format!("expected a size in MiB, found `{}`", value),
+            )
+        })?;
+        config.minimum_image_size = Some(size * 1024 * 1024); // MiB -> Byte
+    }
+    if let Some(value) = var("BOOTIMAGE_EXPORT")? {
+        let mut formats = Vec::new();
+        for format in value.split_whitespace() {
+            formats.push(
+                format
+                    .parse()
+                    .map_err(|err: String| invalid("BOOTIMAGE_EXPORT", &err))?,
+            );
+        }
+        config.export = Some(formats);
+    }
+    if let Some(value) = var("BOOTIMAGE_RUN_COMMAND")? {
+        let run_command: Vec<String> = value.split_whitespace().map(String::from).collect();
+        if run_command.is_empty() {
+            Err(invalid(
+                "BOOTIMAGE_RUN_COMMAND",
+                "the command must not be empty",
+            ))?
+        }
+        config.run_command = Some(run_command);
+    }
+    Ok(())
+}
+
+/// Reads the keys of the configuration table `table` into `config`, overriding
+/// the values that are already set.
+fn read_table(
+    source: &Source,
+    table: &str,
+    metadata: &Table,
+    config: &mut ConfigBuilder,
+) -> Result<(), Error> {
     for (key, value) in metadata {
-        match (key.as_str(), value.clone()) {
-            ("default-target", Value::String(s)) => config.default_target = From::from(s),
-            ("output", Value::String(s)) => config.output = Some(PathBuf::from(s)),
-            ("bootloader", Value::Table(t)) => {
-                let mut bootloader_config = BootloaderConfigBuilder::default();
+        match key.as_str() {
+            "default-target" => config.default_target = Some(source.string(table, key, value)?),
+            "output" => config.output = Some(PathBuf::from(source.string(table, key, value)?)),
+            "efi-loader" => config.efi_loader = Some(source.path(table, key, value)?),
+            "format" => config.format = Some(source.parse(table, key, value)?),
+            "build-command" => config.build_command = Some(source.parse(table, key, value)?),
+            "bootloader" => {
+                let t = source.table(table, key, value)?;
+                let bootloader_table = join(table, key);
+                let table = bootloader_table.as_str();
+                let mut bootloader_config = config.bootloader.take().unwrap_or_default();
+                let sources = ["path", "git", "vendor", "url"];
+                if sources.iter().any(|source| t.contains_key(*source)) {
+                    // a profile replaces the bootloader source of the base configuration
+                    bootloader_config.git = None;
+                    bootloader_config.branch = None;
+                    bootloader_config.path = None;
+                    bootloader_config.vendor = None;
+                    bootloader_config.url = None;
+                    bootloader_config.sha256 = None;
+                }
                 for (key, value) in t {
-                    match (key.as_str(), value) {
-                        ("name", Value::String(s)) => bootloader_config.name = From::from(s),
-                        ("precompiled", Value::Boolean(b)) => {
-                            bootloader_config.precompiled = From::from(b)
+                    match key.as_str() {
+                        "name" => bootloader_config.name = Some(source.string(table, key, value)?),
+                        "precompiled" => {
+                            bootloader_config.precompiled = Some(source.boolean(table, key, value)?)
+                        }
+                        "target" => {
+                            bootloader_config.target = Some(source.string(table, key, value)?)
                         }
-                        ("target", Value::String(s)) => bootloader_config.target = From::from(s),
-                        ("version", Value::String(s)) => bootloader_config.version = From::from(s),
-                        ("git", Value::String(s)) => bootloader_config.git = From::from(s),
-                        ("branch", Value::String(s)) => bootloader_config.branch = From::from(s),
-                        ("path", Value::String(s)) => {
-                            bootloader_config.path = Some(Path::new(&s).canonicalize()?);
+                        "version" => {
+                            bootloader_config.version = Some(source.string(table, key, value)?)
                         }
-                        (key, value) => Err(Error::Config(format!(
-                            "unexpected \
-                             `package.metadata.bootimage.bootloader` key `{}` with value `{}`",
-                            key, value
-                        )))?,
+                        "git" => bootloader_config.git = Some(source.string(table, key, value)?),
+                        "branch" => {
+                            bootloader_config.branch = Some(source.string(table, key, value)?)
+                        }
+                        "path" => bootloader_config.path = Some(source.path(table, key, value)?),
+                        "linking" => {
+                            bootloader_config.linking = Some(source.parse(table, key, value)?)
+                        }
+                        "url" => bootloader_config.url = Some(source.string(table, key, value)?),
+                        "sha256" => {
+                            bootloader_config.sha256 = Some(source.sha256(table, key, value)?)
+                        }
+                        "vendor" => {
+                            bootloader_config.vendor = Some(source.path(table, key, value)?)
+                        }
+                        "features" => {
+                            bootloader_config.features = Some(source.strings(table, key, value)?)
+                        }
+                        key => Err(source.error(table, key, "is not a known key"))?,
+                    }
+                }
+
+                let conflicts = [
+                    ("path", "git"),
+                    ("path", "vendor"),
+                    ("git", "vendor"),
+                    ("url", "path"),
+                    ("url", "git"),
+                    ("url", "vendor"),
+                    ("url", "precompiled"),
+                ];
+                for &(first, second) in &conflicts {
+                    if t.contains_key(first) && t.contains_key(second) {
+                        Err(source.error(table, second, &format!("conflicts with `{}`", first)))?
                     }
                 }
+                if t.contains_key("branch") && !t.contains_key("git") {
+                    Err(source.error(table, "branch", "requires `git`"))?
+                }
+                if t.contains_key("url") && !t.contains_key("sha256") {
+                    Err(source.error(table, "url", "requires the `sha256` hash of the bootloader"))?
+                }
                 config.bootloader = Some(bootloader_config);
             }
-            ("minimum-image-size", Value::Integer(x)) => {
+            "minimum-image-size" => {
+                let x = source.integer(table, key, value)?;
                 if x >= 0 {
                     config.minimum_image_size = Some((x * 1024 * 1024) as u64); // MiB -> Byte
                 } else {
-                    Err(Error::Config(format!(
-                        "unexpected `package.metadata.bootimage` \
-                         key `minimum-image-size` with negative value `{}`",
-                        value
-                    )))?
+                    Err(source.error(table, key, &format!("must not be negative, found `{}`", x)))?
+                }
+            }
+            "sector-size" => {
+                let x = source.integer(table, key, value)?;
+                if x == 512 || x == 4096 {
+                    config.sector_size = Some(x as u64);
+                } else {
+                    Err(source.error(table, key, &format!("must be 512 or 4096, found `{}`", x)))?
+                }
+            }
+            "kernel-alignment" => config.kernel_alignment = Some(source.size(table, key, value)?),
+            "image-alignment" => config.image_alignment = Some(source.size(table, key, value)?),
+            "pad-byte" => {
+                let x = source.integer(table, key, value)?;
+                if (0..=255).contains(&x) {
+                    config.pad_byte = Some(x as u8);
+                } else {
+                    Err(source.error(table, key, &format!("must be a byte value, found `{}`", x)))?
+                }
+            }
+            "strip-kernel" => config.strip_kernel = Some(source.boolean(table, key, value)?),
+            "kernel-compression" => {
+                config.kernel_compression = Some(source.parse(table, key, value)?)
+            }
+            "max-kernel-size" => config.max_kernel_size = Some(source.size(table, key, value)?),
+            "max-image-size" => config.max_image_size = Some(source.size(table, key, value)?),
+            "emit-map" => config.emit_map = Some(source.boolean(table, key, value)?),
+            "emit-checksums" => config.emit_checksums = Some(source.boolean(table, key, value)?),
+            "embed-metadata" => config.embed_metadata = Some(source.boolean(table, key, value)?),
+            "info-block-version" => {
+                let x = source.integer(table, key, value)?;
+                if x == 1 || x == 2 {
+                    config.info_block_version = Some(x as u16);
+                } else {
+                    Err(source.error(table, key, &format!("must be 1 or 2, found `{}`", x)))?
+                }
+            }
+            "kernel-cmdline" => config.kernel_cmdline = Some(source.string(table, key, value)?),
+            "initrd" => config.initrd = Some(source.path(table, key, value)?),
+            "multiboot-modules" => {
+                config.multiboot_modules = Some(source.paths(table, key, value)?)
+            }
+            "limine" => {
+                let t = source.table(table, key, value)?;
+                let limine_table = join(table, key);
+                let table = limine_table.as_str();
+                let mut limine_config = config.limine.take().unwrap_or_default();
+                if t.contains_key("version") || t.contains_key("url") {
+                    // the hash of the base configuration is for a different release
+                    limine_config.url = None;
+                    limine_config.sha256 = None;
+                }
+                for (key, value) in t {
+                    match key.as_str() {
+                        "version" => {
+                            let version = source.string(table, key, value)?;
+                            if !version.starts_with("7.") {
+                                Err(source.error(
+                                    table,
+                                    key,
+                                    &format!(
+                                        "must be a Limine 7.x release like `7.13.3` (which uses \
+                                         `limine.cfg`), found `{}`",
+                                        version
+                                    ),
+                                ))?
+                            }
+                            limine_config.version = Some(version);
+                        }
+                        "url" => limine_config.url = Some(source.string(table, key, value)?),
+                        "sha256" => limine_config.sha256 = Some(source.sha256(table, key, value)?),
+                        "protocol" => {
+                            limine_config.protocol = Some(source.parse(table, key, value)?)
+                        }
+                        key => Err(source.error(table, key, "is not a known key"))?,
+                    }
+                }
+                if t.contains_key("url") && !t.contains_key("sha256") {
+                    Err(source.error(table, "url", "requires the `sha256` hash of the archive"))?
+                }
+                config.limine = Some(limine_config);
+            }
+            "kernels" => {
+                let kernels_table = join(table, key);
+                for (title, value) in source.table(table, key, value)? {
+                    let t = source.table(&kernels_table, title, value)?;
+                    let table = join(&kernels_table, title);
+                    let table = table.as_str();
+                    let (mut kernel, mut cmdline) = (None, None);
+                    for (key, value) in t {
+                        match key.as_str() {
+                            "bin" => {
+                                kernel = Some(MenuKernel::Bin(source.string(table, key, value)?))
+                            }
+                            "path" => {
+                                kernel = Some(MenuKernel::Path(source.path(table, key, value)?))
+                            }
+                            "cmdline" => cmdline = Some(source.string(table, key, value)?),
+                            key => Err(source.error(table, key, "is not a known key"))?,
+                        }
+                    }
+                    if t.contains_key("bin") && t.contains_key("path") {
+                        Err(source.error(table, "path", "conflicts with `bin`"))?
+                    }
+                    let kernel = match kernel {
+                        Some(kernel) => kernel,
+                        None => Err(source.error(
+                            &kernels_table,
+                            title,
+                            "requires the `bin` or the `path` of the kernel",
+                        ))?,
+                    };
+                    config.kernels.insert(title.clone(), MenuEntry { kernel, cmdline });
+                }
+            }
+            "menu-timeout" => {
+                let x = source.integer(table, key, value)?;
+                if x >= 0 {
+                    config.menu_timeout = Some(x as u64);
+                } else {
+                    Err(source.error(table, key, &format!("must not be negative, found `{}`", x)))?
+                }
+            }
+            "syslinux-directory" => {
+                config.syslinux_directory = Some(source.path(table, key, value)?)
+            }
+            "files" => {
+                let files_table = join(table, key);
+                for (guest_path, value) in source.table(table, key, value)? {
+                    let host_path = source.path(&files_table, guest_path, value)?;
+                    config.files.insert(guest_path.clone(), host_path);
+                }
+            }
+            "sparse" => config.sparse = Some(source.boolean(table, key, value)?),
+            "mbr-partition-table" => {
+                config.mbr_partition_table = Some(source.boolean(table, key, value)?)
+            }
+            "data-partition" => {
+                let t = source.table(table, key, value)?;
+                let partition_table = join(table, key);
+                let table = partition_table.as_str();
+                for (key, value) in t {
+                    match key.as_str() {
+                        "filesystem" => {
+                            config.data_filesystem = Some(source.parse(table, key, value)?)
+                        }
+                        "directory" => {
+                            config.data_directory = Some(source.path(table, key, value)?)
+                        }
+                        key => Err(source.error(table, key, "is not a known key"))?,
+                    }
+                }
+            }
+            "info-block" => {
+                let info_table = join(table, key);
+                for (name, value) in source.table(table, key, value)? {
+                    let t = source.table(&info_table, name, value)?;
+                    let table = join(&info_table, name);
+                    let table = table.as_str();
+                    let (mut offset, mut size, mut field_value) = (None, None, None);
+                    for (key, value) in t {
+                        let x = source.integer(table, key, value)?;
+                        match key.as_str() {
+                            "offset" if (0..BLOCK_SIZE as i64).contains(&x) => {
+                                offset = Some(x as usize)
+                            }
+                            "offset" => Err(source.error(
+                                table,
+                                key,
+                                &format!("must be between 0 and {}, found `{}`", BLOCK_SIZE - 1, x),
+                            ))?,
+                            "size" if [1, 2, 4, 8].contains(&x) => size = Some(x as usize),
+                            "size" => Err(source.error(
+                                table,
+                                key,
+                                &format!("must be 1, 2, 4, or 8 bytes, found `{}`", x),
+                            ))?,
+                            "value" if x >= 0 => field_value = Some(x as u64),
+                            "value" => Err(source.error(
+                                table,
+                                key,
+                                &format!("must not be negative, found `{}`", x),
+                            ))?,
+                            key => Err(source.error(table, key, "is not a known key"))?,
+                        }
+                    }
+                    let field = match (offset, size, field_value) {
+                        (Some(offset), Some(size), Some(value)) => InfoBlockField {
+                            offset,
+                            size,
+                            value,
+                        },
+                        _ => Err(source.error(
+                            &info_table,
+                            name,
+                            "requires an `offset`, a `size`, and a `value`",
+                        ))?,
+                    };
+                    if field.offset + field.size > BLOCK_SIZE {
+                        Err(source.error(table, "size", "exceeds the kernel info block"))?
+                    }
+                    if field.size < 8 && field.value >> (field.size * 8) != 0 {
+                        Err(source.error(
+                            table,
+                            "value",
+                            &format!("doesn't fit into {} bytes", field.size),
+                        ))?
+                    }
+                    config.info_block_fields.insert(name.clone(), field);
+                }
+            }
+            // a signing method overrides the one of a workspace table
+            "signing-key" | "signing-command" => {
+                let other = if key == "signing-key" {
+                    "signing-command"
+                } else {
+                    "signing-key"
+                };
+                if metadata.contains_key(other) {
+                    Err(source.error(table, key, &format!("can't be combined with `{}`", other)))?
+                }
+                if key == "signing-key" {
+                    config.signing_key = Some(source.path(table, key, value)?);
+                    config.signing_command = None;
+                } else {
+                    let command = source.strings(table, key, value)?;
+                    if command.is_empty() {
+                        Err(source.error(table, key, "must not be empty"))?
+                    }
+                    config.signing_command = Some(command);
+                    config.signing_key = None;
+                }
+            }
+            "export" => {
+                let mut formats = Vec::new();
+                for format in source.strings(table, key, value)? {
+                    formats.push(format.parse().map_err(|err: String| {
+                        source.error(table, key, &format!("is invalid: {}", err))
+                    })?);
+                }
+                config.export = Some(formats);
+            }
+            "run-command" => config.run_command = Some(source.strings(table, key, value)?),
+            "run-backend" => config.run_backend = Some(source.parse(table, key, value)?),
+            "virtualbox" | "vmware" | "hyperv" => {
+                let t = source.table(table, key, value)?;
+                let (vm_config, granularity) = match key.as_str() {
+                    "virtualbox" => (&mut config.virtualbox, 1),
+                    // VMware only accepts multiples of 4 MiB, Hyper-V multiples of 2 MiB
+                    "vmware" => (&mut config.vmware, 4),
+                    _ => (&mut config.hyperv, 2),
+                };
+                let vm_table = join(table, key);
+                let table = vm_table.as_str();
+                let mut builder = vm_config.take().unwrap_or_default();
+                for (key, value) in t {
+                    match key.as_str() {
+                        "vm-name" => builder.vm_name = Some(source.string(table, key, value)?),
+                        "memory" => {
+                            let memory = source.size(table, key, value)?;
+                            if !memory.is_multiple_of(granularity * 1024 * 1024) {
+                                Err(source.error(
+                                    table,
+                                    key,
+                                    &format!(
+                                        "must be a multiple of {} MiB, found `{}`",
+                                        granularity, value
+                                    ),
+                                ))?
+                            }
+                            builder.memory = Some(memory);
+                        }
+                        "headless" => builder.headless = Some(source.boolean(table, key, value)?),
+                        key => Err(source.error(table, key, "is not a known key"))?,
+                    }
+                }
+                *vm_config = Some(builder);
+            }
+            "firmware" => config.firmware = Some(source.parse(table, key, value)?),
+            "ovmf" => {
+                let t = source.table(table, key, value)?;
+                let ovmf_table = join(table, key);
+                let table = ovmf_table.as_str();
+                let mut builder = config.ovmf.take().unwrap_or_default();
+                for (key, value) in t {
+                    match key.as_str() {
+                        "code" => builder.code = Some(source.path(table, key, value)?),
+                        "vars" => builder.vars = Some(source.path(table, key, value)?),
+                        "url" => {
+                            let url = source.string(table, key, value)?;
+                            if !url.contains("{}") {
+                                Err(source.error(
+                                    table,
+                                    key,
+                                    "must contain `{}`, which is replaced by `CODE` or `VARS`",
+                                ))?
+                            }
+                            builder.url = Some(url);
+                        }
+                        "code-sha256" => {
+                            builder.code_sha256 = Some(source.sha256(table, key, value)?)
+                        }
+                        "vars-sha256" => {
+                            builder.vars_sha256 = Some(source.sha256(table, key, value)?)
+                        }
+                        key => Err(source.error(table, key, "is not a known key"))?,
+                    }
+                }
+                if builder.code_sha256.is_some() != builder.vars_sha256.is_some() {
+                    let (key, other) = match builder.code_sha256 {
+                        Some(_) => ("code-sha256", "vars-sha256"),
+                        None => ("vars-sha256", "code-sha256"),
+                    };
+                    Err(source.error(table, key, &format!("requires `{}`", other)))?
+                }
+                if builder.code.is_some() != builder.vars.is_some() {
+                    let (key, other) = match builder.code {
+                        Some(_) => ("code", "vars"),
+                        None => ("vars", "code"),
+                    };
+                    Err(source.error(table, key, &format!("requires `{}`", other)))?
+                }
+                config.ovmf = Some(builder);
+            }
+            "microvm" => {
+                let t = source.table(table, key, value)?;
+                let microvm_table = join(table, key);
+                let table = microvm_table.as_str();
+                let mut builder = config.microvm.take().unwrap_or_default();
+                for (key, value) in t {
+                    match key.as_str() {
+                        "memory" => {
+                            let memory = source.size(table, key, value)?;
+                            if memory == 0 || !memory.is_multiple_of(1024 * 1024) {
+                                Err(source.error(
+                                    table,
+                                    key,
+                                    &format!(
+                                        "must be a positive multiple of 1 MiB, found `{}`",
+                                        value
+                                    ),
+                                ))?
+                            }
+                            builder.memory = Some(memory);
+                        }
+                        "vcpus" => {
+                            let x = source.integer(table, key, value)?;
+                            if x > 0 && x <= 255 {
+                                builder.vcpus = Some(x as u32);
+                            } else {
+                                Err(source.error(
+                                    table,
+                                    key,
+                                    &format!("must be between 1 and 255, found `{}`", x),
+                                ))?
+                            }
+                        }
+                        key => Err(source.error(table, key, "is not a known key"))?,
+                    }
+                }
+                config.microvm = Some(builder);
+            }
+            "bochs" => {
+                let t = source.table(table, key, value)?;
+                let bochs_table = join(table, key);
+                let table = bochs_table.as_str();
+                let mut builder = config.bochs.take().unwrap_or_default();
+                for (key, value) in t {
+                    match key.as_str() {
+                        "memory" => {
+                            let memory = source.size(table, key, value)?;
+                            if memory == 0 || !memory.is_multiple_of(1024 * 1024) {
+                                Err(source.error(
+                                    table,
+                                    key,
+                                    &format!(
+                                        "must be a positive multiple of 1 MiB, found `{}`",
+                                        value
+                                    ),
+                                ))?
+                            }
+                            builder.memory = Some(memory);
+                        }
+                        "magic-break" => {
+                            builder.magic_break = Some(source.boolean(table, key, value)?)
+                        }
+                        "headless" => builder.headless = Some(source.boolean(table, key, value)?),
+                        key => Err(source.error(table, key, "is not a known key"))?,
+                    }
+                }
+                config.bochs = Some(builder);
+            }
+            "capture-serial" => config.capture_serial = Some(source.boolean(table, key, value)?),
+            "accel" => config.accel = Some(source.boolean(table, key, value)?),
+            "deterministic" => config.deterministic = Some(source.boolean(table, key, value)?),
+            "display" => config.display = Some(source.parse(table, key, value)?),
+            "qemu" => {
+                let t = source.table(table, key, value)?;
+                let qemu_table = join(table, key);
+                let table = qemu_table.as_str();
+                let mut qemu = config.qemu.take().unwrap_or_default();
+                for (key, value) in t {
+                    match key.as_str() {
+                        "memory" => {
+                            let memory = source.size(table, key, value)?;
+                            if memory == 0 || !memory.is_multiple_of(1024 * 1024) {
+                                Err(source.error(
+                                    table,
+                                    key,
+                                    &format!(
+                                        "must be a positive multiple of 1 MiB, found `{}`",
+                                        value
+                                    ),
+                                ))?
+                            }
+                            qemu.memory = Some(memory);
+                        }
+                        "smp" => {
+                            let x = source.integer(table, key, value)?;
+                            if x > 0 && x <= 255 {
+                                qemu.smp = Some(x as u32);
+                            } else {
+                                Err(source.error(
+                                    table,
+                                    key,
+                                    &format!("must be between 1 and 255, found `{}`", x),
+                                ))?
+                            }
+                        }
+                        "cpu" => {
+                            let cpu = source.string(table, key, value)?;
+                            if cpu.is_empty() || cpu.contains(char::is_whitespace) {
+                                Err(source.error(
+                                    table,
+                                    key,
+                                    &format!("must be a QEMU CPU model, found `{}`", cpu),
+                                ))?
+                            }
+                            qemu.cpu = Some(cpu);
+                        }
+                        "machine" => qemu.machine = Some(source.parse(table, key, value)?),
+                        key => Err(source.error(table, key, "is not a known key"))?,
+                    }
+                }
+                config.qemu = Some(qemu);
+            }
+            "screenshots" => {
+                let t = source.table(table, key, value)?;
+                let screenshots_table = join(table, key);
+                let table = screenshots_table.as_str();
+                let mut screenshots = config.screenshots.take().unwrap_or_default();
+                for (key, value) in t {
+                    match key.as_str() {
+                        "at" => screenshots.at = source.seconds(table, key, value)?,
+                        "at-end" => screenshots.at_end = source.boolean(table, key, value)?,
+                        "golden-dir" => {
+                            let dir = source.string(table, key, value)?;
+                            screenshots.golden_dir = Some(PathBuf::from(dir));
+                        }
+                        "tolerance" => {
+                            let tolerance = match *value {
+                                Value::Float(x) => x,
+                                Value::Integer(x) => x as f64,
+                                _ => Err(source.type_error(table, key, "a number", value))?,
+                            };
+                            if !(0.0..=1.0).contains(&tolerance) {
+                                Err(source.error(
+                                    table,
+                                    key,
+                                    &format!(
+                                        "must be a fraction between 0 and 1, found `{}`",
+                                        value
+                                    ),
+                                ))?
+                            }
+                            screenshots.tolerance = tolerance;
+                        }
+                        key => Err(source.error(table, key, "is not a known key"))?,
+                    }
+                }
+                config.screenshots = Some(screenshots);
+            }
+            "reproducible" => config.reproducible = Some(source.boolean(table, key, value)?),
+            "allow-unverified-downloads" => {
+                config.allow_unverified_downloads = Some(source.boolean(table, key, value)?)
+            }
+            "backtrace-marker" => {
+                let marker = source.string(table, key, value)?;
+                if marker.is_empty() {
+                    Err(source.error(table, key, "must not be empty"))?
+                }
+                config.backtrace_marker = Some(marker);
+            }
+            "serial-port" => {
+                let x = source.integer(table, key, value)?;
+                if x > 0 && x <= i64::from(u16::MAX) {
+                    config.serial_port = Some(x as u16);
+                } else {
+                    Err(source.error(
+                        table,
+                        key,
+                        &format!("must be a port between 1 and 65535, found `{}`", x),
+                    ))?
+                }
+            }
+            "test-jobs" => {
+                let x = source.integer(table, key, value)?;
+                if x > 0 {
+                    config.test_jobs = Some(x as usize);
+                } else {
+                    Err(source.error(table, key, &format!("must be positive, found `{}`", x)))?
+                }
+            }
+            "test-prefix" => {
+                let prefix = source.string(table, key, value)?;
+                if prefix.is_empty() {
+                    Err(source.error(table, key, "must not be empty"))?
+                }
+                config.test_prefix = Some(prefix);
+            }
+            "run-timeout" => config.run_timeout = Some(source.timeout(table, key, value)?),
+            "test-success-exit-code" => {
+                config.test_success_exit_code = Some(source.exit_code(table, key, value)?)
+            }
+            "pre-build" | "post-build" | "pre-run" => {
+                let hook = source.strings(table, key, value)?;
+                if hook.is_empty() {
+                    Err(source.error(table, key, "must not be empty"))?
+                }
+                match key.as_str() {
+                    "pre-build" => config.pre_build = Some(hook),
+                    "post-build" => config.post_build = Some(hook),
+                    _ => config.pre_run = Some(hook),
                 }
             }
-            ("run-command", Value::Array(array)) => {
-                let mut command = Vec::new();
-                for value in array {
-                    match value {
-                        Value::String(s) => command.push(s),
-                        _ => Err(Error::Config(format!(
-                            "run-command must be a list of strings"
-                        )))?,
+            "run" => {
+                let run_table = join(table, key);
+                for (name, value) in source.table(table, key, value)? {
+                    let t = source.table(&run_table, name, value)?;
+                    let table = join(&run_table, name);
+                    let table = table.as_str();
+                    let mut run_profile = RunProfile::default();
+                    for (key, value) in t {
+                        match key.as_str() {
+                            "command" => {
+                                run_profile.command = Some(source.strings(table, key, value)?)
+                            }
+                            "args" => run_profile.args = source.strings(table, key, value)?,
+                            "env" => {
+                                let env_table = join(table, key);
+                                for (name, value) in source.table(table, key, value)? {
+                                    let value = source.string(&env_table, name, value)?;
+                                    run_profile.env.push((name.clone(), value));
+                                }
+                            }
+                            key => Err(source.error(table, key, "is not a known key"))?,
+                        }
                     }
+                    config.run_profiles.insert(name.clone(), run_profile);
                 }
-                config.run_command = Some(command);
             }
-            (key, value) => Err(Error::Config(format!(
-                "unexpected `package.metadata.bootimage` \
-                 key `{}` with value `{}`",
-                key, value
-            )))?,
+            "test" => {
+                let test_table = join(table, key);
+                for (name, value) in source.table(table, key, value)? {
+                    let t = source.table(&test_table, name, value)?;
+                    let table = join(&test_table, name);
+                    let table = table.as_str();
+                    // the keys are merged with those of a base table
+                    let test = config.tests.entry(name.clone()).or_default();
+                    for (key, value) in t {
+                        match key.as_str() {
+                            "timeout" => test.timeout = Some(source.timeout(table, key, value)?),
+                            "success-exit-code" => {
+                                test.success_exit_code = Some(source.exit_code(table, key, value)?)
+                            }
+                            "run-args" => test.run_args = source.strings(table, key, value)?,
+                            "screenshot-at" => {
+                                test.screenshot_at = Some(source.seconds(table, key, value)?)
+                            }
+                            "screenshot-at-end" => {
+                                test.screenshot_at_end = Some(source.boolean(table, key, value)?)
+                            }
+                            "peers" => {
+                                let peers = source.strings(table, key, value)?;
+                                // the last byte of the MAC address numbers the VMs
+                                if peers.len() > 254 {
+                                    Err(source.error(table, key, "must not exceed 254 peers"))?
+                                }
+                                test.peers = peers;
+                            }
+                            "nic-model" => {
+                                test.nic_model = Some(source.string(table, key, value)?)
+                            }
+                            key => Err(source.error(table, key, "is not a known key"))?,
+                        }
+                    }
+                }
+            }
+            // profile overrides are read by `read_config` and can't be nested
+            "profile" if [METADATA_TABLE, WORKSPACE_TABLE, LEGACY_TABLE, ""].contains(&table) => {}
+            key => Err(source.error(table, key, "is not a known key"))?,
         }
     }
-    Ok(config.into())
+    Ok(())
+}
+
+/// Returns the dotted name of `key` in the table with the dotted name `table`.
+fn join(table: &str, key: &str) -> String {
+    if table.is_empty() {
+        key.to_owned()
+    } else {
+        format!("{}.{}", table, key)
+    }
+}
+
+/// The source of the configuration, used to point configuration errors to the offending key.
+struct Source<'a> {
+    path: &'a Path,
+    content: &'a str,
+}
+
+impl<'a> Source<'a> {
+    /// Creates an error for `key` of the table with the dotted name `table`.
+    fn error(&self, table: &str, key: &str, message: &str) -> Error {
+        let name = join(table, key);
+        match self.locate(table, key) {
+            Some((line, column)) => Error::Config(format!(
+                "{}:{}:{}: `{}` {}",
+                self.path.display(),
+                line,
+                column,
+                name,
+                message
+            )),
+            None => Error::Config(format!("{}: `{}` {}", self.path.display(), name, message)),
+        }
+    }
+
+    /// Returns the line and column (both starting at 1) where `key` of `table`
+    /// is defined.
+    ///
+    /// Keys of inline tables are located at the definition of the inline table,
+    /// tables that are only defined through the headers of their sub-tables at
+    /// the first of these headers.
+    fn locate(&self, table: &str, key: &str) -> Option<(usize, usize)> {
+        let name = join(table, key);
+        let mut current_table = String::new();
+        for (i, line) in self.content.lines().enumerate() {
+            let trimmed = line.trim_start();
+            if trimmed.starts_with('[') {
+                let header = trimmed.trim_start_matches('[');
+                current_table = header.split(']').next().unwrap_or("").trim().to_owned();
+                let defines_key = !key.is_empty()
+                    && (current_table == name || current_table.starts_with(&format!("{}.", name)));
+                if (current_table == table && key.is_empty()) || defines_key {
+                    return Some((i + 1, line.len() - trimmed.len() + 1));
+                }
+                continue;
+            }
+            if current_table != table {
+                continue;
+            }
+            let rest = trimmed
+                .strip_prefix(key)
+                .or_else(|| trimmed.strip_prefix(&format!("\"{}\"", key)));
+            if rest.is_some_and(|rest| rest.trim_start().starts_with('=')) {
+                return Some((i + 1, line.len() - trimmed.len() + 1));
+            }
+        }
+        if table.is_empty() {
+            return None;
+        }
+        let (parent, name) = match table.rfind('.') {
+            Some(i) => (&table[..i], &table[i + 1..]),
+            None => ("", table),
+        };
+        self.locate(parent, name)
+    }
+
+    fn type_error(&self, table: &str, key: &str, expected: &str, value: &Value) -> Error {
+        self.error(
+            table,
+            key,
+            &format!(
+                "must be {}, found {} `{}`",
+                expected,
+                value.type_str(),
+                value
+            ),
+        )
+    }
+
+    fn table(&self, table: &str, key: &str, value: &'a Value) -> Result<&'a Table, Error> {
+        value
+            .as_table()
+            .ok_or_else(|| self.type_error(table, key, "a table", value))
+    }
+
+    fn string(&self, table: &str, key: &str, value: &Value) -> Result<String, Error> {
+        match *value {
+            Value::String(ref s) => Ok(s.clone()),
+            _ => Err(self.type_error(table, key, "a string", value)),
+        }
+    }
+
+    fn boolean(&self, table: &str, key: &str, value: &Value) -> Result<bool, Error> {
+        value
+            .as_bool()
+            .ok_or_else(|| self.type_error(table, key, "a boolean", value))
+    }
+
+    fn integer(&self, table: &str, key: &str, value: &Value) -> Result<i64, Error> {
+        value
+            .as_integer()
+            .ok_or_else(|| self.type_error(table, key, "an integer", value))
+    }
+
+    /// Reads a positive number of seconds.
+    fn timeout(&self, table: &str, key: &str, value: &Value) -> Result<Duration, Error> {
+        let x = self.integer(table, key, value)?;
+        if x > 0 {
+            Ok(Duration::from_secs(x as u64))
+        } else {
+            Err(self.error(
+                table,
+                key,
+                &format!("must be a positive number of seconds, found `{}`", x),
+            ))
+        }
+    }
+
+    /// Reads a list of non-negative numbers of seconds, which can be fractional.
+    fn seconds(&self, table: &str, key: &str, value: &Value) -> Result<Vec<Duration>, Error> {
+        let expected = "a list of non-negative numbers of seconds";
+        let array = value
+            .as_array()
+            .ok_or_else(|| self.type_error(table, key, expected, value))?;
+        array
+            .iter()
+            .map(|value| match *value {
+                Value::Integer(x) if x >= 0 => Ok(Duration::from_secs(x as u64)),
+                Value::Float(x) if x >= 0.0 && x.is_finite() => Ok(Duration::from_secs_f64(x)),
+                _ => Err(self.type_error(table, key, expected, value)),
+            })
+            .collect()
+    }
+
+    fn exit_code(&self, table: &str, key: &str, value: &Value) -> Result<i32, Error> {
+        let x = self.integer(table, key, value)?;
+        if (0..=255).contains(&x) {
+            Ok(x as i32)
+        } else {
+            Err(self.error(
+                table,
+                key,
+                &format!("must be an exit status between 0 and 255, found `{}`", x),
+            ))
+        }
+    }
+
+    /// Reads a number of bytes, given as an integer or as a string with a binary
+    /// unit (e.g. `"512 KiB"`).
+    fn size(&self, table: &str, key: &str, value: &Value) -> Result<u64, Error> {
+        let invalid = || {
+            self.error(
+                table,
+                key,
+                &format!("must be a size like `1048576` or `\"1 MiB\"`, found `{}`", value),
+            )
+        };
+        match *value {
+            Value::Integer(x) if x > 0 => Ok(x as u64),
+            Value::String(ref s) => {
+                let s = s.trim();
+                let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+                let number: u64 = s[..split].parse().map_err(|_| invalid())?;
+                let unit = match s[split..].trim() {
+                    "" | "B" => 1,
+                    "KiB" => 1024,
+                    "MiB" => 1024 * 1024,
+                    "GiB" => 1024 * 1024 * 1024,
+                    _ => return Err(invalid()),
+                };
+                number
+                    .checked_mul(unit)
+                    .filter(|&size| size > 0)
+                    .ok_or_else(invalid)
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    fn strings(&self, table: &str, key: &str, value: &Value) -> Result<Vec<String>, Error> {
+        let array = value
+            .as_array()
+            .ok_or_else(|| self.type_error(table, key, "a list of strings", value))?;
+        array
+            .iter()
+            .map(|value| match *value {
+                Value::String(ref s) => Ok(s.clone()),
+                _ => Err(self.type_error(table, key, "a list of strings", value)),
+            })
+            .collect()
+    }
+
+    /// Parses a string value with the `FromStr` implementation of `T`.
+    fn parse<T>(&self, table: &str, key: &str, value: &Value) -> Result<T, Error>
+    where
+        T: FromStr<Err = String>,
+    {
+        self.string(table, key, value)?
+            .parse()
+            .map_err(|err: String| self.error(table, key, &format!("is invalid: {}", err)))
+    }
+
+    /// Reads a SHA-256 hash of 64 hexadecimal digits, which is returned in lowercase.
+    fn sha256(&self, table: &str, key: &str, value: &Value) -> Result<String, Error> {
+        let s = self.string(table, key, value)?;
+        if s.len() != 64 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(self.error(
+                table,
+                key,
+                &format!("must be 64 hexadecimal digits, found `{}`", s),
+            ));
+        }
+        Ok(s.to_ascii_lowercase())
+    }
+
+    /// Canonicalizes a path value, which must exist.
+    fn path(&self, table: &str, key: &str, value: &Value) -> Result<PathBuf, Error> {
+        let s = self.string(table, key, value)?;
+        Path::new(&s)
+            .canonicalize()
+            .map_err(|err| self.error(table, key, &format!("refers to `{}`: {}", s, err)))
+    }
+
+    /// Canonicalizes a list of path values, which must exist.
+    fn paths(&self, table: &str, key: &str, value: &Value) -> Result<Vec<PathBuf>, Error> {
+        self.strings(table, key, value)?
+            .iter()
+            .map(|s| {
+                Path::new(s)
+                    .canonicalize()
+                    .map_err(|err| self.error(table, key, &format!("refers to `{}`: {}", s, err)))
+            })
+            .collect()
+    }
 }
 
 #[derive(Default)]
 struct ConfigBuilder {
     manifest_path: Option<PathBuf>,
+    default_run: Option<String>,
     default_target: Option<String>,
     output: Option<PathBuf>,
+    format: Option<ImageFormat>,
     bootloader: Option<BootloaderConfigBuilder>,
     minimum_image_size: Option<u64>,
+    sector_size: Option<u64>,
+    kernel_alignment: Option<u64>,
+    image_alignment: Option<u64>,
+    pad_byte: Option<u8>,
+    strip_kernel: Option<bool>,
+    kernel_compression: Option<KernelCompression>,
+    max_kernel_size: Option<u64>,
+    max_image_size: Option<u64>,
+    emit_map: Option<bool>,
+    emit_checksums: Option<bool>,
+    embed_metadata: Option<bool>,
+    info_block_version: Option<u16>,
+    kernel_cmdline: Option<String>,
+    initrd: Option<PathBuf>,
+    multiboot_modules: Option<Vec<PathBuf>>,
+    limine: Option<LimineConfigBuilder>,
+    kernels: BTreeMap<String, MenuEntry>,
+    menu_timeout: Option<u64>,
+    syslinux_directory: Option<PathBuf>,
+    files: BTreeMap<String, PathBuf>,
+    data_filesystem: Option<Filesystem>,
+    data_directory: Option<PathBuf>,
+    mbr_partition_table: Option<bool>,
+    sparse: Option<bool>,
+    info_block_fields: BTreeMap<String, InfoBlockField>,
+    signing_key: Option<PathBuf>,
+    signing_command: Option<Vec<String>>,
     run_command: Option<Vec<String>>,
+    run_profiles: BTreeMap<String, RunProfile>,
+    run_backend: Option<RunBackend>,
+    virtualbox: Option<VmConfigBuilder>,
+    vmware: Option<VmConfigBuilder>,
+    hyperv: Option<VmConfigBuilder>,
+    microvm: Option<MicrovmConfigBuilder>,
+    bochs: Option<BochsConfigBuilder>,
+    firmware: Option<Firmware>,
+    ovmf: Option<OvmfConfigBuilder>,
+    tests: BTreeMap<String, TestConfig>,
+    pre_build: Option<Vec<String>>,
+    post_build: Option<Vec<String>>,
+    pre_run: Option<Vec<String>>,
+    test_success_exit_code: Option<i32>,
+    run_timeout: Option<Duration>,
+    test_prefix: Option<String>,
+    test_jobs: Option<usize>,
+    capture_serial: Option<bool>,
+    accel: Option<bool>,
+    deterministic: Option<bool>,
+    display: Option<Display>,
+    screenshots: Option<ScreenshotConfig>,
+    qemu: Option<QemuConfig>,
+    reproducible: Option<bool>,
+    allow_unverified_downloads: Option<bool>,
+    backtrace_marker: Option<String>,
+    serial_port: Option<u16>,
+    efi_loader: Option<PathBuf>,
+    export: Option<Vec<ExportFormat>>,
+    build_command: Option<BuildCommand>,
 }
 
 #[derive(Default)]
@@ -131,45 +2115,208 @@ struct BootloaderConfigBuilder {
     branch: Option<String>,
     git: Option<String>,
     path: Option<PathBuf>,
+    vendor: Option<PathBuf>,
+    features: Option<Vec<String>>,
+    url: Option<String>,
+    sha256: Option<String>,
+    linking: Option<Linking>,
+}
+
+#[derive(Default)]
+struct VmConfigBuilder {
+    vm_name: Option<String>,
+    memory: Option<u64>,
+    headless: Option<bool>,
+}
+
+#[derive(Default)]
+struct OvmfConfigBuilder {
+    code: Option<PathBuf>,
+    vars: Option<PathBuf>,
+    url: Option<String>,
+    code_sha256: Option<String>,
+    vars_sha256: Option<String>,
+}
+
+#[derive(Default)]
+struct MicrovmConfigBuilder {
+    memory: Option<u64>,
+    vcpus: Option<u32>,
+}
+
+#[derive(Default)]
+struct BochsConfigBuilder {
+    memory: Option<u64>,
+    magic_break: Option<bool>,
+    headless: Option<bool>,
 }
 
-impl Into<Config> for ConfigBuilder {
-    fn into(self) -> Config {
+#[derive(Default)]
+struct LimineConfigBuilder {
+    version: Option<String>,
+    url: Option<String>,
+    sha256: Option<String>,
+    protocol: Option<LimineProtocol>,
+}
+
+impl From<ConfigBuilder> for Config {
+    fn from(builder: ConfigBuilder) -> Self {
         let default_bootloader_config = BootloaderConfigBuilder {
             precompiled: Some(true),
             ..Default::default()
         };
+        let sector_size = builder.sector_size.unwrap_or(512);
         Config {
-            manifest_path: self.manifest_path.expect("manifest path must be set"),
-            default_target: self.default_target,
-            output: self.output.unwrap_or(PathBuf::from("bootimage.bin")),
-            bootloader: self.bootloader.unwrap_or(default_bootloader_config).into(),
-            minimum_image_size: self.minimum_image_size,
-            run_command: self.run_command.unwrap_or(vec![
-                "qemu-system-x86_64".into(),
-                "-drive".into(),
-                "format=raw,file={}".into(),
-            ]),
+            manifest_path: builder.manifest_path.expect("manifest path must be set"),
+            default_run: builder.default_run,
+            default_target: builder.default_target,
+            output: builder.output.unwrap_or(PathBuf::from("bootimage.bin")),
+            format: builder.format.unwrap_or(ImageFormat::Raw),
+            bootloader: builder
+                .bootloader
+                .unwrap_or(default_bootloader_config)
+                .into(),
+            minimum_image_size: builder.minimum_image_size,
+            sector_size,
+            kernel_alignment: builder.kernel_alignment.unwrap_or(512),
+            image_alignment: builder.image_alignment.unwrap_or(sector_size),
+            pad_byte: builder.pad_byte.unwrap_or(0),
+            strip_kernel: builder.strip_kernel.unwrap_or(false),
+            kernel_compression: builder.kernel_compression.unwrap_or(KernelCompression::None),
+            max_kernel_size: builder.max_kernel_size,
+            max_image_size: builder.max_image_size,
+            emit_map: builder.emit_map.unwrap_or(false),
+            emit_checksums: builder.emit_checksums.unwrap_or(false),
+            embed_metadata: builder.embed_metadata.unwrap_or(false),
+            info_block_version: builder.info_block_version.unwrap_or(1),
+            kernel_cmdline: builder.kernel_cmdline,
+            initrd: builder.initrd,
+            multiboot_modules: builder.multiboot_modules.unwrap_or_default(),
+            limine: builder.limine.unwrap_or_default().into(),
+            kernels: builder.kernels,
+            menu_timeout: builder.menu_timeout.unwrap_or(5),
+            syslinux_directory: builder.syslinux_directory,
+            files: builder.files,
+            data_filesystem: builder.data_filesystem.unwrap_or(Filesystem::Fat),
+            data_directory: builder.data_directory,
+            mbr_partition_table: builder.mbr_partition_table.unwrap_or(false),
+            sparse: builder.sparse.unwrap_or(true),
+            info_block_fields: builder.info_block_fields,
+            signing_key: builder.signing_key,
+            signing_command: builder.signing_command,
+            run_command: builder.run_command,
+            run_profiles: builder.run_profiles,
+            run_backend: builder.run_backend.unwrap_or(RunBackend::Qemu),
+            firmware: builder.firmware.unwrap_or(match builder.format {
+                Some(ImageFormat::Gpt) => Firmware::Uefi,
+                _ => Firmware::Bios,
+            }),
+            ovmf: builder.ovmf.unwrap_or_default().into(),
+            virtualbox: builder.virtualbox.unwrap_or_default().into(),
+            vmware: builder.vmware.unwrap_or_default().into(),
+            hyperv: builder.hyperv.unwrap_or_default().into(),
+            microvm: builder.microvm.unwrap_or_default().into(),
+            bochs: builder.bochs.unwrap_or_default().into(),
+            pre_build: builder.pre_build,
+            post_build: builder.post_build,
+            pre_run: builder.pre_run,
+            test_success_exit_code: builder.test_success_exit_code,
+            run_timeout: builder.run_timeout,
+            test_prefix: builder.test_prefix.unwrap_or("test-".into()),
+            test_jobs: builder.test_jobs,
+            tests: builder.tests,
+            capture_serial: builder.capture_serial.unwrap_or(false),
+            accel: builder.accel.unwrap_or(true),
+            deterministic: builder.deterministic.unwrap_or(false),
+            display: builder.display,
+            screenshots: builder.screenshots.unwrap_or_default(),
+            qemu: builder.qemu.unwrap_or_default(),
+            reproducible: builder.reproducible.unwrap_or(false),
+            allow_unverified_downloads: builder.allow_unverified_downloads.unwrap_or(false),
+            backtrace_marker: builder.backtrace_marker,
+            serial_port: builder.serial_port,
+            efi_loader: builder.efi_loader,
+            export: builder.export.unwrap_or_default(),
+            build_command: builder.build_command,
+        }
+    }
+}
+
+impl From<VmConfigBuilder> for VmConfig {
+    fn from(builder: VmConfigBuilder) -> Self {
+        VmConfig {
+            vm_name: builder.vm_name,
+            memory: builder.memory.unwrap_or(128 * 1024 * 1024),
+            headless: builder.headless.unwrap_or(false),
+        }
+    }
+}
+
+impl From<OvmfConfigBuilder> for OvmfConfig {
+    fn from(builder: OvmfConfigBuilder) -> Self {
+        OvmfConfig {
+            code: builder.code,
+            vars: builder.vars,
+            url: builder.url.unwrap_or_else(|| {
+                "https://retrage.github.io/edk2-nightly/bin/RELEASEX64_OVMF_{}.fd".into()
+            }),
+            code_sha256: builder.code_sha256,
+            vars_sha256: builder.vars_sha256,
+        }
+    }
+}
+
+impl From<MicrovmConfigBuilder> for MicrovmConfig {
+    fn from(builder: MicrovmConfigBuilder) -> Self {
+        MicrovmConfig {
+            memory: builder.memory.unwrap_or(128 * 1024 * 1024),
+            vcpus: builder.vcpus.unwrap_or(1),
+        }
+    }
+}
+
+impl From<BochsConfigBuilder> for BochsConfig {
+    fn from(builder: BochsConfigBuilder) -> Self {
+        BochsConfig {
+            memory: builder.memory.unwrap_or(128 * 1024 * 1024),
+            magic_break: builder.magic_break.unwrap_or(false),
+            headless: builder.headless.unwrap_or(false),
+        }
+    }
+}
+
+impl From<LimineConfigBuilder> for LimineConfig {
+    fn from(builder: LimineConfigBuilder) -> Self {
+        LimineConfig {
+            version: builder.version,
+            url: builder.url,
+            sha256: builder.sha256,
+            protocol: builder.protocol.unwrap_or(LimineProtocol::Limine),
         }
     }
 }
 
-impl Into<BootloaderConfig> for BootloaderConfigBuilder {
-    fn into(self) -> BootloaderConfig {
-        let precompiled = self.precompiled.unwrap_or(false);
+impl From<BootloaderConfigBuilder> for BootloaderConfig {
+    fn from(builder: BootloaderConfigBuilder) -> Self {
+        let precompiled = builder.precompiled.unwrap_or(false);
         let default_name = if precompiled {
             "bootloader_precompiled"
         } else {
             "bootloader"
         };
         BootloaderConfig {
-            name: self.name.unwrap_or(default_name.into()),
+            name: builder.name.unwrap_or(default_name.into()),
             precompiled,
-            target: self.target.unwrap_or("x86_64-bootloader".into()),
-            version: self.version,
-            git: self.git,
-            branch: self.branch,
-            path: self.path,
+            target: builder.target.unwrap_or("x86_64-bootloader".into()),
+            version: builder.version,
+            git: builder.git,
+            branch: builder.branch,
+            path: builder.path,
+            vendor: builder.vendor,
+            features: builder.features.unwrap_or_default(),
+            url: builder.url,
+            sha256: builder.sha256,
+            linking: builder.linking.unwrap_or(Linking::Append),
         }
     }
 }
@@ -0,0 +1,247 @@
+//! Exposes the serial console of the VM on a TCP port, to which `bootimage attach`
+//! (or any other TCP client, like `nc`) connects.
+//!
+//! bootimage reads the serial output of the run command (as with `capture-serial`)
+//! and sends it to every connected client, so that several people or tools can
+//! observe a long-running VM without the terminal that launched it, and the VM keeps
+//! running when they disconnect. The input of the clients is written to the serial
+//! input of the VM. The server listens on localhost for the whole invocation of
+//! bootimage (e.g. for all test kernels of `bootimage test`), and clients that
+//! connect late first receive the most recent output.
+//!
+//! `bootimage attach` reconnects until it is detached, so that it follows the VMs of
+//! consecutive invocations of bootimage.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, TcpListener, TcpStream};
+use std::path::Path;
+use std::process::ChildStdin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+use Error;
+
+/// The number of bytes of recent output that a client receives when it connects.
+const HISTORY_LEN: usize = 64 * 1024;
+/// The time after which a client that doesn't read its output is disconnected, so
+/// that it can't stall the VM.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+/// The interval in which `bootimage attach` tries to reconnect.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The console of this invocation of bootimage, once it has been started.
+static CONSOLE: OnceLock<Console> = OnceLock::new();
+
+struct Console {
+    clients: Mutex<Vec<TcpStream>>,
+    history: Mutex<VecDeque<u8>>,
+    /// The serial input of the VM that currently runs.
+    input: Mutex<Option<ChildStdin>>,
+}
+
+/// Starts listening for clients on `port` of localhost, unless the console has
+/// already been started.
+pub(crate) fn start(port: u16) -> Result<(), Error> {
+    if CONSOLE.get().is_some() {
+        return Ok(());
+    }
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).map_err(|err| {
+        Error::Run(format!("failed to listen on serial console port {}: {}", port, err))
+    })?;
+    let console = CONSOLE.get_or_init(|| Console {
+        clients: Mutex::new(Vec::new()),
+        history: Mutex::new(VecDeque::new()),
+        input: Mutex::new(None),
+    });
+    println!(
+        "Serving the serial console on port {}, attach with `bootimage attach {}`",
+        port, port
+    );
+    thread::spawn(move || {
+        for client in listener.incoming().flatten() {
+            console.connect(client);
+        }
+    });
+    Ok(())
+}
+
+/// Whether the console has been started.
+pub(crate) fn is_running() -> bool {
+    CONSOLE.get().is_some()
+}
+
+/// Sends serial output to all clients.
+pub(crate) fn broadcast(output: &[u8]) {
+    let console = match CONSOLE.get() {
+        Some(console) => console,
+        None => return,
+    };
+    let mut history = console.history.lock().expect("console history poisoned");
+    history.extend(output);
+    let excess = history.len().saturating_sub(HISTORY_LEN);
+    history.drain(..excess);
+    // clients that have disconnected (or don't read) are dropped
+    let mut clients = console.clients.lock().expect("console clients poisoned");
+    clients.retain(|client| (&*client).write_all(output).is_ok());
+}
+
+/// Sets the serial input of the VM that currently runs, to which the input of the
+/// clients is written (`None` discards it).
+pub(crate) fn set_input(input: Option<ChildStdin>) {
+    if let Some(console) = CONSOLE.get() {
+        *console.input.lock().expect("console input poisoned") = input;
+    }
+}
+
+impl Console {
+    fn connect(&'static self, client: TcpStream) {
+        let _ = client.set_nodelay(true);
+        let _ = client.set_write_timeout(Some(WRITE_TIMEOUT));
+        let reader = match client.try_clone() {
+            Ok(reader) => reader,
+            Err(_) => return,
+        };
+        {
+            // the history is locked until the client is registered, so that it
+            // misses no output in between
+            let history = self.history.lock().expect("console history poisoned");
+            let (front, back) = history.as_slices();
+            if (&client).write_all(front).and((&client).write_all(back)).is_err() {
+                return;
+            }
+            self.clients.lock().expect("console clients poisoned").push(client);
+        }
+        thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            while let Some(len) = read_chunk(&reader, &mut buffer) {
+                let mut input = self.input.lock().expect("console input poisoned");
+                // the VM may have exited, which discards the input
+                if let Some(ref mut stdin) = *input {
+                    let _ = stdin.write_all(&buffer[..len]).and_then(|_| stdin.flush());
+                }
+            }
+        });
+    }
+}
+
+/// Reads the next chunk of a connection into `buffer`, returning its length, or
+/// `None` once the connection is closed.
+fn read_chunk(mut stream: &TcpStream, buffer: &mut [u8]) -> Option<usize> {
+    loop {
+        match stream.read(buffer) {
+            Ok(0) => return None,
+            Ok(len) => return Some(len),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => return None,
+        }
+    }
+}
+
+/// Connects to the serial console at `address` (e.g. `localhost:4555`) and echoes
+/// its output (and appends it to `log`) until the input ends, reconnecting whenever
+/// the console goes away.
+///
+/// The input is read line by line, so that it is edited with the line editing of
+/// the terminal, and every line is sent with a carriage return like the Enter key
+/// of a serial terminal.
+pub(crate) fn attach(address: &str, log: Option<&Path>) -> Result<(), Error> {
+    let mut log = match log {
+        Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+        None => None,
+    };
+    let (line_sender, lines) = mpsc::channel::<Vec<u8>>();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+        loop {
+            let mut line = Vec::new();
+            match stdin.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if line.ends_with(b"\n") {
+                        line.pop();
+                        line.push(b'\r');
+                    }
+                    if line_sender.send(line).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    let mut waiting = false;
+    loop {
+        let stream = match TcpStream::connect(address) {
+            Ok(stream) => stream,
+            Err(err) => {
+                if !waiting {
+                    println!("Waiting for the serial console on {} ({})", address, err);
+                    waiting = true;
+                }
+                // input without a console is discarded
+                if let Err(RecvTimeoutError::Disconnected) = lines.recv_timeout(RECONNECT_INTERVAL)
+                {
+                    return Ok(());
+                }
+                continue;
+            }
+        };
+        waiting = false;
+        eprintln!("Attached to the serial console on {} (detach with Ctrl-D)", address);
+        if !follow(&stream, &lines, log.as_mut())? {
+            return Ok(());
+        }
+        eprintln!("The serial console on {} was closed", address);
+    }
+}
+
+/// Echoes the output of a console connection and sends it the input lines, until
+/// the console closes the connection (`true`) or the input ends (`false`).
+fn follow(
+    stream: &TcpStream,
+    lines: &mpsc::Receiver<Vec<u8>>,
+    mut log: Option<&mut File>,
+) -> Result<bool, Error> {
+    let closed = AtomicBool::new(false);
+    thread::scope(|scope| {
+        let output = scope.spawn(|| {
+            let mut buffer = [0; 4096];
+            let stdout = io::stdout();
+            let mut result = Ok(());
+            while let Some(len) = read_chunk(stream, &mut buffer) {
+                let mut stdout = stdout.lock();
+                result = stdout.write_all(&buffer[..len]).and_then(|_| stdout.flush());
+                if let (Ok(()), Some(ref mut log)) = (&result, &mut log) {
+                    result = log.write_all(&buffer[..len]);
+                }
+                if result.is_err() {
+                    break;
+                }
+            }
+            closed.store(true, Ordering::SeqCst);
+            result
+        });
+        let mut reconnect = true;
+        while !closed.load(Ordering::SeqCst) {
+            match lines.recv_timeout(Duration::from_millis(100)) {
+                Ok(line) => {
+                    // a failed write means that the console closed the connection
+                    let _ = (&*stream).write_all(&line);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    reconnect = false;
+                    let _ = stream.shutdown(Shutdown::Both);
+                    break;
+                }
+            }
+        }
+        output.join().expect("console output thread panicked")?;
+        Ok(reconnect)
+    })
+}
@@ -0,0 +1,21 @@
+//! The CRC-32 checksum (IEEE 802.3 polynomial) used by GPT headers.
+
+const POLYNOMIAL: u32 = 0xedb8_8320;
+
+/// Calculates the CRC-32 checksum of `data`.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    update(0, data)
+}
+
+/// Continues the checksum calculation of `crc` with the bytes in `data`.
+pub(crate) fn update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (POLYNOMIAL & mask);
+        }
+    }
+    !crc
+}
@@ -0,0 +1,119 @@
+//! Attaches a debugger to the GDB server of QEMU for `bootimage debug`.
+//!
+//! The debuggers are backends of the `Debugger` trait, which know how to load
+//! the kernel symbols and connect to the GDB server. The `rust-` wrappers are
+//! preferred, since they load the pretty printers for Rust types.
+
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+use Error;
+
+/// The port of the GDB server that QEMU starts for `-s`.
+pub(crate) const GDB_PORT: u16 = 1234;
+
+/// The debuggers that `bootimage debug` can launch, selected with `--debugger`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebuggerKind {
+    Gdb,
+    Lldb,
+}
+
+impl FromStr for DebuggerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gdb" => Ok(DebuggerKind::Gdb),
+            "lldb" => Ok(DebuggerKind::Lldb),
+            other => Err(format!(
+                "unknown debugger `{}` (expected `gdb` or `lldb`)",
+                other
+            )),
+        }
+    }
+}
+
+impl DebuggerKind {
+    fn backend(self) -> &'static dyn Debugger {
+        match self {
+            DebuggerKind::Gdb => &Gdb,
+            DebuggerKind::Lldb => &Lldb,
+        }
+    }
+}
+
+/// A debugger that can connect to the GDB server of QEMU.
+trait Debugger {
+    /// The executables of the debugger, in order of preference.
+    fn executables(&self) -> &'static [&'static str];
+
+    /// Adds the arguments that load the symbols of `kernel_elf` and connect to the
+    /// GDB server on `port`.
+    fn args(&self, command: &mut Command, kernel_elf: &Path, port: u16);
+}
+
+struct Gdb;
+
+impl Debugger for Gdb {
+    fn executables(&self) -> &'static [&'static str] {
+        &["rust-gdb", "gdb"]
+    }
+
+    fn args(&self, command: &mut Command, kernel_elf: &Path, port: u16) {
+        command.arg(kernel_elf);
+        command.arg("-ex").arg(format!("target remote :{}", port));
+    }
+}
+
+struct Lldb;
+
+impl Debugger for Lldb {
+    fn executables(&self) -> &'static [&'static str] {
+        &["rust-lldb", "lldb"]
+    }
+
+    fn args(&self, command: &mut Command, kernel_elf: &Path, port: u16) {
+        command.arg(kernel_elf);
+        command.arg("-o").arg(format!("gdb-remote {}", port));
+    }
+}
+
+/// Launches a debugger with the symbols of `kernel_elf` that connects to QEMU.
+///
+/// On Unix, bootimage is replaced by the debugger, so that Ctrl-C is only handled by
+/// the debugger. Quitting the debugger kills the kernel, which exits QEMU.
+pub(crate) fn launch(kind: DebuggerKind, kernel_elf: &Path) -> Result<(), Error> {
+    let debugger = kind.backend();
+    for executable in debugger.executables() {
+        let mut command = Command::new(executable);
+        debugger.args(&mut command, kernel_elf, GDB_PORT);
+        match exec(&mut command) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+            result => return result.map_err(Error::from),
+        }
+    }
+    let names: Vec<_> = debugger
+        .executables()
+        .iter()
+        .map(|name| format!("`{}`", name))
+        .collect();
+    Err(Error::Run(format!(
+        "none of the debuggers {} was found, install one of them to use `bootimage debug`",
+        names.join(", ")
+    )))
+}
+
+#[cfg(unix)]
+fn exec(command: &mut Command) -> io::Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    // `exec` only returns if the debugger couldn't be started
+    Err(command.exec())
+}
+
+#[cfg(not(unix))]
+fn exec(command: &mut Command) -> io::Result<()> {
+    command.status().map(|_| ())
+}
@@ -0,0 +1,1142 @@
+//! Decodes the x86 instructions that kernels and bootloaders consist of.
+//!
+//! The general-purpose and system instructions (and the common SSE moves) are
+//! decoded in 16-bit, 32-bit, and 64-bit mode and printed in Intel syntax. Other
+//! instructions, e.g. x87 and AVX, are printed as `(x87)` or `(bad)`.
+
+use std::str::FromStr;
+
+/// The maximum length of an x86 instruction.
+const MAX_LENGTH: usize = 15;
+
+const REG8: [&str; 8] = ["al", "cl", "dl", "bl", "ah", "ch", "dh", "bh"];
+const REG8_REX: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b",
+    "r13b", "r14b", "r15b",
+];
+const REG16: [&str; 16] = [
+    "ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w", "r11w", "r12w", "r13w",
+    "r14w", "r15w",
+];
+const REG32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d",
+    "r13d", "r14d", "r15d",
+];
+const REG64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+const SEGMENTS: [&str; 6] = ["es", "cs", "ss", "ds", "fs", "gs"];
+const CONDITIONS: [&str; 16] = [
+    "o", "no", "b", "ae", "e", "ne", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g",
+];
+const ARITHMETIC: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const SHIFTS: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "sal", "sar"];
+
+/// The processor mode that determines the default operand and address sizes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Real mode, e.g. the first stage of a BIOS bootloader.
+    Bits16,
+    /// Protected mode.
+    Bits32,
+    /// Long mode.
+    Bits64,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "16" => Ok(Mode::Bits16),
+            "32" => Ok(Mode::Bits32),
+            "64" => Ok(Mode::Bits64),
+            other => Err(format!(
+                "unknown mode `{}` (expected `16`, `32`, or `64`)",
+                other
+            )),
+        }
+    }
+}
+
+/// A decoded instruction.
+pub(crate) struct Instruction {
+    pub length: usize,
+    /// The instruction in Intel syntax.
+    pub text: String,
+    /// The address that a branch or a RIP-relative operand refers to.
+    pub target: Option<u64>,
+}
+
+/// Decodes the instruction at the start of `code`, which is located at `address`.
+///
+/// Invalid, truncated, and unsupported instructions are one byte long.
+pub(crate) fn decode(code: &[u8], address: u64, mode: Mode) -> Instruction {
+    let mut decoder = Decoder {
+        code: &code[..code.len().min(MAX_LENGTH)],
+        pos: 0,
+        address,
+        mode,
+        operand_size: 0,
+        address_size: 0,
+        operand_prefix: false,
+        rex: 0,
+        segment: None,
+        rep: None,
+        lock: false,
+        opcode: 0,
+        modrm: None,
+        rip_relative: None,
+        target: None,
+    };
+    match decoder.instruction() {
+        Some(text) => {
+            let end = address.wrapping_add(decoder.pos as u64);
+            Instruction {
+                length: decoder.pos,
+                text,
+                target: decoder
+                    .rip_relative
+                    .map(|disp| end.wrapping_add(disp as u64))
+                    .or(decoder.target),
+            }
+        }
+        None => Instruction {
+            length: 1,
+            text: "(bad)".into(),
+            target: None,
+        },
+    }
+}
+
+/// The size of an operand.
+#[derive(Clone, Copy)]
+enum Size {
+    B,
+    W,
+    D,
+    Q,
+    /// The operand size (16, 32, or 64 bits).
+    V,
+    /// The operand size, but at most 32 bits (e.g. for immediates).
+    Z,
+    /// The stack operand size, which defaults to 64 bits in long mode.
+    S,
+    /// 64 bits with `REX.W`, otherwise 32 bits.
+    Y,
+    /// An XMM register.
+    X,
+}
+
+/// An operand in the notation of the Intel opcode tables.
+#[derive(Clone, Copy)]
+enum Op {
+    /// A register or memory operand (ModRM `rm`).
+    E(Size),
+    /// A general-purpose register (ModRM `reg`).
+    G(Size),
+    /// A general-purpose register (ModRM `rm`), regardless of the ModRM `mod`.
+    R(Size),
+    /// A memory operand without a size (ModRM `rm`).
+    M,
+    /// A general-purpose register encoded in the low bits of the opcode.
+    Embedded(Size),
+    /// An immediate of the given size, sign-extended to the operand size.
+    I(Size),
+    /// A sign-extended 8-bit immediate.
+    Ibs,
+    /// An immediate of the full operand size (up to 64 bits).
+    Iv,
+    /// A relative branch target.
+    J(Size),
+    /// A memory operand at an absolute address (`moffs`).
+    O(Size),
+    /// The accumulator (`al`, `ax`, `eax`, or `rax`).
+    Acc(Size),
+    /// A segment register (ModRM `reg`).
+    Sw,
+    /// A control register (ModRM `reg`).
+    Cr,
+    /// A debug register (ModRM `reg`).
+    Dr,
+    /// A far pointer (`segment:offset`).
+    Ap,
+    /// An XMM register (ModRM `reg`).
+    Xmm,
+    /// An XMM register or memory operand of the given size (ModRM `rm`).
+    XmmOrMem(Size),
+    Fixed(&'static str),
+}
+
+use self::Op::*;
+use self::Size::*;
+
+struct Decoder<'a> {
+    code: &'a [u8],
+    pos: usize,
+    address: u64,
+    mode: Mode,
+    operand_size: u8,
+    address_size: u8,
+    operand_prefix: bool,
+    rex: u8,
+    segment: Option<&'static str>,
+    /// The `repne` (`0xf2`) or `rep` (`0xf3`) prefix.
+    rep: Option<u8>,
+    lock: bool,
+    /// The last opcode byte, which encodes the register of `Z` operands.
+    opcode: u8,
+    modrm: Option<u8>,
+    rip_relative: Option<i64>,
+    target: Option<u64>,
+}
+
+impl<'a> Decoder<'a> {
+    fn instruction(&mut self) -> Option<String> {
+        let mut address_prefix = false;
+        loop {
+            match *self.code.get(self.pos)? {
+                0x66 => self.operand_prefix = true,
+                0x67 => address_prefix = true,
+                0x26 => self.segment = Some("es"),
+                0x2e => self.segment = Some("cs"),
+                0x36 => self.segment = Some("ss"),
+                0x3e => self.segment = Some("ds"),
+                0x64 => self.segment = Some("fs"),
+                0x65 => self.segment = Some("gs"),
+                0xf0 => self.lock = true,
+                prefix @ 0xf2 | prefix @ 0xf3 => self.rep = Some(prefix),
+                _ => break,
+            }
+            self.pos += 1;
+        }
+        let long = self.mode == Mode::Bits64;
+        if long && self.code.get(self.pos)? & 0xf0 == 0x40 {
+            self.rex = self.u8()?;
+        }
+        let (operand_size, address_size) = match self.mode {
+            Mode::Bits16 => (16, 16),
+            Mode::Bits32 => (32, 32),
+            Mode::Bits64 => (32, 64),
+        };
+        self.operand_size = if self.rex & 8 != 0 {
+            64
+        } else if self.operand_prefix {
+            48 - operand_size
+        } else {
+            operand_size
+        };
+        self.address_size = match (address_prefix, self.mode) {
+            (false, _) => address_size,
+            (true, Mode::Bits16) => 32,
+            (true, Mode::Bits32) => 16,
+            (true, Mode::Bits64) => 32,
+        };
+
+        let opcode = self.u8()?;
+        self.opcode = opcode;
+        let (mnemonic, operands) = match opcode {
+            0x00..=0x3f if opcode & 7 < 6 => {
+                let operands = match opcode & 7 {
+                    0 => vec![E(B), G(B)],
+                    1 => vec![E(V), G(V)],
+                    2 => vec![G(B), E(B)],
+                    3 => vec![G(V), E(V)],
+                    4 => vec![Acc(B), I(B)],
+                    _ => vec![Acc(V), I(Z)],
+                };
+                (ARITHMETIC[opcode as usize >> 3].to_owned(), operands)
+            }
+            0x06 | 0x0e | 0x16 | 0x1e if !long => {
+                ("push".into(), vec![Fixed(SEGMENTS[opcode as usize >> 3])])
+            }
+            0x07 | 0x17 | 0x1f if !long => {
+                ("pop".into(), vec![Fixed(SEGMENTS[opcode as usize >> 3])])
+            }
+            0x0f => self.two_byte()?,
+            0x27 if !long => ("daa".into(), vec![]),
+            0x2f if !long => ("das".into(), vec![]),
+            0x37 if !long => ("aaa".into(), vec![]),
+            0x3f if !long => ("aas".into(), vec![]),
+            0x40..=0x47 if !long => ("inc".into(), vec![Embedded(V)]),
+            0x48..=0x4f if !long => ("dec".into(), vec![Embedded(V)]),
+            0x50..=0x57 => ("push".into(), vec![Embedded(S)]),
+            0x58..=0x5f => ("pop".into(), vec![Embedded(S)]),
+            0x60 if !long => (self.sized("pusha", "pushad", "pushad"), vec![]),
+            0x61 if !long => (self.sized("popa", "popad", "popad"), vec![]),
+            0x62 if !long => ("bound".into(), vec![G(V), M]),
+            0x63 if long => ("movsxd".into(), vec![G(V), E(D)]),
+            0x63 => ("arpl".into(), vec![E(W), G(W)]),
+            0x68 => ("push".into(), vec![I(S)]),
+            0x69 => ("imul".into(), vec![G(V), E(V), I(Z)]),
+            0x6a => {
+                // the immediate is sign-extended to the size of a stack slot
+                self.operand_size = self.stack_size();
+                ("push".into(), vec![Ibs])
+            }
+            0x6b => ("imul".into(), vec![G(V), E(V), Ibs]),
+            0x6c => (self.string("insb"), vec![]),
+            0x6d => (self.string(&self.sized("insw", "insd", "insd")), vec![]),
+            0x6e => (self.string("outsb"), vec![]),
+            0x6f => (self.string(&self.sized("outsw", "outsd", "outsd")), vec![]),
+            0x70..=0x7f => (format!("j{}", CONDITIONS[opcode as usize & 15]), vec![J(B)]),
+            0x80 | 0x81 | 0x83 => {
+                let operands = match opcode {
+                    0x80 => vec![E(B), I(B)],
+                    0x81 => vec![E(V), I(Z)],
+                    _ => vec![E(V), Ibs],
+                };
+                (ARITHMETIC[self.reg_field()? as usize].to_owned(), operands)
+            }
+            0x82 if !long => (
+                ARITHMETIC[self.reg_field()? as usize].to_owned(),
+                vec![E(B), I(B)],
+            ),
+            0x84 => ("test".into(), vec![E(B), G(B)]),
+            0x85 => ("test".into(), vec![E(V), G(V)]),
+            0x86 => ("xchg".into(), vec![E(B), G(B)]),
+            0x87 => ("xchg".into(), vec![E(V), G(V)]),
+            0x88 => ("mov".into(), vec![E(B), G(B)]),
+            0x89 => ("mov".into(), vec![E(V), G(V)]),
+            0x8a => ("mov".into(), vec![G(B), E(B)]),
+            0x8b => ("mov".into(), vec![G(V), E(V)]),
+            0x8c => ("mov".into(), vec![E(W), Sw]),
+            0x8d => ("lea".into(), vec![G(V), M]),
+            0x8e => ("mov".into(), vec![Sw, E(W)]),
+            0x8f if self.reg_field()? == 0 => ("pop".into(), vec![E(S)]),
+            0x90 if self.rep == Some(0xf3) => ("pause".into(), vec![]),
+            0x90 if self.rex & 1 == 0 => ("nop".into(), vec![]),
+            0x90..=0x97 => ("xchg".into(), vec![Embedded(V), Acc(V)]),
+            0x98 => (self.sized("cbw", "cwde", "cdqe"), vec![]),
+            0x99 => (self.sized("cwd", "cdq", "cqo"), vec![]),
+            0x9a if !long => ("call far".into(), vec![Ap]),
+            0x9b => ("fwait".into(), vec![]),
+            0x9c => (self.stack_sized("pushf", "pushfd", "pushfq"), vec![]),
+            0x9d => (self.stack_sized("popf", "popfd", "popfq"), vec![]),
+            0x9e => ("sahf".into(), vec![]),
+            0x9f => ("lahf".into(), vec![]),
+            0xa0 => ("mov".into(), vec![Acc(B), O(B)]),
+            0xa1 => ("mov".into(), vec![Acc(V), O(V)]),
+            0xa2 => ("mov".into(), vec![O(B), Acc(B)]),
+            0xa3 => ("mov".into(), vec![O(V), Acc(V)]),
+            0xa4 => (self.string("movsb"), vec![]),
+            0xa5 => (self.string(&self.sized("movsw", "movsd", "movsq")), vec![]),
+            0xa6 => (self.string("cmpsb"), vec![]),
+            0xa7 => (self.string(&self.sized("cmpsw", "cmpsd", "cmpsq")), vec![]),
+            0xa8 => ("test".into(), vec![Acc(B), I(B)]),
+            0xa9 => ("test".into(), vec![Acc(V), I(Z)]),
+            0xaa => (self.string("stosb"), vec![]),
+            0xab => (self.string(&self.sized("stosw", "stosd", "stosq")), vec![]),
+            0xac => (self.string("lodsb"), vec![]),
+            0xad => (self.string(&self.sized("lodsw", "lodsd", "lodsq")), vec![]),
+            0xae => (self.string("scasb"), vec![]),
+            0xaf => (self.string(&self.sized("scasw", "scasd", "scasq")), vec![]),
+            0xb0..=0xb7 => ("mov".into(), vec![Embedded(B), I(B)]),
+            0xb8..=0xbf => ("mov".into(), vec![Embedded(V), Iv]),
+            0xc0 => (
+                SHIFTS[self.reg_field()? as usize].to_owned(),
+                vec![E(B), I(B)],
+            ),
+            0xc1 => (
+                SHIFTS[self.reg_field()? as usize].to_owned(),
+                vec![E(V), I(B)],
+            ),
+            0xc2 => ("ret".into(), vec![I(W)]),
+            0xc3 => ("ret".into(), vec![]),
+            // with a register operand, these are VEX prefixes
+            0xc4 if !long && !self.register_form()? => ("les".into(), vec![G(V), M]),
+            0xc5 if !long && !self.register_form()? => ("lds".into(), vec![G(V), M]),
+            0xc6 if self.reg_field()? == 0 => ("mov".into(), vec![E(B), I(B)]),
+            0xc7 if self.reg_field()? == 0 => ("mov".into(), vec![E(V), I(Z)]),
+            0xc8 => ("enter".into(), vec![I(W), I(B)]),
+            0xc9 => ("leave".into(), vec![]),
+            0xca => ("retf".into(), vec![I(W)]),
+            0xcb => ("retf".into(), vec![]),
+            0xcc => ("int3".into(), vec![]),
+            0xcd => ("int".into(), vec![I(B)]),
+            0xce if !long => ("into".into(), vec![]),
+            0xcf => (self.sized("iret", "iretd", "iretq"), vec![]),
+            0xd0 => (
+                SHIFTS[self.reg_field()? as usize].to_owned(),
+                vec![E(B), Fixed("1")],
+            ),
+            0xd1 => (
+                SHIFTS[self.reg_field()? as usize].to_owned(),
+                vec![E(V), Fixed("1")],
+            ),
+            0xd2 => (
+                SHIFTS[self.reg_field()? as usize].to_owned(),
+                vec![E(B), Fixed("cl")],
+            ),
+            0xd3 => (
+                SHIFTS[self.reg_field()? as usize].to_owned(),
+                vec![E(V), Fixed("cl")],
+            ),
+            0xd4 if !long => ("aam".into(), vec![I(B)]),
+            0xd5 if !long => ("aad".into(), vec![I(B)]),
+            0xd7 => ("xlat".into(), vec![]),
+            0xd8..=0xdf => {
+                // the operands are only decoded to determine the instruction length
+                if !self.register_form()? {
+                    self.memory(None)?;
+                }
+                return Some("(x87)".into());
+            }
+            0xe0 => ("loopne".into(), vec![J(B)]),
+            0xe1 => ("loope".into(), vec![J(B)]),
+            0xe2 => ("loop".into(), vec![J(B)]),
+            0xe3 => {
+                let mnemonic = match self.address_size {
+                    16 => "jcxz",
+                    32 => "jecxz",
+                    _ => "jrcxz",
+                };
+                (mnemonic.into(), vec![J(B)])
+            }
+            0xe4 => ("in".into(), vec![Acc(B), I(B)]),
+            0xe5 => ("in".into(), vec![Acc(Z), I(B)]),
+            0xe6 => ("out".into(), vec![I(B), Acc(B)]),
+            0xe7 => ("out".into(), vec![I(B), Acc(Z)]),
+            0xe8 => ("call".into(), vec![J(Z)]),
+            0xe9 => ("jmp".into(), vec![J(Z)]),
+            0xea if !long => ("jmp far".into(), vec![Ap]),
+            0xeb => ("jmp".into(), vec![J(B)]),
+            0xec => ("in".into(), vec![Acc(B), Fixed("dx")]),
+            0xed => ("in".into(), vec![Acc(Z), Fixed("dx")]),
+            0xee => ("out".into(), vec![Fixed("dx"), Acc(B)]),
+            0xef => ("out".into(), vec![Fixed("dx"), Acc(Z)]),
+            0xf1 => ("int1".into(), vec![]),
+            0xf4 => ("hlt".into(), vec![]),
+            0xf5 => ("cmc".into(), vec![]),
+            0xf6 | 0xf7 => {
+                let size = if opcode == 0xf6 { B } else { V };
+                match self.reg_field()? {
+                    0 | 1 => (
+                        "test".into(),
+                        vec![E(size), I(if opcode == 0xf6 { B } else { Z })],
+                    ),
+                    reg => {
+                        let mnemonics = ["not", "neg", "mul", "imul", "div", "idiv"];
+                        (mnemonics[reg as usize - 2].into(), vec![E(size)])
+                    }
+                }
+            }
+            0xf8 => ("clc".into(), vec![]),
+            0xf9 => ("stc".into(), vec![]),
+            0xfa => ("cli".into(), vec![]),
+            0xfb => ("sti".into(), vec![]),
+            0xfc => ("cld".into(), vec![]),
+            0xfd => ("std".into(), vec![]),
+            0xfe => match self.reg_field()? {
+                0 => ("inc".into(), vec![E(B)]),
+                1 => ("dec".into(), vec![E(B)]),
+                _ => return None,
+            },
+            0xff => match self.reg_field()? {
+                0 => ("inc".into(), vec![E(V)]),
+                1 => ("dec".into(), vec![E(V)]),
+                2 => ("call".into(), vec![E(S)]),
+                3 => ("call far".into(), vec![M]),
+                4 => ("jmp".into(), vec![E(S)]),
+                5 => ("jmp far".into(), vec![M]),
+                6 => ("push".into(), vec![E(S)]),
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        let mut text = String::new();
+        if self.lock {
+            text.push_str("lock ");
+        }
+        text.push_str(&mnemonic);
+        for (index, &operand) in operands.iter().enumerate() {
+            text.push_str(if index == 0 { " " } else { ", " });
+            let operand = self.operand(operand)?;
+            text.push_str(&operand);
+        }
+        Some(text)
+    }
+
+    /// Decodes the instructions with the `0x0f` opcode prefix.
+    fn two_byte(&mut self) -> Option<(String, Vec<Op>)> {
+        let long = self.mode == Mode::Bits64;
+        let opcode = self.u8()?;
+        self.opcode = opcode;
+        let prefix = self.rep.or(if self.operand_prefix {
+            Some(0x66)
+        } else {
+            None
+        });
+        let instruction = match opcode {
+            0x00 => {
+                let mnemonics = ["sldt", "str", "lldt", "ltr", "verr", "verw"];
+                (
+                    mnemonics.get(self.reg_field()? as usize)?.to_string(),
+                    vec![E(W)],
+                )
+            }
+            0x01 if self.register_form()? => {
+                let mnemonic = match self.modrm()? {
+                    0xc1 => "vmcall",
+                    0xc2 => "vmlaunch",
+                    0xc3 => "vmresume",
+                    0xc4 => "vmxoff",
+                    0xc8 => "monitor",
+                    0xc9 => "mwait",
+                    0xca => "clac",
+                    0xcb => "stac",
+                    0xd0 => "xgetbv",
+                    0xd1 => "xsetbv",
+                    0xf8 if long => "swapgs",
+                    0xf9 => "rdtscp",
+                    _ => match self.reg_field()? {
+                        4 => return Some(("smsw".into(), vec![E(V)])),
+                        6 => return Some(("lmsw".into(), vec![E(W)])),
+                        _ => return None,
+                    },
+                };
+                (mnemonic.into(), vec![])
+            }
+            0x01 => match self.reg_field()? {
+                0 => ("sgdt".into(), vec![M]),
+                1 => ("sidt".into(), vec![M]),
+                2 => ("lgdt".into(), vec![M]),
+                3 => ("lidt".into(), vec![M]),
+                4 => ("smsw".into(), vec![E(W)]),
+                6 => ("lmsw".into(), vec![E(W)]),
+                7 => ("invlpg".into(), vec![M]),
+                _ => return None,
+            },
+            0x02 => ("lar".into(), vec![G(V), E(W)]),
+            0x03 => ("lsl".into(), vec![G(V), E(W)]),
+            0x05 => ("syscall".into(), vec![]),
+            0x06 => ("clts".into(), vec![]),
+            0x07 if self.rex & 8 != 0 => ("sysretq".into(), vec![]),
+            0x07 => ("sysret".into(), vec![]),
+            0x08 => ("invd".into(), vec![]),
+            0x09 => ("wbinvd".into(), vec![]),
+            0x0b => ("ud2".into(), vec![]),
+            0x0d if !self.register_form()? => {
+                let mnemonic = if self.reg_field()? == 1 {
+                    "prefetchw"
+                } else {
+                    "prefetch"
+                };
+                (mnemonic.into(), vec![M])
+            }
+            0x10 | 0x11 => {
+                let (mnemonic, size) = match prefix {
+                    None => ("movups", X),
+                    Some(0x66) => ("movupd", X),
+                    Some(0xf3) => ("movss", D),
+                    _ => ("movsd", Q),
+                };
+                let operands = if opcode == 0x10 {
+                    vec![Xmm, XmmOrMem(size)]
+                } else {
+                    vec![XmmOrMem(size), Xmm]
+                };
+                (mnemonic.into(), operands)
+            }
+            0x18 if !self.register_form()? && self.reg_field()? < 4 => {
+                let mnemonics = ["prefetchnta", "prefetcht0", "prefetcht1", "prefetcht2"];
+                (mnemonics[self.reg_field()? as usize].into(), vec![M])
+            }
+            0x1e if self.rep == Some(0xf3) && self.modrm()? == 0xfa => ("endbr64".into(), vec![]),
+            0x1e if self.rep == Some(0xf3) && self.modrm()? == 0xfb => ("endbr32".into(), vec![]),
+            0x18..=0x1f => ("nop".into(), vec![E(V)]),
+            0x20..=0x23 => {
+                // the general-purpose register always has the size of the mode
+                let size = if long { Q } else { D };
+                let operands = match opcode {
+                    0x20 => vec![R(size), Cr],
+                    0x21 => vec![R(size), Dr],
+                    0x22 => vec![Cr, R(size)],
+                    _ => vec![Dr, R(size)],
+                };
+                ("mov".into(), operands)
+            }
+            0x28 | 0x29 if prefix.is_none() || prefix == Some(0x66) => {
+                let mnemonic = if prefix.is_none() { "movaps" } else { "movapd" };
+                let operands = if opcode == 0x28 {
+                    vec![Xmm, XmmOrMem(X)]
+                } else {
+                    vec![XmmOrMem(X), Xmm]
+                };
+                (mnemonic.into(), operands)
+            }
+            0x30 => ("wrmsr".into(), vec![]),
+            0x31 => ("rdtsc".into(), vec![]),
+            0x32 => ("rdmsr".into(), vec![]),
+            0x33 => ("rdpmc".into(), vec![]),
+            0x34 => ("sysenter".into(), vec![]),
+            0x35 => ("sysexit".into(), vec![]),
+            0x40..=0x4f => (
+                format!("cmov{}", CONDITIONS[opcode as usize & 15]),
+                vec![G(V), E(V)],
+            ),
+            0x57 if prefix.is_none() => ("xorps".into(), vec![Xmm, XmmOrMem(X)]),
+            0x57 if prefix == Some(0x66) => ("xorpd".into(), vec![Xmm, XmmOrMem(X)]),
+            0x6e if prefix == Some(0x66) => (self.movd(), vec![Xmm, E(Y)]),
+            0x7e if prefix == Some(0x66) => (self.movd(), vec![E(Y), Xmm]),
+            0x7e if prefix == Some(0xf3) => ("movq".into(), vec![Xmm, XmmOrMem(Q)]),
+            0x6f | 0x7f if prefix == Some(0x66) || prefix == Some(0xf3) => {
+                let mnemonic = if prefix == Some(0x66) {
+                    "movdqa"
+                } else {
+                    "movdqu"
+                };
+                let operands = if opcode == 0x6f {
+                    vec![Xmm, XmmOrMem(X)]
+                } else {
+                    vec![XmmOrMem(X), Xmm]
+                };
+                (mnemonic.into(), operands)
+            }
+            0x80..=0x8f => (format!("j{}", CONDITIONS[opcode as usize & 15]), vec![J(Z)]),
+            0x90..=0x9f => (
+                format!("set{}", CONDITIONS[opcode as usize & 15]),
+                vec![E(B)],
+            ),
+            0xa0 => ("push".into(), vec![Fixed("fs")]),
+            0xa1 => ("pop".into(), vec![Fixed("fs")]),
+            0xa2 => ("cpuid".into(), vec![]),
+            0xa3 => ("bt".into(), vec![E(V), G(V)]),
+            0xa4 => ("shld".into(), vec![E(V), G(V), I(B)]),
+            0xa5 => ("shld".into(), vec![E(V), G(V), Fixed("cl")]),
+            0xa8 => ("push".into(), vec![Fixed("gs")]),
+            0xa9 => ("pop".into(), vec![Fixed("gs")]),
+            0xaa => ("rsm".into(), vec![]),
+            0xab => ("bts".into(), vec![E(V), G(V)]),
+            0xac => ("shrd".into(), vec![E(V), G(V), I(B)]),
+            0xad => ("shrd".into(), vec![E(V), G(V), Fixed("cl")]),
+            0xae if self.register_form()? => match (self.rep, self.modrm()?) {
+                (None, 0xe8..=0xef) => ("lfence".into(), vec![]),
+                (None, 0xf0..=0xf7) => ("mfence".into(), vec![]),
+                (None, 0xf8..=0xff) => ("sfence".into(), vec![]),
+                (Some(0xf3), _) if long && self.reg_field()? < 4 => {
+                    let mnemonics = ["rdfsbase", "rdgsbase", "wrfsbase", "wrgsbase"];
+                    (mnemonics[self.reg_field()? as usize].into(), vec![E(Y)])
+                }
+                _ => return None,
+            },
+            0xae => {
+                let mnemonics = [
+                    "fxsave", "fxrstor", "ldmxcsr", "stmxcsr", "xsave", "xrstor", "xsaveopt",
+                    "clflush",
+                ];
+                (mnemonics[self.reg_field()? as usize].into(), vec![M])
+            }
+            0xaf => ("imul".into(), vec![G(V), E(V)]),
+            0xb0 => ("cmpxchg".into(), vec![E(B), G(B)]),
+            0xb1 => ("cmpxchg".into(), vec![E(V), G(V)]),
+            0xb2 => ("lss".into(), vec![G(V), M]),
+            0xb3 => ("btr".into(), vec![E(V), G(V)]),
+            0xb4 => ("lfs".into(), vec![G(V), M]),
+            0xb5 => ("lgs".into(), vec![G(V), M]),
+            0xb6 => ("movzx".into(), vec![G(V), E(B)]),
+            0xb7 => ("movzx".into(), vec![G(V), E(W)]),
+            0xb8 if self.rep == Some(0xf3) => ("popcnt".into(), vec![G(V), E(V)]),
+            0xb9 => ("ud1".into(), vec![G(V), E(V)]),
+            0xba if self.reg_field()? >= 4 => {
+                let mnemonics = ["bt", "bts", "btr", "btc"];
+                (
+                    mnemonics[self.reg_field()? as usize - 4].into(),
+                    vec![E(V), I(B)],
+                )
+            }
+            0xbb => ("btc".into(), vec![E(V), G(V)]),
+            0xbc if self.rep == Some(0xf3) => ("tzcnt".into(), vec![G(V), E(V)]),
+            0xbc => ("bsf".into(), vec![G(V), E(V)]),
+            0xbd if self.rep == Some(0xf3) => ("lzcnt".into(), vec![G(V), E(V)]),
+            0xbd => ("bsr".into(), vec![G(V), E(V)]),
+            0xbe => ("movsx".into(), vec![G(V), E(B)]),
+            0xbf => ("movsx".into(), vec![G(V), E(W)]),
+            0xc0 => ("xadd".into(), vec![E(B), G(B)]),
+            0xc1 => ("xadd".into(), vec![E(V), G(V)]),
+            0xc7 => match (self.register_form()?, self.reg_field()?) {
+                (false, 1) if self.rex & 8 != 0 => ("cmpxchg16b".into(), vec![M]),
+                (false, 1) => ("cmpxchg8b".into(), vec![M]),
+                (true, 6) => ("rdrand".into(), vec![E(V)]),
+                (true, 7) => ("rdseed".into(), vec![E(V)]),
+                _ => return None,
+            },
+            0xc8..=0xcf => ("bswap".into(), vec![Embedded(V)]),
+            0xef if prefix == Some(0x66) => ("pxor".into(), vec![Xmm, XmmOrMem(X)]),
+            0xff => ("ud0".into(), vec![G(V), E(V)]),
+            _ => return None,
+        };
+        Some(instruction)
+    }
+
+    fn operand(&mut self, operand: Op) -> Option<String> {
+        let text = match operand {
+            E(size) | XmmOrMem(size) => {
+                let bits = self.bits(size);
+                if self.register_form()? {
+                    let rm = self.rm()?;
+                    match operand {
+                        XmmOrMem(_) => format!("xmm{}", rm),
+                        _ => self.register(bits, rm)?,
+                    }
+                } else {
+                    self.memory(Some(bits))?
+                }
+            }
+            G(size) => {
+                let reg = self.reg()?;
+                self.register(self.bits(size), reg)?
+            }
+            R(size) => {
+                let rm = self.rm()?;
+                self.register(self.bits(size), rm)?
+            }
+            M => {
+                if self.register_form()? {
+                    return None;
+                }
+                self.memory(None)?
+            }
+            Embedded(size) => {
+                let reg = (self.opcode & 7) | ((self.rex & 1) << 3);
+                self.register(self.bits(size), reg)?
+            }
+            I(size) => {
+                let value = match self.bits(size) {
+                    8 => i64::from(self.u8()? as i8),
+                    16 => i64::from(self.u16()? as i16),
+                    _ => i64::from(self.u32()? as i32),
+                };
+                // `Iz` immediates are sign-extended to 64-bit operands
+                let bits = match size {
+                    Z | S => self.bits(V).max(self.bits(size)),
+                    _ => self.bits(size),
+                };
+                self.immediate(value, bits)
+            }
+            Ibs => {
+                let value = i64::from(self.u8()? as i8);
+                self.immediate(value, self.operand_size)
+            }
+            Iv => {
+                let value = match self.operand_size {
+                    16 => i64::from(self.u16()?),
+                    32 => i64::from(self.u32()?),
+                    _ => self.u64()? as i64,
+                };
+                format!("{:#x}", value as u64)
+            }
+            J(size) => {
+                let offset = match self.bits(size) {
+                    8 => i64::from(self.u8()? as i8),
+                    16 => i64::from(self.u16()? as i16),
+                    _ => i64::from(self.u32()? as i32),
+                };
+                let mut target = self
+                    .address
+                    .wrapping_add(self.pos as u64)
+                    .wrapping_add(offset as u64);
+                if self.operand_size == 16 {
+                    target &= 0xffff;
+                } else if self.mode != Mode::Bits64 {
+                    target &= 0xffff_ffff;
+                }
+                self.target = Some(target);
+                format!("{:#x}", target)
+            }
+            O(size) => {
+                let address = match self.address_size {
+                    16 => u64::from(self.u16()?),
+                    32 => u64::from(self.u32()?),
+                    _ => self.u64()?,
+                };
+                let segment = self.segment.map(|s| format!("{}:", s)).unwrap_or_default();
+                format!(
+                    "{} ptr {}[{:#x}]",
+                    pointer(self.bits(size)),
+                    segment,
+                    address
+                )
+            }
+            Acc(size) => self.register(self.bits(size), 0)?,
+            Sw => SEGMENTS.get(self.reg_field()? as usize)?.to_string(),
+            Cr => format!("cr{}", self.reg()?),
+            Dr => format!("dr{}", self.reg()?),
+            Ap => {
+                let offset = if self.operand_size == 16 {
+                    u32::from(self.u16()?)
+                } else {
+                    self.u32()?
+                };
+                format!("{:#x}:{:#x}", self.u16()?, offset)
+            }
+            Xmm => format!("xmm{}", self.reg()?),
+            Fixed(text) => text.into(),
+        };
+        Some(text)
+    }
+
+    /// Formats a memory operand, with `bits` as its size if the operand has one.
+    fn memory(&mut self, bits: Option<u8>) -> Option<String> {
+        let modrm = self.modrm()?;
+        let (md, rm) = (modrm >> 6, modrm & 7);
+        let mut parts = Vec::new();
+        let mut displacement = 0i64;
+        let mut absolute = false;
+        if self.address_size == 16 {
+            let bases = [
+                "bx + si", "bx + di", "bp + si", "bp + di", "si", "di", "bp", "bx",
+            ];
+            match md {
+                0 if rm == 6 => {
+                    displacement = i64::from(self.u16()?);
+                    absolute = true;
+                }
+                0 => {}
+                1 => displacement = i64::from(self.u8()? as i8),
+                _ => displacement = i64::from(self.u16()? as i16),
+            }
+            if !absolute {
+                parts.push(bases[rm as usize].to_owned());
+            }
+        } else {
+            let registers = if self.address_size == 64 {
+                &REG64
+            } else {
+                &REG32
+            };
+            let mut no_base = false;
+            let mut rip = false;
+            if rm == 4 {
+                let sib = self.u8()?;
+                let index = ((sib >> 3) & 7) | ((self.rex & 2) << 2);
+                let base = (sib & 7) | ((self.rex & 1) << 3);
+                if sib & 7 == 5 && md == 0 {
+                    no_base = true;
+                } else {
+                    parts.push(registers[base as usize].to_owned());
+                }
+                if index != 4 {
+                    let scale = 1 << (sib >> 6);
+                    parts.push(if scale == 1 {
+                        registers[index as usize].to_owned()
+                    } else {
+                        format!("{}*{}", scale, registers[index as usize])
+                    });
+                }
+            } else if rm == 5 && md == 0 {
+                if self.mode == Mode::Bits64 {
+                    parts.push(
+                        if self.address_size == 64 {
+                            "rip"
+                        } else {
+                            "eip"
+                        }
+                        .to_owned(),
+                    );
+                    rip = true;
+                } else {
+                    absolute = true;
+                }
+            } else {
+                parts.push(registers[(rm | ((self.rex & 1) << 3)) as usize].to_owned());
+            }
+            match md {
+                0 if no_base || rm == 5 => displacement = i64::from(self.u32()? as i32),
+                0 => {}
+                1 => displacement = i64::from(self.u8()? as i8),
+                _ => displacement = i64::from(self.u32()? as i32),
+            }
+            if rip {
+                self.rip_relative = Some(displacement);
+            }
+            if parts.is_empty() {
+                absolute = true;
+                displacement = i64::from(displacement as u32);
+            }
+        }
+
+        let mut text = String::new();
+        if let Some(bits) = bits {
+            text.push_str(pointer(bits));
+            text.push_str(" ptr ");
+        }
+        if let Some(segment) = self.segment {
+            text.push_str(segment);
+            text.push(':');
+        }
+        text.push('[');
+        if absolute {
+            text.push_str(&format!("{:#x}", displacement));
+        } else {
+            text.push_str(&parts.join(" + "));
+            if displacement > 0 {
+                text.push_str(&format!(" + {:#x}", displacement));
+            } else if displacement < 0 {
+                text.push_str(&format!(" - {:#x}", -displacement));
+            }
+        }
+        text.push(']');
+        Some(text)
+    }
+
+    /// Formats an immediate that is sign-extended to `bits`.
+    fn immediate(&self, value: i64, bits: u8) -> String {
+        let value = if bits >= 64 {
+            value as u64
+        } else {
+            value as u64 & ((1 << bits) - 1)
+        };
+        format!("{:#x}", value)
+    }
+
+    fn register(&self, bits: u8, number: u8) -> Option<String> {
+        let name = match bits {
+            8 if self.rex != 0 => REG8_REX.get(number as usize)?,
+            8 => REG8.get(number as usize)?,
+            16 => &REG16[number as usize],
+            32 => &REG32[number as usize],
+            64 => &REG64[number as usize],
+            _ => return Some(format!("xmm{}", number)),
+        };
+        Some(name.to_string())
+    }
+
+    fn bits(&self, size: Size) -> u8 {
+        match size {
+            B => 8,
+            W => 16,
+            D => 32,
+            Q => 64,
+            V => self.operand_size,
+            Z => self.operand_size.min(32),
+            S => self.stack_size(),
+            Y if self.rex & 8 != 0 => 64,
+            Y => 32,
+            X => 128,
+        }
+    }
+
+    fn stack_size(&self) -> u8 {
+        match (self.mode, self.operand_prefix) {
+            (Mode::Bits64, true) => 16,
+            (Mode::Bits64, false) => 64,
+            _ => self.operand_size,
+        }
+    }
+
+    /// Picks the mnemonic for the operand size.
+    fn sized(&self, bits16: &str, bits32: &str, bits64: &str) -> String {
+        match self.operand_size {
+            16 => bits16,
+            32 => bits32,
+            _ => bits64,
+        }
+        .into()
+    }
+
+    fn stack_sized(&self, bits16: &str, bits32: &str, bits64: &str) -> String {
+        match self.stack_size() {
+            16 => bits16,
+            32 => bits32,
+            _ => bits64,
+        }
+        .into()
+    }
+
+    /// Adds the repeat prefix to a string instruction.
+    fn string(&self, mnemonic: &str) -> String {
+        let compares = mnemonic.starts_with("cmps") || mnemonic.starts_with("scas");
+        match self.rep {
+            Some(0xf3) if compares => format!("repe {}", mnemonic),
+            Some(0xf3) => format!("rep {}", mnemonic),
+            Some(_) if compares => format!("repne {}", mnemonic),
+            _ => mnemonic.into(),
+        }
+    }
+
+    fn movd(&self) -> String {
+        if self.rex & 8 != 0 { "movq" } else { "movd" }.into()
+    }
+
+    fn modrm(&mut self) -> Option<u8> {
+        if self.modrm.is_none() {
+            self.modrm = Some(self.u8()?);
+        }
+        self.modrm
+    }
+
+    /// The ModRM `reg` field without the REX extension, which selects the
+    /// instruction in opcode groups.
+    fn reg_field(&mut self) -> Option<u8> {
+        Some((self.modrm()? >> 3) & 7)
+    }
+
+    /// Whether the ModRM `rm` field is a register instead of a memory operand.
+    fn register_form(&mut self) -> Option<bool> {
+        Some(self.modrm()? >> 6 == 3)
+    }
+
+    fn reg(&mut self) -> Option<u8> {
+        Some(self.reg_field()? | ((self.rex & 4) << 1))
+    }
+
+    fn rm(&mut self) -> Option<u8> {
+        Some((self.modrm()? & 7) | ((self.rex & 1) << 3))
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        let byte = *self.code.get(self.pos)?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from(self.u8()?) | (u16::from(self.u8()?) << 8))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from(self.u16()?) | (u32::from(self.u16()?) << 16))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from(self.u32()?) | (u64::from(self.u32()?) << 32))
+    }
+}
+
+/// The size keyword of a memory operand with `bits` bits.
+fn pointer(bits: u8) -> &'static str {
+    match bits {
+        8 => "byte",
+        16 => "word",
+        32 => "dword",
+        64 => "qword",
+        _ => "xmmword",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rewrites the output of `decode` in the spelling of GNU objdump's Intel syntax, which
+    /// puts no spaces between the operands, capitalizes the size keywords, and writes the
+    /// scale after the index register.
+    fn gnu_syntax(text: &str) -> String {
+        let mut text = text.replace(", ", ",").replace(" + ", "+").replace(" - ", "-");
+        for size in &["byte", "word", "dword", "qword"] {
+            let gnu = format!("{} PTR ", size.to_uppercase());
+            text = text
+                .replace(&format!(" {} ptr ", size), &format!(" {}", gnu))
+                .replace(&format!(",{} ptr ", size), &format!(",{}", gnu));
+        }
+        text.split('+')
+            .map(|term| {
+                // the scaled index follows the `[` or a `+`
+                let (before, term) = term.split_at(term.find('[').map_or(0, |i| i + 1));
+                match term.find('*') {
+                    Some(star) if term.starts_with(|c: char| c.is_ascii_digit()) => {
+                        let end = term.find([']', '-']).unwrap_or(term.len());
+                        let (scale, index) = (&term[..star], &term[star + 1..end]);
+                        format!("{}{}*{}{}", before, index, scale, &term[end..])
+                    }
+                    _ => format!("{}{}", before, term),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("+")
+    }
+
+    /// Each instruction is located at 0x1000 and compared with the output of
+    /// `objdump -D -b binary -m <arch> -M intel --adjust-vma=0x1000`.
+    #[test]
+    fn objdump_known_answers() {
+        let cases: &[(Mode, &[u8], &str)] = &[
+            // ModRM, SIB, and REX prefixes
+            (Mode::Bits64, &[0x48, 0x89, 0xc8], "mov    rax,rcx"),
+            (
+                Mode::Bits64,
+                &[0x48, 0x8b, 0x44, 0x98, 0x10],
+                "mov    rax,QWORD PTR [rax+rbx*4+0x10]",
+            ),
+            (
+                Mode::Bits64,
+                &[0x42, 0x8b, 0x0c, 0x85, 0x00, 0x10, 0x00, 0x00],
+                "mov    ecx,DWORD PTR [r8*4+0x1000]",
+            ),
+            (
+                Mode::Bits64,
+                &[0x41, 0x80, 0x7c, 0x24, 0xff, 0x00],
+                "cmp    BYTE PTR [r12-0x1],0x0",
+            ),
+            (Mode::Bits64, &[0x41, 0xff, 0xe3], "jmp    r11"),
+            (Mode::Bits64, &[0x48, 0x63, 0xc7], "movsxd rax,edi"),
+            // operand size, segment, and lock prefixes
+            (Mode::Bits64, &[0x66, 0x89, 0x08], "mov    WORD PTR [rax],cx"),
+            (Mode::Bits64, &[0x64, 0x48, 0x8b, 0x00], "mov    rax,QWORD PTR fs:[rax]"),
+            (
+                Mode::Bits64,
+                &[0xf0, 0x48, 0x0f, 0xb1, 0x0a],
+                "lock cmpxchg QWORD PTR [rdx],rcx",
+            ),
+            // 64-bit immediates
+            (
+                Mode::Bits64,
+                &[0x48, 0xc7, 0x04, 0x24, 0xff, 0xff, 0xff, 0xff],
+                "mov    QWORD PTR [rsp],0xffffffffffffffff",
+            ),
+            (Mode::Bits64, &[0x48, 0x83, 0xec, 0x08], "sub    rsp,0x8"),
+            (
+                Mode::Bits64,
+                &[0x48, 0x69, 0xc1, 0xe8, 0x03, 0x00, 0x00],
+                "imul   rax,rcx,0x3e8",
+            ),
+            // relative branches
+            (Mode::Bits64, &[0xe8, 0xfb, 0xff, 0xff, 0xff], "call   0x1000"),
+            (Mode::Bits64, &[0x0f, 0x84, 0x00, 0x01, 0x00, 0x00], "je     0x1106"),
+            (Mode::Bits64, &[0xeb, 0xfe], "jmp    0x1000"),
+            // system instructions
+            (Mode::Bits64, &[0x0f, 0x01, 0x10], "lgdt   [rax]"),
+            (Mode::Bits64, &[0x0f, 0x22, 0xd8], "mov    cr3,rax"),
+            (Mode::Bits64, &[0x0f, 0x05], "syscall"),
+            (Mode::Bits64, &[0x0f, 0x28, 0xc1], "movaps xmm0,xmm1"),
+            // protected mode
+            (Mode::Bits32, &[0x8b, 0x44, 0x24, 0x04], "mov    eax,DWORD PTR [esp+0x4]"),
+            (Mode::Bits32, &[0x0f, 0x22, 0xc0], "mov    cr0,eax"),
+            // real mode, including the operand and address size prefixes
+            (Mode::Bits16, &[0xb8, 0x00, 0x7c], "mov    ax,0x7c00"),
+            (Mode::Bits16, &[0x8b, 0x46, 0xfc], "mov    ax,WORD PTR [bp-0x4]"),
+            (Mode::Bits16, &[0x66, 0x31, 0xc0], "xor    eax,eax"),
+            (
+                Mode::Bits16,
+                &[0x67, 0x8b, 0x04, 0x48],
+                "mov    ax,WORD PTR [eax+ecx*2]",
+            ),
+            (Mode::Bits16, &[0xe9, 0xfd, 0xff], "jmp    0x1000"),
+            (Mode::Bits16, &[0xcd, 0x13], "int    0x13"),
+        ];
+        for &(mode, code, objdump) in cases {
+            let instruction = decode(code, 0x1000, mode);
+            let expected = objdump.split_whitespace().collect::<Vec<_>>().join(" ");
+            assert_eq!(gnu_syntax(&instruction.text), expected, "{:02x?}", code);
+            assert_eq!(instruction.length, code.len(), "{:02x?}", code);
+        }
+    }
+
+    #[test]
+    fn rip_relative_target() {
+        // objdump: `lea    r8,[rip+0x10]        # 0x1017`
+        let instruction = decode(&[0x4c, 0x8d, 0x05, 0x10, 0x00, 0x00, 0x00], 0x1000, Mode::Bits64);
+        assert_eq!(instruction.text, "lea r8, [rip + 0x10]");
+        assert_eq!(instruction.target, Some(0x1017));
+    }
+
+    #[test]
+    fn truncated_instruction() {
+        let instruction = decode(&[0x48, 0x8b], 0x1000, Mode::Bits64);
+        assert_eq!(instruction.text, "(bad)");
+        assert_eq!(instruction.length, 1);
+    }
+}
@@ -0,0 +1,140 @@
+//! Writes images that cloud providers import as VM disks: a gzip-compressed tar
+//! archive of the raw disk for Google Compute Engine, and a stream-optimized VMDK
+//! image for the VM import of Amazon EC2.
+//!
+//! GCE only imports raw disks whose size is a multiple of 1 GiB, so the disk image
+//! is padded with zeros. Padding moves the end of the disk, so the backup GPT of
+//! GPT images is moved to the new end, where firmware looks for it, and the
+//! primary header and the protective MBR are updated to the new size.
+
+use super::{read_chunk, vmdk};
+use byteorder::{BigEndian, ByteOrder};
+use gpt;
+use gzip;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const GIB: u64 = 1 << 30;
+/// The size of the chunks that are compressed as separate gzip members, which
+/// keeps the memory usage independent of the size of the disk.
+const CHUNK_SIZE: usize = 4 << 20;
+const BLOCK_SIZE: u64 = 512;
+/// The size of the records that a tar archive consists of.
+const RECORD_SIZE: u64 = 20 * BLOCK_SIZE;
+/// The size of a VMDK grain, to which the capacity of the image is rounded.
+const GRAIN_SIZE: u64 = 64 * 1024;
+
+pub(crate) fn create_gce_image(raw_image: &Path, output_path: &Path) -> io::Result<()> {
+    let size = fs::metadata(raw_image)?.len().div_ceil(GIB).max(1) * GIB;
+    let mut image = PaddedImage::open(raw_image, size)?;
+    let mut output = File::create(output_path)?;
+
+    // gzip files can consist of several members, which are decompressed as one
+    output.write_all(&gzip::compress(&tar_header("disk.raw", size)))?;
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut zeros = None;
+    for _ in 0..size / CHUNK_SIZE as u64 {
+        if read_chunk(&mut image, &mut buffer)? {
+            output.write_all(&gzip::compress(&buffer))?;
+        } else {
+            // most of the padded disk is zeros, whose member is only compressed once
+            let zeros = zeros.get_or_insert_with(|| gzip::compress(&buffer));
+            output.write_all(zeros)?;
+        }
+    }
+    // the end of the archive is marked by two zero blocks, and tar pads it to
+    // complete records
+    let end = (BLOCK_SIZE + size + 2 * BLOCK_SIZE).div_ceil(RECORD_SIZE) * RECORD_SIZE;
+    let trailer = vec![0u8; (end - BLOCK_SIZE - size) as usize];
+    output.write_all(&gzip::compress(&trailer))?;
+    Ok(())
+}
+
+pub(crate) fn create_aws_image(raw_image: &Path, output_path: &Path) -> io::Result<()> {
+    let size = fs::metadata(raw_image)?.len().div_ceil(GRAIN_SIZE).max(1) * GRAIN_SIZE;
+    let mut image = PaddedImage::open(raw_image, size)?;
+    vmdk::create_stream_optimized_image(&mut image, size, output_path)
+}
+
+/// The header of a tar file in the GNU format, which GCE requires, without a
+/// timestamp to keep the archive reproducible.
+fn tar_header(name: &str, size: u64) -> [u8; BLOCK_SIZE as usize] {
+    let mut header = [0u8; BLOCK_SIZE as usize];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..108].copy_from_slice(b"0000644\0"); // mode
+    header[108..116].copy_from_slice(b"0000000\0"); // owner
+    header[116..124].copy_from_slice(b"0000000\0"); // group
+    if size < 1 << 33 {
+        header[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+    } else {
+        // larger sizes don't fit into the 11 octal digits, GNU tar stores them in binary
+        header[124] = 0x80;
+        BigEndian::write_u64(&mut header[128..136], size);
+    }
+    header[136..148].copy_from_slice(b"00000000000\0"); // modification time
+    header[156] = b'0'; // regular file
+    header[257..265].copy_from_slice(b"ustar  \0");
+    // the checksum is calculated with spaces in its own field
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    header
+}
+
+/// The raw disk image, padded with zeros to `size` bytes and with the partition
+/// tables of GPT images updated to the padded size.
+struct PaddedImage {
+    file: File,
+    position: u64,
+    size: u64,
+    /// The sectors that replace the content of the file, as offset and content.
+    patches: Vec<(u64, Vec<u8>)>,
+}
+
+impl PaddedImage {
+    fn open(raw_image: &Path, size: u64) -> io::Result<PaddedImage> {
+        let mut file = File::open(raw_image)?;
+        let patches = if size > file.metadata()?.len() {
+            gpt::resized_tables(&mut file, size)?
+        } else {
+            Vec::new()
+        };
+        file.seek(SeekFrom::Start(0))?;
+        Ok(PaddedImage {
+            file,
+            position: 0,
+            size,
+            patches,
+        })
+    }
+}
+
+impl Read for PaddedImage {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let len = (buffer.len() as u64).min(self.size - self.position) as usize;
+        let buffer = &mut buffer[..len];
+        let mut filled = 0;
+        while filled < len {
+            match self.file.read(&mut buffer[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        // the padding after the end of the file
+        for byte in &mut buffer[filled..] {
+            *byte = 0;
+        }
+        let (start, end) = (self.position, self.position + len as u64);
+        for (offset, content) in &self.patches {
+            let from = start.max(*offset);
+            let to = end.min(offset + content.len() as u64);
+            if from < to {
+                buffer[(from - start) as usize..(to - start) as usize]
+                    .copy_from_slice(&content[(from - offset) as usize..(to - offset) as usize]);
+            }
+        }
+        self.position = end;
+        Ok(len)
+    }
+}
@@ -0,0 +1,70 @@
+//! Converts raw disk images into the formats of desktop hypervisors and cloud
+//! providers.
+
+use config::ExportFormat;
+use std::io::{self, Read};
+use std::path::Path;
+
+mod cloud;
+mod vdi;
+mod vhd;
+mod vmdk;
+
+/// Converts the raw disk image at `raw_image` into `format` and writes it to `output`.
+pub(crate) fn export(raw_image: &Path, format: ExportFormat, output: &Path) -> io::Result<()> {
+    match format {
+        ExportFormat::Vdi => vdi::create_vdi_image(raw_image, output),
+        ExportFormat::Vmdk => vmdk::create_vmdk_image(raw_image, output),
+        ExportFormat::Vhd => vhd::create_vhd_image(raw_image, output),
+        ExportFormat::Gce => cloud::create_gce_image(raw_image, output),
+        ExportFormat::Aws => cloud::create_aws_image(raw_image, output),
+    }
+}
+
+/// Reads the next chunk into `buffer`, filling it with zeros at the end of the file.
+///
+/// Returns whether the chunk contains any non-zero bytes.
+fn read_chunk<R: Read>(raw: &mut R, buffer: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match raw.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    for byte in &mut buffer[filled..] {
+        *byte = 0;
+    }
+    Ok(buffer.iter().any(|&b| b != 0))
+}
+
+/// Calculates the CHS geometry (cylinders, heads, sectors per track) for a
+/// disk of `total_sectors` sectors, as specified for VHD images.
+fn chs_geometry(total_sectors: u64) -> (u16, u8, u8) {
+    let total_sectors = total_sectors.min(65535 * 16 * 255);
+    let (sectors_per_track, heads, cylinder_times_heads) = if total_sectors >= 65535 * 16 * 63 {
+        (255, 16, total_sectors / 255)
+    } else {
+        let mut sectors_per_track = 17;
+        let mut cylinder_times_heads = total_sectors / sectors_per_track;
+        let mut heads = cylinder_times_heads.div_ceil(1024).max(4);
+        if cylinder_times_heads >= heads * 1024 || heads > 16 {
+            sectors_per_track = 31;
+            heads = 16;
+            cylinder_times_heads = total_sectors / sectors_per_track;
+        }
+        if cylinder_times_heads >= heads * 1024 {
+            sectors_per_track = 63;
+            heads = 16;
+            cylinder_times_heads = total_sectors / sectors_per_track;
+        }
+        (sectors_per_track, heads, cylinder_times_heads)
+    };
+    (
+        (cylinder_times_heads / heads) as u16,
+        heads as u8,
+        sectors_per_track as u8,
+    )
+}
@@ -0,0 +1,76 @@
+//! Writes dynamically allocated VDI images as used by VirtualBox.
+//!
+//! Blocks of the raw image that contain only zeros are not allocated.
+
+use super::{chs_geometry, read_chunk};
+use byteorder::{ByteOrder, LittleEndian};
+use crc32;
+use gpt;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const SECTOR_SIZE: u64 = 512;
+const BLOCK_SIZE: u64 = 1 << 20;
+const SIGNATURE: u32 = 0xbeda_107f;
+const VERSION: u32 = 0x0001_0001;
+const HEADER_SIZE: u32 = 0x190;
+const IMAGE_TYPE_DYNAMIC: u32 = 1;
+/// The offset of the block map, directly after the header.
+const BLOCKS_OFFSET: u64 = 0x200;
+const BLOCK_FREE: u32 = 0xffff_ffff;
+
+pub(crate) fn create_vdi_image(raw_image: &Path, output: &Path) -> io::Result<()> {
+    let mut raw = File::open(raw_image)?;
+    let disk_size = raw.metadata()?.len();
+    let block_count = disk_size.div_ceil(BLOCK_SIZE);
+    let data_offset = (BLOCKS_OFFSET + block_count * 4).div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+
+    let mut output = File::create(output)?;
+    output.seek(SeekFrom::Start(data_offset))?;
+
+    let mut block_map = vec![0u8; block_count as usize * 4];
+    let mut buffer = vec![0u8; BLOCK_SIZE as usize];
+    let mut allocated = 0;
+    let mut checksum = 0;
+    for (block, entry) in block_map.chunks_mut(4).enumerate() {
+        if read_chunk(&mut raw, &mut buffer)? {
+            LittleEndian::write_u32(entry, allocated);
+            allocated += 1;
+            output.write_all(&buffer)?;
+            checksum = crc32::update(checksum, &buffer);
+        } else {
+            LittleEndian::write_u32(entry, BLOCK_FREE);
+        }
+        checksum = crc32::update(checksum, &(block as u64).to_le_bytes());
+    }
+
+    let mut header = [0u8; BLOCKS_OFFSET as usize];
+    header[..40].copy_from_slice(b"<<< Oracle VM VirtualBox Disk Image >>>\n");
+    LittleEndian::write_u32(&mut header[0x40..0x44], SIGNATURE);
+    LittleEndian::write_u32(&mut header[0x44..0x48], VERSION);
+    LittleEndian::write_u32(&mut header[0x48..0x4c], HEADER_SIZE);
+    LittleEndian::write_u32(&mut header[0x4c..0x50], IMAGE_TYPE_DYNAMIC);
+    LittleEndian::write_u32(&mut header[0x154..0x158], BLOCKS_OFFSET as u32);
+    LittleEndian::write_u32(&mut header[0x158..0x15c], data_offset as u32);
+    LittleEndian::write_u32(&mut header[0x168..0x16c], SECTOR_SIZE as u32); // legacy geometry
+    LittleEndian::write_u64(&mut header[0x170..0x178], disk_size);
+    LittleEndian::write_u32(&mut header[0x178..0x17c], BLOCK_SIZE as u32);
+    LittleEndian::write_u32(&mut header[0x180..0x184], block_count as u32);
+    LittleEndian::write_u32(&mut header[0x184..0x188], allocated);
+    let mut seed = [0u8; 4];
+    LittleEndian::write_u32(&mut seed, checksum);
+    header[0x188..0x198].copy_from_slice(&gpt::guid(&seed, 0)); // creation UUID
+    header[0x198..0x1a8].copy_from_slice(&gpt::guid(&seed, 1)); // modification UUID
+    let (cylinders, heads, sectors_per_track) = chs_geometry(disk_size / SECTOR_SIZE);
+    LittleEndian::write_u32(&mut header[0x1c8..0x1cc], u32::from(cylinders));
+    LittleEndian::write_u32(&mut header[0x1cc..0x1d0], u32::from(heads));
+    LittleEndian::write_u32(&mut header[0x1d0..0x1d4], u32::from(sectors_per_track));
+    LittleEndian::write_u32(&mut header[0x1d4..0x1d8], SECTOR_SIZE as u32);
+
+    output.seek(SeekFrom::Start(0))?;
+    output.write_all(&header)?;
+    output.write_all(&block_map)?;
+
+    Ok(())
+}
@@ -0,0 +1,61 @@
+//! Writes fixed-size VHD images as used by Hyper-V and Virtual PC.
+//!
+//! A fixed VHD is the raw disk image followed by a 512 byte footer.
+
+use super::{chs_geometry, read_chunk};
+use byteorder::{BigEndian, ByteOrder};
+use crc32;
+use gpt;
+use sparse;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+const SECTOR_SIZE: u64 = 512;
+const DISK_TYPE_FIXED: u32 = 2;
+
+pub(crate) fn create_vhd_image(raw_image: &Path, output: &Path) -> io::Result<()> {
+    let mut raw = File::open(raw_image)?;
+    let size = raw.metadata()?.len().div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+
+    let mut output = File::create(output)?;
+    let mut buffer = vec![0u8; 1 << 20];
+    let mut checksum = 0;
+    let mut remaining = size;
+    while remaining > 0 {
+        let chunk = &mut buffer[..remaining.min(1 << 20) as usize];
+        read_chunk(&mut raw, chunk)?;
+        checksum = crc32::update(checksum, chunk);
+        sparse::write(&mut output, chunk)?;
+        remaining -= chunk.len() as u64;
+    }
+    let mut seed = [0u8; 4];
+    BigEndian::write_u32(&mut seed, checksum);
+    output.write_all(&footer(size, &gpt::guid(&seed, 0)))?;
+
+    Ok(())
+}
+
+fn footer(size: u64, unique_id: &gpt::Guid) -> [u8; SECTOR_SIZE as usize] {
+    let mut footer = [0u8; SECTOR_SIZE as usize];
+    footer[0..8].copy_from_slice(b"conectix");
+    BigEndian::write_u32(&mut footer[8..12], 2); // features: reserved bit must be set
+    BigEndian::write_u32(&mut footer[12..16], 0x0001_0000); // file format version
+    BigEndian::write_u64(&mut footer[16..24], u64::MAX); // no dynamic header
+    // the timestamp (bytes 24..28) stays zero so that the output is reproducible
+    footer[28..32].copy_from_slice(b"bimg"); // creator application
+    BigEndian::write_u32(&mut footer[32..36], 0x0001_0000); // creator version
+    footer[36..40].copy_from_slice(b"Wi2k"); // creator host OS
+    BigEndian::write_u64(&mut footer[40..48], size); // original size
+    BigEndian::write_u64(&mut footer[48..56], size); // current size
+    let (cylinders, heads, sectors_per_track) = chs_geometry(size / SECTOR_SIZE);
+    BigEndian::write_u16(&mut footer[56..58], cylinders);
+    footer[58] = heads;
+    footer[59] = sectors_per_track;
+    BigEndian::write_u32(&mut footer[60..64], DISK_TYPE_FIXED);
+    footer[68..84].copy_from_slice(unique_id);
+
+    let sum = footer.iter().fold(0u32, |sum, &b| sum.wrapping_add(u32::from(b)));
+    BigEndian::write_u32(&mut footer[64..68], !sum);
+    footer
+}
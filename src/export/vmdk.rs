@@ -0,0 +1,223 @@
+//! Writes monolithic sparse VMDK images as used by VMware.
+//!
+//! The image consists of a sparse extent header, the embedded descriptor, the
+//! grain directory and grain tables, and the grains that contain data.
+//! Stream-optimized images, as imported by cloud providers, can be written in a
+//! single pass instead: their grains are compressed and preceded by markers, and
+//! the grain tables and the grain directory follow them, found through a footer.
+
+use super::{chs_geometry, read_chunk};
+use byteorder::{ByteOrder, LittleEndian};
+use crc32;
+use gzip;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const SECTOR_SIZE: u64 = 512;
+const MAGIC: u32 = 0x564d_444b; // "KDMV"
+/// The grain size in sectors (64 KiB).
+const GRAIN_SECTORS: u64 = 128;
+const GRAIN_TABLE_ENTRIES: u64 = 512;
+/// The number of sectors that a grain table occupies.
+const GRAIN_TABLE_SECTORS: u64 = GRAIN_TABLE_ENTRIES * 4 / SECTOR_SIZE;
+const DESCRIPTOR_OFFSET: u64 = 1;
+const DESCRIPTOR_SECTORS: u64 = 20;
+/// The grain directory offset in the header of a stream-optimized image, whose
+/// grain directory is only known when the footer is written.
+const GD_AT_END: u64 = u64::MAX;
+/// The types of the metadata markers of stream-optimized images.
+const MARKER_END_OF_STREAM: u32 = 0;
+const MARKER_GRAIN_TABLE: u32 = 1;
+const MARKER_GRAIN_DIRECTORY: u32 = 2;
+const MARKER_FOOTER: u32 = 3;
+
+pub(crate) fn create_vmdk_image(raw_image: &Path, output_path: &Path) -> io::Result<()> {
+    let mut raw = File::open(raw_image)?;
+    let capacity = raw
+        .metadata()?
+        .len()
+        .div_ceil(GRAIN_SECTORS * SECTOR_SIZE)
+        * GRAIN_SECTORS;
+    let grains = capacity / GRAIN_SECTORS;
+    let grain_tables = grains.div_ceil(GRAIN_TABLE_ENTRIES);
+
+    let gd_offset = DESCRIPTOR_OFFSET + DESCRIPTOR_SECTORS;
+    let gd_sectors = (grain_tables * 4).div_ceil(SECTOR_SIZE);
+    let gt_offset = gd_offset + gd_sectors;
+    let overhead = (gt_offset + grain_tables * GRAIN_TABLE_SECTORS).div_ceil(GRAIN_SECTORS)
+        * GRAIN_SECTORS;
+
+    let mut output = File::create(output_path)?;
+    output.seek(SeekFrom::Start(overhead * SECTOR_SIZE))?;
+
+    let mut grain_directory = vec![0u8; (gd_sectors * SECTOR_SIZE) as usize];
+    for (i, entry) in grain_directory
+        .chunks_mut(4)
+        .take(grain_tables as usize)
+        .enumerate()
+    {
+        LittleEndian::write_u32(entry, (gt_offset + i as u64 * GRAIN_TABLE_SECTORS) as u32);
+    }
+    let mut grain_table_data = vec![0u8; (grain_tables * GRAIN_TABLE_SECTORS * SECTOR_SIZE) as usize];
+    let mut buffer = vec![0u8; (GRAIN_SECTORS * SECTOR_SIZE) as usize];
+    let mut next_grain = overhead;
+    let mut content_id = 0;
+    for grain in 0..grains as usize {
+        if read_chunk(&mut raw, &mut buffer)? {
+            LittleEndian::write_u32(&mut grain_table_data[grain * 4..grain * 4 + 4], next_grain as u32);
+            next_grain += GRAIN_SECTORS;
+            output.write_all(&buffer)?;
+            content_id = crc32::update(content_id, &buffer);
+        }
+    }
+
+    let file_name = output_path
+        .file_name()
+        .expect("output must have a file name")
+        .to_string_lossy();
+    let descriptor = descriptor(capacity, content_id, &file_name, "monolithicSparse");
+    let mut descriptor_sectors = vec![0u8; (DESCRIPTOR_SECTORS * SECTOR_SIZE) as usize];
+    descriptor_sectors[..descriptor.len()].copy_from_slice(descriptor.as_bytes());
+
+    output.seek(SeekFrom::Start(0))?;
+    output.write_all(&header(capacity, gd_offset, overhead))?;
+    output.write_all(&descriptor_sectors)?;
+    output.write_all(&grain_directory)?;
+    output.write_all(&grain_table_data)?;
+
+    Ok(())
+}
+
+/// Writes the `size` bytes of `raw` as a stream-optimized VMDK image to `output_path`,
+/// skipping the grains that contain only zeros. `size` must be a multiple of the
+/// grain size.
+pub(crate) fn create_stream_optimized_image<R: Read>(
+    raw: &mut R,
+    size: u64,
+    output_path: &Path,
+) -> io::Result<()> {
+    let capacity = size / SECTOR_SIZE;
+    let grains = capacity / GRAIN_SECTORS;
+    let grain_tables = grains.div_ceil(GRAIN_TABLE_ENTRIES);
+    let gd_sectors = (grain_tables * 4).div_ceil(SECTOR_SIZE);
+    // the header and the descriptor are followed by the grains, which start at a
+    // grain boundary
+    let overhead = (DESCRIPTOR_OFFSET + DESCRIPTOR_SECTORS).div_ceil(GRAIN_SECTORS) * GRAIN_SECTORS;
+
+    let mut output = File::create(output_path)?;
+    output.seek(SeekFrom::Start(overhead * SECTOR_SIZE))?;
+    let mut sector = overhead;
+    let table_size = (GRAIN_TABLE_SECTORS * SECTOR_SIZE) as usize;
+    let mut grain_table_data = vec![0u8; grain_tables as usize * table_size];
+    let mut buffer = vec![0u8; (GRAIN_SECTORS * SECTOR_SIZE) as usize];
+    let mut content_id = 0;
+    for grain in 0..grains {
+        if !read_chunk(raw, &mut buffer)? {
+            continue;
+        }
+        content_id = crc32::update(content_id, &buffer);
+        let compressed = gzip::compress_zlib(&buffer);
+        // the grain marker is the LBA of the grain and the size of the compressed data
+        let mut grain_data = vec![0u8; 12];
+        LittleEndian::write_u64(&mut grain_data[0..8], grain * GRAIN_SECTORS);
+        LittleEndian::write_u32(&mut grain_data[8..12], compressed.len() as u32);
+        grain_data.extend_from_slice(&compressed);
+        let padded_size = (grain_data.len() as u64).div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+        grain_data.resize(padded_size as usize, 0);
+        output.write_all(&grain_data)?;
+        let entry = grain as usize * 4;
+        LittleEndian::write_u32(&mut grain_table_data[entry..entry + 4], sector as u32);
+        sector += grain_data.len() as u64 / SECTOR_SIZE;
+    }
+
+    let mut grain_directory = vec![0u8; (gd_sectors * SECTOR_SIZE) as usize];
+    for (i, grain_table) in grain_table_data.chunks(table_size).enumerate() {
+        output.write_all(&marker(GRAIN_TABLE_SECTORS, MARKER_GRAIN_TABLE))?;
+        sector += 1;
+        LittleEndian::write_u32(&mut grain_directory[i * 4..i * 4 + 4], sector as u32);
+        output.write_all(grain_table)?;
+        sector += GRAIN_TABLE_SECTORS;
+    }
+    output.write_all(&marker(gd_sectors, MARKER_GRAIN_DIRECTORY))?;
+    let gd_offset = sector + 1;
+    output.write_all(&grain_directory)?;
+    output.write_all(&marker(1, MARKER_FOOTER))?;
+    output.write_all(&stream_optimized_header(capacity, gd_offset, overhead))?;
+    output.write_all(&marker(0, MARKER_END_OF_STREAM))?;
+
+    let file_name = output_path
+        .file_name()
+        .expect("output must have a file name")
+        .to_string_lossy();
+    let descriptor = descriptor(capacity, content_id, &file_name, "streamOptimized");
+    let mut descriptor_sectors = vec![0u8; (DESCRIPTOR_SECTORS * SECTOR_SIZE) as usize];
+    descriptor_sectors[..descriptor.len()].copy_from_slice(descriptor.as_bytes());
+    output.seek(SeekFrom::Start(0))?;
+    output.write_all(&stream_optimized_header(capacity, GD_AT_END, overhead))?;
+    output.write_all(&descriptor_sectors)?;
+
+    Ok(())
+}
+
+/// A metadata marker of a stream-optimized image, which precedes `sectors` sectors
+/// of metadata of the given type.
+fn marker(sectors: u64, marker_type: u32) -> [u8; SECTOR_SIZE as usize] {
+    let mut marker = [0u8; SECTOR_SIZE as usize];
+    LittleEndian::write_u64(&mut marker[0..8], sectors);
+    LittleEndian::write_u32(&mut marker[12..16], marker_type);
+    marker
+}
+
+fn stream_optimized_header(
+    capacity: u64,
+    gd_offset: u64,
+    overhead: u64,
+) -> [u8; SECTOR_SIZE as usize] {
+    let mut header = header(capacity, gd_offset, overhead);
+    LittleEndian::write_u32(&mut header[4..8], 3); // version
+    // flags: valid newline detection test, compressed grains, and markers
+    LittleEndian::write_u32(&mut header[8..12], 1 | 1 << 16 | 1 << 17);
+    LittleEndian::write_u16(&mut header[77..79], 1); // compression: DEFLATE
+    header
+}
+
+fn header(capacity: u64, gd_offset: u64, overhead: u64) -> [u8; SECTOR_SIZE as usize] {
+    let mut header = [0u8; SECTOR_SIZE as usize];
+    LittleEndian::write_u32(&mut header[0..4], MAGIC);
+    LittleEndian::write_u32(&mut header[4..8], 1); // version
+    LittleEndian::write_u32(&mut header[8..12], 1); // flags: valid newline detection test
+    LittleEndian::write_u64(&mut header[12..20], capacity);
+    LittleEndian::write_u64(&mut header[20..28], GRAIN_SECTORS);
+    LittleEndian::write_u64(&mut header[28..36], DESCRIPTOR_OFFSET);
+    LittleEndian::write_u64(&mut header[36..44], DESCRIPTOR_SECTORS);
+    LittleEndian::write_u32(&mut header[44..48], GRAIN_TABLE_ENTRIES as u32);
+    LittleEndian::write_u64(&mut header[56..64], gd_offset);
+    LittleEndian::write_u64(&mut header[64..72], overhead);
+    header[73..77].copy_from_slice(b"\n \r\n");
+    header
+}
+
+fn descriptor(capacity: u64, content_id: u32, file_name: &str, create_type: &str) -> String {
+    let (cylinders, heads, sectors_per_track) = chs_geometry(capacity);
+    format!(
+        "# Disk DescriptorFile\n\
+         version=1\n\
+         CID={:08x}\n\
+         parentCID=ffffffff\n\
+         createType=\"{}\"\n\
+         \n\
+         # Extent description\n\
+         RW {} SPARSE \"{}\"\n\
+         \n\
+         # The Disk Data Base\n\
+         #DDB\n\
+         \n\
+         ddb.virtualHWVersion = \"4\"\n\
+         ddb.geometry.cylinders = \"{}\"\n\
+         ddb.geometry.heads = \"{}\"\n\
+         ddb.geometry.sectors = \"{}\"\n\
+         ddb.adapterType = \"ide\"\n",
+        content_id, create_type, capacity, file_name, cylinders, heads, sectors_per_track
+    )
+}
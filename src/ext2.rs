@@ -0,0 +1,540 @@
+//! Writes ext2 file systems without relying on host tools.
+//!
+//! Like the FAT writer, all blocks are allocated in a single pass, so files are
+//! stored in consecutive blocks (apart from the metadata of the block groups).
+//! The file system uses 4 KiB blocks and revision 1 with 128-byte inodes and
+//! only the `sparse_super` and `filetype` features, which all ext2 drivers support.
+
+use byteorder::{ByteOrder, LittleEndian};
+use gpt;
+use image::round_up;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::{self, Seek, SeekFrom, Write};
+
+const BLOCK_SIZE: u64 = 4096;
+/// The number of blocks of a block group, which are covered by one bitmap block.
+const BLOCKS_PER_GROUP: u64 = 8 * BLOCK_SIZE;
+const INODE_SIZE: u64 = 128;
+const INODES_PER_BLOCK: u64 = BLOCK_SIZE / INODE_SIZE;
+/// One inode per 16 KiB of the file system, the default of `mke2fs`.
+const BYTES_PER_INODE: u64 = 16 * 1024;
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const GROUP_DESCRIPTOR_SIZE: u64 = 32;
+const ROOT_INODE: u32 = 2;
+/// The first non-reserved inode, which is used for `lost+found`.
+const FIRST_INODE: u32 = 11;
+const DIRECT_BLOCKS: usize = 12;
+const POINTERS_PER_BLOCK: u64 = BLOCK_SIZE / 4;
+/// Larger files would require the `large_file` feature.
+const MAX_FILE_SIZE: u64 = i32::MAX as u64;
+
+const FEATURE_INCOMPAT_FILETYPE: u32 = 0x0002;
+const FEATURE_RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+
+const MODE_DIRECTORY: u16 = 0o040_755;
+const MODE_FILE: u16 = 0o100_644;
+const TYPE_FILE: u8 = 1;
+const TYPE_DIRECTORY: u8 = 2;
+
+/// A tree of directories and files that should be written to a file system.
+#[derive(Default)]
+pub(crate) struct Directory {
+    entries: BTreeMap<String, Node>,
+}
+
+enum Node {
+    File(Vec<u8>),
+    Directory(Directory),
+}
+
+impl Directory {
+    /// Adds a file at the given `/`-separated path, creating parent directories.
+    pub fn add_file(&mut self, path: &str, data: Vec<u8>) -> Result<(), String> {
+        let mut components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        let file_name = components
+            .pop()
+            .ok_or_else(|| format!("invalid file path `{}`", path))?;
+        let dir = self.subdirectory(path, &components)?;
+        check_name(file_name)?;
+        if data.len() as u64 > MAX_FILE_SIZE {
+            return Err(format!("`{}` is larger than 2 GiB", path));
+        }
+        match dir.entries.insert(file_name.to_owned(), Node::File(data)) {
+            Some(_) => Err(format!("duplicate file `{}`", path)),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds an empty directory at the given `/`-separated path, creating parent
+    /// directories.
+    pub fn add_directory(&mut self, path: &str) -> Result<(), String> {
+        let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        self.subdirectory(path, &components).map(|_| ())
+    }
+
+    /// The directory at the path `components` (of `path`), which is created if needed.
+    fn subdirectory(&mut self, path: &str, components: &[&str]) -> Result<&mut Directory, String> {
+        let mut dir = self;
+        for &component in components {
+            check_name(component)?;
+            let node = dir
+                .entries
+                .entry(component.to_owned())
+                .or_insert_with(|| Node::Directory(Directory::default()));
+            dir = match *node {
+                Node::Directory(ref mut dir) => dir,
+                Node::File(_) => return Err(format!("`{}` in `{}` is a file", component, path)),
+            };
+        }
+        Ok(dir)
+    }
+
+    /// The size of all blocks of the directory and its contents (in bytes).
+    pub fn size(&self) -> u64 {
+        self.blocks(false) * BLOCK_SIZE
+    }
+
+    /// The number of blocks of the directory and its contents, including the
+    /// indirect blocks and `lost+found` for the root directory.
+    fn blocks(&self, is_root: bool) -> u64 {
+        let own = file_blocks(self.data_size(is_root) as u64) + if is_root { 1 } else { 0 };
+        self.entries.values().fold(own, |blocks, node| {
+            blocks
+                + match *node {
+                    Node::File(ref data) => file_blocks(data.len() as u64),
+                    Node::Directory(ref dir) => dir.blocks(false),
+                }
+        })
+    }
+
+    /// The number of inodes of the contents of the directory.
+    fn inode_count(&self) -> u64 {
+        self.entries.values().fold(0, |count, node| {
+            count
+                + match *node {
+                    Node::File(_) => 1,
+                    Node::Directory(ref dir) => 1 + dir.inode_count(),
+                }
+        })
+    }
+
+    fn subdirectory_count(&self) -> u16 {
+        self.entries
+            .values()
+            .filter(|node| match **node {
+                Node::Directory(_) => true,
+                Node::File(_) => false,
+            })
+            .count() as u16
+    }
+
+    /// The size of the directory entries (in bytes), a multiple of the block size.
+    fn data_size(&self, is_root: bool) -> usize {
+        let names = self.entries.keys().map(String::as_str);
+        let names = if is_root {
+            names.chain(Some("lost+found")).collect()
+        } else {
+            names.collect::<Vec<_>>()
+        };
+        let entries: Vec<_> = names.into_iter().map(|name| (0, 0, name)).collect();
+        directory_data(0, 0, &entries).len()
+    }
+}
+
+/// Writes an ext2 file system of `size` bytes containing `root` to `out` at `offset`.
+/// Only the used parts are written (like by `fat::write_fat`), so the region must
+/// already be zero.
+pub(crate) fn write_ext2<W: Write + Seek>(
+    out: &mut W,
+    offset: u64,
+    size: u64,
+    label: &str,
+    root: &Directory,
+) -> io::Result<()> {
+    let inodes = u64::from(FIRST_INODE) + root.inode_count();
+    let geometry = Geometry::new(size / BLOCK_SIZE, inodes)?;
+    let data_blocks: u64 = (0..geometry.groups)
+        .map(|group| geometry.group_blocks(group) - geometry.metadata_blocks(group))
+        .sum();
+    if root.blocks(true) > data_blocks {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "files don't fit into the ext2 file system",
+        ));
+    }
+
+    let mut layout = Layout {
+        geometry: &geometry,
+        next_block: 0,
+        next_inode: FIRST_INODE + 1,
+        inodes: BTreeMap::new(),
+        directories: vec![0; geometry.groups as usize],
+        chunks: Vec::new(),
+    };
+    layout.directory(root, ROOT_INODE, ROOT_INODE);
+
+    let used_blocks = |group: u64| {
+        let data_start = geometry.data_start(group);
+        let end = geometry.group_start(group) + geometry.group_blocks(group);
+        geometry.metadata_blocks(group) + layout.next_block.clamp(data_start, end) - data_start
+    };
+    let used_inodes = |group: u64| {
+        let first = group * geometry.inodes_per_group + 1;
+        (u64::from(layout.next_inode).saturating_sub(first)).min(geometry.inodes_per_group)
+    };
+    let mut descriptors = vec![0u8; (geometry.descriptor_blocks * BLOCK_SIZE) as usize];
+    for group in 0..geometry.groups {
+        let start = geometry.group_start(group) + geometry.superblock_blocks(group);
+        let descriptor = &mut descriptors[(group * GROUP_DESCRIPTOR_SIZE) as usize..];
+        LittleEndian::write_u32(&mut descriptor[0..4], start as u32); // block bitmap
+        LittleEndian::write_u32(&mut descriptor[4..8], start as u32 + 1); // inode bitmap
+        LittleEndian::write_u32(&mut descriptor[8..12], start as u32 + 2); // inode table
+        let free_blocks = geometry.group_blocks(group) - used_blocks(group);
+        LittleEndian::write_u16(&mut descriptor[12..14], free_blocks as u16);
+        let free_inodes = geometry.inodes_per_group - used_inodes(group);
+        LittleEndian::write_u16(&mut descriptor[14..16], free_inodes as u16);
+        LittleEndian::write_u16(&mut descriptor[16..18], layout.directories[group as usize]);
+
+        out.seek(SeekFrom::Start(offset + start * BLOCK_SIZE))?;
+        out.write_all(&bitmap(used_blocks(group), geometry.group_blocks(group)))?;
+        out.write_all(&bitmap(used_inodes(group), geometry.inodes_per_group))?;
+    }
+
+    let free_blocks: u64 = (0..geometry.groups)
+        .map(|group| geometry.group_blocks(group) - used_blocks(group))
+        .sum();
+    let free_inodes = geometry.groups * geometry.inodes_per_group - u64::from(layout.next_inode - 1);
+    for group in (0..geometry.groups).filter(|&group| geometry.has_superblock(group)) {
+        let superblock = superblock(
+            &geometry,
+            free_blocks as u32,
+            free_inodes as u32,
+            group as u16,
+            label,
+        );
+        let start = offset + geometry.group_start(group) * BLOCK_SIZE;
+        // the superblock of the first group follows the boot block
+        let superblock_offset = if group == 0 { SUPERBLOCK_OFFSET } else { 0 };
+        out.seek(SeekFrom::Start(start + superblock_offset))?;
+        out.write_all(&superblock)?;
+        out.seek(SeekFrom::Start(start + BLOCK_SIZE))?;
+        out.write_all(&descriptors)?;
+    }
+
+    for (number, inode) in &layout.inodes {
+        let index = u64::from(number - 1);
+        let group = index / geometry.inodes_per_group;
+        let table = geometry.group_start(group) + geometry.superblock_blocks(group) + 2;
+        let position = table * BLOCK_SIZE + (index % geometry.inodes_per_group) * INODE_SIZE;
+        out.seek(SeekFrom::Start(offset + position))?;
+        out.write_all(inode)?;
+    }
+
+    for (block, data) in layout.chunks {
+        out.seek(SeekFrom::Start(offset + block * BLOCK_SIZE))?;
+        out.write_all(&data)?;
+    }
+
+    Ok(())
+}
+
+/// The division of the file system into block groups.
+///
+/// Each group starts with a copy of the superblock and the group descriptors (if
+/// it has one, see `has_superblock`), followed by the block bitmap, the inode
+/// bitmap, the inode table, and the data blocks.
+struct Geometry {
+    blocks: u64,
+    groups: u64,
+    inodes_per_group: u64,
+    descriptor_blocks: u64,
+}
+
+impl Geometry {
+    /// The geometry of a file system with `blocks` blocks and at least `inodes` inodes.
+    fn new(blocks: u64, inodes: u64) -> io::Result<Geometry> {
+        let mut geometry = Geometry::with_blocks(blocks, inodes);
+        // like `mke2fs`, a last group that is too small for its metadata is left out
+        let last = geometry.groups - 1;
+        if last > 0 && geometry.group_blocks(last) <= geometry.metadata_blocks(last) {
+            geometry = Geometry::with_blocks(last * BLOCKS_PER_GROUP, inodes);
+        }
+        if geometry.inodes_per_group > 8 * BLOCK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "too many files for the ext2 file system",
+            ));
+        }
+        if geometry.blocks <= geometry.metadata_blocks(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the ext2 file system is too small",
+            ));
+        }
+        Ok(geometry)
+    }
+
+    fn with_blocks(blocks: u64, inodes: u64) -> Geometry {
+        let groups = cmp::max(blocks.div_ceil(BLOCKS_PER_GROUP), 1);
+        let inodes_per_group = cmp::max(
+            blocks * BLOCK_SIZE / BYTES_PER_INODE / groups,
+            inodes.div_ceil(groups),
+        );
+        Geometry {
+            blocks,
+            groups,
+            // the inode table fills whole blocks
+            inodes_per_group: round_up(inodes_per_group, INODES_PER_BLOCK),
+            descriptor_blocks: (groups * GROUP_DESCRIPTOR_SIZE).div_ceil(BLOCK_SIZE),
+        }
+    }
+
+    /// With `sparse_super`, only groups 0, 1, and powers of 3, 5, and 7 contain
+    /// a copy of the superblock.
+    fn has_superblock(&self, group: u64) -> bool {
+        let is_power_of = |base: u64| {
+            let mut n = base;
+            while n < group {
+                n *= base;
+            }
+            n == group
+        };
+        group <= 1 || is_power_of(3) || is_power_of(5) || is_power_of(7)
+    }
+
+    fn group_start(&self, group: u64) -> u64 {
+        group * BLOCKS_PER_GROUP
+    }
+
+    fn group_blocks(&self, group: u64) -> u64 {
+        cmp::min(BLOCKS_PER_GROUP, self.blocks - self.group_start(group))
+    }
+
+    /// The blocks of the superblock and the group descriptors at the start of `group`.
+    fn superblock_blocks(&self, group: u64) -> u64 {
+        if self.has_superblock(group) {
+            1 + self.descriptor_blocks
+        } else {
+            0
+        }
+    }
+
+    fn metadata_blocks(&self, group: u64) -> u64 {
+        self.superblock_blocks(group) + 2 + self.inodes_per_group / INODES_PER_BLOCK
+    }
+
+    fn data_start(&self, group: u64) -> u64 {
+        self.group_start(group) + self.metadata_blocks(group)
+    }
+}
+
+struct Layout<'a> {
+    geometry: &'a Geometry,
+    next_block: u64,
+    next_inode: u32,
+    inodes: BTreeMap<u32, [u8; INODE_SIZE as usize]>,
+    /// The number of directories in each group.
+    directories: Vec<u16>,
+    /// The data to write, keyed by the first block.
+    chunks: Vec<(u64, Vec<u8>)>,
+}
+
+impl<'a> Layout<'a> {
+    /// Allocates the next free block, skipping the metadata of the block groups.
+    fn allocate(&mut self) -> u64 {
+        let group = self.next_block / BLOCKS_PER_GROUP;
+        self.next_block = cmp::max(self.next_block, self.geometry.data_start(group));
+        self.next_block += 1;
+        self.next_block - 1
+    }
+
+    /// Lays out the directory `dir` with the inode `inode` and its contents.
+    fn directory(&mut self, dir: &Directory, inode: u32, parent: u32) {
+        let is_root = inode == ROOT_INODE;
+        let mut entries = Vec::new();
+        for (name, node) in &dir.entries {
+            let file_type = match *node {
+                Node::File(_) => TYPE_FILE,
+                Node::Directory(_) => TYPE_DIRECTORY,
+            };
+            entries.push((self.next_inode, file_type, name.as_str()));
+            self.next_inode += 1;
+        }
+        if is_root {
+            entries.push((FIRST_INODE, TYPE_DIRECTORY, "lost+found"));
+        }
+        let data = directory_data(inode, parent, &entries);
+        let links = 2 + dir.subdirectory_count() + if is_root { 1 } else { 0 };
+        self.inode(inode, MODE_DIRECTORY, links, data);
+        let group = (inode - 1) as usize / self.geometry.inodes_per_group as usize;
+        self.directories[group] += 1;
+
+        if is_root {
+            let data = directory_data(FIRST_INODE, ROOT_INODE, &[]);
+            self.inode(FIRST_INODE, MODE_DIRECTORY, 2, data);
+            let group = (FIRST_INODE - 1) as usize / self.geometry.inodes_per_group as usize;
+            self.directories[group] += 1;
+        }
+        for (node, &(number, _, _)) in dir.entries.values().zip(&entries) {
+            match *node {
+                Node::File(ref data) => self.inode(number, MODE_FILE, 1, data.clone()),
+                Node::Directory(ref subdir) => self.directory(subdir, number, inode),
+            }
+        }
+    }
+
+    /// Stores `data` in newly allocated blocks and adds the inode that refers to them.
+    fn inode(&mut self, number: u32, mode: u16, links: u16, data: Vec<u8>) {
+        let size = data.len() as u64;
+        let blocks: Vec<u64> = (0..size.div_ceil(BLOCK_SIZE))
+            .map(|_| self.allocate())
+            .collect();
+        for (&block, chunk) in blocks.iter().zip(data.chunks(BLOCK_SIZE as usize)) {
+            self.chunks.push((block, chunk.to_vec()));
+        }
+
+        let mut pointers = [0u32; DIRECT_BLOCKS + 3];
+        for (pointer, &block) in pointers.iter_mut().zip(blocks.iter().take(DIRECT_BLOCKS)) {
+            *pointer = block as u32;
+        }
+        let mut allocated = blocks.len() as u64;
+        let mut remaining = blocks.iter().skip(DIRECT_BLOCKS).map(|&block| block as u32);
+        if let Some(block) = self.indirect_block(&mut remaining) {
+            pointers[DIRECT_BLOCKS] = block;
+            allocated += 1;
+        }
+        // the double indirect block refers to further single indirect blocks
+        let mut singles = Vec::new();
+        while let Some(block) = self.indirect_block(&mut remaining) {
+            singles.push(block);
+        }
+        if let Some(block) = self.indirect_block(&mut singles.iter().cloned()) {
+            pointers[DIRECT_BLOCKS + 1] = block;
+            allocated += singles.len() as u64 + 1;
+        }
+
+        let mut inode = [0u8; INODE_SIZE as usize];
+        LittleEndian::write_u16(&mut inode[0..2], mode);
+        LittleEndian::write_u32(&mut inode[4..8], size as u32);
+        LittleEndian::write_u16(&mut inode[26..28], links);
+        // the number of 512-byte sectors, including the indirect blocks
+        LittleEndian::write_u32(&mut inode[28..32], (allocated * BLOCK_SIZE / 512) as u32);
+        for (i, &pointer) in pointers.iter().enumerate() {
+            LittleEndian::write_u32(&mut inode[40 + i * 4..44 + i * 4], pointer);
+        }
+        self.inodes.insert(number, inode);
+    }
+
+    /// Allocates an indirect block for the next block numbers of `blocks`, `None` if
+    /// there are no more.
+    fn indirect_block<I: Iterator<Item = u32>>(&mut self, blocks: &mut I) -> Option<u32> {
+        let pointers: Vec<u32> = blocks.take(POINTERS_PER_BLOCK as usize).collect();
+        if pointers.is_empty() {
+            return None;
+        }
+        let mut data = vec![0u8; BLOCK_SIZE as usize];
+        LittleEndian::write_u32_into(&pointers, &mut data[..pointers.len() * 4]);
+        let block = self.allocate();
+        self.chunks.push((block, data));
+        Some(block as u32)
+    }
+}
+
+/// The number of blocks of a file with `size` bytes, including the indirect blocks.
+fn file_blocks(size: u64) -> u64 {
+    let blocks = size.div_ceil(BLOCK_SIZE);
+    let indirect = blocks.saturating_sub(DIRECT_BLOCKS as u64);
+    let double_indirect = indirect.saturating_sub(POINTERS_PER_BLOCK);
+    let double_indirect_blocks = match double_indirect {
+        0 => 0,
+        blocks => 1 + blocks.div_ceil(POINTERS_PER_BLOCK),
+    };
+    blocks + if indirect > 0 { 1 } else { 0 } + double_indirect_blocks
+}
+
+/// Serializes the entries `(inode, type, name)` of a directory with the inode
+/// `inode` into whole blocks, preceded by the `.` and `..` entries.
+fn directory_data(inode: u32, parent: u32, entries: &[(u32, u8, &str)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    // the offset of the last entry, whose record length extends to the end of the block
+    let mut last = 0;
+    let all = [(inode, TYPE_DIRECTORY, "."), (parent, TYPE_DIRECTORY, "..")];
+    for &(inode, file_type, name) in all.iter().chain(entries) {
+        let length = round_up(8 + name.len() as u64, 4) as usize;
+        // entries must not span blocks
+        if data.len() % BLOCK_SIZE as usize + length > BLOCK_SIZE as usize {
+            let end = round_up(data.len() as u64, BLOCK_SIZE) as usize;
+            let record_length = (end - last) as u16;
+            LittleEndian::write_u16(&mut data[last + 4..last + 6], record_length);
+            data.resize(end, 0);
+        }
+        last = data.len();
+        data.resize(last + length, 0);
+        LittleEndian::write_u32(&mut data[last..last + 4], inode);
+        LittleEndian::write_u16(&mut data[last + 4..last + 6], length as u16);
+        data[last + 6] = name.len() as u8;
+        data[last + 7] = file_type;
+        data[last + 8..last + 8 + name.len()].copy_from_slice(name.as_bytes());
+    }
+    let end = round_up(data.len() as u64, BLOCK_SIZE) as usize;
+    LittleEndian::write_u16(&mut data[last + 4..last + 6], (end - last) as u16);
+    data.resize(end, 0);
+    data
+}
+
+/// A bitmap block with the first `used` of `count` bits set, and all bits behind
+/// `count`, which don't refer to anything.
+fn bitmap(used: u64, count: u64) -> Vec<u8> {
+    let mut bitmap = vec![0u8; BLOCK_SIZE as usize];
+    for bit in (0..used).chain(count..8 * BLOCK_SIZE) {
+        bitmap[(bit / 8) as usize] |= 1 << (bit % 8);
+    }
+    bitmap
+}
+
+fn superblock(
+    geometry: &Geometry,
+    free_blocks: u32,
+    free_inodes: u32,
+    group: u16,
+    label: &str,
+) -> [u8; 1024] {
+    let mut sb = [0u8; 1024];
+    let inodes = geometry.groups * geometry.inodes_per_group;
+    LittleEndian::write_u32(&mut sb[0..4], inodes as u32);
+    LittleEndian::write_u32(&mut sb[4..8], geometry.blocks as u32);
+    LittleEndian::write_u32(&mut sb[12..16], free_blocks);
+    LittleEndian::write_u32(&mut sb[16..20], free_inodes);
+    // the first data block is 0 for blocks larger than 1 KiB
+    LittleEndian::write_u32(&mut sb[24..28], 2); // log2(block size) - 10
+    LittleEndian::write_u32(&mut sb[28..32], 2); // log2(fragment size) - 10
+    LittleEndian::write_u32(&mut sb[32..36], BLOCKS_PER_GROUP as u32);
+    LittleEndian::write_u32(&mut sb[36..40], BLOCKS_PER_GROUP as u32);
+    LittleEndian::write_u32(&mut sb[40..44], geometry.inodes_per_group as u32);
+    // the timestamps are 0, which keeps the output reproducible
+    LittleEndian::write_u16(&mut sb[54..56], 0xffff); // no maximum mount count
+    LittleEndian::write_u16(&mut sb[56..58], 0xef53); // magic
+    LittleEndian::write_u16(&mut sb[58..60], 1); // state: cleanly unmounted
+    LittleEndian::write_u16(&mut sb[60..62], 1); // on errors: continue
+    LittleEndian::write_u32(&mut sb[76..80], 1); // revision
+    LittleEndian::write_u32(&mut sb[84..88], FIRST_INODE);
+    LittleEndian::write_u16(&mut sb[88..90], INODE_SIZE as u16);
+    LittleEndian::write_u16(&mut sb[90..92], group);
+    LittleEndian::write_u32(&mut sb[96..100], FEATURE_INCOMPAT_FILETYPE);
+    LittleEndian::write_u32(&mut sb[100..104], FEATURE_RO_COMPAT_SPARSE_SUPER);
+    sb[104..120].copy_from_slice(&gpt::guid(label.as_bytes(), 0));
+    let label = label.as_bytes();
+    let length = cmp::min(label.len(), 16);
+    sb[120..120 + length].copy_from_slice(&label[..length]);
+    sb
+}
+
+/// Checks that `name` can be used as a file name.
+fn check_name(name: &str) -> Result<(), String> {
+    if name == "." || name == ".." || name.len() > 255 || name.contains('\0') {
+        Err(format!("`{}` is not a valid file name", name))
+    } else {
+        Ok(())
+    }
+}
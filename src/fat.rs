@@ -0,0 +1,923 @@
+//! Writes FAT16 and FAT32 file systems without relying on host tools.
+//!
+//! The file system is laid out in a single pass: all directories and files are
+//! stored in consecutive clusters, so the FAT only consists of linear chains.
+//! Names that aren't valid 8.3 names are stored as long file names (VFAT) with
+//! a generated short alias like `LONGFI~1.TXT`.
+
+use byteorder::{ByteOrder, LittleEndian};
+use image::round_up;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// The size of the boot sector and the FS info sector, which fill the start of
+/// larger sectors.
+const BOOT_SECTOR_SIZE: usize = 512;
+const FAT32_RESERVED_SECTORS: u64 = 32;
+const FAT16_RESERVED_SECTORS: u64 = 1;
+const FAT_COUNT: u64 = 2;
+const FS_INFO_SECTOR: u16 = 1;
+const BACKUP_BOOT_SECTOR: u16 = 6;
+/// The first data cluster, which holds the root directory on FAT32.
+const ROOT_CLUSTER: u32 = 2;
+const DIR_ENTRY_SIZE: usize = 32;
+/// The minimum number of entries of the fixed root directory of FAT16.
+const FAT16_ROOT_ENTRIES: usize = 512;
+
+/// FAT32 requires at least this many data clusters, FAT16 less than that.
+const MIN_CLUSTERS: u64 = 65525;
+/// FAT16 requires at least this many data clusters.
+const MIN_FAT16_CLUSTERS: u64 = 4085;
+/// The largest cluster size that all FAT implementations support.
+const MAX_CLUSTER_SIZE: u64 = 32 * 1024;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// The attributes of a long file name entry (read-only, hidden, system, volume ID).
+const ATTR_LONG_NAME: u8 = 0x0f;
+/// The number of UTF-16 code units of a long file name in one directory entry.
+const LONG_NAME_CHARS: usize = 13;
+/// The offsets of the UTF-16 code units in a long file name entry.
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_LONG_NAME: usize = 255;
+
+const END_OF_CHAIN: u32 = 0x0fff_ffff;
+
+/// 1980-01-01, the FAT epoch. A fixed timestamp keeps the output reproducible.
+const FIXED_DATE: u16 = (1 << 5) | 1;
+
+/// The variant of a FAT file system, which is chosen by its size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FatType {
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// FAT32 for file systems of at least its minimum size, FAT16 for smaller ones.
+    pub fn for_size(size: u64, sector_size: u64) -> FatType {
+        if size >= FatType::Fat32.min_size(sector_size) {
+            FatType::Fat32
+        } else {
+            FatType::Fat16
+        }
+    }
+
+    /// The smallest size (in bytes) of a valid file system with clusters of a
+    /// single sector, e.g. about 33 MiB for FAT32 with 512-byte sectors.
+    pub fn min_size(self, sector_size: u64) -> u64 {
+        let (reserved_sectors, root_sectors, min_clusters) = match self {
+            FatType::Fat16 => (
+                FAT16_RESERVED_SECTORS,
+                (FAT16_ROOT_ENTRIES * DIR_ENTRY_SIZE) as u64 / sector_size,
+                MIN_FAT16_CLUSTERS,
+            ),
+            FatType::Fat32 => (FAT32_RESERVED_SECTORS, 0, MIN_CLUSTERS),
+        };
+        let fat_sectors = ((min_clusters + 2) * self.entry_size()).div_ceil(sector_size);
+        (reserved_sectors + FAT_COUNT * fat_sectors + root_sectors + min_clusters) * sector_size
+    }
+
+    /// The size of a FAT entry in bytes.
+    fn entry_size(self) -> u64 {
+        match self {
+            FatType::Fat16 => 2,
+            FatType::Fat32 => 4,
+        }
+    }
+
+    /// The type of a partition with this file system in an MBR partition table.
+    pub fn mbr_partition_type(self) -> u8 {
+        match self {
+            FatType::Fat16 => 0x0e, // FAT16 with LBA addressing
+            FatType::Fat32 => 0x0c, // FAT32 with LBA addressing
+        }
+    }
+}
+
+/// A tree of directories and files that should be written to a file system.
+#[derive(Default)]
+pub(crate) struct Directory {
+    /// The entries by their upper-case name, since names are case-insensitive.
+    entries: BTreeMap<String, Entry>,
+}
+
+struct Entry {
+    /// The name with its original case.
+    name: String,
+    node: Node,
+}
+
+enum Node {
+    File(Vec<u8>),
+    Directory(Directory),
+}
+
+impl Directory {
+    /// Adds a file at the given `/`-separated path, creating parent directories.
+    pub fn add_file(&mut self, path: &str, data: Vec<u8>) -> Result<(), String> {
+        let mut components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        let file_name = components
+            .pop()
+            .ok_or_else(|| format!("invalid file path `{}`", path))?;
+        let dir = self.subdirectory(path, &components)?;
+        check_name(file_name)?;
+        let entry = Entry {
+            name: file_name.to_owned(),
+            node: Node::File(data),
+        };
+        match dir.entries.insert(file_name.to_uppercase(), entry) {
+            Some(_) => Err(format!("duplicate file `{}`", path)),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds an empty directory at the given `/`-separated path, creating parent
+    /// directories.
+    pub fn add_directory(&mut self, path: &str) -> Result<(), String> {
+        let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        self.subdirectory(path, &components).map(|_| ())
+    }
+
+    /// The directory at the path `components` (of `path`), which is created if needed.
+    fn subdirectory(&mut self, path: &str, components: &[&str]) -> Result<&mut Directory, String> {
+        let mut dir = self;
+        for &component in components {
+            check_name(component)?;
+            let entry = dir
+                .entries
+                .entry(component.to_uppercase())
+                .or_insert_with(|| Entry {
+                    name: component.to_owned(),
+                    node: Node::Directory(Directory::default()),
+                });
+            dir = match entry.node {
+                Node::Directory(ref mut dir) => dir,
+                Node::File(_) => return Err(format!("`{}` in `{}` is a file", component, path)),
+            };
+        }
+        Ok(dir)
+    }
+
+    /// The number of bytes that the files and directories in this tree occupy.
+    pub fn size(&self, cluster_size: u64) -> u64 {
+        self.size_impl(cluster_size, true)
+    }
+
+    fn size_impl(&self, cluster_size: u64, is_root: bool) -> u64 {
+        let own = self.dir_entry_count(is_root) * DIR_ENTRY_SIZE;
+        let own = round_up(own as u64, cluster_size);
+        self.entries.values().fold(own, |size, entry| {
+            size + match entry.node {
+                Node::File(ref data) => round_up(data.len() as u64, cluster_size),
+                Node::Directory(ref dir) => dir.size_impl(cluster_size, false),
+            }
+        })
+    }
+
+    fn dir_entry_count(&self, is_root: bool) -> usize {
+        let entries: usize = self
+            .entries
+            .values()
+            .map(|entry| 1 + long_name_entry_count(&entry.name))
+            .sum();
+        // the root directory contains the volume label, all others `.` and `..`
+        entries + if is_root { 1 } else { 2 }
+    }
+
+    /// The short names of the entries in the order of `entries`.
+    ///
+    /// Valid 8.3 names are used as they are (in upper case), the other names get
+    /// a unique alias like `LONGFI~1.TXT`.
+    fn short_names(&self) -> Vec<[u8; 11]> {
+        let mut taken: BTreeSet<[u8; 11]> = self
+            .entries
+            .values()
+            .filter_map(|entry| short_name(&entry.name).ok())
+            .collect();
+        self.entries
+            .values()
+            .map(|entry| match short_name(&entry.name) {
+                Ok(name) => name,
+                Err(_) => {
+                    let alias = short_alias(&entry.name, &taken);
+                    taken.insert(alias);
+                    alias
+                }
+            })
+            .collect()
+    }
+}
+
+/// Writes a FAT file system of `size` bytes containing `root` to `out` at `offset`.
+///
+/// File systems of at least the minimum size of FAT32 are FAT32, smaller ones FAT16
+/// (see `FatType::for_size`). The file system uses the `sector_size` of the disk,
+/// and `hidden_sectors` is the start sector of the partition on the disk. Only the
+/// used parts of the file system are written, so the region must already be zero,
+/// e.g. behind the end of a file that was extended with `set_len`.
+pub(crate) fn write_fat<W: Write + Seek>(
+    out: &mut W,
+    offset: u64,
+    size: u64,
+    sector_size: u64,
+    hidden_sectors: u32,
+    label: &str,
+    root: &Directory,
+) -> io::Result<()> {
+    let fat_type = FatType::for_size(size, sector_size);
+    let total_sectors = size / sector_size;
+    let (reserved_sectors, root_entries) = match fat_type {
+        FatType::Fat32 => (FAT32_RESERVED_SECTORS, 0),
+        FatType::Fat16 => {
+            // the fixed root directory fills whole sectors
+            let entries = cmp::max(FAT16_ROOT_ENTRIES, root.dir_entry_count(true));
+            let entries_per_sector = sector_size / DIR_ENTRY_SIZE as u64;
+            (FAT16_RESERVED_SECTORS, round_up(entries as u64, entries_per_sector))
+        }
+    };
+    let root_sectors = root_entries * DIR_ENTRY_SIZE as u64 / sector_size;
+    let overhead = reserved_sectors + root_sectors;
+    let (sectors_per_cluster, fat_sectors) =
+        geometry(fat_type, total_sectors, sector_size, overhead);
+    let cluster_size = sectors_per_cluster * sector_size;
+    let cluster_count = total_sectors.saturating_sub(overhead + FAT_COUNT * fat_sectors)
+        / sectors_per_cluster;
+    let min_size = fat_type.min_size(sector_size);
+    let (name, min_clusters) = match fat_type {
+        FatType::Fat32 => ("FAT32", MIN_CLUSTERS),
+        FatType::Fat16 => ("FAT16", MIN_FAT16_CLUSTERS),
+    };
+    if size < min_size || cluster_count < min_clusters {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} file systems must be at least {} bytes", name, min_size),
+        ));
+    }
+    if root.size(cluster_size) > cluster_count * cluster_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("files don't fit into the {} file system", name),
+        ));
+    }
+
+    let mut layout = Layout {
+        cluster_size,
+        next_cluster: ROOT_CLUSTER,
+        fat: vec![0x0fff_fff8, END_OF_CHAIN],
+        chunks: Vec::new(),
+    };
+    // the FAT16 root directory isn't stored in clusters, which is denoted by cluster 0
+    let root_cluster = match fat_type {
+        FatType::Fat32 => {
+            let root_clusters = layout.clusters(root.dir_entry_count(true) * DIR_ENTRY_SIZE);
+            layout.allocate(root_clusters)
+        }
+        FatType::Fat16 => 0,
+    };
+    layout.directory(root, root_cluster, None, label);
+
+    let root_start = offset + (reserved_sectors + FAT_COUNT * fat_sectors) * sector_size;
+    let data_start = root_start + root_sectors * sector_size;
+    let used_clusters = u64::from(layout.next_cluster - ROOT_CLUSTER);
+    let volume_id = label.bytes().fold(0x1980_0101u32, |id, b| {
+        id.rotate_left(5) ^ u32::from(b)
+    });
+
+    let boot_sector = boot_sector(
+        fat_type,
+        sector_size as u16,
+        total_sectors as u32,
+        sectors_per_cluster as u8,
+        fat_sectors as u32,
+        root_entries as u16,
+        hidden_sectors,
+        volume_id,
+        label,
+    );
+    out.seek(SeekFrom::Start(offset))?;
+    out.write_all(&boot_sector)?;
+    if fat_type == FatType::Fat32 {
+        let fs_info = fs_info(
+            (cluster_count - used_clusters) as u32,
+            layout.next_cluster,
+        );
+        for &boot_sector_index in &[0, BACKUP_BOOT_SECTOR] {
+            let start = offset + u64::from(boot_sector_index) * sector_size;
+            out.seek(SeekFrom::Start(start))?;
+            out.write_all(&boot_sector)?;
+            out.seek(SeekFrom::Start(start + u64::from(FS_INFO_SECTOR) * sector_size))?;
+            out.write_all(&fs_info)?;
+        }
+    }
+
+    // the entries of the free clusters are zero, so only the used part is written
+    let fat_size = layout.fat.len() as u64 * fat_type.entry_size();
+    let mut fat = vec![0u8; round_up(fat_size, sector_size) as usize];
+    for (i, &entry) in layout.fat.iter().enumerate() {
+        match fat_type {
+            FatType::Fat32 => LittleEndian::write_u32(&mut fat[i * 4..i * 4 + 4], entry),
+            // the special values keep their meaning when truncated, e.g. 0xfff8 and 0xffff
+            FatType::Fat16 => LittleEndian::write_u16(&mut fat[i * 2..i * 2 + 2], entry as u16),
+        }
+    }
+    for i in 0..FAT_COUNT {
+        out.seek(SeekFrom::Start(
+            offset + (reserved_sectors + i * fat_sectors) * sector_size,
+        ))?;
+        out.write_all(&fat)?;
+    }
+
+    for (cluster, data) in layout.chunks {
+        let position = match cluster {
+            0 => root_start,
+            _ => data_start + u64::from(cluster - ROOT_CLUSTER) * cluster_size,
+        };
+        out.seek(SeekFrom::Start(position))?;
+        out.write_all(&data)?;
+    }
+
+    Ok(())
+}
+
+struct Layout {
+    cluster_size: u64,
+    next_cluster: u32,
+    fat: Vec<u32>,
+    /// The data to write, keyed by the first cluster.
+    chunks: Vec<(u32, Vec<u8>)>,
+}
+
+impl Layout {
+    fn clusters(&self, bytes: usize) -> u32 {
+        (bytes as u64).div_ceil(self.cluster_size) as u32
+    }
+
+    /// Allocates a chain of `count` clusters and returns the first cluster.
+    fn allocate(&mut self, count: u32) -> u32 {
+        if count == 0 {
+            return 0;
+        }
+        let first = self.next_cluster;
+        for cluster in first..first + count - 1 {
+            self.fat.push(cluster + 1);
+        }
+        self.fat.push(END_OF_CHAIN);
+        self.next_cluster += count;
+        first
+    }
+
+    /// Lays out the directory `dir` at `cluster` and its contents.
+    ///
+    /// The `parent` is the cluster of the parent directory, which is 0 for the
+    /// root directory, and `None` for the root directory itself.
+    fn directory(&mut self, dir: &Directory, cluster: u32, parent: Option<u32>, label: &str) {
+        let mut data = Vec::with_capacity(dir.dir_entry_count(parent.is_none()) * DIR_ENTRY_SIZE);
+        match parent {
+            None => data.extend_from_slice(&dir_entry(&label_name(label), ATTR_VOLUME_ID, 0, 0)),
+            Some(parent) => {
+                data.extend_from_slice(&dir_entry(b".          ", ATTR_DIRECTORY, cluster, 0));
+                data.extend_from_slice(&dir_entry(b"..         ", ATTR_DIRECTORY, parent, 0));
+            }
+        }
+        self.chunks.push((cluster, Vec::new()));
+        let chunk_index = self.chunks.len() - 1;
+        // `..` entries refer to the root directory with cluster 0
+        let own_cluster = if parent.is_none() { 0 } else { cluster };
+
+        for (entry, name) in dir.entries.values().zip(dir.short_names()) {
+            if long_name_entry_count(&entry.name) > 0 {
+                for long_entry in long_name_entries(&entry.name, &name) {
+                    data.extend_from_slice(&long_entry);
+                }
+            }
+            match entry.node {
+                Node::File(ref contents) => {
+                    let clusters = self.clusters(contents.len());
+                    let first = self.allocate(clusters);
+                    data.extend_from_slice(&dir_entry(
+                        &name,
+                        ATTR_ARCHIVE,
+                        first,
+                        contents.len() as u32,
+                    ));
+                    if first != 0 {
+                        self.chunks.push((first, contents.clone()));
+                    }
+                }
+                Node::Directory(ref subdir) => {
+                    let clusters = self.clusters(subdir.dir_entry_count(false) * DIR_ENTRY_SIZE);
+                    let first = self.allocate(clusters);
+                    data.extend_from_slice(&dir_entry(&name, ATTR_DIRECTORY, first, 0));
+                    self.directory(subdir, first, Some(own_cluster), label);
+                }
+            }
+        }
+        self.chunks[chunk_index].1 = data;
+    }
+}
+
+/// The sectors per cluster and the size of a FAT in sectors, where `overhead` is
+/// the number of sectors in front of the clusters apart from the FATs.
+fn geometry(
+    fat_type: FatType,
+    total_sectors: u64,
+    sector_size: u64,
+    overhead: u64,
+) -> (u64, u64) {
+    let fat_sectors = |sectors_per_cluster| {
+        fat_sectors(fat_type, total_sectors, sector_size, overhead, sectors_per_cluster)
+    };
+    match fat_type {
+        FatType::Fat32 => {
+            let sectors_per_cluster = sectors_per_cluster(total_sectors * sector_size, sector_size);
+            (sectors_per_cluster, fat_sectors(sectors_per_cluster))
+        }
+        // the smallest clusters that keep the cluster count below the FAT32 range
+        FatType::Fat16 => (0..7)
+            .map(|shift| 1 << shift)
+            .take_while(|&sectors_per_cluster| sectors_per_cluster * sector_size <= MAX_CLUSTER_SIZE)
+            .map(|sectors_per_cluster| (sectors_per_cluster, fat_sectors(sectors_per_cluster)))
+            .find(|&(sectors_per_cluster, fat_sectors)| {
+                let data_sectors = total_sectors.saturating_sub(overhead + FAT_COUNT * fat_sectors);
+                data_sectors / sectors_per_cluster < MIN_CLUSTERS
+            })
+            .unwrap_or((MAX_CLUSTER_SIZE / sector_size, 1)),
+    }
+}
+
+fn sectors_per_cluster(size: u64, sector_size: u64) -> u64 {
+    // the cluster sizes recommended by Microsoft for FAT32, in 512-byte sectors
+    let sectors_per_cluster = match size / 512 {
+        0..=532_480 => 1,
+        532_481..=16_777_216 => 8,
+        16_777_217..=33_554_432 => 16,
+        33_554_433..=67_108_864 => 32,
+        _ => 64,
+    };
+    cmp::max(1, sectors_per_cluster * 512 / sector_size)
+}
+
+fn fat_sectors(
+    fat_type: FatType,
+    total_sectors: u64,
+    sector_size: u64,
+    overhead: u64,
+    sectors_per_cluster: u64,
+) -> u64 {
+    let entries_per_sector = sector_size / fat_type.entry_size();
+    let mut fat_sectors = 1;
+    loop {
+        let data_sectors = total_sectors.saturating_sub(overhead + FAT_COUNT * fat_sectors);
+        let clusters = data_sectors / sectors_per_cluster;
+        let needed = (clusters + 2).div_ceil(entries_per_sector);
+        if needed <= fat_sectors {
+            return fat_sectors;
+        }
+        fat_sectors = needed;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn boot_sector(
+    fat_type: FatType,
+    sector_size: u16,
+    total_sectors: u32,
+    sectors_per_cluster: u8,
+    fat_sectors: u32,
+    root_entries: u16,
+    hidden_sectors: u32,
+    volume_id: u32,
+    label: &str,
+) -> [u8; BOOT_SECTOR_SIZE] {
+    let mut sector = [0u8; BOOT_SECTOR_SIZE];
+    sector[3..11].copy_from_slice(b"BOOTIMG ");
+    LittleEndian::write_u16(&mut sector[11..13], sector_size);
+    sector[13] = sectors_per_cluster;
+    sector[16] = FAT_COUNT as u8;
+    sector[21] = 0xf8; // media descriptor: fixed disk
+    LittleEndian::write_u16(&mut sector[24..26], 63); // sectors per track
+    LittleEndian::write_u16(&mut sector[26..28], 255); // heads
+    LittleEndian::write_u32(&mut sector[28..32], hidden_sectors);
+    // the extended boot record follows the FAT32 specific fields
+    let extended = match fat_type {
+        FatType::Fat16 => {
+            sector[0..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+            LittleEndian::write_u16(&mut sector[14..16], FAT16_RESERVED_SECTORS as u16);
+            LittleEndian::write_u16(&mut sector[17..19], root_entries);
+            match total_sectors {
+                0..=0xffff => LittleEndian::write_u16(&mut sector[19..21], total_sectors as u16),
+                _ => LittleEndian::write_u32(&mut sector[32..36], total_sectors),
+            }
+            LittleEndian::write_u16(&mut sector[22..24], fat_sectors as u16);
+            36
+        }
+        FatType::Fat32 => {
+            sector[0..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+            LittleEndian::write_u16(&mut sector[14..16], FAT32_RESERVED_SECTORS as u16);
+            LittleEndian::write_u32(&mut sector[32..36], total_sectors);
+            LittleEndian::write_u32(&mut sector[36..40], fat_sectors);
+            LittleEndian::write_u32(&mut sector[44..48], ROOT_CLUSTER);
+            LittleEndian::write_u16(&mut sector[48..50], FS_INFO_SECTOR);
+            LittleEndian::write_u16(&mut sector[50..52], BACKUP_BOOT_SECTOR);
+            64
+        }
+    };
+    sector[extended] = 0x80; // drive number
+    sector[extended + 2] = 0x29; // extended boot signature
+    LittleEndian::write_u32(&mut sector[extended + 3..extended + 7], volume_id);
+    sector[extended + 7..extended + 18].copy_from_slice(&label_name(label));
+    sector[extended + 18..extended + 26].copy_from_slice(match fat_type {
+        FatType::Fat16 => b"FAT16   ",
+        FatType::Fat32 => b"FAT32   ",
+    });
+    sector[510] = 0x55;
+    sector[511] = 0xaa;
+    sector
+}
+
+fn fs_info(free_clusters: u32, next_free: u32) -> [u8; BOOT_SECTOR_SIZE] {
+    let mut sector = [0u8; BOOT_SECTOR_SIZE];
+    LittleEndian::write_u32(&mut sector[0..4], 0x4161_5252);
+    LittleEndian::write_u32(&mut sector[484..488], 0x6141_7272);
+    LittleEndian::write_u32(&mut sector[488..492], free_clusters);
+    LittleEndian::write_u32(&mut sector[492..496], next_free);
+    LittleEndian::write_u32(&mut sector[508..512], 0xaa55_0000);
+    sector
+}
+
+fn dir_entry(name: &[u8; 11], attributes: u8, cluster: u32, size: u32) -> [u8; DIR_ENTRY_SIZE] {
+    let mut entry = [0u8; DIR_ENTRY_SIZE];
+    entry[0..11].copy_from_slice(name);
+    entry[11] = attributes;
+    LittleEndian::write_u16(&mut entry[16..18], FIXED_DATE); // creation date
+    LittleEndian::write_u16(&mut entry[18..20], FIXED_DATE); // access date
+    LittleEndian::write_u16(&mut entry[20..22], (cluster >> 16) as u16);
+    LittleEndian::write_u16(&mut entry[24..26], FIXED_DATE); // modification date
+    LittleEndian::write_u16(&mut entry[26..28], cluster as u16);
+    LittleEndian::write_u32(&mut entry[28..32], size);
+    entry
+}
+
+/// Checks that `name` can be stored as a (long) file name.
+fn check_name(name: &str) -> Result<(), String> {
+    // this includes `.` and `..`
+    let invalid = name.ends_with([' ', '.'])
+        || name.encode_utf16().count() > MAX_LONG_NAME
+        || name.chars().any(|c| c < ' ' || "\"*:<>?\\|".contains(c));
+    if invalid {
+        Err(format!("`{}` is not a valid file name", name))
+    } else {
+        Ok(())
+    }
+}
+
+/// The number of long file name entries in front of the short entry of `name`,
+/// which is 0 for names that are stored as 8.3 names.
+fn long_name_entry_count(name: &str) -> usize {
+    if short_name(name).is_ok() && name == name.to_uppercase() {
+        0
+    } else {
+        name.encode_utf16().count().div_ceil(LONG_NAME_CHARS)
+    }
+}
+
+/// The long file name entries of `name` in the order in which they precede the
+/// short entry with `short_name`.
+fn long_name_entries(name: &str, short_name: &[u8; 11]) -> Vec<[u8; DIR_ENTRY_SIZE]> {
+    let mut chars: Vec<u16> = name.encode_utf16().collect();
+    // the name is terminated with a NUL and padded with 0xffff, unless it fills all entries
+    if !chars.len().is_multiple_of(LONG_NAME_CHARS) {
+        chars.push(0);
+    }
+    while !chars.len().is_multiple_of(LONG_NAME_CHARS) {
+        chars.push(0xffff);
+    }
+    let checksum = short_name
+        .iter()
+        .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b));
+    let count = chars.len() / LONG_NAME_CHARS;
+    // the entry with the end of the name comes first and is marked with 0x40
+    (0..count)
+        .rev()
+        .map(|i| {
+            let mut entry = [0u8; DIR_ENTRY_SIZE];
+            entry[0] = (i + 1) as u8 | if i + 1 == count { 0x40 } else { 0 };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            let part = &chars[i * LONG_NAME_CHARS..(i + 1) * LONG_NAME_CHARS];
+            for (&offset, &c) in LONG_NAME_OFFSETS.iter().zip(part) {
+                LittleEndian::write_u16(&mut entry[offset..offset + 2], c);
+            }
+            entry
+        })
+        .collect()
+}
+
+/// Generates a short alias for a long file name, like `LONGFI~1.TXT` for
+/// `long file.txt`, that isn't `taken` yet.
+fn short_alias(name: &str, taken: &BTreeSet<[u8; 11]>) -> [u8; 11] {
+    let filter = |s: &str| -> String {
+        s.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                let c = c.to_ascii_uppercase();
+                if is_short_name_char(c) {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    };
+    let (base, extension) = match name.trim_start_matches('.').rfind('.') {
+        Some(index) => {
+            let name = name.trim_start_matches('.');
+            (filter(&name[..index]), filter(&name[index + 1..]))
+        }
+        None => (filter(name), String::new()),
+    };
+    let base = if base.is_empty() { String::from("_") } else { base };
+
+    let mut alias = [b' '; 11];
+    for (dst, src) in alias[8..].iter_mut().zip(extension.bytes()) {
+        *dst = src;
+    }
+    for n in 1.. {
+        let tail = format!("~{}", n);
+        let base: String = base.chars().take(8 - tail.len()).collect();
+        let base = base + &tail;
+        alias[..8].copy_from_slice(&[b' '; 8]);
+        alias[..base.len()].copy_from_slice(base.as_bytes());
+        if !taken.contains(&alias) {
+            break;
+        }
+    }
+    alias
+}
+
+/// Converts a file name to the 8.3 format used in directory entries.
+fn short_name(name: &str) -> Result<[u8; 11], String> {
+    let invalid = || format!("`{}` is not a valid 8.3 file name", name);
+    let (base, extension) = match name.rfind('.') {
+        Some(index) => (&name[..index], &name[index + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return Err(invalid());
+    }
+    if !base.chars().chain(extension.chars()).all(is_short_name_char) {
+        return Err(invalid());
+    }
+
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.to_uppercase().as_bytes());
+    short_name[8..8 + extension.len()].copy_from_slice(extension.to_uppercase().as_bytes());
+    Ok(short_name)
+}
+
+fn is_short_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+fn label_name(label: &str) -> [u8; 11] {
+    let mut name = [b' '; 11];
+    for (dst, src) in name.iter_mut().zip(label.to_uppercase().bytes()) {
+        *dst = src;
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const MIB: u64 = 1024 * 1024;
+
+    /// Writes a file system of `size` bytes with 512-byte sectors into a buffer.
+    fn format(size: u64, root: &Directory) -> Vec<u8> {
+        let mut image = Cursor::new(vec![0u8; size as usize]);
+        write_fat(&mut image, 0, size, 512, 2048, "bootimage", root).unwrap();
+        image.into_inner()
+    }
+
+    fn u16_at(image: &[u8], offset: usize) -> u16 {
+        LittleEndian::read_u16(&image[offset..offset + 2])
+    }
+
+    fn u32_at(image: &[u8], offset: usize) -> u32 {
+        LittleEndian::read_u32(&image[offset..offset + 4])
+    }
+
+    /// The standard checksum of a short name that long file name entries refer to.
+    fn reference_checksum(short_name: &[u8]) -> u8 {
+        let mut sum = 0u8;
+        for &c in short_name {
+            sum = (if sum & 1 != 0 { 0x80u8 } else { 0 })
+                .wrapping_add(sum >> 1)
+                .wrapping_add(c);
+        }
+        sum
+    }
+
+    /// The offset of the FAT32 data cluster `cluster`.
+    fn fat32_cluster_offset(image: &[u8], cluster: u32) -> usize {
+        let reserved = u16_at(image, 14) as usize;
+        let fat_sectors = u32_at(image, 36) as usize;
+        let cluster_size = image[13] as usize * 512;
+        (reserved + 2 * fat_sectors) * 512 + (cluster as usize - 2) * cluster_size
+    }
+
+    #[test]
+    fn fat16_boot_sector() {
+        let image = format(16 * MIB, &Directory::default());
+        assert_eq!(&image[0..3], &[0xeb, 0x3c, 0x90]);
+        assert_eq!(u16_at(&image, 11), 512); // bytes per sector
+        assert_eq!(u16_at(&image, 14), 1); // reserved sectors
+        assert_eq!(image[16], 2); // FATs
+        assert_eq!(u16_at(&image, 17), 512); // root entries
+        assert_eq!(u16_at(&image, 19), 32768); // total sectors
+        assert_eq!(image[21], 0xf8);
+        assert_eq!(u32_at(&image, 28), 2048); // hidden sectors
+        assert_eq!(image[38], 0x29);
+        assert_eq!(&image[43..54], b"BOOTIMAGE  ");
+        assert_eq!(&image[54..62], b"FAT16   ");
+        assert_eq!(&image[510..512], &[0x55, 0xaa]);
+
+        // the cluster count determines the FAT type, not the type field
+        let fat_sectors = u64::from(u16_at(&image, 22));
+        let root_sectors = 512 * 32 / 512;
+        let data_sectors = 32768 - 1 - 2 * fat_sectors - root_sectors;
+        let clusters = data_sectors / u64::from(image[13]);
+        assert!((MIN_FAT16_CLUSTERS..MIN_CLUSTERS).contains(&clusters));
+        // the media descriptor and the end of chain marker in the first two entries
+        assert_eq!(&image[512..516], &[0xf8, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn fat32_boot_sector() {
+        let image = format(64 * MIB, &Directory::default());
+        assert_eq!(&image[0..3], &[0xeb, 0x58, 0x90]);
+        assert_eq!(u16_at(&image, 11), 512);
+        assert_eq!(u16_at(&image, 14), 32); // reserved sectors
+        assert_eq!(u16_at(&image, 17), 0); // no fixed root directory
+        assert_eq!(u16_at(&image, 19), 0);
+        assert_eq!(u32_at(&image, 32), 131072); // total sectors
+        assert_eq!(u32_at(&image, 44), 2); // root cluster
+        assert_eq!(u16_at(&image, 48), 1); // FS info sector
+        assert_eq!(u16_at(&image, 50), 6); // backup boot sector
+        assert_eq!(image[66], 0x29);
+        assert_eq!(&image[71..82], b"BOOTIMAGE  ");
+        assert_eq!(&image[82..90], b"FAT32   ");
+        assert_eq!(&image[510..512], &[0x55, 0xaa]);
+        assert_eq!(&image[6 * 512..7 * 512], &image[..512]);
+
+        let fat_sectors = u64::from(u32_at(&image, 36));
+        let clusters = (131072 - 32 - 2 * fat_sectors) / u64::from(image[13]);
+        assert!(clusters >= MIN_CLUSTERS);
+        for &fs_info in &[512, 7 * 512] {
+            assert_eq!(u32_at(&image, fs_info), 0x4161_5252);
+            assert_eq!(u32_at(&image, fs_info + 484), 0x6141_7272);
+            assert_eq!(u32_at(&image, fs_info + 508), 0xaa55_0000);
+            // only the root directory is used
+            assert_eq!(u32_at(&image, fs_info + 488) as u64, clusters - 1);
+            assert_eq!(u32_at(&image, fs_info + 492), 3);
+        }
+    }
+
+    #[test]
+    fn cluster_chains() {
+        let image_size = 64 * MIB;
+        let cluster_size = sectors_per_cluster(image_size, 512) as usize * 512;
+        let contents: Vec<u8> = (0..3 * cluster_size - 1).map(|i| i as u8).collect();
+        let mut root = Directory::default();
+        root.add_file("KERNEL.ELF", contents.clone()).unwrap();
+        root.add_file("EMPTY", Vec::new()).unwrap();
+        let image = format(image_size, &root);
+
+        // the root directory in cluster 2, the file in clusters 3 to 5
+        let fat = 32 * 512;
+        let entries: Vec<u32> = (0..7).map(|i| u32_at(&image, fat + i * 4)).collect();
+        assert_eq!(entries, [0x0fff_fff8, END_OF_CHAIN, END_OF_CHAIN, 4, 5, END_OF_CHAIN, 0]);
+        let fat_sectors = u32_at(&image, 36) as usize;
+        assert_eq!(&image[fat..fat + 512], &image[fat + fat_sectors * 512..][..512]);
+
+        let root_dir = fat32_cluster_offset(&image, 2);
+        // the volume label, then the entries in the order of their names
+        assert_eq!(&image[root_dir..root_dir + 11], b"BOOTIMAGE  ");
+        assert_eq!(image[root_dir + 11], ATTR_VOLUME_ID);
+        let empty = root_dir + 32;
+        assert_eq!(&image[empty..empty + 11], b"EMPTY      ");
+        assert_eq!(u16_at(&image, empty + 26), 0);
+        assert_eq!(u32_at(&image, empty + 28), 0);
+        let kernel = root_dir + 64;
+        assert_eq!(&image[kernel..kernel + 11], b"KERNEL  ELF");
+        assert_eq!(image[kernel + 11], ATTR_ARCHIVE);
+        assert_eq!(u16_at(&image, kernel + 20), 0);
+        assert_eq!(u16_at(&image, kernel + 26), 3);
+        assert_eq!(u32_at(&image, kernel + 28) as usize, contents.len());
+        let data = fat32_cluster_offset(&image, 3);
+        assert_eq!(&image[data..data + contents.len()], &contents[..]);
+    }
+
+    #[test]
+    fn fat16_subdirectory() {
+        let mut root = Directory::default();
+        root.add_file("EFI/BOOT/BOOTX64.EFI", vec![0xaa; 100]).unwrap();
+        let image = format(16 * MIB, &root);
+        let sectors_per_cluster = image[13] as usize;
+        let fat_sectors = u16_at(&image, 22) as usize;
+        let root_dir = (1 + 2 * fat_sectors) * 512;
+        let data = root_dir + 512 * 32;
+        let cluster = |n: usize| data + (n - 2) * sectors_per_cluster * 512;
+
+        // FAT16 entries are 2 bytes and the root directory isn't in a cluster
+        let fat = 512;
+        let entries: Vec<u16> = (0..6).map(|i| u16_at(&image, fat + i * 2)).collect();
+        assert_eq!(entries, [0xfff8, 0xffff, 0xffff, 0xffff, 0xffff, 0]);
+        assert_eq!(&image[root_dir + 32..root_dir + 43], b"EFI        ");
+        assert_eq!(image[root_dir + 43], ATTR_DIRECTORY);
+        assert_eq!(u16_at(&image, root_dir + 32 + 26), 2);
+
+        // `..` of a directory in the root directory refers to cluster 0
+        let efi = cluster(2);
+        assert_eq!(&image[efi..efi + 11], b".          ");
+        assert_eq!(u16_at(&image, efi + 26), 2);
+        assert_eq!(&image[efi + 32..efi + 43], b"..         ");
+        assert_eq!(u16_at(&image, efi + 32 + 26), 0);
+        let boot = cluster(3);
+        assert_eq!(u16_at(&image, boot + 32 + 26), 2);
+        assert_eq!(&image[boot + 64..boot + 75], b"BOOTX64 EFI");
+        assert_eq!(u16_at(&image, boot + 64 + 26), 4);
+        assert_eq!(&image[cluster(4)..cluster(4) + 100], &[0xaa; 100][..]);
+    }
+
+    #[test]
+    fn long_file_names() {
+        let name = "long file name.txt";
+        let mut root = Directory::default();
+        root.add_file(name, b"data".to_vec()).unwrap();
+        let image = format(64 * MIB, &root);
+        let root_dir = fat32_cluster_offset(&image, 2);
+
+        // 18 characters take two entries, the last part of the name comes first
+        let (second, first, short) = (root_dir + 32, root_dir + 64, root_dir + 96);
+        assert_eq!(&image[short..short + 11], b"LONGFI~1TXT");
+        let checksum = reference_checksum(&image[short..short + 11]);
+        for &(entry, sequence) in &[(second, 0x42), (first, 0x01)] {
+            assert_eq!(image[entry], sequence);
+            assert_eq!(image[entry + 11], ATTR_LONG_NAME);
+            assert_eq!(image[entry + 13], checksum);
+            assert_eq!(u16_at(&image, entry + 26), 0);
+        }
+        let chars = |entry: usize| -> Vec<u16> {
+            LONG_NAME_OFFSETS.iter().map(|&offset| u16_at(&image, entry + offset)).collect()
+        };
+        let mut expected: Vec<u16> = name.encode_utf16().collect();
+        expected.push(0);
+        expected.resize(26, 0xffff);
+        assert_eq!(chars(first), &expected[..13]);
+        assert_eq!(chars(second), &expected[13..]);
+    }
+
+    #[test]
+    fn lower_case_short_names_get_long_names() {
+        // `readme.txt` is a valid 8.3 name, but only in upper case
+        assert_eq!(long_name_entry_count("README.TXT"), 0);
+        assert_eq!(long_name_entry_count("readme.txt"), 1);
+        assert_eq!(short_name("readme.txt").unwrap(), *b"README  TXT");
+        // a name that fills the entries exactly has no terminator
+        let entries = long_name_entries("abcdefghijklm", b"ABCDEF~1   ");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0][0], 0x41);
+        assert_eq!(u16_at(&entries[0], 30), u16::from(b'm'));
+    }
+
+    #[test]
+    fn short_name_aliases() {
+        let mut dir = Directory::default();
+        for name in &["long file one.txt", "Long File Two.txt", "LONGFI~1.TXT", ".hidden"] {
+            dir.add_file(name, Vec::new()).unwrap();
+        }
+        // the entries are ordered by their upper-case names, and the existing
+        // 8.3 name `LONGFI~1.TXT` takes the first alias
+        let names: Vec<[u8; 11]> = dir.short_names();
+        assert_eq!(
+            names,
+            [*b"HIDDEN~1   ", *b"LONGFI~2TXT", *b"LONGFI~3TXT", *b"LONGFI~1TXT"]
+        );
+
+        let mut taken = BTreeSet::new();
+        for n in 1..=9 {
+            taken.insert(short_alias("many names.bin", &taken));
+            assert_eq!(taken.len(), n);
+        }
+        // the tail grows into the base name
+        assert_eq!(short_alias("many names.bin", &taken), *b"MANYN~10BIN");
+        assert_eq!(short_alias("a.b.c.d", &BTreeSet::new()), *b"ABC~1   D  ");
+        assert_eq!(short_alias("ümlaut+plus", &BTreeSet::new()), *b"_MLAUT~1   ");
+    }
+}
@@ -0,0 +1,297 @@
+//! Writes disk images to removable block devices like USB sticks.
+//!
+//! Unlike `dd`, the device is checked before anything is written: it must be a
+//! whole removable disk (or a disk attached through USB) that isn't mounted and
+//! that is large enough for the image, and it is described by its vendor, model,
+//! and size before the user confirms. The devices are looked up in sysfs, so
+//! flashing is only supported on Linux. Optionally, the device is read back
+//! afterwards to detect bad media and incomplete writes.
+
+use size::Bytes;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use Error;
+
+/// The sysfs directory with an entry for every block device and partition.
+const SYSFS_BLOCK: &str = "/sys/class/block";
+/// The files that list the mounted file systems and the active swap areas.
+const MOUNT_TABLES: &[&str] = &["/proc/mounts", "/proc/swaps"];
+/// The size of the chunks that are written (and synchronized) at once.
+const CHUNK_SIZE: usize = 4 << 20;
+
+/// A block device that the image is written to.
+struct Device {
+    /// The path of the device node, with symlinks like `/dev/disk/by-id/...` resolved.
+    path: PathBuf,
+    /// The vendor and the model, as far as the kernel knows them.
+    description: String,
+    /// The capacity in bytes.
+    size: u64,
+}
+
+/// Writes the disk image at `image` to the removable block device at `device`
+/// after checking the device and, unless `yes` is set, asking for confirmation.
+///
+/// With `verify`, the device is read back and compared with the image.
+pub(crate) fn flash(image: &Path, device: &Path, yes: bool, verify: bool) -> Result<(), Error> {
+    let image_size = fs::metadata(image)?.len();
+    let device = check_device(device, image_size)?;
+
+    println!(
+        "Flashing {} ({}) to {}: {} ({})",
+        image.display(),
+        Bytes(image_size),
+        device.path.display(),
+        device.description,
+        Bytes(device.size)
+    );
+    if !yes {
+        confirm(&device)?;
+    }
+
+    let mut input = File::open(image)?;
+    let mut output = OpenOptions::new()
+        .write(true)
+        .open(&device.path)
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::PermissionDenied => Error::Flash(format!(
+                "no permission to write to {} (writing to block devices usually requires root \
+                 privileges or membership in the `disk` group)",
+                device.path.display()
+            )),
+            _ => Error::Flash(format!("failed to open {}: {}", device.path.display(), err)),
+        })?;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut written = 0;
+    loop {
+        let len = input.read(&mut buffer)?;
+        if len == 0 {
+            break;
+        }
+        output.write_all(&buffer[..len])?;
+        // the page cache would otherwise absorb the whole image at once, so that the
+        // progress would stall at 100% while the data is written to the device
+        output.sync_data()?;
+        written += len as u64;
+        print_progress(written, image_size);
+    }
+    eprintln!();
+    output.sync_all()?;
+    drop(output);
+    if verify {
+        verify_device(image, &device.path, image_size)?;
+    }
+    println!(
+        "Flashed {} to {}, the device can be removed now",
+        image.display(),
+        device.path.display()
+    );
+    Ok(())
+}
+
+/// Reads the first `image_size` bytes of the device back and compares them with
+/// the image, failing with the offset of the first difference.
+fn verify_device(image: &Path, device: &Path, image_size: u64) -> Result<(), Error> {
+    println!("Verifying {}", device.display());
+    // the page cache of a block device is dropped when its last file descriptor is
+    // closed, so the reopened device is read from the medium instead of the cache
+    let mut image_file = File::open(image)?;
+    let mut device_file = File::open(device)?;
+    let mut expected = vec![0; CHUNK_SIZE];
+    let mut actual = vec![0; CHUNK_SIZE];
+    let mut verified = 0;
+    loop {
+        let len = image_file.read(&mut expected)?;
+        if len == 0 {
+            break;
+        }
+        device_file.read_exact(&mut actual[..len]).map_err(|err| {
+            eprintln!();
+            Error::Flash(format!(
+                "verification failed: reading {} at offset {:#x} failed: {}",
+                device.display(),
+                verified,
+                err
+            ))
+        })?;
+        if let Some(index) = (0..len).find(|&index| expected[index] != actual[index]) {
+            eprintln!();
+            return Err(Error::Flash(format!(
+                "verification failed: {} differs from the image at offset {:#x} (byte {} of \
+                 {}), the medium is probably faulty or smaller than it claims",
+                device.display(),
+                verified + index as u64,
+                verified + index as u64,
+                image_size
+            )));
+        }
+        verified += len as u64;
+        print_progress(verified, image_size);
+    }
+    eprintln!();
+    Ok(())
+}
+
+/// Prints how many of the `total` bytes are `done`, overwriting the previous line.
+fn print_progress(done: u64, total: u64) {
+    eprint!(
+        "\r    {} / {} ({:.0}%)",
+        Bytes(done),
+        Bytes(total),
+        done as f64 * 100.0 / total.max(1) as f64
+    );
+}
+
+/// Checks that `path` is a whole removable disk that isn't in use and that holds at
+/// least `image_size` bytes.
+fn check_device(path: &Path, image_size: u64) -> Result<Device, Error> {
+    if !cfg!(target_os = "linux") {
+        return Err(Error::Flash("flashing is only supported on Linux".into()));
+    }
+    let path = fs::canonicalize(path).map_err(|err| {
+        Error::Flash(format!("the device {} doesn't exist: {}", path.display(), err))
+    })?;
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let sys_dir = Path::new(SYSFS_BLOCK).join(&name);
+    if !is_block_device(&path)? || !sys_dir.exists() {
+        return Err(Error::Flash(format!(
+            "{} is not a block device (e.g. /dev/sdb)",
+            path.display()
+        )));
+    }
+    if sys_dir.join("partition").exists() {
+        // the entries of partitions are subdirectories of the entry of their disk
+        let disk = fs::canonicalize(&sys_dir)?
+            .parent()
+            .and_then(|dir| dir.file_name())
+            .map(|disk| format!("/dev/{}", disk.to_string_lossy()))
+            .unwrap_or_else(|| "the disk".into());
+        return Err(Error::Flash(format!(
+            "{} is a partition, flash the whole disk ({}) instead",
+            path.display(),
+            disk
+        )));
+    }
+
+    // many USB sticks and card readers set the removable flag, but USB hard disks
+    // and SSDs don't, so devices that are attached through USB are accepted too
+    let removable = read_sys_file(&sys_dir.join("removable")).as_deref() == Some("1");
+    let usb = fs::canonicalize(&sys_dir)?
+        .components()
+        .any(|component| component.as_os_str().to_string_lossy().starts_with("usb"));
+    if !removable && !usb {
+        return Err(Error::Flash(format!(
+            "{} is neither removable nor attached through USB, refusing to overwrite what is \
+             probably an internal disk",
+            path.display()
+        )));
+    }
+
+    // the size is always given in 512-byte sectors, independent of the sector size
+    let sectors = read_sys_file(&sys_dir.join("size")).and_then(|size| size.parse::<u64>().ok());
+    let size = sectors.unwrap_or(0) * 512;
+    if size == 0 {
+        return Err(Error::Flash(format!(
+            "{} has no medium (e.g. an empty card reader)",
+            path.display()
+        )));
+    }
+    if image_size > size {
+        return Err(Error::Flash(format!(
+            "the image ({}) is larger than {} ({})",
+            Bytes(image_size),
+            path.display(),
+            Bytes(size)
+        )));
+    }
+
+    let mut names = vec![name.clone()];
+    for entry in fs::read_dir(&sys_dir)? {
+        let entry = entry?;
+        if entry.path().join("partition").exists() {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    for table in MOUNT_TABLES {
+        let file = match File::open(table) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        for line in io::BufReader::new(file).lines() {
+            let line = line?;
+            let source = line.split_whitespace().next().unwrap_or_default();
+            let source = fs::canonicalize(source).unwrap_or_else(|_| PathBuf::from(source));
+            if source.parent() != Some(Path::new("/dev")) {
+                continue;
+            }
+            let source_name = source.file_name().map(|name| name.to_string_lossy());
+            if source_name.is_some_and(|source_name| names.iter().any(|name| *name == source_name))
+            {
+                return Err(Error::Flash(format!(
+                    "{} is in use (it is listed in {}), unmount it first",
+                    source.display(),
+                    table
+                )));
+            }
+        }
+    }
+
+    let description = ["vendor", "model"]
+        .iter()
+        .filter_map(|file| read_sys_file(&sys_dir.join("device").join(file)))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let description = match description {
+        ref description if description.is_empty() => "unknown device".into(),
+        description => description,
+    };
+    Ok(Device {
+        path,
+        description,
+        size,
+    })
+}
+
+/// Asks the user whether the device should be overwritten.
+fn confirm(device: &Device) -> Result<(), Error> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        return Err(Error::Flash(
+            "flashing requires a confirmation, pass `--yes` to flash without asking".into(),
+        ));
+    }
+    print!(
+        "All data on {} will be lost. Continue? [y/N] ",
+        device.path.display()
+    );
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    stdin.lock().read_line(&mut answer)?;
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(Error::Flash("aborted, nothing was written".into())),
+    }
+}
+
+/// Reads a sysfs attribute, without the trailing whitespace.
+fn read_sys_file(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|content| content.trim().to_owned())
+}
+
+#[cfg(unix)]
+fn is_block_device(path: &Path) -> Result<bool, Error> {
+    use std::os::unix::fs::FileTypeExt;
+
+    Ok(fs::metadata(path)?.file_type().is_block_device())
+}
+
+#[cfg(not(unix))]
+fn is_block_device(_path: &Path) -> Result<bool, Error> {
+    Ok(false)
+}
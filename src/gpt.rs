@@ -0,0 +1,253 @@
+//! Writes GUID partition tables (GPT) including the protective MBR.
+
+use byteorder::{ByteOrder, LittleEndian};
+use crc32;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const HEADER_SIZE: usize = 92;
+const ENTRY_COUNT: usize = 128;
+const ENTRY_SIZE: usize = 128;
+
+/// The number of sectors occupied by the partition entry array, e.g. 32 for
+/// 512-byte sectors.
+pub(crate) fn entry_sectors(sector_size: u64) -> u64 {
+    ((ENTRY_COUNT * ENTRY_SIZE) as u64).div_ceil(sector_size)
+}
+
+/// The first sector that can be used for partitions (after the protective MBR,
+/// the primary header, and the primary entry array).
+pub(crate) fn first_usable_lba(sector_size: u64) -> u64 {
+    2 + entry_sectors(sector_size)
+}
+
+/// The number of sectors at the end of the disk occupied by the backup GPT.
+pub(crate) fn backup_sectors(sector_size: u64) -> u64 {
+    1 + entry_sectors(sector_size)
+}
+
+/// A GUID in its on-disk (mixed-endian) byte order.
+pub(crate) type Guid = [u8; 16];
+
+/// The partition type GUID C12A7328-F81F-11D2-BA4B-00A0C93EC93B.
+pub(crate) const EFI_SYSTEM_PARTITION: Guid = [
+    0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9,
+    0x3b,
+];
+
+/// The partition type GUID EBD0A0A2-B9E5-4433-87C0-68B6B72699C7 (basic data).
+pub(crate) const BASIC_DATA_PARTITION: Guid = [
+    0xa2, 0xa0, 0xd0, 0xeb, 0xe5, 0xb9, 0x33, 0x44, 0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99,
+    0xc7,
+];
+
+/// The partition type GUID 0FC63DAF-8483-4772-8E79-3D69D8477DE4 (Linux file system).
+pub(crate) const LINUX_FILESYSTEM: Guid = [
+    0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d,
+    0xe4,
+];
+
+pub(crate) struct Partition<'a> {
+    pub type_guid: Guid,
+    pub name: &'a str,
+    pub first_lba: u64,
+    /// The last sector of the partition (inclusive).
+    pub last_lba: u64,
+}
+
+/// Writes the protective MBR partition table and the primary and backup GPT to `out`.
+///
+/// Only the partition table and signature of the MBR are written, so boot code
+/// that was written to the first sector before is preserved. The primary
+/// partition entry array is placed at `entries_lba` (usually LBA 2) and all
+/// partitions must start at or after `first_usable_lba`. All LBAs refer to
+/// sectors of `sector_size` bytes. The GUIDs are derived from `seed` so that
+/// identical inputs result in identical images.
+pub(crate) fn write_partition_tables<W: Write + Seek>(
+    out: &mut W,
+    sector_size: u64,
+    disk_sectors: u64,
+    entries_lba: u64,
+    first_usable_lba: u64,
+    seed: &[u8],
+    partitions: &[Partition],
+) -> io::Result<()> {
+    assert!(partitions.len() <= ENTRY_COUNT, "too many partitions");
+    let last_lba = disk_sectors - 1;
+
+    let mut entries = vec![0u8; ENTRY_COUNT * ENTRY_SIZE];
+    for (i, (partition, entry)) in partitions
+        .iter()
+        .zip(entries.chunks_mut(ENTRY_SIZE))
+        .enumerate()
+    {
+        assert!(partition.first_lba >= first_usable_lba);
+        assert!(partition.last_lba < disk_sectors - backup_sectors(sector_size));
+        entry[0..16].copy_from_slice(&partition.type_guid);
+        entry[16..32].copy_from_slice(&guid(seed, i as u32 + 1));
+        LittleEndian::write_u64(&mut entry[32..40], partition.first_lba);
+        LittleEndian::write_u64(&mut entry[40..48], partition.last_lba);
+        for (j, c) in partition.name.encode_utf16().take(36).enumerate() {
+            LittleEndian::write_u16(&mut entry[56 + j * 2..58 + j * 2], c);
+        }
+    }
+    let entries_crc = crc32::crc32(&entries);
+    let disk_guid = guid(seed, 0);
+
+    let header = |current_lba, backup_lba, entries_lba| Header {
+        current_lba,
+        backup_lba,
+        entries_lba,
+        first_usable_lba,
+        disk_sectors,
+        disk_guid,
+        entries_crc,
+    };
+    let backup_entries_lba = last_lba - entry_sectors(sector_size);
+    let primary = header(1, last_lba, entries_lba).to_bytes(sector_size);
+    let backup = header(last_lba, 1, backup_entries_lba).to_bytes(sector_size);
+
+    out.seek(SeekFrom::Start(446))?;
+    out.write_all(&protective_mbr_entry(disk_sectors))?;
+    out.write_all(&[0u8; 48])?; // the other three MBR partition entries
+    out.write_all(&[0x55, 0xaa])?;
+    out.seek(SeekFrom::Start(sector_size))?;
+    out.write_all(&primary)?;
+    out.seek(SeekFrom::Start(entries_lba * sector_size))?;
+    out.write_all(&entries)?;
+
+    out.seek(SeekFrom::Start(backup_entries_lba * sector_size))?;
+    out.write_all(&entries)?;
+    out.write_all(&backup)?;
+
+    Ok(())
+}
+
+/// The sectors that change when a disk image with a GPT is enlarged to
+/// `disk_size` bytes, as pairs of offset and content: the protective MBR partition, the
+/// primary header, the old backup GPT (which is cleared), and the backup entry
+/// array and header at the new end of the disk.
+///
+/// The image is read from `image`, whose sector size is detected from the position
+/// of the primary header. Nothing changes if the image has no GPT.
+pub(crate) fn resized_tables<R: Read + Seek>(
+    image: &mut R,
+    disk_size: u64,
+) -> io::Result<Vec<(u64, Vec<u8>)>> {
+    let mut mbr = vec![0u8; 512];
+    image.seek(SeekFrom::Start(0))?;
+    image.read_exact(&mut mbr)?;
+    let mut primary = None;
+    for &sector_size in &[512u64, 4096] {
+        let mut sector = vec![0u8; sector_size as usize];
+        image.seek(SeekFrom::Start(sector_size))?;
+        if image.read_exact(&mut sector).is_ok() && &sector[0..8] == b"EFI PART" {
+            primary = Some((sector_size, sector));
+            break;
+        }
+    }
+    let (sector_size, mut primary) = match primary {
+        Some(primary) => primary,
+        None => return Ok(Vec::new()),
+    };
+    let entries_lba = LittleEndian::read_u64(&primary[72..80]);
+    let old_last_lba = LittleEndian::read_u64(&primary[32..40]);
+    let mut entries = vec![0u8; (entry_sectors(sector_size) * sector_size) as usize];
+    image.seek(SeekFrom::Start(entries_lba * sector_size))?;
+    image.read_exact(&mut entries)?;
+
+    let disk_sectors = disk_size / sector_size;
+    let last_lba = disk_sectors - 1;
+    let backup_entries_lba = last_lba - entry_sectors(sector_size);
+    let set_crc = |sector: &mut [u8]| {
+        LittleEndian::write_u32(&mut sector[16..20], 0);
+        let header_crc = crc32::crc32(&sector[..HEADER_SIZE]);
+        LittleEndian::write_u32(&mut sector[16..20], header_crc);
+    };
+    LittleEndian::write_u64(&mut primary[32..40], last_lba);
+    let last_usable_lba = disk_sectors - backup_sectors(sector_size) - 1;
+    LittleEndian::write_u64(&mut primary[48..56], last_usable_lba);
+    let mut backup = primary.clone();
+    set_crc(&mut primary);
+    LittleEndian::write_u64(&mut backup[24..32], last_lba);
+    LittleEndian::write_u64(&mut backup[32..40], 1);
+    LittleEndian::write_u64(&mut backup[72..80], backup_entries_lba);
+    set_crc(&mut backup);
+
+    let mut sectors = Vec::new();
+    // a hybrid MBR has other partitions besides the protective one, which stay as they are
+    if mbr[446 + 4] == 0xee {
+        mbr[446..462].copy_from_slice(&protective_mbr_entry(disk_sectors));
+        sectors.push((0, mbr));
+    }
+    sectors.push((1, primary));
+    let old_backup_sectors = backup_sectors(sector_size);
+    if old_last_lba >= old_backup_sectors && old_last_lba < backup_entries_lba {
+        let old_backup = old_last_lba + 1 - old_backup_sectors;
+        sectors.push((old_backup, vec![0u8; (old_backup_sectors * sector_size) as usize]));
+    }
+    sectors.push((backup_entries_lba, entries));
+    sectors.push((last_lba, backup));
+    Ok(sectors
+        .into_iter()
+        .map(|(lba, content)| (lba * sector_size, content))
+        .collect())
+}
+
+struct Header {
+    current_lba: u64,
+    backup_lba: u64,
+    entries_lba: u64,
+    first_usable_lba: u64,
+    disk_sectors: u64,
+    disk_guid: Guid,
+    entries_crc: u32,
+}
+
+impl Header {
+    fn to_bytes(&self, sector_size: u64) -> Vec<u8> {
+        let mut sector = vec![0u8; sector_size as usize];
+        sector[0..8].copy_from_slice(b"EFI PART");
+        LittleEndian::write_u32(&mut sector[8..12], 0x0001_0000); // revision 1.0
+        LittleEndian::write_u32(&mut sector[12..16], HEADER_SIZE as u32);
+        LittleEndian::write_u64(&mut sector[24..32], self.current_lba);
+        LittleEndian::write_u64(&mut sector[32..40], self.backup_lba);
+        LittleEndian::write_u64(&mut sector[40..48], self.first_usable_lba);
+        let last_usable_lba = self.disk_sectors - backup_sectors(sector_size) - 1;
+        LittleEndian::write_u64(&mut sector[48..56], last_usable_lba);
+        sector[56..72].copy_from_slice(&self.disk_guid);
+        LittleEndian::write_u64(&mut sector[72..80], self.entries_lba);
+        LittleEndian::write_u32(&mut sector[80..84], ENTRY_COUNT as u32);
+        LittleEndian::write_u32(&mut sector[84..88], ENTRY_SIZE as u32);
+        LittleEndian::write_u32(&mut sector[88..92], self.entries_crc);
+        let header_crc = crc32::crc32(&sector[..HEADER_SIZE]);
+        LittleEndian::write_u32(&mut sector[16..20], header_crc);
+        sector
+    }
+}
+
+/// The MBR partition entry that covers the whole disk with a GPT protective partition.
+fn protective_mbr_entry(disk_sectors: u64) -> [u8; 16] {
+    let mut entry = [0u8; 16];
+    entry[1..4].copy_from_slice(&[0x00, 0x02, 0x00]); // CHS of LBA 1
+    entry[4] = 0xee;
+    entry[5..8].copy_from_slice(&[0xff, 0xff, 0xff]);
+    LittleEndian::write_u32(&mut entry[8..12], 1);
+    let size = (disk_sectors - 1).min(u64::from(u32::MAX));
+    LittleEndian::write_u32(&mut entry[12..16], size as u32);
+    entry
+}
+
+/// Derives a version 4 GUID from `seed` and `index`.
+pub(crate) fn guid(seed: &[u8], index: u32) -> Guid {
+    let mut guid = [0u8; 16];
+    let mut prefix = [0u8; 8];
+    LittleEndian::write_u32(&mut prefix[0..4], index);
+    for (i, chunk) in guid.chunks_mut(4).enumerate() {
+        LittleEndian::write_u32(&mut prefix[4..8], i as u32);
+        let crc = crc32::update(crc32::crc32(&prefix), seed);
+        LittleEndian::write_u32(chunk, crc);
+    }
+    guid[7] = (guid[7] & 0x0f) | 0x40;
+    guid[8] = (guid[8] & 0x3f) | 0x80;
+    guid
+}
@@ -0,0 +1,129 @@
+//! Assembles bootable ISO images with GRUB, using the `grub-mkrescue` tool.
+//!
+//! The Multiboot2 kernels, the initrd, and the additional Multiboot2 modules are
+//! staged in a directory together with a generated `grub.cfg`, from which
+//! `grub-mkrescue` creates an ISO image that boots on BIOS and UEFI firmware.
+
+use config::Config;
+use image::{self, BootEntry};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use Error;
+
+/// The names of `grub-mkrescue`, which some distributions call `grub2-mkrescue`.
+const MKRESCUE: &[&str] = &["grub-mkrescue", "grub2-mkrescue"];
+
+/// Writes a GRUB ISO image to `output` with a menu entry for each of the
+/// Multiboot2 kernels of `entries`, the first of which is booted by default.
+///
+/// The files of the image are staged in `staging_dir`, which is recreated.
+pub(crate) fn create_grub_iso(
+    config: &Config,
+    entries: &[BootEntry],
+    staging_dir: &Path,
+    output: &Path,
+) -> Result<(), Error> {
+    if staging_dir.exists() {
+        fs::remove_dir_all(staging_dir)?;
+    }
+    let boot_dir = staging_dir.join("boot");
+    fs::create_dir_all(boot_dir.join("grub"))?;
+    for (index, entry) in entries.iter().enumerate() {
+        fs::write(boot_dir.join(image::menu_kernel_name(index)), &entry.data)?;
+    }
+
+    let mut modules = Vec::new();
+    if let Some(ref initrd) = config.initrd {
+        fs::copy(initrd, boot_dir.join("initrd")).map_err(|err| {
+            Error::Config(format!(
+                "failed to read the initrd at {}: {}",
+                initrd.display(),
+                err
+            ))
+        })?;
+        modules.push("initrd".to_owned());
+    }
+    if !config.multiboot_modules.is_empty() {
+        fs::create_dir(boot_dir.join("modules"))?;
+    }
+    for module in &config.multiboot_modules {
+        let name = module
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let path = format!("modules/{}", name);
+        if name.is_empty() || modules.contains(&path) {
+            return Err(Error::Config(format!(
+                "the Multiboot2 modules must have distinct file names, found {}",
+                module.display()
+            )));
+        }
+        fs::copy(module, boot_dir.join(&path)).map_err(|err| {
+            Error::Config(format!(
+                "failed to read the Multiboot2 module at {}: {}",
+                module.display(),
+                err
+            ))
+        })?;
+        modules.push(path);
+    }
+    fs::write(boot_dir.join("grub").join("grub.cfg"), grub_cfg(config, entries, &modules))?;
+
+    for executable in MKRESCUE {
+        let result = Command::new(executable)
+            .arg("-o")
+            .arg(output)
+            .arg(staging_dir)
+            .output();
+        let result = match result {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+            result => result?,
+        };
+        if !result.status.success() {
+            return Err(Error::Image(format!(
+                "`{}` failed ({}), it requires `xorriso` and, for UEFI support, `mtools`:\n{}",
+                executable,
+                result.status,
+                String::from_utf8_lossy(&result.stderr).trim_end()
+            )));
+        }
+        return Ok(());
+    }
+    Err(Error::Image(
+        "`grub-mkrescue` was not found, install GRUB together with `xorriso` and `mtools` \
+         (e.g. `apt install grub-pc-bin grub-efi-amd64-bin grub-common xorriso mtools`) to \
+         create `grub-iso` images"
+            .into(),
+    ))
+}
+
+/// A `grub.cfg` with a menu entry for each kernel, with the given modules (paths
+/// relative to `/boot`), which are passed to the kernels with their path as
+/// command line.
+///
+/// A single kernel is booted immediately, a menu waits for the `menu-timeout`.
+fn grub_cfg(config: &Config, entries: &[BootEntry], modules: &[String]) -> String {
+    let timeout = if entries.len() > 1 {
+        config.menu_timeout
+    } else {
+        0
+    };
+    let mut cfg = format!("set timeout={}\nset default=0\n", timeout);
+    for (index, entry) in entries.iter().enumerate() {
+        let kernel = image::menu_kernel_name(index);
+        cfg.push_str(&format!("\nmenuentry \"{}\" {{\n", entry.title.replace('"', "\\\"")));
+        match entry.cmdline {
+            Some(ref cmdline) => {
+                cfg.push_str(&format!("    multiboot2 /boot/{} {}\n", kernel, cmdline))
+            }
+            None => cfg.push_str(&format!("    multiboot2 /boot/{}\n", kernel)),
+        }
+        for module in modules {
+            cfg.push_str(&format!("    module2 /boot/{} {}\n", module, module));
+        }
+        cfg.push_str("    boot\n}\n");
+    }
+    cfg
+}
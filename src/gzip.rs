@@ -0,0 +1,499 @@
+//! Compresses and decompresses data in the gzip format, used for compressed kernels.
+//! The compressor also writes zlib streams, used by stream-optimized VMDK images.
+//!
+//! The compressor writes a single DEFLATE block with the fixed Huffman codes,
+//! whose matches are found with hash chains over a 32 KiB window. The
+//! decompressor handles all block types, so that any gzip file can be read.
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use crc32;
+
+/// The magic, the DEFLATE method, no flags, no timestamp (to keep the output
+/// reproducible), no extra flags, and an unknown operating system.
+const HEADER: [u8; 10] = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+const FLAG_HEADER_CRC: u8 = 0x02;
+const FLAG_EXTRA: u8 = 0x04;
+const FLAG_NAME: u8 = 0x08;
+const FLAG_COMMENT: u8 = 0x10;
+
+const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// The number of earlier positions that are compared for each match.
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+const END_OF_BLOCK: u16 = 256;
+
+/// The base lengths of the length symbols 257 to 285 and their extra bits.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+/// The base distances of the distance symbols 0 to 29 and their extra bits.
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order of the code length code lengths in a dynamic block header.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Compresses `data` into a gzip file.
+pub(crate) fn compress(data: &[u8]) -> Vec<u8> {
+    let mut bytes = deflate(data, HEADER.to_vec());
+    let mut trailer = [0u8; 8];
+    LittleEndian::write_u32(&mut trailer[0..4], crc32::crc32(data));
+    LittleEndian::write_u32(&mut trailer[4..8], data.len() as u32);
+    bytes.extend_from_slice(&trailer);
+    bytes
+}
+
+/// Compresses `data` into a zlib stream, the DEFLATE data with a two-byte header
+/// and an Adler-32 checksum instead of the gzip header and trailer.
+pub(crate) fn compress_zlib(data: &[u8]) -> Vec<u8> {
+    // the DEFLATE method with a 32 KiB window and the fastest compression level
+    let mut bytes = deflate(data, vec![0x78, 0x01]);
+    let mut trailer = [0u8; 4];
+    BigEndian::write_u32(&mut trailer, adler32(data));
+    bytes.extend_from_slice(&trailer);
+    bytes
+}
+
+/// Appends the DEFLATE data of `data` to `bytes`.
+fn deflate(data: &[u8], bytes: Vec<u8>) -> Vec<u8> {
+    let mut out = BitWriter {
+        bytes,
+        buffer: 0,
+        count: 0,
+    };
+    // a single final block with the fixed Huffman codes
+    out.write(1, 1);
+    out.write(1, 2);
+
+    let mut chains = Chains {
+        data,
+        head: vec![usize::MAX; 1 << HASH_BITS],
+        previous: vec![usize::MAX; WINDOW_SIZE],
+    };
+    let mut position = 0;
+    while position < data.len() {
+        let (length, distance) = chains.longest_match(position);
+        if length >= MIN_MATCH {
+            write_length(&mut out, length);
+            write_distance(&mut out, distance);
+            for i in position..position + length {
+                chains.insert(i);
+            }
+            position += length;
+        } else {
+            write_literal(&mut out, u16::from(data[position]));
+            chains.insert(position);
+            position += 1;
+        }
+    }
+    write_literal(&mut out, END_OF_BLOCK);
+    out.flush();
+    out.bytes
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MODULUS: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    // the sums can't overflow within 5552 bytes, so they are only reduced per chunk
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += u32::from(byte);
+            b += a;
+        }
+        a %= MODULUS;
+        b %= MODULUS;
+    }
+    (b << 16) | a
+}
+
+/// Decompresses a gzip file and checks its CRC-32 and size.
+pub(crate) fn decompress(file: &[u8]) -> Result<Vec<u8>, String> {
+    let truncated = || "the gzip file is truncated".to_owned();
+    if file.len() < HEADER.len() || file[0..2] != HEADER[0..2] {
+        return Err("not a gzip file".into());
+    }
+    if file[2] != 8 {
+        return Err(format!("unsupported gzip compression method {}", file[2]));
+    }
+    let flags = file[3];
+    let mut position = HEADER.len();
+    if flags & FLAG_EXTRA != 0 {
+        let length = file.get(position..position + 2).ok_or_else(truncated)?;
+        position += 2 + usize::from(LittleEndian::read_u16(length));
+    }
+    for &flag in &[FLAG_NAME, FLAG_COMMENT] {
+        if flags & flag != 0 {
+            // a NUL-terminated string
+            let length = file
+                .get(position..)
+                .and_then(|rest| rest.iter().position(|&b| b == 0))
+                .ok_or_else(truncated)?;
+            position += length + 1;
+        }
+    }
+    if flags & FLAG_HEADER_CRC != 0 {
+        position += 2;
+    }
+
+    let mut input = BitReader {
+        bytes: file.get(position..).ok_or_else(truncated)?,
+        position: 0,
+        buffer: 0,
+        count: 0,
+    };
+    let data = inflate(&mut input)?;
+    let end = position + input.position;
+    let trailer = file.get(end..end + 8).ok_or_else(truncated)?;
+    if LittleEndian::read_u32(&trailer[0..4]) != crc32::crc32(&data) {
+        return Err("the CRC-32 of the gzip file doesn't match".into());
+    }
+    if LittleEndian::read_u32(&trailer[4..8]) != data.len() as u32 {
+        return Err("the size of the gzip file doesn't match the decompressed data".into());
+    }
+    if end + 8 != file.len() {
+        return Err(format!("{} bytes follow the gzip file", file.len() - end - 8));
+    }
+    Ok(data)
+}
+
+/// The earlier positions of the 3-byte sequences of `data` within the window.
+struct Chains<'a> {
+    data: &'a [u8],
+    /// The last position of each hash value.
+    head: Vec<usize>,
+    /// The previous position with the same hash value, indexed by position.
+    previous: Vec<usize>,
+}
+
+impl<'a> Chains<'a> {
+    fn hash(&self, position: usize) -> usize {
+        let sequence = u32::from(self.data[position])
+            | u32::from(self.data[position + 1]) << 8
+            | u32::from(self.data[position + 2]) << 16;
+        (sequence.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, position: usize) {
+        if position + MIN_MATCH <= self.data.len() {
+            let hash = self.hash(position);
+            self.previous[position % WINDOW_SIZE] = self.head[hash];
+            self.head[hash] = position;
+        }
+    }
+
+    /// The longest earlier match of the data at `position`, as length and distance.
+    fn longest_match(&self, position: usize) -> (usize, usize) {
+        if position + MIN_MATCH > self.data.len() {
+            return (0, 0);
+        }
+        let max_length = (self.data.len() - position).min(MAX_MATCH);
+        let mut best = (0, 0);
+        let mut candidate = self.head[self.hash(position)];
+        // positions that are out of the window may have been overwritten already
+        for _ in 0..MAX_CHAIN {
+            if candidate == usize::MAX || position - candidate > WINDOW_SIZE {
+                break;
+            }
+            let length = self.data[candidate..]
+                .iter()
+                .zip(&self.data[position..position + max_length])
+                .take_while(|&(a, b)| a == b)
+                .count();
+            if length > best.0 {
+                best = (length, position - candidate);
+                if length == max_length {
+                    break;
+                }
+            }
+            candidate = self.previous[candidate % WINDOW_SIZE];
+        }
+        best
+    }
+}
+
+/// Writes a literal/length symbol with the fixed Huffman code.
+fn write_literal(out: &mut BitWriter, symbol: u16) {
+    let (code, length) = match symbol {
+        0..=143 => (0x30 + symbol, 8),
+        144..=255 => (0x190 + symbol - 144, 9),
+        256..=279 => (symbol - 256, 7),
+        _ => (0xc0 + symbol - 280, 8),
+    };
+    out.write_code(code, length);
+}
+
+fn write_length(out: &mut BitWriter, length: usize) {
+    let index = LENGTH_BASE
+        .iter()
+        .rposition(|&base| usize::from(base) <= length)
+        .expect("match length of at least 3");
+    write_literal(out, 257 + index as u16);
+    let extra = length - usize::from(LENGTH_BASE[index]);
+    out.write(extra as u32, LENGTH_EXTRA[index]);
+}
+
+fn write_distance(out: &mut BitWriter, distance: usize) {
+    let index = DISTANCE_BASE
+        .iter()
+        .rposition(|&base| usize::from(base) <= distance)
+        .expect("distance of at least 1");
+    // the fixed distance codes are the 5-bit symbols
+    out.write_code(index as u16, 5);
+    let extra = distance - usize::from(DISTANCE_BASE[index]);
+    out.write(extra as u32, DISTANCE_EXTRA[index]);
+}
+
+/// Decompresses the DEFLATE blocks of `input`.
+fn inflate(input: &mut BitReader) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    loop {
+        let last = input.read(1)? == 1;
+        match input.read(2)? {
+            0 => {
+                input.align();
+                let length = input.read(16)?;
+                if input.read(16)? != !length & 0xffff {
+                    return Err("the length of a stored DEFLATE block is invalid".into());
+                }
+                for _ in 0..length {
+                    out.push(input.read(8)? as u8);
+                }
+            }
+            1 => {
+                let mut lengths = [0u8; 288 + 30];
+                for (symbol, length) in lengths.iter_mut().enumerate() {
+                    *length = match symbol {
+                        0..=143 => 8,
+                        144..=255 => 9,
+                        256..=279 => 7,
+                        280..=287 => 8,
+                        _ => 5,
+                    };
+                }
+                let literals = Huffman::new(&lengths[..288])?;
+                let distances = Huffman::new(&lengths[288..])?;
+                inflate_block(input, &mut out, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = read_dynamic_codes(input)?;
+                inflate_block(input, &mut out, &literals, &distances)?;
+            }
+            _ => return Err("invalid DEFLATE block type".into()),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn read_dynamic_codes(input: &mut BitReader) -> Result<(Huffman, Huffman), String> {
+    let literal_count = input.read(5)? as usize + 257;
+    let distance_count = input.read(5)? as usize + 1;
+    let code_length_count = input.read(4)? as usize + 4;
+    let mut code_lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[symbol] = input.read(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths)?;
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let symbol = code_lengths.decode(input)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths
+                    .last()
+                    .ok_or("a DEFLATE code length repeats a missing length")?;
+                (previous, 3 + input.read(2)?)
+            }
+            17 => (0, 3 + input.read(3)?),
+            _ => (0, 11 + input.read(7)?),
+        };
+        for _ in 0..repeat {
+            lengths.push(value);
+        }
+    }
+    if lengths.len() != literal_count + distance_count {
+        return Err("the DEFLATE code lengths exceed the number of symbols".into());
+    }
+    Ok((
+        Huffman::new(&lengths[..literal_count])?,
+        Huffman::new(&lengths[literal_count..])?,
+    ))
+}
+
+fn inflate_block(
+    input: &mut BitReader,
+    out: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<(), String> {
+    loop {
+        let symbol = literals.decode(input)?;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            257..=285 => {
+                let index = usize::from(symbol - 257);
+                let length = usize::from(LENGTH_BASE[index])
+                    + input.read(LENGTH_EXTRA[index])? as usize;
+                let index = usize::from(distances.decode(input)?);
+                if index >= DISTANCE_BASE.len() {
+                    return Err("invalid DEFLATE distance symbol".into());
+                }
+                let distance = usize::from(DISTANCE_BASE[index])
+                    + input.read(DISTANCE_EXTRA[index])? as usize;
+                if distance > out.len() {
+                    return Err("a DEFLATE distance points in front of the data".into());
+                }
+                // the match may overlap the bytes that it produces
+                let from = out.len() - distance;
+                for i in 0..length {
+                    let byte = out[from + i];
+                    out.push(byte);
+                }
+            }
+            _ => return Err("invalid DEFLATE length symbol".into()),
+        }
+    }
+}
+
+/// A canonical Huffman code, given by the number of codes of each length and
+/// the symbols ordered by their codes.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman, String> {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[usize::from(length)] += 1;
+        }
+        counts[0] = 0;
+        // incomplete codes are allowed, but not more codes than fit into the lengths
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - i32::from(count);
+            if left < 0 {
+                return Err("a DEFLATE Huffman code is over-subscribed".into());
+            }
+        }
+        let mut offsets = [0u16; 16];
+        for length in 1..16 {
+            offsets[length] = offsets[length - 1] + counts[length - 1];
+        }
+        let mut symbols = vec![0; lengths.iter().filter(|&&length| length > 0).count()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length > 0 {
+                symbols[usize::from(offsets[usize::from(length)])] = symbol as u16;
+                offsets[usize::from(length)] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, input: &mut BitReader) -> Result<u16, String> {
+        // the codes of each length follow the codes of the shorter lengths
+        let mut code = 0;
+        let mut first = 0;
+        let mut index = 0;
+        for length in 1..16 {
+            code |= input.read(1)? as i32;
+            let count = i32::from(self.counts[length]);
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("invalid DEFLATE Huffman code".into())
+    }
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    count: u8,
+}
+
+impl BitWriter {
+    /// Writes the `count` lowest bits of `value`, starting with the least significant one.
+    fn write(&mut self, value: u32, count: u8) {
+        for i in 0..count {
+            self.buffer |= ((value >> i) & 1) << self.count;
+            self.count += 1;
+            if self.count == 8 {
+                self.bytes.push(self.buffer as u8);
+                self.buffer = 0;
+                self.count = 0;
+            }
+        }
+    }
+
+    /// Writes a Huffman code, which starts with its most significant bit.
+    fn write_code(&mut self, code: u16, length: u8) {
+        let reversed = u32::from(code.reverse_bits() >> (16 - length));
+        self.write(reversed, length);
+    }
+
+    fn flush(&mut self) {
+        if self.count > 0 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer = 0;
+            self.count = 0;
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    /// The number of bytes that were read.
+    position: usize,
+    buffer: u32,
+    count: u8,
+}
+
+impl<'a> BitReader<'a> {
+    /// Reads `count` bits, starting with the least significant one.
+    fn read(&mut self, count: u8) -> Result<u32, String> {
+        let mut value = 0;
+        for i in 0..count {
+            if self.count == 0 {
+                let byte = *self
+                    .bytes
+                    .get(self.position)
+                    .ok_or("the DEFLATE data is truncated")?;
+                self.buffer = u32::from(byte);
+                self.position += 1;
+                self.count = 8;
+            }
+            value |= (self.buffer & 1) << i;
+            self.buffer >>= 1;
+            self.count -= 1;
+        }
+        Ok(value)
+    }
+
+    /// Skips the remaining bits of the current byte.
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+}
@@ -1,19 +1,125 @@
 use std::process;
+use Error;
 
 const HELP: &str = include_str!("help.txt");
 const BUILD_HELP: &str = include_str!("build_help.txt");
 const RUN_HELP: &str = include_str!("run_help.txt");
+const TEST_HELP: &str = include_str!("test_help.txt");
+const DEBUG_HELP: &str = include_str!("debug_help.txt");
+const ADDR2LINE_HELP: &str = include_str!("addr2line_help.txt");
+const OBJDUMP_HELP: &str = include_str!("objdump_help.txt");
+const SIZE_HELP: &str = include_str!("size_help.txt");
+const REPLAY_HELP: &str = include_str!("replay_help.txt");
+const RUNNER_HELP: &str = include_str!("runner_help.txt");
+const INSPECT_HELP: &str = include_str!("inspect_help.txt");
+const EXTRACT_HELP: &str = include_str!("extract_help.txt");
+const VERIFY_HELP: &str = include_str!("verify_help.txt");
+const SERVE_HELP: &str = include_str!("serve_help.txt");
+const FLASH_HELP: &str = include_str!("flash_help.txt");
+const ATTACH_HELP: &str = include_str!("attach_help.txt");
+const WATCH_HELP: &str = include_str!("watch_help.txt");
+const CLEAN_HELP: &str = include_str!("clean_help.txt");
+const CHECK_HELP: &str = include_str!("check_help.txt");
+const CLIPPY_HELP: &str = include_str!("clippy_help.txt");
 
-pub(crate) fn help() {
+pub(crate) fn help() -> Result<(), Error> {
     print!("{}", HELP);
+    Ok(())
 }
 
-pub(crate) fn build_help() {
+pub(crate) fn build_help() -> Result<(), Error> {
     print!("{}", BUILD_HELP);
+    Ok(())
 }
 
-pub(crate) fn run_help() {
+pub(crate) fn run_help() -> Result<(), Error> {
     print!("{}", RUN_HELP);
+    Ok(())
+}
+
+pub(crate) fn test_help() -> Result<(), Error> {
+    print!("{}", TEST_HELP);
+    Ok(())
+}
+
+pub(crate) fn debug_help() -> Result<(), Error> {
+    print!("{}", DEBUG_HELP);
+    Ok(())
+}
+
+pub(crate) fn addr2line_help() -> Result<(), Error> {
+    print!("{}", ADDR2LINE_HELP);
+    Ok(())
+}
+
+pub(crate) fn objdump_help() -> Result<(), Error> {
+    print!("{}", OBJDUMP_HELP);
+    Ok(())
+}
+
+pub(crate) fn size_help() -> Result<(), Error> {
+    print!("{}", SIZE_HELP);
+    Ok(())
+}
+
+pub(crate) fn replay_help() -> Result<(), Error> {
+    print!("{}", REPLAY_HELP);
+    Ok(())
+}
+
+pub(crate) fn runner_help() -> Result<(), Error> {
+    print!("{}", RUNNER_HELP);
+    Ok(())
+}
+
+pub(crate) fn inspect_help() -> Result<(), Error> {
+    print!("{}", INSPECT_HELP);
+    Ok(())
+}
+
+pub(crate) fn extract_help() -> Result<(), Error> {
+    print!("{}", EXTRACT_HELP);
+    Ok(())
+}
+
+pub(crate) fn verify_help() -> Result<(), Error> {
+    print!("{}", VERIFY_HELP);
+    Ok(())
+}
+
+pub(crate) fn serve_help() -> Result<(), Error> {
+    print!("{}", SERVE_HELP);
+    Ok(())
+}
+
+pub(crate) fn flash_help() -> Result<(), Error> {
+    print!("{}", FLASH_HELP);
+    Ok(())
+}
+
+pub(crate) fn attach_help() -> Result<(), Error> {
+    print!("{}", ATTACH_HELP);
+    Ok(())
+}
+
+pub(crate) fn watch_help() -> Result<(), Error> {
+    print!("{}", WATCH_HELP);
+    Ok(())
+}
+
+pub(crate) fn clean_help() -> Result<(), Error> {
+    print!("{}", CLEAN_HELP);
+    Ok(())
+}
+
+pub(crate) fn check_help() -> Result<(), Error> {
+    print!("{}", CHECK_HELP);
+    Ok(())
+}
+
+pub(crate) fn clippy_help() -> Result<(), Error> {
+    print!("{}", CLIPPY_HELP);
+    Ok(())
 }
 
 pub(crate) fn no_subcommand() -> ! {
@@ -0,0 +1,214 @@
+//! Boots disk images in Hyper-V VMs on Windows, for hosts on which QEMU can't use
+//! hardware virtualization because Hyper-V owns it.
+//!
+//! The VM is a generation 1 VM, which boots from IDE disks with the BIOS, and is
+//! created on the first run and updated before every run through the Hyper-V
+//! cmdlets of PowerShell: the disk image is converted into a VHD image (ISO images
+//! are attached as a DVD instead) that replaces the previous one, and the first COM
+//! port is connected to a named pipe, which is read while the VM runs. Hyper-V
+//! only creates the pipe when the VM starts, so output that the kernel writes
+//! before the pipe is opened (usually a few milliseconds) is lost.
+
+use build::find_executable;
+use config::{Config, ExportFormat, Firmware, ImageFormat};
+use export;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use Error;
+
+/// The interval in which the state of the VM is polled, which runs PowerShell.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A started Hyper-V VM.
+pub(crate) struct Vm {
+    powershell: PathBuf,
+    name: String,
+    /// The named pipe that the first COM port is connected to.
+    serial: PathBuf,
+}
+
+/// Creates or updates the VM of the configuration with the image and starts it.
+///
+/// The VHD image is written to `work_dir`.
+pub(crate) fn start(config: &Config, work_dir: &Path) -> Result<Vm, Error> {
+    if !cfg!(windows) {
+        return Err(Error::Run(
+            "the `hyperv` run backend is only supported on Windows".into(),
+        ));
+    }
+    if config.firmware == Firmware::Uefi {
+        return Err(Error::Run(
+            "generation 1 Hyper-V VMs boot with the BIOS, use the `hybrid` format or \
+             `firmware = \"bios\"` to boot the image in them"
+                .into(),
+        ));
+    }
+    let powershell = find_executable("powershell", &[])
+        .or_else(|| find_executable("pwsh", &[]))
+        .ok_or_else(|| {
+            Error::Run("PowerShell, which manages Hyper-V VMs, was not found in `PATH`".into())
+        })?;
+    let name = config.hyperv.vm_name.clone().unwrap_or_else(|| {
+        config
+            .output
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "bootimage".into())
+    });
+    // Hyper-V resolves relative paths against its own working directory
+    fs::create_dir_all(work_dir)?;
+    let work_dir = work_dir.canonicalize()?;
+    let raw_image = match config.format {
+        ImageFormat::Multiboot2 => {
+            return Err(Error::Run(
+                "Hyper-V can't boot Multiboot2 kernels directly, use the `grub-iso` or `limine` \
+                 format to boot them"
+                    .into(),
+            ))
+        }
+        ImageFormat::GrubIso | ImageFormat::Isolinux => None,
+        _ => Some(config.raw_image_path()),
+    };
+    let vm = Vm {
+        powershell,
+        // backslashes are the only characters that pipe names can't contain
+        serial: PathBuf::from(format!(r"\\.\pipe\bootimage-{}", name.replace('\\', "-"))),
+        name,
+    };
+    let name = quote(&vm.name);
+
+    // e.g. the VM of a previous run that was interrupted, whose disk can't be
+    // replaced while it runs
+    let prepared = vm.powershell(&format!(
+        "$vm = Get-VM -Name {name} -ErrorAction SilentlyContinue
+         if (-not $vm) {{
+             New-VM -Name {name} -Generation 1 -NoVHD | Out-Null
+             'created'
+         }} elseif ($vm.State -eq 'Saved') {{
+             Remove-VMSavedState -VMName {name}
+         }} elseif ($vm.State -ne 'Off') {{
+             Stop-VM -Name {name} -TurnOff -Force
+             'turned off'
+         }}",
+        name = name,
+    ))?;
+    match prepared.trim() {
+        "created" => println!("Created Hyper-V VM `{}`", vm.name),
+        "turned off" => println!("Turned off the running Hyper-V VM `{}`", vm.name),
+        _ => {}
+    }
+
+    let memory = config.hyperv.memory;
+    let medium = match raw_image {
+        Some(raw_image) => {
+            let vhd = work_dir.join("hyperv.vhd");
+            export::export(&raw_image, ExportFormat::Vhd, &vhd)?;
+            format!(
+                "Add-VMHardDiskDrive -VMName {name} -ControllerType IDE -ControllerNumber 0 \
+                     -ControllerLocation 0 -Path {vhd}
+                 Get-VMDvdDrive -VMName {name} | Set-VMDvdDrive -Path $null
+                 Set-VMBios -VMName {name} -StartupOrder @('IDE', 'CD', 'LegacyNetworkAdapter', \
+                     'Floppy')",
+                name = name,
+                vhd = quote(&vhd.to_string_lossy()),
+            )
+        }
+        None => format!(
+            "if (-not (Get-VMDvdDrive -VMName {name})) {{
+                 Add-VMDvdDrive -VMName {name} -ControllerNumber 1 -ControllerLocation 0
+             }}
+             Get-VMDvdDrive -VMName {name} | Set-VMDvdDrive -Path {iso}
+             Set-VMBios -VMName {name} -StartupOrder @('CD', 'IDE', 'LegacyNetworkAdapter', \
+                 'Floppy')",
+            name = name,
+            iso = quote(&config.image_path().canonicalize()?.to_string_lossy()),
+        ),
+    };
+    println!("Starting Hyper-V VM `{}`", vm.name);
+    vm.powershell(&format!(
+        "Set-VMMemory -VMName {name} -DynamicMemoryEnabled $false -StartupBytes {memory}
+         Get-VMHardDiskDrive -VMName {name} | Remove-VMHardDiskDrive
+         {medium}
+         Set-VMComPort -VMName {name} -Number 1 -Path {serial}
+         Start-VM -Name {name}",
+        name = name,
+        memory = memory,
+        medium = medium,
+        serial = quote(&vm.serial.to_string_lossy()),
+    ))?;
+    if !config.hyperv.headless {
+        // the VM runs without a window, which is provided by the VM connection tool
+        Command::new("vmconnect").args(["localhost", &vm.name]).spawn()?;
+    }
+    Ok(vm)
+}
+
+impl Vm {
+    /// The named pipe that the serial output of the VM is written to, which
+    /// Hyper-V creates when the VM starts.
+    pub(crate) fn serial(&self) -> &Path {
+        &self.serial
+    }
+
+    /// Waits until the VM is turned off (e.g. by the kernel through ACPI) and turns
+    /// it off after `timeout`.
+    pub(crate) fn wait(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        let start = Instant::now();
+        loop {
+            match self.state()?.as_str() {
+                "Off" => return Ok(()),
+                "Saved" => {
+                    return Err(Error::Run(format!(
+                        "the Hyper-V VM `{}` was saved",
+                        self.name
+                    )))
+                }
+                _ => {}
+            }
+            if let Some(timeout) = timeout {
+                if start.elapsed() >= timeout {
+                    // a hung kernel would otherwise block forever
+                    self.powershell(&format!(
+                        "Stop-VM -Name {} -TurnOff -Force",
+                        quote(&self.name)
+                    ))?;
+                    return Err(Error::Timeout(timeout));
+                }
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// The state of the VM (e.g. `Running` or `Off`).
+    fn state(&self) -> Result<String, Error> {
+        let output = self.powershell(&format!("(Get-VM -Name {}).State", quote(&self.name)))?;
+        Ok(output.trim().to_owned())
+    }
+
+    /// Runs a PowerShell script, which stops at the first failing cmdlet, and
+    /// returns its output.
+    fn powershell(&self, script: &str) -> Result<String, Error> {
+        let output = Command::new(&self.powershell)
+            .args(["-NoProfile", "-NonInteractive", "-Command"])
+            .arg(format!("$ErrorActionPreference = 'Stop'\n{}", script))
+            .output()?;
+        if !output.status.success() {
+            return Err(Error::Run(format!(
+                "managing the Hyper-V VM `{}` failed ({}), note that this requires \
+                 administrator rights or membership in the `Hyper-V Administrators` group:\n{}",
+                self.name,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+/// Quotes a string as a literal PowerShell string, in which only `'` is special.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
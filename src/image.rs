@@ -0,0 +1,1084 @@
+//! Assembles disk images from the bootloader and the kernel.
+
+use byteorder::{ByteOrder, LittleEndian};
+use config::{Config, Filesystem, InfoBlockField, KernelCompression};
+use crc32;
+use ext2;
+use fat;
+use gpt;
+use gzip;
+use lz4;
+use mbr;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use xmas_elf::{self, program};
+use Error;
+
+pub(crate) const BLOCK_SIZE: usize = 512;
+pub(crate) type KernelInfoBlock = [u8; BLOCK_SIZE];
+
+/// The build metadata record is placed in the second half of the kernel info
+/// block, which the bootloader doesn't read. It consists of the magic, a 16-bit
+/// little-endian length, and `key=value` lines.
+pub(crate) const METADATA_OFFSET: usize = BLOCK_SIZE / 2;
+pub(crate) const METADATA_MAGIC: &[u8] = b"BIMETA01";
+const METADATA_HEADER_SIZE: usize = 10;
+
+/// Partitions start at multiples of 1 MiB (in bytes), as recommended for alignment.
+const PARTITION_ALIGNMENT: u64 = 1024 * 1024;
+/// The minimum size of the EFI system partition (in bytes).
+const ESP_MIN_SIZE: u64 = 64 * 1024 * 1024;
+/// The minimum size of the data partition with the `files` (in bytes), which is
+/// a FAT16 file system up to the minimum size of FAT32.
+const DATA_MIN_SIZE: u64 = 4 * 1024 * 1024;
+
+/// The version 2 kernel info block starts with a magic, so that bootloaders can
+/// tell it apart from the version 1 block, which only contains the kernel size.
+///
+/// Its header consists of the magic, the 16-bit version and flags, and the
+/// 32-bit kernel size, CRC-32 of the kernel, and offset and size of the initrd,
+/// all little-endian.
+pub(crate) const INFO_BLOCK_MAGIC: &[u8] = b"BTIB";
+const INFO_BLOCK_HEADER_SIZE: usize = 24;
+/// The kernel info block contains a build metadata record.
+pub(crate) const INFO_BLOCK_FLAG_METADATA: u16 = 1 << 0;
+/// The kernel info block contains a kernel command line.
+pub(crate) const INFO_BLOCK_FLAG_CMDLINE: u16 = 1 << 1;
+/// An initrd is appended to the kernel.
+pub(crate) const INFO_BLOCK_FLAG_INITRD: u16 = 1 << 2;
+/// The kernel is compressed, see `write_compression_info`.
+pub(crate) const INFO_BLOCK_FLAG_COMPRESSED: u16 = 1 << 3;
+
+/// The compression record directly follows the header of a version 2 kernel
+/// info block: the 32-bit size of the uncompressed kernel and the 8-bit
+/// compression algorithm (`1` for an LZ4 frame, `2` for gzip).
+const COMPRESSION_OFFSET: usize = INFO_BLOCK_HEADER_SIZE;
+const COMPRESSION_SIZE: usize = 8;
+
+/// The kernel command line is a NUL-terminated string in the first half of the
+/// kernel info block, between the `info-block` fields behind the header and the
+/// build metadata record.
+pub(crate) const CMDLINE_OFFSET: usize = 64;
+
+/// The fields of a kernel info block.
+pub(crate) struct InfoBlock {
+    pub version: u16,
+    pub flags: u16,
+    pub kernel_size: u32,
+    /// The CRC-32 of the kernel, which only version 2 blocks contain.
+    pub kernel_crc: Option<u32>,
+    /// The offset of the initrd from the start of the kernel and its size.
+    pub initrd: Option<(u32, u32)>,
+    /// The compression of the kernel and its uncompressed size.
+    pub compression: Option<(KernelCompression, u32)>,
+}
+
+/// An entry of the boot menu of bootloaders like GRUB, Limine, and SYSLINUX.
+pub(crate) struct BootEntry {
+    pub title: String,
+    /// The kernel ELF file or, for bootloaders that boot it with `memdisk`, the
+    /// raw disk image with the kernel.
+    pub data: Vec<u8>,
+    /// The command line that the bootloader passes to the kernel.
+    pub cmdline: Option<String>,
+}
+
+/// Creates a kernel info block of the given `version` (1 or 2) for `kernel`.
+pub(crate) fn create_kernel_info_block(
+    kernel: &[u8],
+    version: u16,
+) -> Result<KernelInfoBlock, String> {
+    if kernel.len() as u64 > u64::from(u32::MAX) {
+        return Err(format!(
+            "the kernel is {} bytes large, but the kernel info block records its size in 32 \
+             bits, so the BIOS bootloader can only load kernels below 4 GiB",
+            kernel.len()
+        ));
+    }
+    let kernel_size = kernel.len() as u32;
+
+    let mut kernel_info_block = [0u8; BLOCK_SIZE];
+    if version == 1 {
+        LittleEndian::write_u32(&mut kernel_info_block[0..4], kernel_size);
+    } else {
+        kernel_info_block[0..4].copy_from_slice(INFO_BLOCK_MAGIC);
+        LittleEndian::write_u16(&mut kernel_info_block[4..6], version);
+        LittleEndian::write_u32(&mut kernel_info_block[8..12], kernel_size);
+        LittleEndian::write_u32(&mut kernel_info_block[12..16], crc32::crc32(kernel));
+    }
+
+    Ok(kernel_info_block)
+}
+
+/// Parses a kernel info block, `None` if `block` isn't one.
+///
+/// The rest of the block may contain the build metadata record and the
+/// `info-block` fields of the configuration, so it isn't checked.
+pub(crate) fn parse_kernel_info_block(block: &[u8]) -> Option<InfoBlock> {
+    let block = block.get(..BLOCK_SIZE)?;
+    let info_block = if &block[0..4] == INFO_BLOCK_MAGIC {
+        let flags = LittleEndian::read_u16(&block[6..8]);
+        InfoBlock {
+            version: LittleEndian::read_u16(&block[4..6]),
+            flags,
+            kernel_size: LittleEndian::read_u32(&block[8..12]),
+            kernel_crc: Some(LittleEndian::read_u32(&block[12..16])),
+            initrd: Some((
+                LittleEndian::read_u32(&block[16..20]),
+                LittleEndian::read_u32(&block[20..24]),
+            ))
+            .filter(|_| flags & INFO_BLOCK_FLAG_INITRD != 0),
+            compression: compression_algorithm(block[COMPRESSION_OFFSET + 4])
+                .map(|compression| {
+                    (compression, LittleEndian::read_u32(&block[COMPRESSION_OFFSET..][..4]))
+                })
+                .filter(|_| flags & INFO_BLOCK_FLAG_COMPRESSED != 0),
+        }
+    } else {
+        InfoBlock {
+            version: 1,
+            flags: 0,
+            kernel_size: LittleEndian::read_u32(&block[0..4]),
+            kernel_crc: None,
+            initrd: None,
+            compression: None,
+        }
+    };
+    Some(info_block).filter(|info_block| info_block.version >= 1 && info_block.kernel_size > 0)
+}
+
+/// Writes the `info-block` fields of the configuration into the kernel info block.
+///
+/// The fields must not overlap each other, the header of the block, the kernel
+/// command line, or the build metadata record, so this is called after those
+/// are written.
+pub(crate) fn write_info_block_fields(
+    kernel_info_block: &mut KernelInfoBlock,
+    fields: &BTreeMap<String, InfoBlockField>,
+) -> Result<(), String> {
+    let mut reserved = vec![(
+        "the header of the kernel info block".to_owned(),
+        0..if &kernel_info_block[0..4] == INFO_BLOCK_MAGIC {
+            INFO_BLOCK_HEADER_SIZE
+        } else {
+            4
+        },
+    )];
+    if &kernel_info_block[0..4] == INFO_BLOCK_MAGIC
+        && LittleEndian::read_u16(&kernel_info_block[6..8]) & INFO_BLOCK_FLAG_COMPRESSED != 0
+    {
+        reserved.push((
+            "the compression record".to_owned(),
+            COMPRESSION_OFFSET..COMPRESSION_OFFSET + COMPRESSION_SIZE,
+        ));
+    }
+    if kernel_info_block[CMDLINE_OFFSET] != 0 {
+        reserved.push(("the kernel command line".to_owned(), CMDLINE_OFFSET..METADATA_OFFSET));
+    }
+    if kernel_info_block[METADATA_OFFSET..].starts_with(METADATA_MAGIC) {
+        reserved.push(("the build metadata record".to_owned(), METADATA_OFFSET..BLOCK_SIZE));
+    }
+    for (name, field) in fields {
+        let range = field.offset..field.offset + field.size;
+        if let Some((other, _)) = reserved
+            .iter()
+            .find(|(_, other)| range.start < other.end && other.start < range.end)
+        {
+            return Err(format!(
+                "the kernel info block field `{}` ({} bytes at offset {}) overlaps {}",
+                name, field.size, field.offset, other
+            ));
+        }
+        let mut bytes = [0u8; 8];
+        LittleEndian::write_u64(&mut bytes, field.value);
+        kernel_info_block[range.clone()].copy_from_slice(&bytes[..field.size]);
+        reserved.push((format!("the field `{}`", name), range));
+    }
+    Ok(())
+}
+
+/// Writes the build metadata record into the kernel info block.
+pub(crate) fn write_metadata(
+    kernel_info_block: &mut KernelInfoBlock,
+    metadata: &[(&str, String)],
+) -> Result<(), String> {
+    let mut record = String::new();
+    for &(key, ref value) in metadata {
+        record.push_str(&format!("{}={}\n", key, value));
+    }
+    let space = BLOCK_SIZE - METADATA_OFFSET - METADATA_HEADER_SIZE;
+    if record.len() > space {
+        return Err(format!(
+            "the build metadata takes {} bytes, but only {} bytes fit into the kernel info block",
+            record.len(),
+            space
+        ));
+    }
+    let block = &mut kernel_info_block[METADATA_OFFSET..];
+    block[..8].copy_from_slice(METADATA_MAGIC);
+    LittleEndian::write_u16(&mut block[8..10], record.len() as u16);
+    block[METADATA_HEADER_SIZE..][..record.len()].copy_from_slice(record.as_bytes());
+    set_flag(kernel_info_block, INFO_BLOCK_FLAG_METADATA);
+    Ok(())
+}
+
+/// Writes the kernel command line into the kernel info block.
+pub(crate) fn write_cmdline(
+    kernel_info_block: &mut KernelInfoBlock,
+    cmdline: &str,
+) -> Result<(), String> {
+    // one byte is left for the terminating NUL
+    let space = METADATA_OFFSET - CMDLINE_OFFSET - 1;
+    if cmdline.is_empty() || cmdline.len() > space {
+        return Err(format!(
+            "the kernel command line must be between 1 and {} bytes long, found {} bytes",
+            space,
+            cmdline.len()
+        ));
+    }
+    if cmdline.contains('\0') {
+        return Err("the kernel command line must not contain NUL characters".into());
+    }
+    kernel_info_block[CMDLINE_OFFSET..][..cmdline.len()].copy_from_slice(cmdline.as_bytes());
+    set_flag(kernel_info_block, INFO_BLOCK_FLAG_CMDLINE);
+    Ok(())
+}
+
+/// Replaces the kernel command line of an existing kernel info block, e.g. of a
+/// disk image that is served by `bootimage serve`.
+pub(crate) fn replace_cmdline(
+    kernel_info_block: &mut KernelInfoBlock,
+    cmdline: &str,
+) -> Result<(), String> {
+    let has_cmdline = read_cmdline(kernel_info_block).is_some();
+    let region = &mut kernel_info_block[CMDLINE_OFFSET..METADATA_OFFSET];
+    if !has_cmdline && region.iter().any(|&b| b != 0) {
+        return Err(
+            "the kernel info block has `info-block` fields where the command line is stored"
+                .into(),
+        );
+    }
+    region.iter_mut().for_each(|b| *b = 0);
+    write_cmdline(kernel_info_block, cmdline)
+}
+
+/// Reads the kernel command line of a kernel info block, `None` if it has none.
+///
+/// Version 1 blocks have no flags, so their command line is only recognized if
+/// it is printable text.
+pub(crate) fn read_cmdline(kernel_info_block: &[u8]) -> Option<String> {
+    let block = kernel_info_block.get(..BLOCK_SIZE)?;
+    if &block[0..4] == INFO_BLOCK_MAGIC
+        && LittleEndian::read_u16(&block[6..8]) & INFO_BLOCK_FLAG_CMDLINE == 0
+    {
+        return None;
+    }
+    let region = &block[CMDLINE_OFFSET..METADATA_OFFSET];
+    let length = region.iter().position(|&b| b == 0)?;
+    let cmdline = &region[..length];
+    if cmdline.is_empty() || cmdline.iter().any(|&b| !(0x20..0x7f).contains(&b)) {
+        return None;
+    }
+    Some(String::from_utf8_lossy(cmdline).into_owned())
+}
+
+/// Records the initrd that is appended to the kernel in the kernel info block.
+///
+/// The initrd starts at the next block behind the kernel, so its offset from the
+/// start of the kernel is the kernel size rounded up to whole blocks.
+pub(crate) fn write_initrd_info(
+    kernel_info_block: &mut KernelInfoBlock,
+    kernel_size: u64,
+    initrd_size: u64,
+) -> Result<(), String> {
+    if &kernel_info_block[0..4] != INFO_BLOCK_MAGIC {
+        return Err("`initrd` requires `info-block-version = 2`, since version 1 kernel info \
+                    blocks can't record the location of the initrd"
+            .into());
+    }
+    let offset = round_up(kernel_size, BLOCK_SIZE as u64);
+    if offset > u64::from(u32::MAX) || initrd_size > u64::from(u32::MAX) {
+        return Err("the kernel and the initrd must be smaller than 4 GiB".into());
+    }
+    LittleEndian::write_u32(&mut kernel_info_block[16..20], offset as u32);
+    LittleEndian::write_u32(&mut kernel_info_block[20..24], initrd_size as u32);
+    set_flag(kernel_info_block, INFO_BLOCK_FLAG_INITRD);
+    Ok(())
+}
+
+/// Records the compression of the kernel and its uncompressed size in the kernel
+/// info block, whose kernel size and CRC-32 refer to the compressed kernel.
+pub(crate) fn write_compression_info(
+    kernel_info_block: &mut KernelInfoBlock,
+    compression: KernelCompression,
+    uncompressed_size: u64,
+) -> Result<(), String> {
+    if &kernel_info_block[0..4] != INFO_BLOCK_MAGIC {
+        return Err("`kernel-compression` requires `info-block-version = 2`, since version 1 \
+                    kernel info blocks can't record the compression of the kernel"
+            .into());
+    }
+    if uncompressed_size > u64::from(u32::MAX) {
+        return Err("the uncompressed kernel must be smaller than 4 GiB".into());
+    }
+    let algorithm = match compression {
+        KernelCompression::None => return Ok(()),
+        KernelCompression::Lz4 => 1,
+        KernelCompression::Gzip => 2,
+    };
+    let record = &mut kernel_info_block[COMPRESSION_OFFSET..][..COMPRESSION_SIZE];
+    LittleEndian::write_u32(&mut record[..4], uncompressed_size as u32);
+    record[4] = algorithm;
+    set_flag(kernel_info_block, INFO_BLOCK_FLAG_COMPRESSED);
+    Ok(())
+}
+
+fn compression_algorithm(algorithm: u8) -> Option<KernelCompression> {
+    match algorithm {
+        1 => Some(KernelCompression::Lz4),
+        2 => Some(KernelCompression::Gzip),
+        _ => None,
+    }
+}
+
+/// Compresses the kernel for `kernel-compression`.
+pub(crate) fn compress_kernel(kernel: &[u8], compression: KernelCompression) -> Vec<u8> {
+    match compression {
+        KernelCompression::None => kernel.to_vec(),
+        KernelCompression::Lz4 => lz4::compress(kernel),
+        KernelCompression::Gzip => gzip::compress(kernel),
+    }
+}
+
+/// Decompresses a kernel that was compressed with `compression`.
+pub(crate) fn decompress_kernel(
+    kernel: &[u8],
+    compression: KernelCompression,
+) -> Result<Vec<u8>, String> {
+    match compression {
+        KernelCompression::None => Ok(kernel.to_vec()),
+        KernelCompression::Lz4 => lz4::decompress(kernel),
+        KernelCompression::Gzip => gzip::decompress(kernel),
+    }
+}
+
+/// Appends the initrd to the image behind the padded kernel and pads it to whole
+/// blocks with `pad_byte`.
+fn write_initrd(output: &mut File, initrd: &Path, pad_byte: u8) -> Result<(), Error> {
+    let initrd_size = io::copy(&mut File::open(initrd)?, output)?;
+    write_block_padding(output, initrd_size, pad_byte)?;
+    Ok(())
+}
+
+/// Pads data of `size` bytes that was just written to `output` to whole blocks.
+fn write_block_padding(output: &mut File, size: u64, pad_byte: u8) -> io::Result<()> {
+    let padding_size = ((512 - (size % 512)) % 512) as usize;
+    output.write_all(&[pad_byte; 512][..padding_size])
+}
+
+/// Extends `output` to `size` bytes with `pad_byte`, and positions it at the end.
+///
+/// Zero padding is left as a hole, like the free space of the partitions.
+fn pad_to(output: &mut File, size: u64, pad_byte: u8) -> io::Result<()> {
+    let end = output.seek(SeekFrom::End(0))?;
+    if end >= size {
+        return Ok(());
+    }
+    if pad_byte == 0 {
+        output.set_len(size)?;
+    } else {
+        let padding = vec![pad_byte; 1 << 20];
+        let mut remaining = size - end;
+        while remaining > 0 {
+            let n = cmp::min(remaining, padding.len() as u64);
+            output.write_all(&padding[..n as usize])?;
+            remaining -= n;
+        }
+    }
+    output.seek(SeekFrom::End(0))?;
+    Ok(())
+}
+
+/// Rounds `value` up to a multiple of `align`.
+pub(crate) fn round_up(value: u64, align: u64) -> u64 {
+    value.div_ceil(align) * align
+}
+
+/// Pads `output` to the `minimum-image-size` and then to a multiple of the
+/// `image-alignment`.
+fn pad_image(output: &mut File, config: &Config) -> io::Result<()> {
+    let size = output.metadata()?.len();
+    let size = cmp::max(size, config.minimum_image_size.unwrap_or(0));
+    let size = round_up(size, config.image_alignment);
+    pad_to(output, size, config.pad_byte)
+}
+
+/// Checks that the kernel behind `bootloader_data` and the kernel info block
+/// starts at a multiple of the `kernel-alignment`.
+///
+/// The bootloader reads the kernel info block directly behind itself, so the
+/// kernel can't be moved and the bootloader has to be linked accordingly.
+fn check_kernel_alignment(config: &Config, bootloader_data: &[u8]) -> Result<(), Error> {
+    let block_size = BLOCK_SIZE as u64;
+    let kernel_offset = round_up(bootloader_data.len() as u64, block_size) + block_size;
+    if !kernel_offset.is_multiple_of(config.kernel_alignment) {
+        return Err(Error::Image(format!(
+            "the kernel starts at {:#x}, which is not a multiple of the `kernel-alignment` \
+             ({} bytes), so the bootloader has to end {} bytes before such a boundary",
+            kernel_offset, config.kernel_alignment, BLOCK_SIZE
+        )));
+    }
+    Ok(())
+}
+
+/// Sets a flag of a version 2 kernel info block, version 1 blocks have no flags.
+fn set_flag(kernel_info_block: &mut KernelInfoBlock, flag: u16) {
+    if &kernel_info_block[0..4] == INFO_BLOCK_MAGIC {
+        let flags = LittleEndian::read_u16(&kernel_info_block[6..8]) | flag;
+        LittleEndian::write_u16(&mut kernel_info_block[6..8], flags);
+    }
+}
+
+/// Reads the build metadata record of a kernel info block, `None` if it has none.
+pub(crate) fn read_metadata(kernel_info_block: &[u8]) -> Option<Vec<(String, String)>> {
+    let block = kernel_info_block.get(METADATA_OFFSET..BLOCK_SIZE)?;
+    if &block[..8] != METADATA_MAGIC {
+        return None;
+    }
+    let length = usize::from(LittleEndian::read_u16(&block[8..10]));
+    let record = block[METADATA_HEADER_SIZE..].get(..length)?;
+    let record = String::from_utf8_lossy(record);
+    let metadata = record
+        .lines()
+        .filter_map(|line| {
+            let split = line.find('=')?;
+            Some((line[..split].to_owned(), line[split + 1..].to_owned()))
+        })
+        .collect();
+    Some(metadata)
+}
+
+pub(crate) fn create_disk_image(
+    config: &Config,
+    mut kernel: File,
+    kernel_info_block: KernelInfoBlock,
+    bootloader_data: &[u8],
+) -> Result<(), Error> {
+    check_kernel_alignment(config, bootloader_data)?;
+    let files = data_partition(config, bootloader_data)?;
+    if config.mbr_partition_table {
+        check_partition_table_area(bootloader_data, "no MBR partition table can be added")?;
+    }
+    // reproducible builds always write the whole image in the same order, and the
+    // partition table changes with the size of the kernel
+    let existing = if config.reproducible || files.is_some() || config.mbr_partition_table {
+        None
+    } else {
+        open_for_update(&config.output, bootloader_data)?
+    };
+    let mut output = match existing {
+        // only the kernel changed, so the bootloader is kept and the rest is rewritten
+        Some(output) => {
+            println!("Updating disk image at {}", config.output.display());
+            output
+        }
+        None => {
+            println!("Creating disk image at {}", config.output.display());
+            let mut output = File::create(&config.output)?;
+            output.write_all(bootloader_data)?;
+            output
+        }
+    };
+    output.write_all(&kernel_info_block)?;
+
+    // write out kernel elf file
+    let kernel_size = kernel.metadata()?.len();
+    let mut buffer = [0u8; 1024];
+    loop {
+        let (n, interrupted) = match kernel.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => (n, false),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (0, true),
+            Err(e) => Err(e)?,
+        };
+        if !interrupted {
+            output.write_all(&buffer[..n])?
+        }
+    }
+
+    write_block_padding(&mut output, kernel_size, config.pad_byte)?;
+    if let Some(ref initrd) = config.initrd {
+        write_initrd(&mut output, initrd, config.pad_byte)?;
+    }
+    // an updated image may contain the rest of a larger kernel
+    let end = output.stream_position()?;
+    output.set_len(end)?;
+    // the kernel partition starts with the kernel info block
+    let kernel_lba = bootloader_data.len() as u64 / BLOCK_SIZE as u64;
+    if config.mbr_partition_table {
+        write_kernel_partition(&mut output, kernel_lba)?;
+    }
+    if let Some(ref files) = files {
+        append_data_partition(&mut output, files, data_partition_index(config), config.pad_byte)?;
+    }
+    pad_image(&mut output, config)?;
+
+    Ok(())
+}
+
+/// Opens an existing disk image at `path` for writing if it starts with
+/// `bootloader_data`, positioned right behind the bootloader.
+///
+/// Rewriting only the kernel is much faster than recreating images with a large
+/// `minimum-image-size`.
+fn open_for_update(path: &Path, bootloader_data: &[u8]) -> io::Result<Option<File>> {
+    let mut output = match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(output) => output,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut existing = Vec::with_capacity(bootloader_data.len());
+    (&mut output)
+        .take(bootloader_data.len() as u64)
+        .read_to_end(&mut existing)?;
+    if existing != bootloader_data {
+        return Ok(None);
+    }
+    Ok(Some(output))
+}
+
+/// Creates a disk image from a bootloader ELF file that already contains the kernel.
+///
+/// Like `objcopy -O binary`, the loadable segments are placed at their physical
+/// address relative to the lowest one.
+pub(crate) fn create_flat_disk_image(config: &Config, bootloader_elf: &[u8]) -> Result<(), Error> {
+    let elf_file = xmas_elf::ElfFile::new(bootloader_elf)
+        .map_err(|err| Error::Image(format!("invalid bootloader ELF file: {}", err)))?;
+    let segments: Vec<_> = elf_file
+        .program_iter()
+        .filter(|ph| ph.get_type() == Ok(program::Type::Load) && ph.file_size() > 0)
+        .collect();
+    let base = segments
+        .iter()
+        .map(|ph| ph.physical_addr())
+        .min()
+        .ok_or_else(|| Error::Image("the bootloader has no loadable segments".into()))?;
+
+    let mut data = Vec::new();
+    for ph in &segments {
+        let start = (ph.physical_addr() - base) as usize;
+        let end = start + ph.file_size() as usize;
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        let offset = ph.offset() as usize;
+        data[start..end].copy_from_slice(&bootloader_elf[offset..offset + ph.file_size() as usize]);
+    }
+    let padded_size = data.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+    data.resize(padded_size, config.pad_byte);
+    let files = data_partition(config, &data)?;
+    if config.mbr_partition_table {
+        check_partition_table_area(&data, "no MBR partition table can be added")?;
+    }
+
+    println!("Creating disk image at {}", config.output.display());
+
+    let mut output = File::create(&config.output)?;
+    output.write_all(&data)?;
+    // the kernel is embedded into the bootloader, so the kernel partition covers
+    // everything behind the first sector
+    if config.mbr_partition_table && data.len() > BLOCK_SIZE {
+        write_kernel_partition(&mut output, 1)?;
+    }
+    if let Some(ref files) = files {
+        append_data_partition(&mut output, files, data_partition_index(config), config.pad_byte)?;
+    }
+    pad_image(&mut output, config)?;
+
+    Ok(())
+}
+
+/// Creates a GPT-partitioned disk image whose EFI system partition contains the
+/// UEFI loader (as `EFI/BOOT/BOOTX64.EFI`) and the kernel (as `KERNEL.ELF`).
+pub(crate) fn create_uefi_disk_image(config: &Config, mut kernel: File) -> Result<(), Error> {
+    let mut kernel_elf = Vec::new();
+    kernel.read_to_end(&mut kernel_elf)?;
+    let esp = efi_system_partition(config, &kernel_elf)?;
+
+    println!("Creating disk image at {}", config.output.display());
+
+    let mut output = File::create(&config.output)?;
+    write_gpt_disk(
+        &mut output,
+        config,
+        &kernel_elf,
+        &esp,
+        2,
+        gpt::first_usable_lba(config.sector_size),
+    )
+}
+
+/// The file name of the kernel of the boot menu entry `index`: `kernel.elf` for
+/// the first one, `kernel-1.elf` for the second one, and so on.
+pub(crate) fn menu_kernel_name(index: usize) -> String {
+    match index {
+        0 => "kernel.elf".into(),
+        index => format!("kernel-{}.elf", index),
+    }
+}
+
+/// Creates a disk image for the Limine bootloader, whose MBR partition table has
+/// an active FAT partition at 1 MiB with the `boot_files` and, for `files` or a
+/// `data-directory`, a data partition behind it.
+///
+/// The boot code is installed into the image afterwards with `limine bios-install`,
+/// so the first sector only contains the partition table and the boot signature.
+pub(crate) fn create_limine_disk_image(
+    config: &Config,
+    boot_files: &fat::Directory,
+) -> Result<(), Error> {
+    let block_size = BLOCK_SIZE as u64;
+    let start = PARTITION_ALIGNMENT / block_size;
+    let size = fat_size(boot_files, DATA_MIN_SIZE, block_size);
+    let sectors = size / block_size;
+    let files = data_files(config)?;
+
+    println!("Creating disk image at {}", config.output.display());
+
+    let mut output = File::create(&config.output)?;
+    output.set_len((start + sectors) * block_size)?;
+    let offset = start * block_size;
+    fat::write_fat(&mut output, offset, size, block_size, start as u32, "LIMINE", boot_files)?;
+    let partition = mbr::Partition {
+        active: true,
+        partition_type: fat::FatType::for_size(size, block_size).mbr_partition_type(),
+        first_lba: start,
+        sectors,
+    };
+    mbr::write_partition(&mut output, 0, &partition)?;
+    output.seek(SeekFrom::Start(510))?;
+    output.write_all(&[0x55, 0xaa])?;
+    output.seek(SeekFrom::End(0))?;
+    if let Some(ref files) = files {
+        append_data_partition(&mut output, files, 1, config.pad_byte)?;
+    }
+    pad_image(&mut output, config)?;
+
+    Ok(())
+}
+
+/// Creates a disk image that boots on both BIOS and UEFI firmware.
+///
+/// The BIOS boots the first sector of the bootloader at LBA 0, which also holds
+/// the protective MBR, while UEFI firmware boots the loader on the EFI system
+/// partition. Since the GPT header occupies LBA 1, the remaining sectors of the
+/// bootloader are moved behind the kernel and the disk address packet that the
+/// first stage uses to load them is patched to point there. The kernel info
+/// block and the kernel stay at their usual offset, so the bootloader finds
+/// them as before.
+pub(crate) fn create_hybrid_disk_image(
+    config: &Config,
+    mut kernel: File,
+    kernel_info_block: KernelInfoBlock,
+    bootloader_data: &[u8],
+) -> Result<(), Error> {
+    let mut kernel_elf = Vec::new();
+    kernel.read_to_end(&mut kernel_elf)?;
+    let esp = efi_system_partition(config, &kernel_elf)?;
+    check_kernel_alignment(config, bootloader_data)?;
+
+    let block_size = BLOCK_SIZE as u64;
+    let bootloader_sectors = (bootloader_data.len() as u64).div_ceil(block_size);
+    if bootloader_sectors < 2 {
+        return Err(Error::Image(
+            "hybrid images require a bootloader that spans multiple sectors".into(),
+        ));
+    }
+    let mut first_sector = [0u8; BLOCK_SIZE];
+    first_sector.copy_from_slice(&bootloader_data[..BLOCK_SIZE]);
+    if first_sector[446..510].iter().any(|&b| b != 0) {
+        return Err(Error::Image(
+            "the first bootloader sector uses the MBR partition table area".into(),
+        ));
+    }
+    let dap_offset = find_disk_address_packet(&first_sector)?;
+
+    // LBA 0: boot sector, LBA 1: GPT header, then the kernel at its usual offset,
+    // followed by the initrd
+    let kernel_sectors = (kernel_elf.len() as u64).div_ceil(block_size);
+    let initrd_sectors = match config.initrd {
+        Some(ref initrd) => fs::metadata(initrd)?.len().div_ceil(block_size),
+        None => 0,
+    };
+    let relocated_lba = bootloader_sectors + 1 + kernel_sectors + initrd_sectors;
+    let mut bios_end = relocated_lba + bootloader_sectors - 1;
+    // reuse the gap left by the moved bootloader sectors for the GPT entries if possible
+    let entry_sectors = gpt::entry_sectors(block_size);
+    let entries_lba = if bootloader_sectors - 2 >= entry_sectors {
+        2
+    } else {
+        bios_end += entry_sectors;
+        bios_end - entry_sectors
+    };
+    LittleEndian::write_u64(
+        &mut first_sector[dap_offset + 8..dap_offset + 16],
+        relocated_lba,
+    );
+
+    println!("Creating disk image at {}", config.output.display());
+
+    let mut output = File::create(&config.output)?;
+    output.write_all(&first_sector)?;
+    output.seek(SeekFrom::Start(bootloader_sectors * block_size))?;
+    output.write_all(&kernel_info_block)?;
+    output.write_all(&kernel_elf)?;
+    write_block_padding(&mut output, kernel_elf.len() as u64, config.pad_byte)?;
+    if let Some(ref initrd) = config.initrd {
+        write_initrd(&mut output, initrd, config.pad_byte)?;
+    }
+    output.seek(SeekFrom::Start(relocated_lba * block_size))?;
+    output.write_all(&bootloader_data[BLOCK_SIZE..])?;
+
+    write_gpt_disk(
+        &mut output,
+        config,
+        &kernel_elf,
+        &esp,
+        entries_lba,
+        bios_end,
+    )
+}
+
+/// Finds the disk address packet in the first bootloader sector that loads the
+/// rest of the bootloader, which is recognizable by its start LBA of 1.
+fn find_disk_address_packet(sector: &[u8; BLOCK_SIZE]) -> Result<usize, Error> {
+    let candidates: Vec<usize> = (0..446 - 16)
+        .filter(|&i| {
+            sector[i] == 0x10 && sector[i + 1] == 0
+                && LittleEndian::read_u64(&sector[i + 8..i + 16]) == 1
+        })
+        .collect();
+    match candidates.len() {
+        1 => Ok(candidates[0]),
+        _ => Err(Error::Image(
+            "could not locate the disk address packet in the first bootloader sector, \
+             so the bootloader can't be relocated for a hybrid image"
+                .into(),
+        )),
+    }
+}
+
+/// The contents of the data partition in the configured file system.
+enum DataPartition {
+    Fat(fat::Directory),
+    Ext2(ext2::Directory),
+}
+
+impl DataPartition {
+    /// The size of the partition (in bytes), which leaves room for growth.
+    fn size(&self, sector_size: u64) -> u64 {
+        match *self {
+            DataPartition::Fat(ref files) => fat_size(files, DATA_MIN_SIZE, sector_size),
+            DataPartition::Ext2(ref files) => {
+                cmp::max(DATA_MIN_SIZE, (files.size() * 2).div_ceil(1 << 20) << 20)
+            }
+        }
+    }
+
+    fn add_file(&mut self, path: &str, data: Vec<u8>) -> Result<(), String> {
+        match *self {
+            DataPartition::Fat(ref mut files) => files.add_file(path, data),
+            DataPartition::Ext2(ref mut files) => files.add_file(path, data),
+        }
+    }
+
+    fn add_directory(&mut self, path: &str) -> Result<(), String> {
+        match *self {
+            DataPartition::Fat(ref mut files) => files.add_directory(path),
+            DataPartition::Ext2(ref mut files) => files.add_directory(path),
+        }
+    }
+
+    /// Writes the file system of `size` bytes at the sector `start` of `output`.
+    fn write(&self, output: &mut File, sector_size: u64, start: u64, size: u64) -> io::Result<()> {
+        let offset = start * sector_size;
+        match *self {
+            DataPartition::Fat(ref files) => {
+                fat::write_fat(output, offset, size, sector_size, start as u32, "DATA", files)
+            }
+            DataPartition::Ext2(ref files) => ext2::write_ext2(output, offset, size, "DATA", files),
+        }
+    }
+
+    /// The type of the partition in an MBR partition table.
+    fn mbr_partition_type(&self, size: u64) -> u8 {
+        match *self {
+            DataPartition::Fat(_) => {
+                fat::FatType::for_size(size, BLOCK_SIZE as u64).mbr_partition_type()
+            }
+            DataPartition::Ext2(_) => mbr::LINUX,
+        }
+    }
+
+    /// The type of the partition in a GUID partition table.
+    fn gpt_partition_type(&self) -> gpt::Guid {
+        match *self {
+            DataPartition::Fat(_) => gpt::BASIC_DATA_PARTITION,
+            DataPartition::Ext2(_) => gpt::LINUX_FILESYSTEM,
+        }
+    }
+}
+
+/// Reads the `files` and the `data-partition` directory of the configuration
+/// into the tree of the data partition, `None` if there are none.
+///
+/// The partition is added to the MBR partition table in the first sector of
+/// `bootloader_data`, which must not be used by the bootloader.
+fn data_partition(config: &Config, bootloader_data: &[u8]) -> Result<Option<DataPartition>, Error> {
+    let files = match data_files(config)? {
+        Some(files) => files,
+        None => return Ok(None),
+    };
+    check_partition_table_area(bootloader_data, "no data partition can be added")?;
+    Ok(Some(files))
+}
+
+/// Checks that the MBR partition table area of the first sector of
+/// `bootloader_data` is unused, so that partitions can be added.
+fn check_partition_table_area(bootloader_data: &[u8], consequence: &str) -> Result<(), Error> {
+    let table = bootloader_data.get(mbr::TABLE_OFFSET..510);
+    if table.is_none_or(|table| table.iter().any(|&b| b != 0)) {
+        return Err(Error::Image(format!(
+            "the first bootloader sector uses the MBR partition table area, so {}",
+            consequence
+        )));
+    }
+    Ok(())
+}
+
+/// The data partition follows the kernel partition in the MBR partition table.
+fn data_partition_index(config: &Config) -> usize {
+    if config.mbr_partition_table {
+        1
+    } else {
+        0
+    }
+}
+
+/// Adds the active kernel partition for `mbr-partition-table`, which covers the
+/// sectors from `first_lba` up to the current end of `output`, as the first entry
+/// of the MBR partition table.
+fn write_kernel_partition(output: &mut File, first_lba: u64) -> Result<(), Error> {
+    let end = output.seek(SeekFrom::End(0))?;
+    let partition = mbr::Partition {
+        active: true,
+        partition_type: mbr::NON_FS_DATA,
+        first_lba,
+        sectors: end / BLOCK_SIZE as u64 - first_lba,
+    };
+    mbr::write_partition(output, 0, &partition)?;
+    output.seek(SeekFrom::End(0))?;
+    Ok(())
+}
+
+/// Reads the `files` and the `data-partition` directory of the configuration into
+/// a tree for the configured file system, `None` if there are none.
+fn data_files(config: &Config) -> Result<Option<DataPartition>, Error> {
+    if config.files.is_empty() && config.data_directory.is_none() {
+        return Ok(None);
+    }
+    let mut files = match config.data_filesystem {
+        Filesystem::Fat => DataPartition::Fat(fat::Directory::default()),
+        Filesystem::Ext2 => DataPartition::Ext2(ext2::Directory::default()),
+    };
+    if let Some(ref directory) = config.data_directory {
+        add_host_directory(&mut files, directory, "")?;
+    }
+    for (guest_path, host_path) in &config.files {
+        let data = fs::read(host_path).map_err(|err| {
+            Error::Config(format!(
+                "failed to read {} for `files`: {}",
+                host_path.display(),
+                err
+            ))
+        })?;
+        files
+            .add_file(guest_path, data)
+            .map_err(|err| Error::Config(format!("invalid path in `files`: {}", err)))?;
+    }
+    Ok(Some(files))
+}
+
+/// Adds the contents of the host directory `directory` to `files` at `guest_path`.
+fn add_host_directory(
+    files: &mut DataPartition,
+    directory: &Path,
+    guest_path: &str,
+) -> Result<(), Error> {
+    let io_err = |path: &Path, err: io::Error| {
+        Error::Config(format!(
+            "failed to read {} for the data partition: {}",
+            path.display(),
+            err
+        ))
+    };
+    let invalid = |err: String| Error::Config(format!("invalid file in the data partition: {}", err));
+    files.add_directory(guest_path).map_err(invalid)?;
+    let mut entries: Vec<PathBuf> = fs::read_dir(directory)
+        .and_then(|entries| entries.map(|entry| entry.map(|entry| entry.path())).collect())
+        .map_err(|err| io_err(directory, err))?;
+    entries.sort();
+    for path in entries {
+        let name = path.file_name().and_then(|name| name.to_str()).ok_or_else(|| {
+            Error::Config(format!("{} is not a valid UTF-8 file name", path.display()))
+        })?;
+        let entry_path = format!("{}/{}", guest_path, name);
+        if path.is_dir() {
+            add_host_directory(files, &path, &entry_path)?;
+        } else {
+            let data = fs::read(&path).map_err(|err| io_err(&path, err))?;
+            files.add_file(&entry_path, data).map_err(invalid)?;
+        }
+    }
+    Ok(())
+}
+
+/// Appends a data partition with `files` at the next 1 MiB boundary behind the
+/// end of `output` and adds it as entry `index` of the MBR partition table.
+///
+/// The gap in front of the partition is filled with `pad_byte`.
+fn append_data_partition(
+    output: &mut File,
+    files: &DataPartition,
+    index: usize,
+    pad_byte: u8,
+) -> Result<(), Error> {
+    let block_size = BLOCK_SIZE as u64;
+    let end = output.seek(SeekFrom::End(0))?;
+    let start = end.div_ceil(PARTITION_ALIGNMENT) * PARTITION_ALIGNMENT / block_size;
+    let size = files.size(block_size);
+    let sectors = size / block_size;
+    if start + sectors > u64::from(u32::MAX) {
+        return Err(Error::Image(
+            "the data partition must end within the first 2 TiB of the disk".into(),
+        ));
+    }
+
+    pad_to(output, start * block_size, pad_byte)?;
+    output.set_len((start + sectors) * block_size)?;
+    files.write(output, block_size, start, size)?;
+
+    let partition = mbr::Partition {
+        active: false,
+        partition_type: files.mbr_partition_type(size),
+        first_lba: start,
+        sectors,
+    };
+    mbr::write_partition(output, index, &partition)?;
+    output.seek(SeekFrom::End(0))?;
+    Ok(())
+}
+
+/// The size of a FAT file system for `files`, which leaves room for growth.
+pub(crate) fn fat_size(files: &fat::Directory, min_size: u64, sector_size: u64) -> u64 {
+    // FAT16 needs more space with larger sectors
+    let min_size = cmp::max(min_size, fat::FatType::Fat16.min_size(sector_size));
+    cmp::max(min_size, files.size(4096) * 2).div_ceil(1 << 20) << 20
+}
+
+/// Creates the EFI system partition contents: the configured UEFI loader and the kernel.
+fn efi_system_partition(config: &Config, kernel_elf: &[u8]) -> Result<fat::Directory, Error> {
+    let efi_loader_path = config.efi_loader.as_ref().ok_or_else(|| {
+        Error::Config(format!(
+            "the `{}` image format requires an `efi-loader` to be configured",
+            config.format
+        ))
+    })?;
+    let mut efi_loader = Vec::new();
+    File::open(efi_loader_path)?.read_to_end(&mut efi_loader)?;
+
+    let mut esp = fat::Directory::default();
+    esp.add_file("EFI/BOOT/BOOTX64.EFI", efi_loader)
+        .expect("valid EFI loader path");
+    esp.add_file("KERNEL.ELF", kernel_elf.to_vec())
+        .expect("valid kernel path");
+    Ok(esp)
+}
+
+/// Writes the EFI system partition after `reserved_sectors` (aligned to 1 MiB)
+/// and the partition tables, which use `entries_lba` for the primary entry array.
+///
+/// The space behind the partitions up to the `minimum-image-size` and the
+/// `image-alignment` is filled with the `pad-byte`.
+fn write_gpt_disk(
+    output: &mut File,
+    config: &Config,
+    kernel_elf: &[u8],
+    esp: &fat::Directory,
+    entries_lba: u64,
+    reserved_sectors: u64,
+) -> Result<(), Error> {
+    let block_size = config.sector_size;
+    let alignment = PARTITION_ALIGNMENT / block_size;
+    let esp_start = reserved_sectors.div_ceil(alignment) * alignment;
+    let esp_size = fat_size(esp, ESP_MIN_SIZE, block_size);
+    let esp_sectors = esp_size / block_size;
+    let files = data_files(config)?;
+    // the data partition directly follows the ESP, whose size is a multiple of 1 MiB
+    let data_start = esp_start + esp_sectors;
+    let data_size = files.as_ref().map_or(0, |files| files.size(block_size));
+    let data_sectors = data_size / block_size;
+    let backup_sectors = gpt::backup_sectors(block_size);
+    let mut disk_size = (data_start + data_sectors + backup_sectors) * block_size;
+    if let Some(min_size) = config.minimum_image_size {
+        disk_size = cmp::max(disk_size, min_size);
+    }
+    let disk_size = disk_size.div_ceil(config.image_alignment) * config.image_alignment;
+    let disk_sectors = disk_size.div_ceil(block_size);
+
+    output.set_len((data_start + data_sectors) * block_size)?;
+    pad_to(
+        output,
+        (disk_sectors - backup_sectors) * block_size,
+        config.pad_byte,
+    )?;
+    output.set_len(disk_sectors * block_size)?;
+    fat::write_fat(
+        output,
+        esp_start * block_size,
+        esp_size,
+        block_size,
+        esp_start as u32,
+        "EFI",
+        esp,
+    )?;
+    let mut partitions = vec![gpt::Partition {
+        type_guid: gpt::EFI_SYSTEM_PARTITION,
+        name: "EFI System Partition",
+        first_lba: esp_start,
+        last_lba: esp_start + esp_sectors - 1,
+    }];
+    if let Some(ref files) = files {
+        files.write(output, block_size, data_start, data_size)?;
+        partitions.push(gpt::Partition {
+            type_guid: files.gpt_partition_type(),
+            name: "Data",
+            first_lba: data_start,
+            last_lba: data_start + data_sectors - 1,
+        });
+    }
+    gpt::write_partition_tables(
+        output,
+        block_size,
+        disk_sectors,
+        entries_lba,
+        reserved_sectors,
+        kernel_elf,
+        &partitions,
+    )?;
+
+    Ok(())
+}
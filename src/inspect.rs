@@ -0,0 +1,240 @@
+//! Parses the layout of an existing BIOS disk image for `bootimage inspect`.
+//!
+//! The size of the bootloader isn't recorded in the image, so the kernel info
+//! block is found by scanning the image block by block for a valid info block
+//! that is directly followed by the ELF header of the kernel. ISO images are
+//! inspected through the disk image that they contain.
+
+use byteorder::{ByteOrder, LittleEndian};
+use config::KernelCompression;
+use image::{self, BLOCK_SIZE};
+use mbr;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// The offset of the disk image in an ISO image created by `bootimage`.
+const ISO_BOOT_IMAGE_OFFSET: u64 = 23 * 2048;
+const ISO_MAGIC_OFFSET: u64 = 16 * 2048 + 1;
+const QCOW2_MAGIC: &[u8] = b"QFI\xfb";
+
+/// The parts of a disk image whose kernel is appended to the bootloader.
+pub(crate) struct Layout {
+    /// The container of the disk image (`raw` or `iso`).
+    pub container: &'static str,
+    /// The offset of the disk image in the file, which is non-zero for ISO images.
+    pub disk_offset: u64,
+    /// The size of the file.
+    pub total: u64,
+    /// Whether the first sector ends with the `0x55 0xaa` boot signature.
+    pub boot_signature: bool,
+    /// Whether the disk has a GUID partition table, i.e. is a hybrid image.
+    pub gpt: bool,
+    /// The entries of the MBR partition table, which are empty for GPT disks.
+    pub mbr_partitions: Vec<mbr::Partition>,
+    /// The size of the bootloader in front of the kernel info block.
+    pub bootloader: u64,
+    /// The version of the kernel info block, `1` or `2`.
+    pub info_block_version: u16,
+    pub info_block_flags: u16,
+    /// The kernel size field of the kernel info block.
+    pub kernel_size: u32,
+    /// The CRC-32 field of a version 2 kernel info block.
+    pub kernel_crc: Option<u32>,
+    /// The compression of the kernel and its uncompressed size.
+    pub compression: Option<(KernelCompression, u32)>,
+    /// The ELF class of the (uncompressed) kernel, `32` or `64`.
+    pub kernel_class: u8,
+    pub kernel_entry: u64,
+    /// The offset of the initrd in the file and its size.
+    pub initrd: Option<(u64, u64)>,
+    /// The offset of the data partition with the `files` and its size.
+    pub data_partition: Option<(u64, u64)>,
+    /// The kernel command line that was embedded with `kernel-cmdline`.
+    pub cmdline: Option<String>,
+    /// The build metadata that was embedded with `embed-metadata`.
+    pub metadata: Option<Vec<(String, String)>>,
+}
+
+impl Layout {
+    pub fn info_block_offset(&self) -> u64 {
+        self.disk_offset + self.bootloader
+    }
+
+    pub fn kernel_offset(&self) -> u64 {
+        self.info_block_offset() + BLOCK_SIZE as u64
+    }
+
+    pub fn padding_offset(&self) -> u64 {
+        self.kernel_offset() + u64::from(self.kernel_size)
+    }
+
+    /// Everything after the kernel except for the initrd and the data partition,
+    /// i.e. the block alignment, the `minimum-image-size`, and the end of the ISO
+    /// or hybrid image.
+    pub fn padding(&self) -> u64 {
+        let initrd_size = self.initrd.map_or(0, |(_, size)| size);
+        let data_size = self.data_partition.map_or(0, |(_, size)| size);
+        self.total - self.padding_offset() - initrd_size - data_size
+    }
+}
+
+/// Reads the layout of the disk image at `path`.
+pub(crate) fn layout(path: &Path) -> Result<Layout, String> {
+    let io_err = |err: io::Error| format!("failed to read {}: {}", path.display(), err);
+    let mut file = File::open(path).map_err(io_err)?;
+    let total = file.metadata().map_err(io_err)?.len();
+
+    let mut magic = [0u8; 5];
+    read_at(&mut file, 0, &mut magic[..4]).map_err(io_err)?;
+    if &magic[..4] == QCOW2_MAGIC {
+        return Err("qcow2 images can't be inspected (convert them with `qemu-img convert`)".into());
+    }
+    read_at(&mut file, ISO_MAGIC_OFFSET, &mut magic).map_err(io_err)?;
+    let (container, disk_offset) = if &magic == b"CD001" {
+        ("iso", ISO_BOOT_IMAGE_OFFSET)
+    } else {
+        ("raw", 0)
+    };
+
+    let mut first_sectors = [0u8; 2 * BLOCK_SIZE];
+    read_at(&mut file, disk_offset, &mut first_sectors).map_err(io_err)?;
+    let boot_signature = first_sectors[510..512] == [0x55, 0xaa];
+    let gpt = &first_sectors[BLOCK_SIZE..BLOCK_SIZE + 8] == b"EFI PART";
+    let mbr_partitions = if gpt {
+        Vec::new()
+    } else {
+        mbr::read_partitions(&first_sectors)
+    };
+    // the data partition of a raw image has the type of a FAT16, FAT32, or Linux
+    // (ext2) file system
+    let data_partition = mbr_partitions
+        .iter()
+        .filter(|partition| [0x0c, 0x0e, mbr::LINUX].contains(&partition.partition_type))
+        .map(|partition| {
+            (
+                disk_offset + partition.first_lba * BLOCK_SIZE as u64,
+                partition.sectors * BLOCK_SIZE as u64,
+            )
+        })
+        .find(|&(offset, size)| size > 0 && offset + size <= total);
+
+    file.seek(SeekFrom::Start(disk_offset)).map_err(io_err)?;
+    let mut reader = BufReader::new(file);
+    let mut block = [0u8; BLOCK_SIZE];
+    let mut next = [0u8; BLOCK_SIZE];
+    if !read_block(&mut reader, &mut block).map_err(io_err)? {
+        return Err("the image is smaller than a single block".into());
+    }
+    let mut offset = 0;
+    while read_block(&mut reader, &mut next).map_err(io_err)? {
+        // the info block is followed by the kernel, which starts with the ELF magic
+        // unless it's compressed
+        let info_block = image::parse_kernel_info_block(&block)
+            .filter(|info_block| next[..4] == *b"\x7fELF" || info_block.compression.is_some());
+        if let Some(info_block) = info_block {
+            let kernel_size = info_block.kernel_size;
+            let mut layout = Layout {
+                container,
+                disk_offset,
+                total,
+                boot_signature,
+                gpt,
+                mbr_partitions: mbr_partitions.clone(),
+                bootloader: offset,
+                info_block_version: info_block.version,
+                info_block_flags: info_block.flags,
+                kernel_size,
+                kernel_crc: info_block.kernel_crc,
+                compression: info_block.compression,
+                kernel_class: 0,
+                kernel_entry: 0,
+                initrd: None,
+                data_partition,
+                cmdline: image::read_cmdline(&block),
+                metadata: image::read_metadata(&block),
+            };
+            if layout.padding_offset() > total {
+                return Err(format!(
+                    "the kernel info block at {:#x} declares a kernel of {} bytes, \
+                     but the image only contains {} bytes of it",
+                    layout.info_block_offset(),
+                    kernel_size,
+                    total - layout.kernel_offset()
+                ));
+            }
+            if let Some((offset, size)) = info_block.initrd {
+                let offset = layout.kernel_offset() + u64::from(offset);
+                let size = u64::from(size);
+                if offset < layout.padding_offset() || offset + size > total {
+                    return Err(format!(
+                        "the kernel info block at {:#x} declares an initrd of {} bytes at \
+                         {:#x}, which is not part of the image behind the kernel",
+                        layout.info_block_offset(),
+                        size,
+                        offset
+                    ));
+                }
+                layout.initrd = Some((offset, size));
+            }
+            let header = match layout.compression {
+                Some((compression, _)) => {
+                    let mut kernel = vec![0u8; kernel_size as usize];
+                    let mut file = File::open(path).map_err(io_err)?;
+                    read_at(&mut file, layout.kernel_offset(), &mut kernel).map_err(io_err)?;
+                    let kernel = image::decompress_kernel(&kernel, compression).map_err(|err| {
+                        format!(
+                            "the {}-compressed kernel at {:#x} is invalid: {}",
+                            compression,
+                            layout.kernel_offset(),
+                            err
+                        )
+                    })?;
+                    if kernel.len() < 32 || kernel[..4] != *b"\x7fELF" {
+                        return Err(format!(
+                            "the decompressed kernel at {:#x} is not an ELF file",
+                            layout.kernel_offset()
+                        ));
+                    }
+                    kernel[..32].to_vec()
+                }
+                None => next[..32].to_vec(),
+            };
+            layout.kernel_class = if header[4] == 1 { 32 } else { 64 };
+            layout.kernel_entry = if header[4] == 1 {
+                u64::from(LittleEndian::read_u32(&header[24..28]))
+            } else {
+                LittleEndian::read_u64(&header[24..32])
+            };
+            return Ok(layout);
+        }
+        block = next;
+        offset += BLOCK_SIZE as u64;
+    }
+    Err("no kernel info block found (only BIOS images with an appended kernel can be \
+         inspected)"
+        .into())
+}
+
+fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    match file.read_exact(buf) {
+        // a short file simply has no magic at the offset
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            for byte in buf.iter_mut() {
+                *byte = 0;
+            }
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Reads a whole block, returns `false` at the end of the file.
+fn read_block<R: Read>(reader: &mut R, block: &mut [u8; BLOCK_SIZE]) -> io::Result<bool> {
+    match reader.read_exact(block) {
+        Ok(()) => Ok(true),
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
@@ -0,0 +1,43 @@
+//! Generates iPXE scripts that boot the disk image from `bootimage serve`.
+//!
+//! The disk image is booted with `sanboot`, which reads it through HTTP range
+//! requests and boots it like a local hard disk (or CD for ISO images), both on
+//! BIOS and UEFI firmware.
+
+/// An iPXE script that boots the disk image at the path `image` of the HTTP server
+/// at `base_url`.
+///
+/// The script only sets the `base-url` variable if it isn't set yet, so it can be
+/// set before the script is chained to boot from another server. The same holds
+/// for the `cmdline` variable (with the default command line `cmdline`) if
+/// `replaces_cmdline` is set, in which case the command line is passed to the
+/// server, which writes it into the kernel info block of the served image.
+pub(crate) fn script(
+    title: &str,
+    base_url: &str,
+    image: &str,
+    replaces_cmdline: bool,
+    cmdline: Option<&str>,
+) -> String {
+    let mut script = format!(
+        "#!ipxe\n# Boots {} from the HTTP server of `bootimage serve`.\n#\n\
+         # Set `base-url` before chaining this script to boot from another server",
+        title
+    );
+    if replaces_cmdline {
+        script.push_str(",\n# and `cmdline` to boot the kernel with another command line.\n\n");
+    } else {
+        script.push_str(".\n# The kernel command line is part of the image.\n\n");
+    }
+    script.push_str(&format!("isset ${{base-url}} || set base-url {}\n", base_url));
+    let mut url = format!("${{base-url}}/{}", image);
+    if replaces_cmdline {
+        if let Some(cmdline) = cmdline {
+            script.push_str(&format!("isset ${{cmdline}} || set cmdline {}\n", cmdline));
+        }
+        url.push_str("?cmdline=${cmdline:uristring}");
+    }
+    script.push_str(&format!("echo Booting {} from ${{base-url}}\n", title));
+    script.push_str(&format!("sanboot --no-describe {}\n", url));
+    script
+}
@@ -0,0 +1,219 @@
+//! Creates bootable ISO 9660 images with an El Torito boot catalog.
+//!
+//! The raw disk image is stored as the only file of the ISO and referenced by
+//! the boot catalog as a hard disk emulation boot image, so the BIOS maps it as
+//! the first hard disk and boots it just like the raw disk image.
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use sparse;
+use Error;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+const SECTOR_SIZE: usize = 2048;
+type Sector = [u8; SECTOR_SIZE];
+
+/// The first 16 sectors are the (unused) system area. The primary volume
+/// descriptor is followed by the boot record and the set terminator.
+const PRIMARY_VOLUME_DESCRIPTOR_LBA: u32 = 16;
+const L_PATH_TABLE_LBA: u32 = 19;
+const M_PATH_TABLE_LBA: u32 = 20;
+const ROOT_DIRECTORY_LBA: u32 = 21;
+const BOOT_CATALOG_LBA: u32 = 22;
+const BOOT_IMAGE_LBA: u32 = 23;
+
+const BOOT_IMAGE_NAME: &[u8] = b"BOOT.IMG;1";
+const PATH_TABLE_SIZE: u32 = 10;
+const ROOT_DIRECTORY_SIZE: u32 = SECTOR_SIZE as u32;
+
+/// El Torito media type for hard disk emulation.
+const MEDIA_TYPE_HARD_DISK: u8 = 4;
+
+/// Writes an ISO image to `output` that boots the raw disk image at `disk_image`,
+/// which is copied with `sparse::copy`, so that its zero regions stay holes.
+pub(crate) fn create_iso_image(disk_image: &Path, output: &Path) -> Result<(), Error> {
+    let mut boot_image = File::open(disk_image)?;
+    let boot_image_size = boot_image.metadata()?.len();
+    if boot_image_size > u64::from(u32::MAX) {
+        return Err(Error::Image(format!(
+            "the disk image is {} bytes large, but ISO 9660 records the size of a file in 32 \
+             bits, so the ISO can only contain disk images below 4 GiB",
+            boot_image_size
+        )));
+    }
+    let boot_image_sectors = sectors(boot_image_size as usize);
+    let mut first_sector = Vec::new();
+    Read::by_ref(&mut boot_image).take(512).read_to_end(&mut first_sector)?;
+
+    let volume_size = BOOT_IMAGE_LBA + boot_image_sectors;
+    let partition_type = first_sector.get(0x1c2).cloned().unwrap_or(0);
+
+    let mut output = File::create(output)?;
+    for _ in 0..PRIMARY_VOLUME_DESCRIPTOR_LBA {
+        output.write_all(&[0u8; SECTOR_SIZE])?;
+    }
+    output.write_all(&primary_volume_descriptor(volume_size))?;
+    output.write_all(&boot_record())?;
+    output.write_all(&terminator())?;
+    output.write_all(&path_table::<LittleEndian>())?;
+    output.write_all(&path_table::<BigEndian>())?;
+    output.write_all(&root_directory(boot_image_size as u32))?;
+    output.write_all(&boot_catalog(partition_type))?;
+    output.write_all(&first_sector)?;
+    sparse::copy(&mut boot_image, &mut output)?;
+    // the boot image is padded to whole sectors
+    output.set_len(u64::from(volume_size) * SECTOR_SIZE as u64)?;
+
+    Ok(())
+}
+
+fn sectors(bytes: usize) -> u32 {
+    bytes.div_ceil(SECTOR_SIZE) as u32
+}
+
+fn primary_volume_descriptor(volume_size: u32) -> Sector {
+    let mut sector = [0u8; SECTOR_SIZE];
+    write_descriptor_header(&mut sector, 1);
+    write_padded(&mut sector[8..40], b"");
+    write_padded(&mut sector[40..72], b"BOOTIMAGE");
+    write_both_u32(&mut sector[80..88], volume_size);
+    write_both_u16(&mut sector[120..124], 1); // volume set size
+    write_both_u16(&mut sector[124..128], 1); // volume sequence number
+    write_both_u16(&mut sector[128..132], SECTOR_SIZE as u16);
+    write_both_u32(&mut sector[132..140], PATH_TABLE_SIZE);
+    LittleEndian::write_u32(&mut sector[140..144], L_PATH_TABLE_LBA);
+    BigEndian::write_u32(&mut sector[148..152], M_PATH_TABLE_LBA);
+    directory_record(
+        &mut sector[156..190],
+        ROOT_DIRECTORY_LBA,
+        ROOT_DIRECTORY_SIZE,
+        true,
+        &[0],
+    );
+    // volume set, publisher, data preparer, application, and file identifiers
+    write_padded(&mut sector[190..813], b"");
+    // creation, modification, expiration, and effective dates (all unspecified)
+    for date in sector[813..881].chunks_mut(17) {
+        write_unspecified_date(date);
+    }
+    sector[881] = 1; // file structure version
+    sector
+}
+
+fn boot_record() -> Sector {
+    let mut sector = [0u8; SECTOR_SIZE];
+    write_descriptor_header(&mut sector, 0);
+    sector[7..30].copy_from_slice(b"EL TORITO SPECIFICATION");
+    LittleEndian::write_u32(&mut sector[71..75], BOOT_CATALOG_LBA);
+    sector
+}
+
+fn terminator() -> Sector {
+    let mut sector = [0u8; SECTOR_SIZE];
+    write_descriptor_header(&mut sector, 255);
+    sector
+}
+
+fn path_table<B: ByteOrder>() -> Sector {
+    let mut sector = [0u8; SECTOR_SIZE];
+    sector[0] = 1; // length of the directory identifier
+    B::write_u32(&mut sector[2..6], ROOT_DIRECTORY_LBA);
+    B::write_u16(&mut sector[6..8], 1); // parent directory number
+    sector
+}
+
+fn root_directory(boot_image_size: u32) -> Sector {
+    let mut sector = [0u8; SECTOR_SIZE];
+    let mut offset = 0;
+    offset += directory_record(
+        &mut sector[offset..],
+        ROOT_DIRECTORY_LBA,
+        ROOT_DIRECTORY_SIZE,
+        true,
+        &[0],
+    );
+    offset += directory_record(
+        &mut sector[offset..],
+        ROOT_DIRECTORY_LBA,
+        ROOT_DIRECTORY_SIZE,
+        true,
+        &[1],
+    );
+    directory_record(
+        &mut sector[offset..],
+        BOOT_IMAGE_LBA,
+        boot_image_size,
+        false,
+        BOOT_IMAGE_NAME,
+    );
+    sector
+}
+
+fn boot_catalog(partition_type: u8) -> Sector {
+    let mut sector = [0u8; SECTOR_SIZE];
+    {
+        let validation_entry = &mut sector[0..32];
+        validation_entry[0] = 1; // header id
+        validation_entry[1] = 0; // platform id: 80x86
+        validation_entry[30] = 0x55;
+        validation_entry[31] = 0xaa;
+        let sum = validation_entry
+            .chunks(2)
+            .fold(0u16, |sum, word| sum.wrapping_add(LittleEndian::read_u16(word)));
+        LittleEndian::write_u16(&mut validation_entry[28..30], 0u16.wrapping_sub(sum));
+    }
+    {
+        let initial_entry = &mut sector[32..64];
+        initial_entry[0] = 0x88; // bootable
+        initial_entry[1] = MEDIA_TYPE_HARD_DISK;
+        initial_entry[4] = partition_type;
+        LittleEndian::write_u16(&mut initial_entry[6..8], 1); // load the MBR only
+        LittleEndian::write_u32(&mut initial_entry[8..12], BOOT_IMAGE_LBA);
+    }
+    sector
+}
+
+/// Writes a directory record to the start of `buffer` and returns its length.
+fn directory_record(buffer: &mut [u8], lba: u32, size: u32, is_dir: bool, name: &[u8]) -> usize {
+    let len = 33 + name.len() + (name.len() + 1) % 2;
+    buffer[0] = len as u8;
+    write_both_u32(&mut buffer[2..10], lba);
+    write_both_u32(&mut buffer[10..18], size);
+    // the recording date (bytes 18..25) is left unspecified
+    buffer[25] = if is_dir { 2 } else { 0 };
+    write_both_u16(&mut buffer[28..32], 1); // volume sequence number
+    buffer[32] = name.len() as u8;
+    buffer[33..33 + name.len()].copy_from_slice(name);
+    len
+}
+
+fn write_descriptor_header(sector: &mut Sector, descriptor_type: u8) {
+    sector[0] = descriptor_type;
+    sector[1..6].copy_from_slice(b"CD001");
+    sector[6] = 1; // version
+}
+
+fn write_padded(buffer: &mut [u8], value: &[u8]) {
+    for byte in buffer.iter_mut() {
+        *byte = b' ';
+    }
+    buffer[..value.len()].copy_from_slice(value);
+}
+
+fn write_unspecified_date(buffer: &mut [u8]) {
+    for byte in buffer[..16].iter_mut() {
+        *byte = b'0';
+    }
+    buffer[16] = 0;
+}
+
+fn write_both_u16(buffer: &mut [u8], value: u16) {
+    LittleEndian::write_u16(&mut buffer[0..2], value);
+    BigEndian::write_u16(&mut buffer[2..4], value);
+}
+
+fn write_both_u32(buffer: &mut [u8], value: u32) {
+    LittleEndian::write_u32(&mut buffer[0..4], value);
+    BigEndian::write_u32(&mut buffer[4..8], value);
+}
@@ -0,0 +1,254 @@
+//! Creates disk images that boot the kernel with the Limine bootloader.
+//!
+//! A pinned Limine binary release is downloaded, extracted, and cached together
+//! with its `limine` host tool, which is built from the release with `make`. The
+//! kernels, their modules, the BIOS stage, the UEFI loader, and a generated
+//! `limine.cfg` are placed on a FAT partition, and `limine bios-install` writes
+//! the boot code into the disk image afterwards.
+
+use build::{cache_dir, download, extract_tar, is_offline};
+use config::{Config, LimineProtocol};
+use fat;
+use image::{self, BootEntry};
+use sha256;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use Error;
+
+/// The files of a binary release that are installed into the disk image.
+const BIOS_STAGE: &str = "limine-bios.sys";
+const UEFI_LOADER: &str = "BOOTX64.EFI";
+/// The file next to the extracted release that records the hash of its archive.
+const HASH_FILE: &str = "archive.sha256";
+
+/// Writes a disk image to the output that boots the kernels of `entries` with
+/// Limine, the first of which is booted by default.
+///
+/// The release is downloaded into the user-level cache or, without one, into
+/// `out_dir`.
+pub(crate) fn create_limine_image(
+    config: &Config,
+    entries: &[BootEntry],
+    out_dir: &Path,
+) -> Result<(), Error> {
+    let release_dir = release(config, out_dir)?;
+    let read_release = |name: &str| {
+        let path = release_dir.join(name);
+        fs::read(&path).map_err(|err| {
+            Error::Download(format!(
+                "the Limine release lacks {}: {}",
+                path.display(),
+                err
+            ))
+        })
+    };
+
+    let mut files = fat::Directory::default();
+    let invalid =
+        |err: String| Error::Config(format!("invalid file for the Limine image: {}", err));
+    files.add_file("boot/limine/limine-bios.sys", read_release(BIOS_STAGE)?).map_err(invalid)?;
+    files.add_file("EFI/BOOT/BOOTX64.EFI", read_release(UEFI_LOADER)?).map_err(invalid)?;
+    for (index, entry) in entries.iter().enumerate() {
+        let path = format!("boot/{}", image::menu_kernel_name(index));
+        files.add_file(&path, entry.data.clone()).map_err(invalid)?;
+    }
+    let mut modules = Vec::new();
+    if let Some(ref initrd) = config.initrd {
+        let data = fs::read(initrd).map_err(|err| {
+            Error::Config(format!(
+                "failed to read the initrd at {}: {}",
+                initrd.display(),
+                err
+            ))
+        })?;
+        files.add_file("boot/initrd", data).map_err(invalid)?;
+        modules.push("initrd".to_owned());
+    }
+    for module in &config.multiboot_modules {
+        let name = module
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let path = format!("modules/{}", name);
+        if name.is_empty() || modules.contains(&path) {
+            return Err(Error::Config(format!(
+                "the modules must have distinct file names, found {}",
+                module.display()
+            )));
+        }
+        let data = fs::read(module).map_err(|err| {
+            Error::Config(format!(
+                "failed to read the module at {}: {}",
+                module.display(),
+                err
+            ))
+        })?;
+        files.add_file(&format!("boot/{}", path), data).map_err(invalid)?;
+        modules.push(path);
+    }
+    files
+        .add_file("boot/limine/limine.cfg", limine_cfg(config, entries, &modules).into_bytes())
+        .map_err(invalid)?;
+
+    image::create_limine_disk_image(config, &files)?;
+
+    let tool = release_dir.join("limine");
+    let output = Command::new(&tool)
+        .arg("bios-install")
+        .arg(&config.output)
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Image(format!(
+            "`{} bios-install` failed ({}):\n{}",
+            tool.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        )));
+    }
+    Ok(())
+}
+
+/// A `limine.cfg` (in the syntax of Limine 7) with an entry for each kernel,
+/// with the given modules (paths relative to `/boot`), which are passed to the
+/// kernels with their path as command line.
+///
+/// A single kernel is booted immediately, a menu waits for the `menu-timeout`.
+fn limine_cfg(config: &Config, entries: &[BootEntry], modules: &[String]) -> String {
+    let timeout = if entries.len() > 1 {
+        config.menu_timeout
+    } else {
+        0
+    };
+    // Multiboot2 modules get their command line through `MODULE_STRING`
+    let module_cmdline = match config.limine.protocol {
+        LimineProtocol::Limine => "MODULE_CMDLINE",
+        LimineProtocol::Multiboot2 => "MODULE_STRING",
+    };
+    let mut cfg = format!("TIMEOUT={}\n", timeout);
+    for (index, entry) in entries.iter().enumerate() {
+        cfg.push_str(&format!("\n:{}\n", entry.title));
+        cfg.push_str(&format!("    PROTOCOL={}\n", config.limine.protocol));
+        cfg.push_str(&format!(
+            "    KERNEL_PATH=boot():/boot/{}\n",
+            image::menu_kernel_name(index)
+        ));
+        if let Some(ref cmdline) = entry.cmdline {
+            cfg.push_str(&format!("    CMDLINE={}\n", cmdline));
+        }
+        for module in modules {
+            cfg.push_str(&format!("    MODULE_PATH=boot():/boot/{}\n", module));
+            cfg.push_str(&format!("    {}={}\n", module_cmdline, module));
+        }
+    }
+    cfg
+}
+
+/// Returns the directory of the configured Limine release with the built `limine`
+/// tool, downloading and building it if it isn't cached yet.
+fn release(config: &Config, out_dir: &Path) -> Result<PathBuf, Error> {
+    let version = config.limine.version.as_ref().ok_or_else(|| {
+        Error::Config(
+            "the `limine` image format requires the version of the Limine release, e.g. \
+             `version = \"7.13.3\"` in `[package.metadata.bootimage.limine]`"
+                .into(),
+        )
+    })?;
+    let url = config.limine.url.clone().unwrap_or_else(|| {
+        format!(
+            "https://github.com/limine-bootloader/limine/archive/refs/tags/v{}-binary.tar.gz",
+            version
+        )
+    });
+    // the `limine` tool is built from the release, so unverified archives run their
+    // makefile
+    if config.limine.sha256.is_none() && !config.allow_unverified_downloads {
+        return Err(Error::Config(format!(
+            "the Limine release isn't pinned: set `sha256` in \
+             `[package.metadata.bootimage.limine]` to the SHA-256 hash of {}, or allow \
+             unverified downloads with `allow-unverified-downloads = true` or \
+             `--allow-unverified-downloads`",
+            url
+        )));
+    }
+    let release_dir = cache_dir()
+        .map(|dir| dir.join("limine").join(version))
+        .unwrap_or_else(|| out_dir.join("limine").join(version));
+    let cached_hash = fs::read_to_string(release_dir.join(HASH_FILE)).ok();
+    let cached = release_dir.join("limine").is_file()
+        && cached_hash.as_ref().is_some_and(|hash| {
+            config.limine.sha256.as_ref().is_none_or(|expected| expected == hash.trim())
+        });
+    if cached {
+        return Ok(release_dir);
+    }
+    if is_offline() {
+        return Err(Error::Download(format!(
+            "Limine {} from {} is not available offline: build once without `--offline` to \
+             download it",
+            version, url
+        )));
+    }
+
+    println!("Downloading Limine {} from {}", version, url);
+    fs::create_dir_all(out_dir)?;
+    let archive = out_dir.join("limine.tar.gz");
+    download(&url, &archive).map_err(Error::Download)?;
+    let actual = sha256::to_hex(&sha256::sha256(&fs::read(&archive)?));
+    match config.limine.sha256 {
+        Some(ref expected) if *expected != actual => {
+            fs::remove_file(&archive)?;
+            return Err(Error::Download(format!(
+                "the Limine release downloaded from {} has the SHA-256 hash {}, expected {}",
+                url, actual, expected
+            )));
+        }
+        Some(_) => {}
+        None => eprintln!(
+            "Warning: the Limine release isn't pinned, add `sha256 = \"{}\"` to \
+             `[package.metadata.bootimage.limine]` to verify it",
+            actual
+        ),
+    }
+
+    // the release is prepared next to its final location, so that an interrupted
+    // build never leaves an incomplete release behind
+    let tmp_dir = release_dir.with_file_name(format!("{}.tmp", version));
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir)?;
+    }
+    fs::create_dir_all(&tmp_dir)?;
+    // the release is in a directory named after the tag
+    extract_tar(&archive, &tmp_dir, 1).map_err(Error::Download)?;
+    fs::remove_file(&archive)?;
+    build_tool(&tmp_dir)?;
+    fs::write(tmp_dir.join(HASH_FILE), &actual)?;
+    if release_dir.exists() {
+        fs::remove_dir_all(&release_dir)?;
+    }
+    fs::rename(&tmp_dir, &release_dir)?;
+    Ok(release_dir)
+}
+
+/// Builds the `limine` host tool of the release in `dir` with its makefile.
+fn build_tool(dir: &Path) -> Result<(), Error> {
+    let output = match Command::new("make").arg("-C").arg(dir).arg("limine").output() {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::Image(
+                "`make` was not found, install it together with a C compiler to build the \
+                 `limine` tool of the Limine release"
+                    .into(),
+            ))
+        }
+        output => output?,
+    };
+    if !output.status.success() {
+        return Err(Error::Image(format!(
+            "failed to build the `limine` tool of the Limine release ({}):\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        )));
+    }
+    Ok(())
+}
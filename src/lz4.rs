@@ -0,0 +1,280 @@
+//! Compresses and decompresses data in the LZ4 frame format, used for compressed kernels.
+//!
+//! The compressor is a simple greedy matcher with a hash table of the last
+//! position of each 4-byte sequence, which is fast and good enough for kernels.
+//! Frames contain the content size and a content checksum, so that a
+//! bootloader can check the decompressed kernel.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+const MAGIC: u32 = 0x184d_2204;
+/// Version 1, independent blocks, content size, and content checksum.
+const FRAME_FLAGS: u8 = 0x40 | 0x20 | 0x08 | 0x04;
+/// The maximum block size of 4 MiB.
+const BLOCK_DESCRIPTOR: u8 = 7 << 4;
+const BLOCK_SIZE: usize = 4 * 1024 * 1024;
+/// Blocks whose size has this bit set are stored uncompressed.
+const UNCOMPRESSED_BLOCK: u32 = 1 << 31;
+
+const MIN_MATCH: usize = 4;
+/// The last 5 bytes of a block are always literals.
+const LAST_LITERALS: usize = 5;
+/// The last match must start at least 12 bytes before the end of a block.
+const MATCH_FIND_LIMIT: usize = 12;
+const MAX_OFFSET: usize = 65535;
+const HASH_BITS: u32 = 16;
+
+const PRIME1: u32 = 2_654_435_761;
+const PRIME2: u32 = 2_246_822_519;
+const PRIME3: u32 = 3_266_489_917;
+const PRIME4: u32 = 668_265_263;
+const PRIME5: u32 = 374_761_393;
+
+/// Compresses `data` into an LZ4 frame.
+pub(crate) fn compress(data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(data.len() / 2);
+    let mut header = [0u8; 15];
+    LittleEndian::write_u32(&mut header[0..4], MAGIC);
+    header[4] = FRAME_FLAGS;
+    header[5] = BLOCK_DESCRIPTOR;
+    LittleEndian::write_u64(&mut header[6..14], data.len() as u64);
+    // the header checksum covers the frame descriptor
+    header[14] = (xxh32(&header[4..14], 0) >> 8) as u8;
+    frame.extend_from_slice(&header);
+
+    let mut block = Vec::with_capacity(BLOCK_SIZE);
+    for chunk in data.chunks(BLOCK_SIZE) {
+        block.clear();
+        compress_block(chunk, &mut block);
+        let mut size = [0u8; 4];
+        if block.len() < chunk.len() {
+            LittleEndian::write_u32(&mut size, block.len() as u32);
+            frame.extend_from_slice(&size);
+            frame.extend_from_slice(&block);
+        } else {
+            LittleEndian::write_u32(&mut size, chunk.len() as u32 | UNCOMPRESSED_BLOCK);
+            frame.extend_from_slice(&size);
+            frame.extend_from_slice(chunk);
+        }
+    }
+    let mut trailer = [0u8; 8];
+    LittleEndian::write_u32(&mut trailer[4..8], xxh32(data, 0));
+    frame.extend_from_slice(&trailer);
+    frame
+}
+
+/// Decompresses an LZ4 frame and checks its checksums and content size.
+pub(crate) fn decompress(frame: &[u8]) -> Result<Vec<u8>, String> {
+    let truncated = || "the LZ4 frame is truncated".to_owned();
+    if frame.len() < 7 || LittleEndian::read_u32(&frame[0..4]) != MAGIC {
+        return Err("not an LZ4 frame".into());
+    }
+    let flags = frame[4];
+    if flags >> 6 != 1 {
+        return Err(format!("unsupported LZ4 frame version {}", flags >> 6));
+    }
+    let block_checksums = flags & 0x10 != 0;
+    let descriptor_size = 2 + if flags & 0x08 != 0 { 8 } else { 0 } + if flags & 0x01 != 0 { 4 } else { 0 };
+    let descriptor = frame.get(4..4 + descriptor_size).ok_or_else(truncated)?;
+    let header_checksum = *frame.get(4 + descriptor_size).ok_or_else(truncated)?;
+    if (xxh32(descriptor, 0) >> 8) as u8 != header_checksum {
+        return Err("the LZ4 frame header checksum doesn't match".into());
+    }
+    if flags & 0x01 != 0 {
+        return Err("LZ4 frames with a dictionary are not supported".into());
+    }
+    let content_size = if flags & 0x08 != 0 {
+        Some(LittleEndian::read_u64(&descriptor[2..10]))
+    } else {
+        None
+    };
+
+    let mut data = Vec::with_capacity(content_size.unwrap_or(0) as usize);
+    let mut position = 5 + descriptor_size;
+    loop {
+        let size = LittleEndian::read_u32(frame.get(position..position + 4).ok_or_else(truncated)?);
+        position += 4;
+        if size == 0 {
+            break;
+        }
+        let length = (size & !UNCOMPRESSED_BLOCK) as usize;
+        let block = frame.get(position..position + length).ok_or_else(truncated)?;
+        position += length + if block_checksums { 4 } else { 0 };
+        if size & UNCOMPRESSED_BLOCK != 0 {
+            data.extend_from_slice(block);
+        } else {
+            decompress_block(block, &mut data)?;
+        }
+    }
+    if flags & 0x04 != 0 {
+        let checksum = frame.get(position..position + 4).ok_or_else(truncated)?;
+        position += 4;
+        if LittleEndian::read_u32(checksum) != xxh32(&data, 0) {
+            return Err("the LZ4 content checksum doesn't match".into());
+        }
+    }
+    if position != frame.len() {
+        return Err(format!("{} bytes follow the LZ4 frame", frame.len() - position));
+    }
+    if content_size.is_some_and(|size| size != data.len() as u64) {
+        return Err("the LZ4 content size doesn't match the decompressed data".into());
+    }
+    Ok(data)
+}
+
+/// Appends the LZ4 sequences of `input` to `out`.
+fn compress_block(input: &[u8], out: &mut Vec<u8>) {
+    let mut table = vec![0usize; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut position = 0;
+    if input.len() > MATCH_FIND_LIMIT {
+        let match_limit = input.len() - LAST_LITERALS;
+        while position + MATCH_FIND_LIMIT <= input.len() {
+            let sequence = LittleEndian::read_u32(&input[position..]);
+            let hash = (sequence.wrapping_mul(PRIME1) >> (32 - HASH_BITS)) as usize;
+            // the table holds positions plus one, so that zero means empty
+            let candidate = table[hash];
+            table[hash] = position + 1;
+            if candidate > 0 {
+                let candidate = candidate - 1;
+                if position - candidate <= MAX_OFFSET
+                    && LittleEndian::read_u32(&input[candidate..]) == sequence
+                {
+                    let mut length = MIN_MATCH;
+                    while position + length < match_limit
+                        && input[candidate + length] == input[position + length]
+                    {
+                        length += 1;
+                    }
+                    write_sequence(out, &input[anchor..position], Some((position - candidate, length)));
+                    position += length;
+                    anchor = position;
+                    continue;
+                }
+            }
+            position += 1;
+        }
+    }
+    write_sequence(out, &input[anchor..], None);
+}
+
+/// Writes a sequence of `literals` followed by a match of the given offset and
+/// length, which only the last sequence of a block lacks.
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], found: Option<(usize, usize)>) {
+    let match_length = found.map_or(0, |(_, length)| length - MIN_MATCH);
+    let token = (literals.len().min(15) << 4) | match_length.min(15);
+    out.push(token as u8);
+    if literals.len() >= 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = found {
+        out.extend_from_slice(&[offset as u8, (offset >> 8) as u8]);
+        if match_length >= 15 {
+            write_length(out, match_length - 15);
+        }
+    }
+}
+
+fn write_length(out: &mut Vec<u8>, mut length: usize) {
+    while length >= 255 {
+        out.push(255);
+        length -= 255;
+    }
+    out.push(length as u8);
+}
+
+/// Appends the decompressed sequences of `block` to `out`.
+fn decompress_block(block: &[u8], out: &mut Vec<u8>) -> Result<(), String> {
+    let invalid = || "the LZ4 block is invalid".to_owned();
+    let start = out.len();
+    let mut position = 0;
+    loop {
+        let token = *block.get(position).ok_or_else(invalid)?;
+        position += 1;
+        let mut literals = usize::from(token >> 4);
+        if literals == 15 {
+            literals += read_length(block, &mut position).ok_or_else(invalid)?;
+        }
+        out.extend_from_slice(block.get(position..position + literals).ok_or_else(invalid)?);
+        position += literals;
+        if position == block.len() {
+            return Ok(());
+        }
+        let offset = block.get(position..position + 2).ok_or_else(invalid)?;
+        let offset = usize::from(LittleEndian::read_u16(offset));
+        position += 2;
+        let mut length = usize::from(token & 0x0f);
+        if length == 15 {
+            length += read_length(block, &mut position).ok_or_else(invalid)?;
+        }
+        length += MIN_MATCH;
+        if offset == 0 || offset > out.len() - start {
+            return Err(invalid());
+        }
+        // the match may overlap the bytes that it produces
+        let from = out.len() - offset;
+        for i in 0..length {
+            let byte = out[from + i];
+            out.push(byte);
+        }
+    }
+}
+
+fn read_length(block: &[u8], position: &mut usize) -> Option<usize> {
+    let mut length = 0;
+    loop {
+        let byte = *block.get(*position)?;
+        *position += 1;
+        length += usize::from(byte);
+        if byte != 255 {
+            return Some(length);
+        }
+    }
+}
+
+/// The 32-bit xxHash of `data`, which LZ4 frames use as checksum.
+fn xxh32(data: &[u8], seed: u32) -> u32 {
+    let round = |acc: u32, input: u32| {
+        acc.wrapping_add(input.wrapping_mul(PRIME2))
+            .rotate_left(13)
+            .wrapping_mul(PRIME1)
+    };
+    let mut chunks = data.chunks_exact(16);
+    let mut hash = if data.len() >= 16 {
+        let mut state = [
+            seed.wrapping_add(PRIME1).wrapping_add(PRIME2),
+            seed.wrapping_add(PRIME2),
+            seed,
+            seed.wrapping_sub(PRIME1),
+        ];
+        for chunk in &mut chunks {
+            for (i, lane) in state.iter_mut().enumerate() {
+                *lane = round(*lane, LittleEndian::read_u32(&chunk[i * 4..]));
+            }
+        }
+        state[0]
+            .rotate_left(1)
+            .wrapping_add(state[1].rotate_left(7))
+            .wrapping_add(state[2].rotate_left(12))
+            .wrapping_add(state[3].rotate_left(18))
+    } else {
+        seed.wrapping_add(PRIME5)
+    };
+    hash = hash.wrapping_add(data.len() as u32);
+
+    let mut words = chunks.remainder().chunks_exact(4);
+    for word in &mut words {
+        hash = hash.wrapping_add(LittleEndian::read_u32(word).wrapping_mul(PRIME3));
+        hash = hash.rotate_left(17).wrapping_mul(PRIME4);
+    }
+    for &byte in words.remainder() {
+        hash = hash.wrapping_add(u32::from(byte).wrapping_mul(PRIME5));
+        hash = hash.rotate_left(11).wrapping_mul(PRIME1);
+    }
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(PRIME2);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(PRIME3);
+    hash ^ (hash >> 16)
+}
@@ -1,39 +1,136 @@
 extern crate byteorder;
 extern crate cargo_metadata;
+extern crate rustc_demangle;
+#[macro_use]
+extern crate serde_json;
 extern crate tempdir;
 extern crate toml;
 extern crate xmas_elf;
 
 use std::{io, process};
-use args::Args;
+use std::time::Duration;
+use args::{
+    Args, AttachArgs, CleanArgs, ExtractArgs, FlashArgs, InspectArgs, RunnerArgs, ServeArgs,
+    VerifyArgs, WatchArgs,
+};
 
+mod accel;
 mod args;
+mod bochs;
 mod config;
+mod console;
 mod build;
+mod crc32;
+mod debug;
+mod disasm;
+mod export;
+mod ext2;
+mod fat;
+mod flash;
+mod gpt;
+mod grub;
+mod gzip;
+mod hyperv;
+mod ipxe;
+mod iso;
+mod limine;
+mod lz4;
+mod map;
+mod microvm;
+mod mbr;
+mod multiboot2;
+mod network;
+mod objdump;
+mod ovmf;
+mod qcow2;
+mod qmp;
+mod remote;
+mod replay;
+mod report;
+mod screenshot;
+mod serve;
+mod sha256;
+mod size;
+mod sparse;
+mod strip;
+mod symbolize;
+mod syslinux;
+mod validate;
+mod verify;
+mod virtualbox;
+mod vmware;
+mod watch;
 mod help;
+mod image;
+mod inspect;
 
 enum Command {
     NoSubcommand,
     Build(Args),
     Run(Args),
+    Test(Args),
+    Debug(Args),
+    Addr2line(Args),
+    Objdump(Args),
+    Size(Args),
+    Replay(Args),
+    Runner(RunnerArgs),
+    Inspect(InspectArgs),
+    Extract(ExtractArgs),
+    Verify(VerifyArgs),
+    Serve(ServeArgs),
+    Flash(FlashArgs),
+    Attach(AttachArgs),
+    Watch(WatchArgs),
+    Clean(CleanArgs),
+    Check(Args),
+    Clippy(Args),
     Help,
     BuildHelp,
     RunHelp,
+    TestHelp,
+    DebugHelp,
+    Addr2lineHelp,
+    ObjdumpHelp,
+    SizeHelp,
+    ReplayHelp,
+    RunnerHelp,
+    InspectHelp,
+    ExtractHelp,
+    VerifyHelp,
+    ServeHelp,
+    FlashHelp,
+    AttachHelp,
+    WatchHelp,
+    CleanHelp,
+    CheckHelp,
+    ClippyHelp,
     Version,
 }
 
 pub fn main() {
-    use std::io::Write;
     if let Err(err) = run() {
-        writeln!(io::stderr(), "Error: {:?}", err).unwrap();
+        eprintln!("Error: {:?}", err);
         process::exit(1);
     }
 }
 
 #[derive(Debug)]
 pub enum Error {
+    Args(String),
     Config(String),
     Bootloader(String, io::Error),
+    Download(String),
+    Image(String),
+    Kernel(String),
+    Hook(String),
+    Sign(String),
+    Run(String),
+    Serve(String),
+    Flash(String),
+    Timeout(Duration),
+    /// Screenshots that are missing or differ from their golden images.
+    Screenshot(String),
     Io(io::Error),
     Toml(toml::de::Error),
     CargoMetadata(cargo_metadata::Error),
@@ -58,14 +155,51 @@ impl From<cargo_metadata::Error> for Error {
 }
 
 fn run() -> Result<(), Error> {
-    let command = args::parse_args();
+    let command = args::parse_args()?;
     match command {
         Command::NoSubcommand => help::no_subcommand(),
         Command::Build(args) => build::build(args),
         Command::Run(args) => build::run(args),
-        Command::Help => Ok(help::help()),
-        Command::BuildHelp => Ok(help::build_help()),
-        Command::RunHelp => Ok(help::run_help()),
-        Command::Version => Ok(println!("bootimage {}", env!("CARGO_PKG_VERSION"))),
+        Command::Test(args) => build::test(args),
+        Command::Debug(args) => build::debug(args),
+        Command::Addr2line(args) => build::addr2line(args),
+        Command::Objdump(args) => build::objdump(args),
+        Command::Size(args) => build::size(args),
+        Command::Replay(args) => build::replay(args),
+        Command::Runner(args) => build::runner(args),
+        Command::Inspect(args) => build::inspect(args),
+        Command::Extract(args) => build::extract(args),
+        Command::Verify(args) => build::verify(args),
+        Command::Serve(args) => build::serve(args),
+        Command::Flash(args) => build::flash(args),
+        Command::Attach(args) => build::attach(args),
+        Command::Watch(args) => build::watch(args),
+        Command::Clean(args) => build::clean(args),
+        Command::Check(args) => build::check(args),
+        Command::Clippy(args) => build::clippy(args),
+        Command::Help => help::help(),
+        Command::BuildHelp => help::build_help(),
+        Command::RunHelp => help::run_help(),
+        Command::TestHelp => help::test_help(),
+        Command::DebugHelp => help::debug_help(),
+        Command::Addr2lineHelp => help::addr2line_help(),
+        Command::ObjdumpHelp => help::objdump_help(),
+        Command::SizeHelp => help::size_help(),
+        Command::ReplayHelp => help::replay_help(),
+        Command::RunnerHelp => help::runner_help(),
+        Command::InspectHelp => help::inspect_help(),
+        Command::ExtractHelp => help::extract_help(),
+        Command::VerifyHelp => help::verify_help(),
+        Command::ServeHelp => help::serve_help(),
+        Command::FlashHelp => help::flash_help(),
+        Command::AttachHelp => help::attach_help(),
+        Command::WatchHelp => help::watch_help(),
+        Command::CleanHelp => help::clean_help(),
+        Command::CheckHelp => help::check_help(),
+        Command::ClippyHelp => help::clippy_help(),
+        Command::Version => {
+            println!("bootimage {}", env!("CARGO_PKG_VERSION"));
+            Ok(())
+        }
     }
 }
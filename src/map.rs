@@ -0,0 +1,147 @@
+//! Writes a linker-map-style report and a symbol table of the kernel.
+//!
+//! Both are derived from the kernel ELF file and sorted by address, so the boot
+//! layout can be inspected and diffed between builds without binutils for the
+//! kernel target.
+
+use rustc_demangle::demangle;
+use xmas_elf::program::Type as SegmentType;
+use xmas_elf::sections::{SectionData, ShType, SHF_ALLOC};
+use xmas_elf::symbol_table::{Binding, Entry, Type};
+use xmas_elf::ElfFile;
+
+pub(crate) struct Symbol {
+    pub address: u64,
+    pub size: u64,
+    /// The index of the section that contains the symbol.
+    pub section: u16,
+    pub kind: &'static str,
+    pub binding: &'static str,
+    /// The demangled name of the symbol.
+    pub name: String,
+}
+
+/// Returns the loaded segments, and the loaded sections with the symbols that
+/// they contain.
+pub(crate) fn map(elf: &[u8]) -> Result<String, String> {
+    let elf_file = ElfFile::new(elf).map_err(|err| format!("invalid kernel ELF: {}", err))?;
+    let symbols = symbols(&elf_file)?;
+
+    let mut map = String::from("Segments:\n");
+    map.push_str(&format!(
+        "    {:<18} {:<18} {:<18} {:>10} {:>10} Flags\n",
+        "Offset", "VirtAddr", "PhysAddr", "FileSize", "MemSize"
+    ));
+    for segment in elf_file.program_iter() {
+        if segment.get_type() != Ok(SegmentType::Load) {
+            continue;
+        }
+        let flags = segment.flags();
+        map.push_str(&format!(
+            "    {:#018x} {:#018x} {:#018x} {:>10} {:>10} {}{}{}\n",
+            segment.offset(),
+            segment.virtual_addr(),
+            segment.physical_addr(),
+            segment.file_size(),
+            segment.mem_size(),
+            if flags.is_read() { 'R' } else { ' ' },
+            if flags.is_write() { 'W' } else { ' ' },
+            if flags.is_execute() { 'E' } else { ' ' },
+        ));
+    }
+
+    map.push_str("\nSections:\n");
+    map.push_str(&format!("    {:<18} {:>10} Section / Symbol\n", "Address", "Size"));
+    let mut sections = Vec::new();
+    for (index, section) in elf_file.section_iter().enumerate() {
+        if section.flags() & SHF_ALLOC != 0 {
+            sections.push((index, section));
+        }
+    }
+    sections.sort_by_key(|&(index, ref section)| (section.address(), index));
+    for (index, section) in sections {
+        map.push_str(&format!(
+            "    {:#018x} {:>10} {}\n",
+            section.address(),
+            section.size(),
+            section.get_name(&elf_file)?
+        ));
+        for symbol in symbols.iter().filter(|symbol| symbol.section as usize == index) {
+            map.push_str(&format!(
+                "    {:#018x} {:>10}     {}\n",
+                symbol.address, symbol.size, symbol.name
+            ));
+        }
+    }
+    Ok(map)
+}
+
+/// Returns the defined symbols with their address, size, type, and binding.
+pub(crate) fn symbol_table(elf: &[u8]) -> Result<String, String> {
+    let elf_file = ElfFile::new(elf).map_err(|err| format!("invalid kernel ELF: {}", err))?;
+    let mut table = String::new();
+    for symbol in symbols(&elf_file)? {
+        table.push_str(&format!(
+            "{:#018x} {:>10} {:<6} {:<6} {}\n",
+            symbol.address, symbol.size, symbol.kind, symbol.binding, symbol.name
+        ));
+    }
+    Ok(table)
+}
+
+/// Reads the defined symbols of the symbol table, sorted by address and name.
+pub(crate) fn symbols(elf_file: &ElfFile) -> Result<Vec<Symbol>, String> {
+    let mut symbols = Vec::new();
+    for section in elf_file.section_iter() {
+        if section.get_type() != Ok(ShType::SymTab) {
+            continue;
+        }
+        match section.get_data(elf_file) {
+            Ok(SectionData::SymbolTable32(entries)) => {
+                read_symbols(elf_file, entries, &mut symbols)?
+            }
+            Ok(SectionData::SymbolTable64(entries)) => {
+                read_symbols(elf_file, entries, &mut symbols)?
+            }
+            _ => {}
+        }
+    }
+    symbols.sort_by(|a, b| (a.address, &a.name).cmp(&(b.address, &b.name)));
+    Ok(symbols)
+}
+
+fn read_symbols<E: Entry>(
+    elf_file: &ElfFile,
+    entries: &[E],
+    symbols: &mut Vec<Symbol>,
+) -> Result<(), String> {
+    for entry in entries {
+        let kind = match entry.get_type() {
+            Ok(Type::NoType) => "NOTYPE",
+            Ok(Type::Object) => "OBJECT",
+            Ok(Type::Func) => "FUNC",
+            Ok(Type::Tls) => "TLS",
+            _ => continue,
+        };
+        let binding = match entry.get_binding() {
+            Ok(Binding::Local) => "LOCAL",
+            Ok(Binding::Global) => "GLOBAL",
+            Ok(Binding::Weak) => "WEAK",
+            _ => "OTHER",
+        };
+        let name = entry.get_name(elf_file)?;
+        // undefined symbols and the empty first symbol aren't part of the layout
+        if entry.shndx() == 0 || name.is_empty() {
+            continue;
+        }
+        symbols.push(Symbol {
+            address: entry.value(),
+            size: entry.size(),
+            section: entry.shndx(),
+            kind,
+            binding,
+            name: format!("{:#}", demangle(name)),
+        });
+    }
+    Ok(())
+}
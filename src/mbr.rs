@@ -0,0 +1,82 @@
+//! Reads and writes the entries of MBR partition tables.
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// The offset of the partition table in the first sector.
+pub(crate) const TABLE_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+const ENTRY_COUNT: usize = 4;
+/// The disk geometry of CHS addresses, which BIOSes use for all LBA disks.
+const HEADS: u64 = 255;
+const SECTORS_PER_TRACK: u64 = 63;
+
+/// The partition type of the kernel partition (`0xda`, non-file-system data).
+pub(crate) const NON_FS_DATA: u8 = 0xda;
+/// The partition type of Linux file systems like ext2.
+pub(crate) const LINUX: u8 = 0x83;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Partition {
+    /// Whether the partition is marked as active, i.e. bootable.
+    pub active: bool,
+    pub partition_type: u8,
+    pub first_lba: u64,
+    pub sectors: u64,
+}
+
+/// Writes `partition` as entry `index` (0 to 3) of the partition table in the
+/// first sector of `out`.
+pub(crate) fn write_partition<W: Write + Seek>(
+    out: &mut W,
+    index: usize,
+    partition: &Partition,
+) -> io::Result<()> {
+    let last_lba = partition.first_lba + partition.sectors - 1;
+    if last_lba > u64::from(u32::MAX) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "MBR partitions must end within the first 2 TiB of the disk",
+        ));
+    }
+    let mut entry = [0u8; ENTRY_SIZE];
+    entry[0] = if partition.active { 0x80 } else { 0 };
+    entry[1..4].copy_from_slice(&chs(partition.first_lba));
+    entry[4] = partition.partition_type;
+    entry[5..8].copy_from_slice(&chs(last_lba));
+    LittleEndian::write_u32(&mut entry[8..12], partition.first_lba as u32);
+    LittleEndian::write_u32(&mut entry[12..16], partition.sectors as u32);
+    out.seek(SeekFrom::Start((TABLE_OFFSET + index * ENTRY_SIZE) as u64))?;
+    out.write_all(&entry)
+}
+
+/// Reads the used entries of the partition table in the first sector `sector`.
+pub(crate) fn read_partitions(sector: &[u8]) -> Vec<Partition> {
+    sector[TABLE_OFFSET..TABLE_OFFSET + ENTRY_COUNT * ENTRY_SIZE]
+        .chunks(ENTRY_SIZE)
+        .filter(|entry| entry[4] != 0)
+        .map(|entry| Partition {
+            active: entry[0] == 0x80,
+            partition_type: entry[4],
+            first_lba: u64::from(LittleEndian::read_u32(&entry[8..12])),
+            sectors: u64::from(LittleEndian::read_u32(&entry[12..16])),
+        })
+        .collect()
+}
+
+/// The CHS address of `lba`, or the maximum address (cylinder 1023, head 254,
+/// sector 63) if it's beyond the range of CHS addresses.
+fn chs(lba: u64) -> [u8; 3] {
+    let cylinder = lba / (HEADS * SECTORS_PER_TRACK);
+    if cylinder > 1023 {
+        return [0xfe, 0xff, 0xff];
+    }
+    let head = (lba / SECTORS_PER_TRACK) % HEADS;
+    let sector = lba % SECTORS_PER_TRACK + 1;
+    // the upper two bits of the 10-bit cylinder are stored in the sector byte
+    [
+        head as u8,
+        sector as u8 | ((cylinder >> 2) & 0xc0) as u8,
+        cylinder as u8,
+    ]
+}
@@ -0,0 +1,138 @@
+//! Boots the kernel directly in a microVM monitor, Firecracker or cloud-hypervisor,
+//! instead of booting the disk image (experimental).
+//!
+//! MicroVM monitors have no firmware and no disks to boot from, they load the
+//! kernel ELF file into memory themselves and start it through the PVH boot
+//! protocol (cloud-hypervisor) or in 64-bit mode at its entry point (Firecracker),
+//! so only kernels that support these entry points boot. In exchange, they start
+//! in a few milliseconds, which makes boot tests much faster. The serial port of
+//! the microVM is connected to the output of the monitor, like `-serial stdio` of
+//! QEMU.
+
+use build::find_executable;
+use byteorder::{ByteOrder, LittleEndian};
+use config::{Config, RunBackend};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use xmas_elf::program::Type as SegmentType;
+use xmas_elf::ElfFile;
+use Error;
+
+/// The type of the ELF note that contains the 32-bit PVH entry point.
+const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
+/// The device through which the monitors access hardware virtualization.
+const KVM_DEVICE: &str = "/dev/kvm";
+
+/// Prepares the invocation of the monitor of the run backend that boots `kernel`.
+///
+/// The configuration file of Firecracker and its log are written to `work_dir`.
+pub(crate) fn command(config: &Config, kernel: &Path, work_dir: &Path) -> Result<Command, Error> {
+    let monitor = match config.run_backend {
+        RunBackend::Firecracker => "firecracker",
+        RunBackend::CloudHypervisor => "cloud-hypervisor",
+        _ => unreachable!("the run backend is not a microVM monitor"),
+    };
+    if !cfg!(target_os = "linux") {
+        return Err(Error::Run(format!("{} only runs on Linux hosts", monitor)));
+    }
+    if !Path::new(KVM_DEVICE).exists() {
+        return Err(Error::Run(format!(
+            "{} requires KVM, but {} doesn't exist (enable hardware virtualization or load the \
+             `kvm` kernel module)",
+            monitor, KVM_DEVICE
+        )));
+    }
+    let program = find_executable(monitor, &[]).ok_or_else(|| {
+        Error::Run(format!(
+            "`{}` was not found in `PATH`, install it or use the `qemu` run backend",
+            monitor
+        ))
+    })?;
+    let cmdline = config.kernel_cmdline.clone().unwrap_or_default();
+    let memory_mib = config.microvm.memory / (1024 * 1024);
+
+    let mut command = Command::new(program);
+    match config.run_backend {
+        RunBackend::Firecracker => {
+            fs::create_dir_all(work_dir)?;
+            // Firecracker logs to its output, which is the serial output, by default
+            let log = work_dir.join("firecracker.log");
+            fs::write(&log, "")?;
+            let vm_config = json!({
+                "boot-source": {
+                    "kernel_image_path": kernel.to_string_lossy(),
+                    "boot_args": cmdline,
+                },
+                "drives": [],
+                "machine-config": {
+                    "vcpu_count": config.microvm.vcpus,
+                    "mem_size_mib": memory_mib,
+                },
+                "logger": {
+                    "log_path": log.to_string_lossy(),
+                    "level": "Warning",
+                },
+            });
+            let config_file = work_dir.join("firecracker.json");
+            fs::write(&config_file, format!("{:#}\n", vm_config))?;
+            command.arg("--no-api").arg("--config-file").arg(config_file);
+        }
+        _ => {
+            let elf = fs::read(kernel)?;
+            if !has_pvh_entry(&elf).map_err(Error::Kernel)? {
+                return Err(Error::Kernel(
+                    "cloud-hypervisor boots kernels through the PVH boot protocol, but the kernel \
+                     has no PVH entry point (a `XEN_ELFNOTE_PHYS32_ENTRY` ELF note with the \
+                     physical address of its 32-bit entry point)"
+                        .into(),
+                ));
+            }
+            command.arg("--kernel").arg(kernel);
+            if !cmdline.is_empty() {
+                command.arg("--cmdline").arg(cmdline);
+            }
+            command
+                .arg("--cpus")
+                .arg(format!("boot={}", config.microvm.vcpus))
+                .arg("--memory")
+                .arg(format!("size={}M", memory_mib))
+                .args(["--serial", "tty", "--console", "off"]);
+        }
+    }
+    Ok(command)
+}
+
+/// Whether the kernel has a `XEN_ELFNOTE_PHYS32_ENTRY` note, through which PVH
+/// loaders find its entry point.
+fn has_pvh_entry(elf: &[u8]) -> Result<bool, String> {
+    let elf_file = ElfFile::new(elf).map_err(|err| format!("invalid kernel ELF: {}", err))?;
+    for segment in elf_file.program_iter() {
+        if segment.get_type() != Ok(SegmentType::Note) {
+            continue;
+        }
+        let start = segment.offset() as usize;
+        let notes = start
+            .checked_add(segment.file_size() as usize)
+            .and_then(|end| elf.get(start..end))
+            .ok_or("a note segment of the kernel is outside of the file")?;
+        // each note is a header with the sizes of the name and the descriptor and the
+        // type, followed by the name and the descriptor, which are padded to 4 bytes
+        let mut offset = 0;
+        while offset + 12 <= notes.len() {
+            let name_size = LittleEndian::read_u32(&notes[offset..]) as usize;
+            let desc_size = LittleEndian::read_u32(&notes[offset + 4..]) as usize;
+            let note_type = LittleEndian::read_u32(&notes[offset + 8..]);
+            let name = notes.get(offset + 12..offset + 12 + name_size).unwrap_or_default();
+            if note_type == XEN_ELFNOTE_PHYS32_ENTRY && name == b"Xen\0" {
+                return Ok(true);
+            }
+            offset += 12 + align4(name_size) + align4(desc_size);
+        }
+    }
+    Ok(false)
+}
+
+fn align4(size: usize) -> usize {
+    (size + 3) & !3
+}
@@ -0,0 +1,176 @@
+//! Creates kernels that can be booted by Multiboot2 bootloaders like GRUB.
+//!
+//! A Multiboot2 kernel is a plain ELF file whose first 32 KiB contain a
+//! Multiboot2 header. If the kernel already has a header, it is validated.
+//! Otherwise a minimal header (without any tags) is written into an unused,
+//! zeroed gap of the ELF file, e.g. the alignment padding between the program
+//! headers and the first section, so that the kernel is loaded through its ELF
+//! program headers.
+
+use byteorder::{ByteOrder, LittleEndian};
+use xmas_elf::program::Type as SegmentType;
+use xmas_elf::sections::ShType;
+use xmas_elf::ElfFile;
+
+const MAGIC: u32 = 0xe852_50d6;
+/// 32-bit protected mode of i386, the only architecture that GRUB supports on x86.
+const ARCHITECTURE_I386: u32 = 0;
+/// The header has to be within this many bytes from the start of the file.
+const SEARCH_LIMIT: usize = 32 * 1024;
+const ALIGNMENT: usize = 8;
+/// The fixed fields of the header: magic, architecture, length, and checksum.
+const HEADER_SIZE: usize = 16;
+const END_TAG_SIZE: usize = 8;
+/// Multiboot2 bootloaders load the kernel in 32-bit protected mode, so they can
+/// only place segments in the first 4 GiB of physical memory.
+const PHYSICAL_LIMIT: u64 = 1 << 32;
+
+/// Returns the kernel `elf` with a valid Multiboot2 header, injecting one if it
+/// has none.
+pub(crate) fn kernel(elf: &[u8]) -> Result<Vec<u8>, String> {
+    let elf_file = ElfFile::new(elf).map_err(|err| format!("invalid kernel ELF: {}", err))?;
+    for segment in elf_file.program_iter() {
+        if segment.get_type() != Ok(SegmentType::Load) {
+            continue;
+        }
+        if segment.physical_addr().saturating_add(segment.mem_size()) > PHYSICAL_LIMIT {
+            return Err(format!(
+                "the segment of the kernel at {:#x} has the physical load address {:#x}, but \
+                 Multiboot2 bootloaders can only load segments below 4 GiB (set the load \
+                 addresses with `AT` in the linker script)",
+                segment.virtual_addr(),
+                segment.physical_addr()
+            ));
+        }
+    }
+
+    if let Some(offset) = find_header(elf) {
+        validate_header(elf, offset)?;
+        return Ok(elf.to_vec());
+    }
+    let size = HEADER_SIZE + END_TAG_SIZE;
+    let offset = free_space(elf, &elf_file, size).ok_or_else(|| {
+        "the kernel has no Multiboot2 header and its first 32 KiB have no free space for \
+         one (place a section with the header at the start of the kernel in the linker \
+         script, e.g. `.multiboot2 : { KEEP(*(.multiboot2)) }`)"
+            .to_owned()
+    })?;
+    let mut kernel = elf.to_vec();
+    let header = &mut kernel[offset..offset + size];
+    LittleEndian::write_u32(&mut header[0..4], MAGIC);
+    LittleEndian::write_u32(&mut header[4..8], ARCHITECTURE_I386);
+    LittleEndian::write_u32(&mut header[8..12], size as u32);
+    LittleEndian::write_u32(&mut header[12..16], checksum(ARCHITECTURE_I386, size as u32));
+    // the end tag has type 0 and a size of 8
+    LittleEndian::write_u32(&mut header[20..24], END_TAG_SIZE as u32);
+    Ok(kernel)
+}
+
+/// The offset of the Multiboot2 header in `elf`, found by its magic and checksum.
+fn find_header(elf: &[u8]) -> Option<usize> {
+    let limit = elf.len().min(SEARCH_LIMIT);
+    (0..limit.saturating_sub(HEADER_SIZE - 1))
+        .step_by(ALIGNMENT)
+        .find(|&offset| {
+            let header = &elf[offset..offset + HEADER_SIZE];
+            LittleEndian::read_u32(&header[0..4]) == MAGIC
+                && checksum(
+                    LittleEndian::read_u32(&header[4..8]),
+                    LittleEndian::read_u32(&header[8..12]),
+                ) == LittleEndian::read_u32(&header[12..16])
+        })
+}
+
+/// Checks the architecture of the header at `offset` and that its tags end with
+/// an end tag at the end of the header.
+fn validate_header(elf: &[u8], offset: usize) -> Result<(), String> {
+    let architecture = LittleEndian::read_u32(&elf[offset + 4..offset + 8]);
+    if architecture != ARCHITECTURE_I386 {
+        return Err(format!(
+            "the Multiboot2 header of the kernel at {:#x} is for the architecture {}, but x86 \
+             bootloaders only load the architecture {} (i386)",
+            offset, architecture, ARCHITECTURE_I386
+        ));
+    }
+    let length = LittleEndian::read_u32(&elf[offset + 8..offset + 12]) as usize;
+    let end = offset.checked_add(length).filter(|&end| end <= elf.len() && length >= HEADER_SIZE);
+    let end = end.ok_or_else(|| {
+        format!(
+            "the Multiboot2 header of the kernel at {:#x} declares a length of {} bytes, which \
+             doesn't fit into the ELF file",
+            offset, length
+        )
+    })?;
+    let mut tag = offset + HEADER_SIZE;
+    while tag + END_TAG_SIZE <= end {
+        let tag_type = LittleEndian::read_u16(&elf[tag..tag + 2]);
+        let size = LittleEndian::read_u32(&elf[tag + 4..tag + 8]) as usize;
+        if tag_type == 0 && size == END_TAG_SIZE {
+            if tag + END_TAG_SIZE == end {
+                return Ok(());
+            }
+            break;
+        }
+        if size < END_TAG_SIZE {
+            return Err(format!(
+                "the Multiboot2 header tag at {:#x} has an invalid size of {} bytes",
+                tag, size
+            ));
+        }
+        // tags are padded to 8-byte alignment
+        tag += size.div_ceil(ALIGNMENT) * ALIGNMENT;
+    }
+    Err(format!(
+        "the tags of the Multiboot2 header of the kernel at {:#x} don't end with an end tag \
+         at the end of the header ({} bytes)",
+        offset, length
+    ))
+}
+
+/// An aligned offset in the first 32 KiB of `elf` where `size` zero bytes aren't
+/// part of the ELF header, the header tables, or the data of a section (or of a
+/// segment, if the file has no section headers).
+fn free_space(elf: &[u8], elf_file: &ElfFile, size: usize) -> Option<usize> {
+    let header = &elf_file.header.pt2;
+    let mut used = vec![
+        (0, u64::from(header.header_size())),
+        (
+            header.ph_offset(),
+            u64::from(header.ph_count()) * u64::from(header.ph_entry_size()),
+        ),
+        (
+            header.sh_offset(),
+            u64::from(header.sh_count()) * u64::from(header.sh_entry_size()),
+        ),
+    ];
+    // the padding between the sections of a segment is loaded, but never used
+    if header.sh_count() > 0 {
+        for section in elf_file.section_iter() {
+            if section.get_type() != Ok(ShType::NoBits) {
+                used.push((section.offset(), section.size()));
+            }
+        }
+    } else {
+        for segment in elf_file.program_iter() {
+            used.push((segment.offset(), segment.file_size()));
+        }
+    }
+    let limit = elf.len().min(SEARCH_LIMIT);
+    (0..limit.saturating_sub(size - 1))
+        .step_by(ALIGNMENT)
+        .find(|&offset| {
+            let (start, end) = (offset as u64, (offset + size) as u64);
+            used.iter()
+                .all(|&(used_start, used_size)| {
+                    end <= used_start || used_start.saturating_add(used_size) <= start
+                })
+                && elf[offset..offset + size].iter().all(|&byte| byte == 0)
+        })
+}
+
+/// The checksum field, which makes the sum of the fixed fields zero.
+fn checksum(architecture: u32, length: u32) -> u32 {
+    0u32.wrapping_sub(MAGIC)
+        .wrapping_sub(architecture)
+        .wrapping_sub(length)
+}
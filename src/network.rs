@@ -0,0 +1,57 @@
+//! Connects the VMs of network tests through a virtual Ethernet network.
+//!
+//! The test kernel and its peers each get a network card whose QEMU backend is a UDP
+//! multicast socket on the loopback interface, so that all VMs of a test share one
+//! Ethernet segment without a bridge or TAP devices, which would require root. Every
+//! test gets its own port, which keeps the networks of concurrently running tests
+//! apart. The cards have the MAC addresses `52:54:00:00:00:01` (the test kernel),
+//! `52:54:00:00:00:02` (the first peer), and so on, from which the kernels can derive
+//! their role and their address.
+
+use std::io;
+use std::net::{Ipv4Addr, UdpSocket};
+
+/// The network card of the VMs if the test doesn't configure one.
+pub(crate) const DEFAULT_NIC_MODEL: &str = "e1000";
+/// The multicast group of the networks, which are told apart by their port.
+const MULTICAST_GROUP: Ipv4Addr = Ipv4Addr::new(230, 0, 0, 1);
+
+/// The network of a test.
+pub(crate) struct Network {
+    port: u16,
+}
+
+impl Network {
+    /// A new network on a port that no other test uses.
+    pub(crate) fn new() -> io::Result<Network> {
+        // a free port, which is released again for QEMU
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        Ok(Network {
+            port: socket.local_addr()?.port(),
+        })
+    }
+
+    /// The arguments that connect a VM with a `nic_model` card to the network, where
+    /// `index` numbers the VMs from 0 (the test kernel).
+    ///
+    /// The disks are attached with `-snapshot`, so that several instances of one disk
+    /// image can run at the same time.
+    pub(crate) fn qemu_args(&self, nic_model: &str, index: usize) -> Vec<String> {
+        vec![
+            "-netdev".into(),
+            format!(
+                "socket,id=bootimage-net,mcast={}:{},localaddr={}",
+                MULTICAST_GROUP,
+                self.port,
+                Ipv4Addr::LOCALHOST
+            ),
+            "-device".into(),
+            format!(
+                "{},netdev=bootimage-net,mac=52:54:00:00:00:{:02x}",
+                nic_model,
+                index + 1
+            ),
+            "-snapshot".into(),
+        ]
+    }
+}
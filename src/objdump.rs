@@ -0,0 +1,198 @@
+//! Disassembles the code sections of the kernel for `bootimage objdump`.
+//!
+//! Code is disassembled in the mode of the ELF class (32-bit or 64-bit), except
+//! for sections that are named after another mode, like `.text16` or `.boot32`,
+//! which usually contain the real mode or protected mode entry code.
+
+use disasm::{self, Mode};
+use map::{self, Symbol};
+use std::str::FromStr;
+use xmas_elf::header::Class;
+use xmas_elf::sections::{ShType, SHF_ALLOC, SHF_EXECINSTR};
+use xmas_elf::ElfFile;
+
+/// The part of the kernel that is disassembled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selection {
+    /// A symbol by its demangled name or the last components of its path.
+    Symbol(String),
+    /// The addresses from `start` up to (excluding) `end`.
+    Range(u64, u64),
+}
+
+impl FromStr for Selection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let address = |s: &str| {
+            let digits = s.trim_start_matches("0x");
+            u64::from_str_radix(digits, 16)
+                .ok()
+                .filter(|_| s.starts_with("0x"))
+        };
+        match s.find("..") {
+            Some(split) => match (address(&s[..split]), address(&s[split + 2..])) {
+                (Some(start), Some(end)) if start < end => Ok(Selection::Range(start, end)),
+                _ => Err(format!(
+                    "invalid address range `{}` (expected e.g. `0x200000..0x200100`)",
+                    s
+                )),
+            },
+            None => Ok(Selection::Symbol(s.to_owned())),
+        }
+    }
+}
+
+/// Disassembles the code sections of the ELF file `elf`, or only the selected
+/// parts of them.
+///
+/// The mode of all sections can be overridden with `mode`.
+pub(crate) fn disassemble(
+    elf: &[u8],
+    selections: &[Selection],
+    mode: Option<Mode>,
+) -> Result<String, String> {
+    let elf_file = ElfFile::new(elf).map_err(|err| format!("invalid kernel ELF: {}", err))?;
+    let symbols = map::symbols(&elf_file)?;
+    let default_mode = match elf_file.header.pt1.class() {
+        Class::ThirtyTwo => Mode::Bits32,
+        _ => Mode::Bits64,
+    };
+
+    let mut ranges = Vec::new();
+    for selection in selections {
+        match *selection {
+            Selection::Symbol(ref name) => {
+                let suffix = format!("::{}", name);
+                let mut found = false;
+                for (index, symbol) in symbols.iter().enumerate() {
+                    if symbol.name != *name && !symbol.name.ends_with(&suffix) {
+                        continue;
+                    }
+                    // symbols without a size extend up to the next symbol
+                    let end = if symbol.size > 0 {
+                        symbol.address + symbol.size
+                    } else {
+                        symbols[index + 1..]
+                            .iter()
+                            .map(|next| next.address)
+                            .find(|&next| next > symbol.address)
+                            .unwrap_or(u64::MAX)
+                    };
+                    ranges.push((symbol.address, end));
+                    found = true;
+                }
+                if !found {
+                    return Err(format!("the kernel has no symbol `{}`", name));
+                }
+            }
+            Selection::Range(start, end) => ranges.push((start, end)),
+        }
+    }
+
+    let mut output = String::new();
+    for (index, section) in elf_file.section_iter().enumerate() {
+        let flags = section.flags();
+        if flags & SHF_ALLOC == 0
+            || flags & SHF_EXECINSTR == 0
+            || section.get_type() == Ok(ShType::NoBits)
+        {
+            continue;
+        }
+        let name = section.get_name(&elf_file)?;
+        let start = section.address();
+        let data = section.raw_data(&elf_file);
+        let end = start + data.len() as u64;
+        let parts: Vec<(u64, u64)> = if selections.is_empty() {
+            vec![(start, end)]
+        } else {
+            ranges
+                .iter()
+                .map(|&(from, to)| (from.max(start), to.min(end)))
+                .filter(|&(from, to)| from < to)
+                .collect()
+        };
+        if parts.is_empty() {
+            continue;
+        }
+
+        let mode = mode.unwrap_or_else(|| section_mode(name, default_mode));
+        let bits = match mode {
+            Mode::Bits16 => 16,
+            Mode::Bits32 => 32,
+            Mode::Bits64 => 64,
+        };
+        output.push_str(&format!(
+            "\nDisassembly of section {} ({}-bit):\n",
+            name, bits
+        ));
+        for (from, to) in parts {
+            let code = &data[(from - start) as usize..(to - start) as usize];
+            let mut offset = 0;
+            if symbols.iter().all(|symbol| symbol.address != from) {
+                output.push_str(&format!("\n{:016x}:\n", from));
+            }
+            while offset < code.len() {
+                let address = from + offset as u64;
+                for symbol in symbols
+                    .iter()
+                    .filter(|symbol| symbol.address == address && symbol.section as usize == index)
+                {
+                    output.push_str(&format!("\n{:016x} <{}>:\n", address, symbol.name));
+                }
+                let instruction = disasm::decode(&code[offset..], address, mode);
+                let bytes: Vec<String> = code[offset..offset + instruction.length]
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect();
+                output.push_str(&format!(
+                    "{:>16x}:  {:<30} {}",
+                    address,
+                    bytes.join(" "),
+                    instruction.text
+                ));
+                if let Some(target) = instruction.target {
+                    if let Some(symbol) = describe(&symbols, target) {
+                        output.push_str(&format!("  # {:#x} <{}>", target, symbol));
+                    }
+                }
+                output.push('\n');
+                offset += instruction.length;
+            }
+        }
+    }
+    if output.is_empty() {
+        return Err("the selection is not part of a code section of the kernel".into());
+    }
+    Ok(output)
+}
+
+/// The mode of a section, which defaults to the mode of the ELF class.
+fn section_mode(name: &str, default: Mode) -> Mode {
+    let (prefix, mode) = if let Some(prefix) = name.strip_suffix("16") {
+        (prefix, Mode::Bits16)
+    } else if let Some(prefix) = name.strip_suffix("32") {
+        (prefix, Mode::Bits32)
+    } else {
+        return default;
+    };
+    // e.g. `.text16`, but not a function section like `.text.foo17h0123456789abcd32`
+    if prefix.is_empty() || prefix.contains(|c: char| c.is_ascii_digit()) {
+        default
+    } else {
+        mode
+    }
+}
+
+/// Describes `address` relative to the symbol that contains it, e.g. `main+0x10`.
+fn describe(symbols: &[Symbol], address: u64) -> Option<String> {
+    let index = symbols.partition_point(|symbol| symbol.address <= address);
+    let symbol = symbols[..index]
+        .iter()
+        .rev()
+        .find(|symbol| address - symbol.address < symbol.size.max(1))?;
+    Some(match address - symbol.address {
+        0 => symbol.name.clone(),
+        offset => format!("{}+{:#x}", symbol.name, offset),
+    })
+}
@@ -0,0 +1,180 @@
+//! Provides the OVMF firmware with which QEMU boots UEFI images.
+//!
+//! OVMF consists of the firmware code, which is mapped read-only, and a variable
+//! store, in which the firmware keeps its settings (e.g. the boot entries). The
+//! firmware is taken from the configuration, from the usual installation
+//! directories of the distributions and of QEMU, or downloaded into the user-level
+//! cache. The variable store is copied into the output directory, so that each
+//! project has its own writable copy and the installed template stays unchanged.
+
+use build::{cache_dir, download, is_offline, qemu_escape};
+use config::Config;
+use sha256;
+use std::fs;
+use std::path::{Path, PathBuf};
+use Error;
+
+/// The code and variable store templates of known OVMF installations.
+const INSTALLED: &[(&str, &str)] = &[
+    // Debian and Ubuntu
+    ("/usr/share/OVMF/OVMF_CODE_4M.fd", "/usr/share/OVMF/OVMF_VARS_4M.fd"),
+    ("/usr/share/OVMF/OVMF_CODE.fd", "/usr/share/OVMF/OVMF_VARS.fd"),
+    // Fedora
+    ("/usr/share/edk2/ovmf/OVMF_CODE.fd", "/usr/share/edk2/ovmf/OVMF_VARS.fd"),
+    // Arch Linux
+    ("/usr/share/edk2/x64/OVMF_CODE.4m.fd", "/usr/share/edk2/x64/OVMF_VARS.4m.fd"),
+    ("/usr/share/edk2-ovmf/x64/OVMF_CODE.fd", "/usr/share/edk2-ovmf/x64/OVMF_VARS.fd"),
+    // openSUSE
+    ("/usr/share/qemu/ovmf-x86_64-code.bin", "/usr/share/qemu/ovmf-x86_64-vars.bin"),
+];
+/// The firmware that QEMU ships itself, relative to its data directory.
+const QEMU_CODE: &str = "edk2-x86_64-code.fd";
+const QEMU_VARS: &str = "edk2-i386-vars.fd";
+/// The writable variable store of the project, relative to the output directory.
+const VARS_FILE: &str = "OVMF_VARS.fd";
+
+/// The `-drive` arguments that attach OVMF to the QEMU at `qemu` as flash memory.
+///
+/// The firmware is downloaded into the user-level cache or, without one, into
+/// `out_dir`, which also receives the writable variable store.
+pub(crate) fn qemu_args(
+    config: &Config,
+    qemu: &Path,
+    out_dir: &Path,
+) -> Result<Vec<String>, Error> {
+    let (code, vars) = firmware(config, qemu, out_dir)?;
+    Ok(drive_args(&code, &vars))
+}
+
+/// The firmware code and the writable variable store in `out_dir` for the QEMU at
+/// `qemu`, which are found or downloaded like for `qemu_args`.
+pub(crate) fn firmware(
+    config: &Config,
+    qemu: &Path,
+    out_dir: &Path,
+) -> Result<(PathBuf, PathBuf), Error> {
+    let (code, vars_template) = match (&config.ovmf.code, &config.ovmf.vars) {
+        (Some(code), Some(vars)) => (code.clone(), vars.clone()),
+        _ => match find_installed(qemu) {
+            Some(firmware) => firmware,
+            None => download_firmware(config, out_dir)?,
+        },
+    };
+
+    // the variable store of a different firmware build doesn't fit the code
+    let vars = out_dir.join(VARS_FILE);
+    let template_len = fs::metadata(&vars_template)?.len();
+    if fs::metadata(&vars).map(|metadata| metadata.len()).ok() != Some(template_len) {
+        fs::create_dir_all(out_dir)?;
+        // written instead of copied, since installed templates are often read-only
+        fs::write(&vars, fs::read(&vars_template)?)?;
+    }
+    Ok((code, vars))
+}
+
+/// The `-drive` arguments that attach the firmware `code` and the variable store
+/// `vars` as flash memory.
+pub(crate) fn drive_args(code: &Path, vars: &Path) -> Vec<String> {
+    vec![
+        "-drive".into(),
+        format!("if=pflash,format=raw,unit=0,readonly=on,file={}", qemu_escape(code)),
+        "-drive".into(),
+        format!("if=pflash,format=raw,unit=1,file={}", qemu_escape(vars)),
+    ]
+}
+
+/// Searches the known OVMF installations, including the firmware next to `qemu`.
+fn find_installed(qemu: &Path) -> Option<(PathBuf, PathBuf)> {
+    let installed = INSTALLED
+        .iter()
+        .map(|&(code, vars)| (PathBuf::from(code), PathBuf::from(vars)));
+    // e.g. `/usr/local/bin/qemu-system-x86_64` and `/usr/local/share/qemu`, or the
+    // `share` directory next to `qemu-system-x86_64.exe` on Windows
+    let qemu_dir = qemu
+        .canonicalize()
+        .ok()
+        .and_then(|qemu| qemu.parent().map(Path::to_path_buf));
+    let bundled = qemu_dir.into_iter().flat_map(|dir| {
+        let data_dirs = vec![dir.join("../share/qemu"), dir.join("share")];
+        data_dirs.into_iter().map(|dir| (dir.join(QEMU_CODE), dir.join(QEMU_VARS)))
+    });
+    installed.chain(bundled).find(|(code, vars)| code.is_file() && vars.is_file())
+}
+
+/// Downloads OVMF from the configured URL, unless it was downloaded before, and
+/// verifies the pinned hashes of the code and the variable store.
+fn download_firmware(config: &Config, out_dir: &Path) -> Result<(PathBuf, PathBuf), Error> {
+    let url = &config.ovmf.url;
+    let hashes = [("CODE", &config.ovmf.code_sha256), ("VARS", &config.ovmf.vars_sha256)];
+    // QEMU runs the firmware, so an unverified download needs to be allowed
+    if config.ovmf.code_sha256.is_none() && !config.allow_unverified_downloads {
+        return Err(Error::Config(format!(
+            "OVMF, which boots UEFI images, isn't installed and its download from {} isn't \
+             pinned: install it (e.g. the `ovmf` package), set `code` and `vars` or \
+             `code-sha256` and `vars-sha256` in `[package.metadata.bootimage.ovmf]`, or allow \
+             unverified downloads with `allow-unverified-downloads = true` or \
+             `--allow-unverified-downloads`",
+            url.replace("{}", "{CODE,VARS}")
+        )));
+    }
+    // each URL gets its own directory, so that changing it downloads again
+    let key = &sha256::to_hex(&sha256::sha256(url.as_bytes()))[..16];
+    let dir = cache_dir()
+        .map(|dir| dir.join("ovmf").join(key))
+        .unwrap_or_else(|| out_dir.join("ovmf").join(key));
+    let code = dir.join("OVMF_CODE.fd");
+    let vars = dir.join("OVMF_VARS.fd");
+    let verified = |path: &Path, expected: &Option<String>| match *expected {
+        Some(ref expected) => fs::read(path)
+            .map(|data| sha256::to_hex(&sha256::sha256(&data)) == *expected)
+            .unwrap_or(false),
+        None => path.is_file(),
+    };
+    if verified(&code, hashes[0].1) && verified(&vars, hashes[1].1) {
+        return Ok((code, vars));
+    }
+    if is_offline() {
+        return Err(Error::Download(format!(
+            "OVMF, which boots UEFI images, is neither installed nor available offline: install \
+             it (e.g. the `ovmf` package), set `code` and `vars` in \
+             `[package.metadata.bootimage.ovmf]`, or run once without `--offline` to download it \
+             from {}",
+            url.replace("{}", "{CODE,VARS}")
+        )));
+    }
+
+    println!("Downloading OVMF from {}", url.replace("{}", "{CODE,VARS}"));
+    // the files are downloaded next to their final location, so that an interrupted
+    // download never leaves an incomplete firmware behind
+    fs::create_dir_all(&dir)?;
+    for (&(part, expected), path) in hashes.iter().zip(&[&code, &vars]) {
+        let part_url = url.replace("{}", part);
+        let tmp_path = path.with_extension("fd.tmp");
+        download(&part_url, &tmp_path).map_err(|err| {
+            Error::Download(format!(
+                "{}, install OVMF (e.g. the `ovmf` package) or set `code` and `vars` in \
+                 `[package.metadata.bootimage.ovmf]`",
+                err
+            ))
+        })?;
+        let actual = sha256::to_hex(&sha256::sha256(&fs::read(&tmp_path)?));
+        match *expected {
+            Some(ref expected) if *expected != actual => {
+                fs::remove_file(&tmp_path)?;
+                return Err(Error::Download(format!(
+                    "the OVMF firmware downloaded from {} has the SHA-256 hash {}, expected {}",
+                    part_url, actual, expected
+                )));
+            }
+            Some(_) => {}
+            None => eprintln!(
+                "Warning: the OVMF firmware isn't pinned, add `{}-sha256 = \"{}\"` to \
+                 `[package.metadata.bootimage.ovmf]` to verify it",
+                part.to_lowercase(),
+                actual
+            ),
+        }
+        fs::rename(&tmp_path, path)?;
+    }
+    Ok((code, vars))
+}
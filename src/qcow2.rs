@@ -0,0 +1,143 @@
+//! Converts raw disk images to the qcow2 format used by QEMU.
+//!
+//! Clusters of the raw image that contain only zeros are not allocated, so the
+//! qcow2 image only grows with the actual content, independent of the virtual
+//! disk size.
+
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const CLUSTER_BITS: u32 = 16;
+const CLUSTER_SIZE: u64 = 1 << CLUSTER_BITS;
+const HEADER_SIZE: usize = 72;
+/// The number of 8-byte entries in an L2 table.
+const L2_ENTRIES: u64 = CLUSTER_SIZE / 8;
+/// The number of 16-bit refcounts in a refcount block.
+const REFCOUNT_BLOCK_ENTRIES: u64 = CLUSTER_SIZE / 2;
+/// Marks a cluster with a refcount of exactly one.
+const OFLAG_COPIED: u64 = 1 << 63;
+
+/// Writes a qcow2 image to `output` with the contents of the raw image at `raw_image`.
+pub(crate) fn create_qcow2_image(raw_image: &Path, output: &Path) -> io::Result<()> {
+    let mut raw = File::open(raw_image)?;
+    let virtual_size = raw.metadata()?.len();
+    let virtual_clusters = virtual_size.div_ceil(CLUSTER_SIZE);
+
+    // first pass: find the clusters that contain data
+    let mut buffer = vec![0u8; CLUSTER_SIZE as usize];
+    let mut data_clusters = Vec::new();
+    for cluster in 0..virtual_clusters {
+        read_cluster(&mut raw, &mut buffer)?;
+        if buffer.iter().any(|&b| b != 0) {
+            data_clusters.push(cluster);
+        }
+    }
+    let mut l2_tables = BTreeMap::new();
+    for &cluster in &data_clusters {
+        let next = l2_tables.len() as u64;
+        l2_tables.entry(cluster / L2_ENTRIES).or_insert(next);
+    }
+
+    let l1_entries = virtual_clusters.div_ceil(L2_ENTRIES);
+    let l1_clusters = (l1_entries * 8).div_ceil(CLUSTER_SIZE).max(1);
+    let metadata_clusters = 1 + l1_clusters + l2_tables.len() as u64;
+    let (refcount_table_clusters, refcount_blocks) =
+        refcount_clusters(metadata_clusters + data_clusters.len() as u64);
+
+    // cluster layout: header, L1 table, refcount table, refcount blocks, L2 tables, data
+    let l1_offset = CLUSTER_SIZE;
+    let refcount_table_offset = l1_offset + l1_clusters * CLUSTER_SIZE;
+    let refcount_blocks_offset = refcount_table_offset + refcount_table_clusters * CLUSTER_SIZE;
+    let l2_offset = refcount_blocks_offset + refcount_blocks * CLUSTER_SIZE;
+    let data_offset = l2_offset + l2_tables.len() as u64 * CLUSTER_SIZE;
+    let total_clusters = data_offset / CLUSTER_SIZE + data_clusters.len() as u64;
+
+    let mut header = [0u8; HEADER_SIZE];
+    header[0..4].copy_from_slice(b"QFI\xfb");
+    BigEndian::write_u32(&mut header[4..8], 2); // version
+    BigEndian::write_u32(&mut header[20..24], CLUSTER_BITS);
+    BigEndian::write_u64(&mut header[24..32], virtual_size);
+    BigEndian::write_u32(&mut header[36..40], l1_entries as u32);
+    BigEndian::write_u64(&mut header[40..48], l1_offset);
+    BigEndian::write_u64(&mut header[48..56], refcount_table_offset);
+    BigEndian::write_u32(&mut header[56..60], refcount_table_clusters as u32);
+
+    let mut l1_table = vec![0u8; (l1_clusters * CLUSTER_SIZE) as usize];
+    for (&l1_index, &l2_index) in &l2_tables {
+        let offset = l2_offset + l2_index * CLUSTER_SIZE;
+        let entry = &mut l1_table[l1_index as usize * 8..][..8];
+        BigEndian::write_u64(entry, offset | OFLAG_COPIED);
+    }
+
+    let mut l2 = vec![0u8; (l2_tables.len() as u64 * CLUSTER_SIZE) as usize];
+    for (i, &cluster) in data_clusters.iter().enumerate() {
+        let l2_index = l2_tables[&(cluster / L2_ENTRIES)];
+        let entry_offset = (l2_index * L2_ENTRIES + cluster % L2_ENTRIES) as usize * 8;
+        let offset = data_offset + i as u64 * CLUSTER_SIZE;
+        BigEndian::write_u64(&mut l2[entry_offset..entry_offset + 8], offset | OFLAG_COPIED);
+    }
+
+    let mut refcount_table = vec![0u8; (refcount_table_clusters * CLUSTER_SIZE) as usize];
+    for i in 0..refcount_blocks {
+        let offset = refcount_blocks_offset + i * CLUSTER_SIZE;
+        BigEndian::write_u64(&mut refcount_table[i as usize * 8..][..8], offset);
+    }
+    let mut refcounts = vec![0u8; (refcount_blocks * CLUSTER_SIZE) as usize];
+    for cluster in 0..total_clusters as usize {
+        BigEndian::write_u16(&mut refcounts[cluster * 2..cluster * 2 + 2], 1);
+    }
+
+    let mut output = File::create(output)?;
+    output.write_all(&header)?;
+    output.seek(SeekFrom::Start(l1_offset))?;
+    output.write_all(&l1_table)?;
+    output.write_all(&refcount_table)?;
+    output.write_all(&refcounts)?;
+    output.write_all(&l2)?;
+
+    // second pass: copy the data clusters
+    for cluster in data_clusters {
+        raw.seek(SeekFrom::Start(cluster * CLUSTER_SIZE))?;
+        read_cluster(&mut raw, &mut buffer)?;
+        output.write_all(&buffer)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the number of refcount table clusters and refcount blocks that are
+/// needed for `clusters` clusters plus the refcount structures themselves.
+fn refcount_clusters(clusters: u64) -> (u64, u64) {
+    let mut table_clusters = 1;
+    let mut blocks = 1;
+    loop {
+        let total = clusters + table_clusters + blocks;
+        let needed_blocks = total.div_ceil(REFCOUNT_BLOCK_ENTRIES);
+        let needed_table_clusters = (needed_blocks * 8).div_ceil(CLUSTER_SIZE);
+        if needed_blocks <= blocks && needed_table_clusters <= table_clusters {
+            return (table_clusters, blocks);
+        }
+        blocks = needed_blocks;
+        table_clusters = needed_table_clusters;
+    }
+}
+
+/// Reads the next cluster into `buffer`, filling it with zeros at the end of the file.
+fn read_cluster(raw: &mut File, buffer: &mut [u8]) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match raw.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    for byte in &mut buffer[filled..] {
+        *byte = 0;
+    }
+    Ok(())
+}
@@ -0,0 +1,229 @@
+//! Controls QEMU through its machine protocol (QMP).
+//!
+//! The default QEMU run command opens a QMP socket (a Unix socket in the temporary
+//! directory, or a TCP port on localhost on Windows) that QEMU listens on without
+//! waiting for a client. bootimage connects when it first needs to control the VM
+//! (e.g. to take a screenshot or to shut it down gracefully after a timeout), and
+//! the connection is shared, since QEMU only serves one client per socket.
+//!
+//! QMP is a line-based JSON protocol: QEMU greets the client, which enables the
+//! commands with `qmp_capabilities`, and answers every command with a `return` or
+//! an `error` object, between which it may send asynchronous events.
+
+use build::qemu_escape;
+use serde_json::{self, Value as JsonValue};
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use Error;
+
+/// The time that QEMU gets to answer a command.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The number of sockets that were created, which makes their names unique for
+/// concurrently running test kernels.
+static SOCKETS: AtomicUsize = AtomicUsize::new(0);
+
+/// A QMP socket, on which QEMU listens.
+pub(crate) struct Socket {
+    address: Address,
+    /// The connection to QEMU, once it is established.
+    connection: Mutex<Option<Qmp>>,
+}
+
+enum Address {
+    Unix(PathBuf),
+    Tcp(u16),
+}
+
+impl Socket {
+    /// A new socket that no other QEMU uses.
+    pub(crate) fn new() -> io::Result<Socket> {
+        Ok(Socket {
+            address: Address::new()?,
+            connection: Mutex::new(None),
+        })
+    }
+
+    /// The arguments that make QEMU listen on the socket.
+    pub(crate) fn qemu_args(&self) -> Vec<String> {
+        let address = match self.address {
+            Address::Unix(ref path) => format!("unix:{}", qemu_escape(path)),
+            Address::Tcp(port) => format!("tcp:{}:{}", Ipv4Addr::LOCALHOST, port),
+        };
+        vec!["-qmp".into(), format!("{},server=on,wait=off", address)]
+    }
+
+    /// Runs `f` with the connection to QEMU, which is established first if needed,
+    /// waiting up to `timeout` for QEMU to listen on the socket.
+    pub(crate) fn with<T, F>(&self, timeout: Duration, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Qmp) -> Result<T, Error>,
+    {
+        let mut connection = self.connection.lock().expect("QMP connection poisoned");
+        if connection.is_none() {
+            *connection = Some(self.address.connect(timeout)?);
+        }
+        let result = f(connection.as_mut().expect("QMP connection was established"));
+        if result.is_err() {
+            // e.g. after QEMU has exited, or after a response that wasn't read
+            *connection = None;
+        }
+        result
+    }
+}
+
+impl Address {
+    fn new() -> io::Result<Address> {
+        if cfg!(unix) {
+            // the temporary directory keeps the path below the length limit of Unix
+            // socket paths (about 100 bytes), which output directories often exceed
+            let path = env::temp_dir().join(format!(
+                "bootimage-qmp-{}-{}.sock",
+                process::id(),
+                SOCKETS.fetch_add(1, Ordering::SeqCst)
+            ));
+            if path.exists() {
+                fs::remove_file(&path)?;
+            }
+            Ok(Address::Unix(path))
+        } else {
+            // a free port, which is released again for QEMU
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+            Ok(Address::Tcp(listener.local_addr()?.port()))
+        }
+    }
+
+    /// Connects to QEMU and negotiates the capabilities, waiting up to `timeout` for
+    /// QEMU to listen on the socket.
+    fn connect(&self, timeout: Duration) -> Result<Qmp, Error> {
+        let start = Instant::now();
+        let (reader, writer): (Box<dyn Read + Send>, Box<dyn Write + Send>) = loop {
+            match self.open() {
+                Ok(streams) => break streams,
+                Err(_) if start.elapsed() < timeout => thread::sleep(Duration::from_millis(50)),
+                Err(err) => {
+                    return Err(Error::Run(format!("failed to connect to QMP socket: {}", err)))
+                }
+            }
+        };
+        let mut qmp = Qmp {
+            reader: BufReader::new(reader),
+            writer,
+        };
+        let greeting = qmp.read_message()?;
+        if greeting.get("QMP").is_none() {
+            return Err(Error::Run(format!("unexpected QMP greeting: {}", greeting)));
+        }
+        qmp.execute("qmp_capabilities", None)?;
+        Ok(qmp)
+    }
+
+    #[cfg(unix)]
+    fn open(&self) -> io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+        use std::os::unix::net::UnixStream;
+
+        match *self {
+            Address::Unix(ref path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+                Ok((Box::new(stream.try_clone()?), Box::new(stream)))
+            }
+            Address::Tcp(port) => open_tcp(port),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn open(&self) -> io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+        match *self {
+            Address::Unix(_) => unreachable!("Unix sockets are only used on Unix hosts"),
+            Address::Tcp(port) => open_tcp(port),
+        }
+    }
+}
+
+impl Drop for Address {
+    fn drop(&mut self) {
+        if let Address::Unix(ref path) = *self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn open_tcp(port: u16) -> io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+    let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port))?;
+    stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+    Ok((Box::new(stream.try_clone()?), Box::new(stream)))
+}
+
+/// A QMP connection to a running QEMU.
+pub(crate) struct Qmp {
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: Box<dyn Write + Send>,
+}
+
+impl Qmp {
+    /// The run state of the VM, e.g. `running`, `paused`, `shutdown`, or
+    /// `guest-panicked`.
+    pub(crate) fn status(&mut self) -> Result<String, Error> {
+        let status = self.execute("query-status", None)?;
+        Ok(status["status"].as_str().unwrap_or("unknown").to_owned())
+    }
+
+    /// Presses the ACPI power button of the VM, which asks the kernel to shut down.
+    pub(crate) fn system_powerdown(&mut self) -> Result<(), Error> {
+        self.execute("system_powerdown", None).map(|_| ())
+    }
+
+    /// Makes QEMU exit immediately, after it has flushed its disk images and logs.
+    pub(crate) fn quit(&mut self) -> Result<(), Error> {
+        self.execute("quit", None).map(|_| ())
+    }
+
+    /// Writes the screen of the VM to `path` (which must be absolute, since QEMU
+    /// resolves it) as a PPM image.
+    pub(crate) fn screendump(&mut self, path: &Path) -> Result<(), Error> {
+        let arguments = json!({ "filename": path.to_string_lossy() });
+        self.execute("screendump", Some(arguments)).map(|_| ())
+    }
+
+    /// Executes a command and returns its result.
+    fn execute(&mut self, command: &str, arguments: Option<JsonValue>) -> Result<JsonValue, Error> {
+        let mut message = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            message["arguments"] = arguments;
+        }
+        writeln!(self.writer, "{}", message)?;
+        self.writer.flush()?;
+        loop {
+            let mut response = self.read_message()?;
+            // events (e.g. `POWERDOWN`) arrive between the responses
+            if let Some(value) = response.get_mut("return") {
+                return Ok(value.take());
+            }
+            if let Some(error) = response.get("error") {
+                return Err(Error::Run(format!(
+                    "the QMP command `{}` failed: {}",
+                    command,
+                    error["desc"].as_str().unwrap_or("unknown error")
+                )));
+            }
+        }
+    }
+
+    fn read_message(&mut self) -> Result<JsonValue, Error> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(Error::Run("QEMU closed the QMP connection".into()));
+        }
+        serde_json::from_str(&line)
+            .map_err(|err| Error::Run(format!("invalid QMP message `{}`: {}", line.trim(), err)))
+    }
+}
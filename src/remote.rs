@@ -0,0 +1,108 @@
+//! Runs the disk image on a remote machine over SSH, e.g. when the development
+//! machine can't virtualize the target (like an ARM laptop that builds x86 kernels).
+//!
+//! The files of the run (the disk image and the OVMF firmware of UEFI images) are
+//! copied into `.cache/bootimage/remote/<output stem>` in the home directory of the
+//! remote user, with rsync, which only transfers the changed parts of a rebuilt
+//! image, or with scp if rsync isn't installed. The run command is started in that
+//! directory through `ssh`, so it refers to the files by their names, and the output
+//! of `ssh` is the serial output of the VM. SSH is configured as usual (e.g. keys and
+//! host aliases in `~/.ssh/config`), so that bootimage never handles passwords itself.
+
+use std::io;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus};
+use Error;
+
+/// A directory on a remote machine.
+pub(crate) struct Remote {
+    /// The SSH destination, e.g. `user@host` or a host alias.
+    host: String,
+    /// The directory relative to the home directory of the remote user.
+    dir: String,
+}
+
+impl Remote {
+    /// The directory of the output `stem` on `host`.
+    pub(crate) fn new(host: &str, stem: &str) -> Remote {
+        Remote {
+            host: host.into(),
+            dir: format!(".cache/bootimage/remote/{}", stem),
+        }
+    }
+
+    /// Copies `files` into the directory, where they keep their file names.
+    pub(crate) fn upload(&self, files: &[PathBuf]) -> Result<(), Error> {
+        let mkdir = Command::new("ssh")
+            .arg("-T")
+            .arg(&self.host)
+            .arg(format!("mkdir -p {}", quote(&self.dir)))
+            .status();
+        self.check("create the directory", mkdir)?;
+
+        println!("Copying the disk image to {}:{}", self.host, self.dir);
+        let destination = format!("{}:{}/", self.host, self.dir);
+        let rsync = Command::new("rsync")
+            .arg("--compress")
+            .arg("--times")
+            .args(files)
+            .arg(&destination)
+            .status();
+        match rsync {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => self.scp(files, &destination),
+            // without rsync on the remote machine, the shell there exits with 127, which
+            // rsync reports as a broken protocol stream (12)
+            Ok(status) if status.code() == Some(12) || status.code() == Some(127) => {
+                println!("rsync failed on {}, copying with scp instead", self.host);
+                self.scp(files, &destination)
+            }
+            rsync => self.check("copy the files", rsync),
+        }
+    }
+
+    /// Copies `files` with scp, for machines without rsync.
+    fn scp(&self, files: &[PathBuf], destination: &str) -> Result<(), Error> {
+        let scp = Command::new("scp")
+            .arg("-q")
+            .arg("-C")
+            .args(files)
+            .arg(destination)
+            .status();
+        self.check("copy the files", scp)
+    }
+
+    /// The `ssh` arguments that run `command` in the directory, with a terminal if
+    /// it is `interactive` (which also stops the command when `ssh` is killed).
+    pub(crate) fn command(&self, command: &[String], interactive: bool) -> Vec<String> {
+        let quoted: Vec<String> = command.iter().map(|arg| quote(arg)).collect();
+        vec![
+            if interactive { "-tt" } else { "-T" }.into(),
+            self.host.clone(),
+            format!("cd {} && exec {}", quote(&self.dir), quoted.join(" ")),
+        ]
+    }
+
+    fn check(&self, action: &str, status: io::Result<ExitStatus>) -> Result<(), Error> {
+        match status {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(Error::Run(format!(
+                "failed to {} on {} ({})",
+                action, self.host, status
+            ))),
+            Err(err) => Err(Error::Run(format!(
+                "failed to {} on {}: {}",
+                action, self.host, err
+            ))),
+        }
+    }
+}
+
+/// Quotes an argument for the POSIX shell of the remote user.
+fn quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.into()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
@@ -0,0 +1,156 @@
+//! Records deterministic runs of the default QEMU run command and replays them.
+//!
+//! With `-icount`, QEMU executes a fixed number of instructions per virtual time
+//! instead of running with the host clock, and its record/replay mode writes every
+//! nondeterministic input of the VM (e.g. interrupts, clock reads, serial input, and
+//! the completion of disk requests) to a replay log. Replaying the log executes the
+//! run again instruction by instruction, so that a rare failure of a test kernel can
+//! be reproduced as often as needed.
+//!
+//! A recording is a directory named after the output in the `replay` directory of
+//! the output directory. It contains the replay log, a copy of the disk image (the
+//! image would otherwise be replaced by the next build), the OVMF variable store of
+//! UEFI runs, and the QEMU command that `bootimage replay` runs again in replay mode.
+//! All disks are attached with `-snapshot`, so that the recorded run doesn't change
+//! the files that the replay starts from.
+
+use build::qemu_escape;
+use config::{Config, ImageFormat};
+use qmp;
+use serde_json::{self, Value as JsonValue};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use Error;
+
+/// The file of the recording that contains the replay log.
+const LOG_FILE: &str = "replay.bin";
+/// The file of the recording that contains the QEMU command.
+const COMMAND_FILE: &str = "command.json";
+
+/// The recording of a deterministic run.
+pub(crate) struct Recording {
+    dir: PathBuf,
+}
+
+impl Recording {
+    /// Starts a new recording of the disk image of `config`, which replaces the previous
+    /// recording.
+    pub(crate) fn create(config: &Config, out_dir: &Path) -> Result<Recording, Error> {
+        let dir = recording_dir(config, out_dir);
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(&dir)?;
+        // QEMU resolves the paths against its own working directory
+        Ok(Recording {
+            dir: dir.canonicalize()?,
+        })
+    }
+
+    /// The directory of the recording, which receives the OVMF variable store.
+    pub(crate) fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The QEMU arguments that attach the disk image and record the run, which replace
+    /// the arguments of the default run command.
+    ///
+    /// Disk requests complete asynchronously, so the disk is attached through the
+    /// `blkreplay` driver, which records when they complete.
+    pub(crate) fn qemu_args(&self, config: &Config) -> Result<Vec<String>, Error> {
+        let image_path = config.image_path();
+        let image = self.dir.join(image_path.file_name().expect("image path has a file name"));
+        fs::copy(&image_path, &image)?;
+        let (format, device) = match config.format {
+            ImageFormat::Iso | ImageFormat::GrubIso | ImageFormat::Isolinux => ("raw", "ide-cd"),
+            ImageFormat::Qcow2 => ("qcow2", "ide-hd"),
+            _ => ("raw", "ide-hd"),
+        };
+        Ok(vec![
+            "-icount".into(),
+            format!("shift=auto,rr=record,rrfile={}", qemu_escape(&self.dir.join(LOG_FILE))),
+            "-snapshot".into(),
+            "-drive".into(),
+            format!("if=none,id=img-direct,format={},file={}", format, qemu_escape(&image)),
+            "-drive".into(),
+            "if=none,id=img-blkreplay,driver=blkreplay,image=img-direct".into(),
+            "-device".into(),
+            format!("{},drive=img-blkreplay", device),
+        ])
+    }
+
+    /// Saves the QEMU command of the run, without its QMP socket, which the replay
+    /// opens anew.
+    pub(crate) fn save(&self, command: &process::Command) -> Result<(), Error> {
+        let mut args = Vec::new();
+        let mut command_args = command.get_args().map(|arg| arg.to_string_lossy().into_owned());
+        while let Some(arg) = command_args.next() {
+            if arg == "-qmp" {
+                command_args.next();
+            } else {
+                args.push(arg);
+            }
+        }
+        let saved = json!({
+            "program": command.get_program().to_string_lossy(),
+            "args": args,
+        });
+        fs::write(self.dir.join(COMMAND_FILE), format!("{:#}\n", saved))?;
+        Ok(())
+    }
+}
+
+/// The directory of the recording of the disk image of `config`.
+pub(crate) fn recording_dir(config: &Config, out_dir: &Path) -> PathBuf {
+    let stem = config
+        .output
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "bootimage".into());
+    out_dir.join("replay").join(stem)
+}
+
+/// Removes the recording of the disk image of `config`, e.g. after the test passed.
+pub(crate) fn discard(config: &Config, out_dir: &Path) -> Result<(), Error> {
+    let dir = recording_dir(config, out_dir);
+    if dir.exists() {
+        fs::remove_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+/// The QEMU command that replays the recording of the disk image of `config`, with
+/// `qmp` as its QMP socket.
+pub(crate) fn command(
+    config: &Config,
+    out_dir: &Path,
+    qmp: &qmp::Socket,
+) -> Result<process::Command, Error> {
+    let dir = recording_dir(config, out_dir);
+    let path = dir.join(COMMAND_FILE);
+    let content = fs::read_to_string(&path).map_err(|_| {
+        Error::Run(format!(
+            "there is no recording in {}, record a run with `--deterministic` first",
+            dir.display()
+        ))
+    })?;
+    let saved: JsonValue = serde_json::from_str(&content)
+        .map_err(|err| Error::Run(format!("invalid recording {}: {}", path.display(), err)))?;
+    let program = saved["program"].as_str();
+    let args = saved["args"].as_array().map(|args| {
+        args.iter()
+            .filter_map(JsonValue::as_str)
+            .map(|arg| arg.replace("rr=record", "rr=replay"))
+            .collect::<Vec<_>>()
+    });
+    match (program, args) {
+        (Some(program), Some(args)) => {
+            let mut command = process::Command::new(program);
+            command.args(qmp.qemu_args());
+            command.args(args);
+            Ok(command)
+        }
+        _ => Err(Error::Run(format!("invalid recording {}", path.display()))),
+    }
+}
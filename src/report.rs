@@ -0,0 +1,124 @@
+//! Writes the results of `bootimage test` as a machine-readable test report.
+//!
+//! JUnit XML reports are understood by most CI systems (e.g. GitLab and
+//! Jenkins), which show the contained test cases in their test dashboards.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// A JUnit XML file with a test case for every test kernel.
+    Junit,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "junit" => Ok(ReportFormat::Junit),
+            other => Err(format!("unknown report format `{}` (expected `junit`)", other)),
+        }
+    }
+}
+
+/// The outcome of a single test kernel.
+pub(crate) struct TestCase {
+    pub name: String,
+    pub duration: Duration,
+    /// The reason why the test failed, or `None` if it passed.
+    pub failure: Option<String>,
+    /// The serial output of the test kernel.
+    pub output: String,
+    /// The log file that the serial output was captured in.
+    pub serial_log: Option<PathBuf>,
+}
+
+/// Writes a report of the test cases of `suite` to `path` in the given format.
+pub(crate) fn write_report(
+    path: &Path,
+    format: ReportFormat,
+    suite: &str,
+    tests: &[TestCase],
+) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = File::create(path)?;
+    match format {
+        ReportFormat::Junit => write_junit(&mut file, suite, tests),
+    }
+}
+
+fn write_junit<W: Write>(out: &mut W, suite: &str, tests: &[TestCase]) -> io::Result<()> {
+    let failures = tests.iter().filter(|test| test.failure.is_some()).count();
+    let time: Duration = tests.iter().map(|test| test.duration).sum();
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        out,
+        r#"<testsuites tests="{}" failures="{}" time="{:.3}">"#,
+        tests.len(),
+        failures,
+        time.as_secs_f64()
+    )?;
+    writeln!(
+        out,
+        r#"  <testsuite name="{}" tests="{}" failures="{}" errors="0" time="{:.3}">"#,
+        escape(suite),
+        tests.len(),
+        failures,
+        time.as_secs_f64()
+    )?;
+    for test in tests {
+        writeln!(
+            out,
+            r#"    <testcase name="{}" classname="{}" time="{:.3}">"#,
+            escape(&test.name),
+            escape(suite),
+            test.duration.as_secs_f64()
+        )?;
+        if let Some(ref log) = test.serial_log {
+            writeln!(out, "      <properties>")?;
+            writeln!(
+                out,
+                r#"        <property name="serial_log" value="{}"/>"#,
+                escape(&log.to_string_lossy())
+            )?;
+            writeln!(out, "      </properties>")?;
+        }
+        if let Some(ref reason) = test.failure {
+            writeln!(out, r#"      <failure message="{}"/>"#, escape(reason))?;
+        }
+        if !test.output.is_empty() {
+            writeln!(out, "      <system-out>{}</system-out>", escape(&test.output))?;
+        }
+        writeln!(out, "    </testcase>")?;
+    }
+    writeln!(out, "  </testsuite>")?;
+    writeln!(out, "</testsuites>")
+}
+
+/// Escapes `text` for XML content and attribute values.
+///
+/// Control characters (e.g. the escape sequences of colored serial output) are
+/// not allowed in XML 1.0 documents, so they are replaced.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => escaped.push('\u{fffd}'),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,238 @@
+//! Takes screenshots of the VM through QMP and compares them with golden images.
+//!
+//! QEMU writes the screen as PPM images (`P6`, 8 bits per channel), which are named
+//! after the output and the time at which they were taken, e.g.
+//! `bootimage-os-2.5s.ppm`, or `bootimage-os-end.ppm` for the last screen before
+//! the run ended. QEMU exits immediately when the kernel writes to `isa-debug-exit`,
+//! so the last screen is captured continuously while the VM runs and reflects the
+//! screen at most `END_INTERVAL` before the end. Golden images have the same names,
+//! and a screenshot matches its golden image if at most the tolerated fraction of
+//! its pixels differs.
+
+use config::{Config, ScreenshotConfig};
+use qmp;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use Error;
+
+/// The interval in which the last screen is captured.
+const END_INTERVAL: Duration = Duration::from_millis(250);
+/// The time that QEMU gets to listen on its QMP socket.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A screenshot that was taken during a run.
+pub(crate) struct Screenshot {
+    /// The time at which it was taken (e.g. `2.5s`), or `end`.
+    label: String,
+    path: PathBuf,
+}
+
+/// Takes the configured screenshots of the VM behind `qmp` until `stopped` is set
+/// and returns the screenshots that were taken.
+pub(crate) fn capture(
+    config: &Config,
+    qmp: &qmp::Socket,
+    out_dir: &Path,
+    stopped: &AtomicBool,
+) -> Result<Vec<Screenshot>, Error> {
+    let dir = screenshot_dir(out_dir)?;
+    let start = Instant::now();
+    let mut pending = config.screenshots.at.clone();
+    pending.sort();
+    pending.reverse();
+    let mut screenshots = Vec::new();
+    let mut last_end = None;
+    let mut end_path = None;
+    let mut connected = false;
+    let mut screendump = |path: &Path| {
+        // QEMU only needs time to listen on the socket before the first connection,
+        // later connections fail at once if it has exited
+        let timeout = if connected { Duration::ZERO } else { CONNECT_TIMEOUT };
+        let result = qmp.with(timeout, |qmp| qmp.screendump(path));
+        connected = true;
+        result.is_ok()
+    };
+    while !stopped.load(Ordering::SeqCst) {
+        while pending.last().is_some_and(|&at| start.elapsed() >= at) {
+            let label = time_label(pending.pop().expect("a screenshot is pending"));
+            let path = dir.join(file_name(config, &label));
+            // the kernel may already have exited, which the missing screenshot reports
+            if screendump(&path) {
+                screenshots.push(Screenshot { label, path });
+            }
+        }
+        let end_due = last_end.is_none_or(|last: Instant| last.elapsed() >= END_INTERVAL);
+        if config.screenshots.at_end && end_due {
+            last_end = Some(Instant::now());
+            let path = dir.join(file_name(config, "end"));
+            // written next to the previous screenshot, which stays complete if QEMU
+            // exits while it writes the new one
+            let tmp_path = path.with_extension("ppm.tmp");
+            if screendump(&tmp_path) {
+                fs::rename(&tmp_path, &path)?;
+                end_path = Some(path);
+            }
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    if let Some(path) = end_path {
+        screenshots.push(Screenshot {
+            label: "end".into(),
+            path,
+        });
+    }
+    Ok(screenshots)
+}
+
+/// Reports the screenshots of a run and compares them with their golden images, or
+/// replaces the golden images with them.
+pub(crate) fn check(config: &Config, screenshots: &[Screenshot]) -> Result<(), Error> {
+    let settings = &config.screenshots;
+    let mut failures = Vec::new();
+    for &at in &settings.at {
+        let label = time_label(at);
+        if !screenshots.iter().any(|screenshot| screenshot.label == label) {
+            failures.push(format!("the run ended before the screenshot at {}", label));
+        }
+    }
+    for screenshot in screenshots {
+        println!("Screenshot at {}: {}", screenshot.label, screenshot.path.display());
+        let golden_dir = match settings.golden_dir {
+            Some(ref golden_dir) => golden_dir,
+            None => continue,
+        };
+        let golden = golden_dir.join(file_name(config, &screenshot.label));
+        if settings.update_golden {
+            fs::create_dir_all(golden_dir)?;
+            fs::copy(&screenshot.path, &golden)?;
+            println!("Updated golden image {}", golden.display());
+        } else if let Err(failure) = compare(settings, &screenshot.path, &golden) {
+            failures.push(format!("the screenshot at {} {}", screenshot.label, failure));
+        }
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Screenshot(failures.join("\n")))
+    }
+}
+
+/// The directory of the screenshots, which is created if needed.
+///
+/// The path is absolute, since QEMU resolves it against its own working directory.
+fn screenshot_dir(out_dir: &Path) -> Result<PathBuf, Error> {
+    let dir = out_dir.join("screenshots");
+    fs::create_dir_all(&dir)?;
+    Ok(dir.canonicalize()?)
+}
+
+/// The file name of the screenshot with the given label.
+fn file_name(config: &Config, label: &str) -> String {
+    let stem = config
+        .output
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "bootimage".into());
+    format!("{}-{}.ppm", stem, label)
+}
+
+/// The label of the screenshot at `at`, e.g. `2s` or `2.5s`.
+fn time_label(at: Duration) -> String {
+    format!("{}s", at.as_secs_f64())
+}
+
+/// Compares the screenshot with its golden image, returning why it doesn't match.
+fn compare(settings: &ScreenshotConfig, screenshot: &Path, golden: &Path) -> Result<(), String> {
+    if !golden.is_file() {
+        return Err(format!(
+            "has no golden image at {} (run with `--update-screenshots` to create it)",
+            golden.display()
+        ));
+    }
+    let image = read_ppm(screenshot)?;
+    let golden_image = read_ppm(golden)?;
+    if (image.width, image.height) != (golden_image.width, golden_image.height) {
+        return Err(format!(
+            "has the size {}x{}, but the golden image {} has the size {}x{}",
+            image.width,
+            image.height,
+            golden.display(),
+            golden_image.width,
+            golden_image.height
+        ));
+    }
+    let differing = image
+        .pixels
+        .chunks(3)
+        .zip(golden_image.pixels.chunks(3))
+        .filter(|(pixel, golden_pixel)| pixel != golden_pixel)
+        .count();
+    let fraction = differing as f64 / (image.width * image.height).max(1) as f64;
+    if fraction > settings.tolerance {
+        return Err(format!(
+            "differs from the golden image {} in {} pixels ({:.2}%, tolerated are {:.2}%)",
+            golden.display(),
+            differing,
+            fraction * 100.0,
+            settings.tolerance * 100.0
+        ));
+    }
+    Ok(())
+}
+
+/// An image with 8-bit RGB pixels.
+struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+/// Reads a binary PPM image (`P6`) with 8 bits per channel.
+fn read_ppm(path: &Path) -> Result<Image, String> {
+    let invalid = |reason: &str| format!("{} is not a valid PPM image: {}", path.display(), reason);
+    let data = fs::read(path).map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+    // the header consists of the magic number, the width, the height, and the maximum
+    // value of a channel, separated by whitespace, in which comments start with `#`
+    let mut fields = Vec::new();
+    let mut offset = 0;
+    while fields.len() < 4 {
+        match data.get(offset) {
+            Some(b'#') => {
+                while data.get(offset).is_some_and(|&byte| byte != b'\n') {
+                    offset += 1;
+                }
+            }
+            Some(byte) if byte.is_ascii_whitespace() => offset += 1,
+            Some(_) => {
+                let start = offset;
+                while data.get(offset).is_some_and(|byte| !byte.is_ascii_whitespace()) {
+                    offset += 1;
+                }
+                fields.push(String::from_utf8_lossy(&data[start..offset]).into_owned());
+            }
+            None => return Err(invalid("the header is incomplete")),
+        }
+    }
+    if fields[0] != "P6" {
+        return Err(invalid("only binary RGB images (`P6`) are supported"));
+    }
+    let number = |field: &str| field.parse::<usize>().map_err(|_| invalid("invalid header"));
+    let (width, height) = (number(&fields[1])?, number(&fields[2])?);
+    if number(&fields[3])? != 255 {
+        return Err(invalid("only 8 bits per channel are supported"));
+    }
+    // a single whitespace character separates the header from the pixels
+    let pixels = data.get(offset + 1..).unwrap_or_default();
+    let len = width * height * 3;
+    if pixels.len() < len {
+        return Err(invalid("the pixels are incomplete"));
+    }
+    Ok(Image {
+        width,
+        height,
+        pixels: pixels[..len].to_vec(),
+    })
+}
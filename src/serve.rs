@@ -0,0 +1,603 @@
+//! Serves the built image and the netboot artifacts over TFTP and HTTP.
+//!
+//! The TFTP server answers read requests (RFC 1350) with the `blksize`, `tsize`,
+//! and `timeout` options (RFC 2347 to 2349), which PXE firmware uses. The HTTP
+//! server answers `GET` and `HEAD` requests, including requests for a single byte
+//! range, which iPXE uses for `sanboot`. A `cmdline` query parameter replaces the
+//! kernel command line of the disk images that have a kernel info block. Before
+//! a file is served, the sources are checked for changes and the image is
+//! rebuilt if they have changed, so a machine that boots from the network always
+//! gets the latest kernel.
+
+use byteorder::{BigEndian, ByteOrder};
+use image::{self, KernelInfoBlock};
+use inspect;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock, RwLockReadGuard};
+use std::thread::{self, Scope};
+use std::time::{Duration, Instant};
+use watch::{fingerprint, Fingerprint, Sources};
+use Error;
+
+/// The minimum interval between two checks of the sources.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// The block size of TFTP transfers without the `blksize` option.
+const TFTP_BLOCK_SIZE: usize = 512;
+/// The largest block size that fits into a UDP datagram (RFC 2348).
+const TFTP_MAX_BLOCK_SIZE: usize = 65464;
+/// The retransmission timeout of TFTP transfers without the `timeout` option.
+const TFTP_TIMEOUT: u64 = 1;
+/// How often a TFTP packet is sent before the transfer is given up.
+const TFTP_RETRIES: u32 = 5;
+/// The TFTP opcodes.
+const RRQ: u16 = 1;
+const WRQ: u16 = 2;
+const DATA: u16 = 3;
+const ACK: u16 = 4;
+const ERROR: u16 = 5;
+const OACK: u16 = 6;
+/// The TFTP error codes.
+const FILE_NOT_FOUND: u16 = 1;
+const ACCESS_VIOLATION: u16 = 2;
+const ILLEGAL_OPERATION: u16 = 4;
+/// The maximum size of the head of an HTTP request.
+const MAX_REQUEST_HEAD: u64 = 8192;
+/// How long an HTTP client may take to send its request.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where and how the files are served.
+pub(crate) struct Options {
+    /// The address that the servers listen on.
+    pub address: IpAddr,
+    pub http_port: u16,
+    pub tftp_port: u16,
+    /// Whether the image is rebuilt as soon as the sources change, instead of
+    /// when the next file is requested.
+    pub watch: bool,
+}
+
+/// A file that is served.
+pub(crate) struct ServedFile {
+    pub path: PathBuf,
+    /// Whether the file is a disk image with a kernel info block, whose kernel
+    /// command line is replaced by the `cmdline` query parameter.
+    pub replaces_cmdline: bool,
+}
+
+struct Server<B> {
+    /// The served files by their path (without a leading `/`).
+    files: BTreeMap<String, ServedFile>,
+    sources: Sources,
+    /// Builds the image and returns whether it succeeded.
+    build: B,
+    /// Held for reading while a file is served and for writing while the image is
+    /// rebuilt, so that no client gets a partially written file.
+    build_lock: RwLock<()>,
+    /// The fingerprint of the sources of the last build and the time of the last check.
+    checked: Mutex<(Fingerprint, Instant)>,
+}
+
+/// Builds the image with `build` and then serves `files` (by their path) until
+/// bootimage is interrupted.
+///
+/// A TFTP port that can't be bound without privileges (like the default port 69)
+/// is skipped with a warning, so that the files are still served over HTTP.
+pub(crate) fn serve<B>(
+    files: BTreeMap<String, ServedFile>,
+    sources: Sources,
+    build: B,
+    options: &Options,
+) -> Result<(), Error>
+where
+    B: Fn() -> bool + Sync,
+{
+    if !build() {
+        return Err(Error::Serve("failed to build the image".into()));
+    }
+    // unlike `bootimage watch`, the sources are summarized after a build, so that a
+    // request never waits for a rebuild because of a change that the build already
+    // contains; a change made while a build runs is picked up with the next change
+    let fingerprint = fingerprint(&sources);
+    let server = Server {
+        files,
+        sources,
+        build,
+        build_lock: RwLock::new(()),
+        checked: Mutex::new((fingerprint, Instant::now())),
+    };
+
+    let http = TcpListener::bind((options.address, options.http_port)).map_err(|err| {
+        Error::Serve(format!(
+            "failed to listen for HTTP on port {}: {}",
+            options.http_port, err
+        ))
+    })?;
+    let tftp = match UdpSocket::bind((options.address, options.tftp_port)) {
+        Ok(socket) => Some(socket),
+        Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            eprintln!(
+                "Warning: TFTP isn't served, since port {} requires privileges (run bootimage \
+                 as root, grant it `CAP_NET_BIND_SERVICE`, or choose a port with `--tftp-port`)",
+                options.tftp_port
+            );
+            None
+        }
+        Err(err) => {
+            return Err(Error::Serve(format!(
+                "failed to listen for TFTP on port {}: {}",
+                options.tftp_port, err
+            )))
+        }
+    };
+
+    println!("Serving HTTP on {}", http.local_addr()?);
+    if let Some(ref tftp) = tftp {
+        println!("Serving TFTP on {}", tftp.local_addr()?);
+    }
+    for name in server.files.keys() {
+        println!("    /{}", name);
+    }
+    if options.watch {
+        println!("Rebuilding when the sources change");
+    } else {
+        println!("Rebuilding on the next request when the sources change");
+    }
+
+    let server = &server;
+    thread::scope(|scope| {
+        if let Some(tftp) = tftp {
+            scope.spawn(move || serve_tftp(scope, server, tftp, options.address));
+        }
+        if options.watch {
+            scope.spawn(move || loop {
+                thread::sleep(CHECK_INTERVAL);
+                server.refresh();
+            });
+        }
+        for stream in http.incoming() {
+            match stream {
+                Ok(stream) => {
+                    scope.spawn(move || {
+                        let peer = stream.peer_addr();
+                        if let Err(err) = respond_http(server, stream) {
+                            if let Ok(peer) = peer {
+                                eprintln!("Warning: HTTP request from {} failed: {}", peer, err);
+                            }
+                        }
+                    });
+                }
+                Err(err) => eprintln!("Warning: failed to accept an HTTP connection: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+impl<B: Fn() -> bool + Sync> Server<B> {
+    /// Rebuilds the image if the sources have changed since the last build.
+    fn refresh(&self) {
+        let mut checked = self.checked.lock().unwrap();
+        if checked.1.elapsed() < CHECK_INTERVAL {
+            return;
+        }
+        if fingerprint(&self.sources) != checked.0 {
+            let _build = self.build_lock.write().unwrap();
+            println!("The sources have changed, rebuilding");
+            if !(self.build)() {
+                eprintln!("Warning: the rebuild failed, serving the files of the last build");
+            }
+            checked.0 = fingerprint(&self.sources);
+        }
+        checked.1 = Instant::now();
+    }
+
+    /// Opens the served file at `path` after rebuilding the image if necessary.
+    ///
+    /// The returned guard prevents rebuilds until the file is sent.
+    fn open(&self, path: &str) -> Option<(RwLockReadGuard<'_, ()>, &ServedFile, File, u64)> {
+        let served = self.files.get(path.trim_start_matches('/'))?;
+        self.refresh();
+        let guard = self.build_lock.read().unwrap();
+        let file = File::open(&served.path).ok()?;
+        let size = file.metadata().ok()?.len();
+        Some((guard, served, file, size))
+    }
+}
+
+/// The address of this machine that other machines in the local network reach
+/// the servers on, i.e. `address` if it is a specific one or else the address of
+/// the network interface with the default route.
+pub(crate) fn host_address(address: IpAddr) -> Option<IpAddr> {
+    if !address.is_unspecified() {
+        return Some(address);
+    }
+    // connecting a UDP socket selects the interface without sending anything
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((Ipv4Addr::new(192, 0, 2, 1), 9)).ok()?;
+    let address = socket.local_addr().ok()?.ip();
+    Some(address).filter(|address| !address.is_loopback() && !address.is_unspecified())
+}
+
+/// Receives TFTP requests on `socket` and answers each of them from a new
+/// socket on `address` in its own thread.
+fn serve_tftp<'scope, 'env, B>(
+    scope: &'scope Scope<'scope, 'env>,
+    server: &'env Server<B>,
+    socket: UdpSocket,
+    address: IpAddr,
+) where
+    B: Fn() -> bool + Sync,
+{
+    let mut buffer = [0; 2048];
+    loop {
+        let (len, client) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(err) => {
+                eprintln!("Warning: failed to receive a TFTP request: {}", err);
+                continue;
+            }
+        };
+        let request = buffer[..len].to_vec();
+        scope.spawn(move || {
+            if let Err(err) = transfer_tftp(server, &request, client, address) {
+                eprintln!("Warning: TFTP transfer to {} failed: {}", client, err);
+            }
+        });
+    }
+}
+
+/// Answers the TFTP `request` of `client`.
+fn transfer_tftp<B>(
+    server: &Server<B>,
+    request: &[u8],
+    client: SocketAddr,
+    address: IpAddr,
+) -> io::Result<()>
+where
+    B: Fn() -> bool + Sync,
+{
+    // the transfer uses a new port, which identifies it for the client
+    let socket = UdpSocket::bind((address, 0))?;
+    socket.connect(client)?;
+    let opcode = if request.len() >= 2 { BigEndian::read_u16(request) } else { 0 };
+    if opcode == WRQ {
+        return send_tftp_error(&socket, ACCESS_VIOLATION, "files can only be read");
+    } else if opcode != RRQ {
+        return send_tftp_error(&socket, ILLEGAL_OPERATION, "expected a read request");
+    }
+    // the file name, the mode, and pairs of option names and values
+    let fields: Vec<String> = request[2..]
+        .split(|&byte| byte == 0)
+        .map(|field| String::from_utf8_lossy(field).into_owned())
+        .collect();
+    let path = &fields[0];
+    let (_guard, _, mut file, size) = match server.open(path) {
+        Some(file) => file,
+        None => return send_tftp_error(&socket, FILE_NOT_FOUND, "file not found"),
+    };
+
+    let mut block_size = TFTP_BLOCK_SIZE;
+    let mut timeout = TFTP_TIMEOUT;
+    let mut acknowledged_options = Vec::new();
+    for option in fields.get(2..).unwrap_or_default().chunks(2) {
+        let (name, value) = match *option {
+            [ref name, ref value] => (name.to_ascii_lowercase(), value),
+            _ => break,
+        };
+        let value = match (name.as_str(), value.parse::<u64>()) {
+            ("blksize", Ok(size)) if size >= 8 => {
+                block_size = (size as usize).min(TFTP_MAX_BLOCK_SIZE);
+                block_size as u64
+            }
+            ("tsize", Ok(_)) => size,
+            ("timeout", Ok(seconds)) if (1..=255).contains(&seconds) => {
+                timeout = seconds;
+                seconds
+            }
+            _ => continue,
+        };
+        acknowledged_options.push((name, value));
+    }
+    socket.set_read_timeout(Some(Duration::from_secs(timeout)))?;
+    if !acknowledged_options.is_empty() {
+        let mut packet = vec![0, OACK as u8];
+        for (name, value) in acknowledged_options {
+            packet.extend_from_slice(name.as_bytes());
+            packet.push(0);
+            packet.extend_from_slice(value.to_string().as_bytes());
+            packet.push(0);
+        }
+        // the client acknowledges the options with block 0
+        if !send_tftp_packet(&socket, &packet, 0)? {
+            return Ok(());
+        }
+    }
+
+    if size > 0 {
+        println!("TFTP: sending {} to {}", path, client);
+    }
+    let mut data = vec![0; block_size];
+    let mut block: u16 = 0;
+    loop {
+        let len = read_full(&mut file, &mut data)?;
+        // the block numbers wrap around for files with more than 65535 blocks
+        block = block.wrapping_add(1);
+        let mut packet = vec![0, DATA as u8, 0, 0];
+        BigEndian::write_u16(&mut packet[2..4], block);
+        packet.extend_from_slice(&data[..len]);
+        if !send_tftp_packet(&socket, &packet, block)? {
+            return Ok(());
+        }
+        // a block shorter than the block size (possibly empty) ends the transfer
+        if len < block_size {
+            return Ok(());
+        }
+    }
+}
+
+/// Sends `packet` until the client acknowledges `block` and returns whether it
+/// did, or `false` if it aborted the transfer with an error packet.
+fn send_tftp_packet(socket: &UdpSocket, packet: &[u8], block: u16) -> io::Result<bool> {
+    let mut response = [0; 1024];
+    for _ in 0..TFTP_RETRIES {
+        socket.send(packet)?;
+        loop {
+            let len = match socket.recv(&mut response) {
+                Ok(len) => len,
+                Err(ref err)
+                    if err.kind() == io::ErrorKind::WouldBlock
+                        || err.kind() == io::ErrorKind::TimedOut =>
+                {
+                    break
+                }
+                Err(err) => return Err(err),
+            };
+            if len < 4 {
+                continue;
+            }
+            match BigEndian::read_u16(&response) {
+                ACK if BigEndian::read_u16(&response[2..]) == block => return Ok(true),
+                // e.g. PXE firmware that only requested the size of the file
+                ERROR => return Ok(false),
+                // a duplicate acknowledgement of an earlier block
+                _ => continue,
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "the client stopped acknowledging the packets",
+    ))
+}
+
+fn send_tftp_error(socket: &UdpSocket, code: u16, message: &str) -> io::Result<()> {
+    let mut packet = vec![0, ERROR as u8, 0, 0];
+    BigEndian::write_u16(&mut packet[2..4], code);
+    packet.extend_from_slice(message.as_bytes());
+    packet.push(0);
+    socket.send(&packet)?;
+    Ok(())
+}
+
+/// Reads from `file` until `buffer` is full or the end of the file is reached.
+fn read_full(file: &mut File, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match file.read(&mut buffer[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    Ok(len)
+}
+
+/// Answers the HTTP request on `stream` and closes the connection.
+fn respond_http<B>(server: &Server<B>, mut stream: TcpStream) -> io::Result<()>
+where
+    B: Fn() -> bool + Sync,
+{
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    let mut reader = BufReader::new((&stream).take(MAX_REQUEST_HEAD));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut range = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        let mut parts = header.splitn(2, ':');
+        let name = parts.next().unwrap_or_default().trim();
+        if name.eq_ignore_ascii_case("range") {
+            range = parts.next().map(|value| value.trim().to_owned());
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+    let mut target = target.splitn(2, '?');
+    let path = percent_decode(target.next().unwrap_or_default());
+    let cmdline = target
+        .next()
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|parameter| parameter.strip_prefix("cmdline="))
+        .map(percent_decode)
+        .filter(|cmdline| !cmdline.is_empty());
+    if method != "GET" && method != "HEAD" {
+        return respond_http_error(&mut stream, "405 Method Not Allowed", "Allow: GET, HEAD\r\n");
+    }
+    if path == "/" {
+        let mut index = String::new();
+        for name in server.files.keys() {
+            index.push_str(&format!("/{}\n", name));
+        }
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            index.len()
+        )?;
+        if method == "GET" {
+            stream.write_all(index.as_bytes())?;
+        }
+        return Ok(());
+    }
+    let (_guard, served, mut file, size) = match server.open(&path) {
+        Some(file) => file,
+        None => return respond_http_error(&mut stream, "404 Not Found", ""),
+    };
+    let patch = match cmdline {
+        Some(ref cmdline) if served.replaces_cmdline => {
+            match cmdline_patch(&served.path, cmdline) {
+                Ok(patch) => Some(patch),
+                Err(err) => {
+                    eprintln!(
+                        "Warning: can't serve {} with the command line `{}`: {}",
+                        path, cmdline, err
+                    );
+                    return respond_http_error(&mut stream, "400 Bad Request", "");
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let (status, start, len, content_range) = match range {
+        None => ("200 OK", 0, size, String::new()),
+        Some(range) => match parse_range(&range, size) {
+            Some((start, end)) => (
+                "206 Partial Content",
+                start,
+                end - start + 1,
+                format!("Content-Range: bytes {}-{}/{}\r\n", start, end, size),
+            ),
+            None => {
+                let content_range = format!("Content-Range: bytes */{}\r\n", size);
+                return respond_http_error(&mut stream, "416 Range Not Satisfiable", &content_range);
+            }
+        },
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n{}\
+         Accept-Ranges: bytes\r\nConnection: close\r\n\r\n",
+        status, len, content_range
+    )?;
+    if method == "GET" {
+        // `sanboot` reads the image in many small ranges, which aren't logged
+        if start == 0 {
+            match cmdline {
+                Some(ref cmdline) if patch.is_some() => println!(
+                    "HTTP: sending {} with the command line `{}` to {}",
+                    path,
+                    cmdline,
+                    stream.peer_addr()?
+                ),
+                _ => println!("HTTP: sending {} to {}", path, stream.peer_addr()?),
+            }
+        }
+        copy_range(&mut file, &mut stream, start, len, patch.as_ref())?;
+    }
+    Ok(())
+}
+
+/// The kernel info block of the disk image at `path` with the kernel command line
+/// `cmdline`, and its offset in the file.
+fn cmdline_patch(path: &Path, cmdline: &str) -> Result<(u64, KernelInfoBlock), String> {
+    let layout = inspect::layout(path)?;
+    let offset = layout.info_block_offset();
+    let mut block = [0; image::BLOCK_SIZE];
+    let mut file = File::open(path).map_err(|err| err.to_string())?;
+    file.seek(SeekFrom::Start(offset)).map_err(|err| err.to_string())?;
+    file.read_exact(&mut block).map_err(|err| err.to_string())?;
+    image::replace_cmdline(&mut block, cmdline)?;
+    Ok((offset, block))
+}
+
+/// Copies `len` bytes at `start` of `file` to `out`, replacing the bytes at the
+/// offset of `patch` with its kernel info block.
+fn copy_range<W: Write>(
+    file: &mut File,
+    out: &mut W,
+    start: u64,
+    len: u64,
+    patch: Option<&(u64, KernelInfoBlock)>,
+) -> io::Result<()> {
+    file.seek(SeekFrom::Start(start))?;
+    let mut buffer = vec![0; 64 * 1024];
+    let end = start + len;
+    let mut position = start;
+    while position < end {
+        let chunk = ((end - position) as usize).min(buffer.len());
+        file.read_exact(&mut buffer[..chunk])?;
+        if let Some(&(offset, ref block)) = patch {
+            let from = position.max(offset);
+            let to = (position + chunk as u64).min(offset + block.len() as u64);
+            if from < to {
+                buffer[(from - position) as usize..(to - position) as usize]
+                    .copy_from_slice(&block[(from - offset) as usize..(to - offset) as usize]);
+            }
+        }
+        out.write_all(&buffer[..chunk])?;
+        position += chunk as u64;
+    }
+    Ok(())
+}
+
+fn respond_http_error(stream: &mut TcpStream, status: &str, headers: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+        status, headers
+    )
+}
+
+/// Parses a `Range` header with a single byte range (e.g. `bytes=0-511`,
+/// `bytes=512-`, or `bytes=-512`) into the first and last byte of the range in a
+/// file of `size` bytes, or `None` if the range can't be satisfied.
+fn parse_range(range: &str, size: u64) -> Option<(u64, u64)> {
+    let range = range.strip_prefix("bytes=")?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (size.checked_sub(suffix.min(size))?, size.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, size.checked_sub(1)?),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(size.checked_sub(1)?)),
+    };
+    if start > end {
+        return None;
+    }
+    Some((start, end))
+}
+
+/// Decodes the `%XX` escapes of a request path.
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| u8::from_str_radix(&String::from_utf8_lossy(hex), 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,114 @@
+//! Breaks down the size of the kernel and the disk image.
+//!
+//! The section sizes and the largest symbols are read from the kernel ELF file,
+//! which shows what takes up space in the kernel (like `cargo bloat`).
+
+use rustc_demangle::demangle;
+use std::fmt;
+use xmas_elf::sections::{SectionData, ShType, SHF_ALLOC};
+use xmas_elf::symbol_table::{Entry, Type};
+use xmas_elf::ElfFile;
+
+/// A section of the kernel ELF file.
+pub(crate) struct Section {
+    pub name: String,
+    pub address: u64,
+    pub size: u64,
+    /// Whether the section is loaded into memory, i.e. not debug info or symbols.
+    pub loaded: bool,
+}
+
+/// A function or data symbol of the kernel.
+pub(crate) struct Symbol {
+    /// The demangled name of the symbol.
+    pub name: String,
+    pub size: u64,
+}
+
+/// The parts of a disk image whose kernel is appended to the bootloader.
+pub(crate) struct Composition {
+    pub bootloader: u64,
+    pub info_block: u64,
+    pub kernel: u64,
+    /// The alignment of the kernel to whole blocks and the `minimum-image-size`.
+    pub padding: u64,
+}
+
+impl Composition {
+    pub fn total(&self) -> u64 {
+        self.bootloader + self.info_block + self.kernel + self.padding
+    }
+}
+
+/// Returns the sections of the ELF file `elf` in file order.
+pub(crate) fn sections(elf: &[u8]) -> Result<Vec<Section>, String> {
+    let elf_file = ElfFile::new(elf).map_err(|err| format!("invalid kernel ELF: {}", err))?;
+    let mut sections = Vec::new();
+    for section in elf_file.section_iter().skip(1) {
+        let name = section.get_name(&elf_file)?;
+        sections.push(Section {
+            name: name.to_owned(),
+            address: section.address(),
+            size: section.size(),
+            loaded: section.flags() & SHF_ALLOC != 0,
+        });
+    }
+    Ok(sections)
+}
+
+/// Returns the `count` largest function and data symbols of the ELF file `elf`.
+pub(crate) fn largest_symbols(elf: &[u8], count: usize) -> Result<Vec<Symbol>, String> {
+    let elf_file = ElfFile::new(elf).map_err(|err| format!("invalid kernel ELF: {}", err))?;
+    let mut symbols = Vec::new();
+    for section in elf_file.section_iter() {
+        if section.get_type() != Ok(ShType::SymTab) {
+            continue;
+        }
+        let entries = match section.get_data(&elf_file) {
+            Ok(SectionData::SymbolTable64(entries)) => entries,
+            _ => continue,
+        };
+        for entry in entries {
+            match entry.get_type() {
+                Ok(Type::Func) | Ok(Type::Object) if entry.size() > 0 => {}
+                _ => continue,
+            }
+            let name = entry.get_name(&elf_file)?;
+            symbols.push(Symbol {
+                name: format!("{:#}", demangle(name)),
+                size: entry.size(),
+            });
+        }
+    }
+    symbols.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+    symbols.truncate(count);
+    Ok(symbols)
+}
+
+/// Formats a size in bytes with a binary unit, e.g. `12.3 KiB`.
+pub(crate) struct Bytes(pub u64);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let units = ["KiB", "MiB", "GiB"];
+        if self.0 < 1024 {
+            return f.pad(&format!("{} B", self.0));
+        }
+        let mut size = self.0 as f64 / 1024.0;
+        let mut unit = 0;
+        while size >= 1024.0 && unit + 1 < units.len() {
+            size /= 1024.0;
+            unit += 1;
+        }
+        f.pad(&format!("{:.1} {}", size, units[unit]))
+    }
+}
+
+/// The share of `part` in `total` in percent.
+pub(crate) fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
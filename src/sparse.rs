@@ -0,0 +1,76 @@
+//! Writes files with holes instead of explicit zeros, and fills the holes in again.
+//!
+//! Disk images consist largely of zeros, e.g. the space up to the
+//! `minimum-image-size` or the free space of the partitions. Seeking over
+//! these regions instead of writing them keeps the images sparse on file
+//! systems that support it, which saves disk space and I/O time.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// The granularity in which zeros are skipped, the block size of most file systems.
+const BLOCK_SIZE: usize = 4096;
+const BUFFER_SIZE: usize = 1 << 20;
+
+/// Writes `data` to `out` at its current position, seeking over blocks of zeros.
+///
+/// The skipped blocks must already be zero, which is the case behind the end
+/// of the file. The file is extended to the end of `data` if needed.
+pub(crate) fn write(out: &mut File, data: &[u8]) -> io::Result<()> {
+    for block in data.chunks(BLOCK_SIZE) {
+        if block.iter().all(|&byte| byte == 0) {
+            out.seek(SeekFrom::Current(block.len() as i64))?;
+        } else {
+            out.write_all(block)?;
+        }
+    }
+    // a hole at the end doesn't extend the file
+    let end = out.stream_position()?;
+    if out.metadata()?.len() < end {
+        out.set_len(end)?;
+    }
+    Ok(())
+}
+
+/// Copies `reader` to `out` at its current position like `write`, and returns
+/// the number of copied bytes.
+pub(crate) fn copy<R: Read>(reader: &mut R, out: &mut File) -> io::Result<u64> {
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut copied = 0;
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        write(out, &buffer[..n])?;
+        copied += n as u64;
+    }
+    Ok(copied)
+}
+
+/// Fills the holes of the file at `path` with zeros, so that all of its blocks
+/// are allocated.
+pub(crate) fn allocate(path: &Path) -> io::Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    loop {
+        let n = match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        // holes read as zeros, so chunks without zero blocks are already allocated
+        let zero_block = buffer[..n]
+            .chunks(BLOCK_SIZE)
+            .any(|block| block.iter().all(|&byte| byte == 0));
+        if zero_block {
+            file.seek(SeekFrom::Current(-(n as i64)))?;
+            file.write_all(&buffer[..n])?;
+        }
+    }
+    file.sync_all()
+}
@@ -0,0 +1,128 @@
+//! Strips the debug information and symbols from the kernel ELF file.
+//!
+//! Only the sections that are loaded into memory (and the data that the program
+//! headers refer to) are kept. The non-loaded sections, like `.debug_*` and
+//! `.symtab`, are usually placed after them by the linker, so the file is
+//! truncated and followed by a new section name table and section header table
+//! that only describe the kept sections.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+const ELF_HEADER_SIZE: usize = 64;
+const SECTION_HEADER_SIZE: usize = 64;
+const SHT_NOBITS: u32 = 8;
+const SHT_STRTAB: u32 = 3;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_INFO_LINK: u64 = 0x40;
+
+/// Returns a copy of the 64-bit little-endian ELF file `elf` without the sections
+/// that aren't loaded into memory.
+pub(crate) fn strip(elf: &[u8]) -> Result<Vec<u8>, String> {
+    if elf.len() < ELF_HEADER_SIZE || &elf[..4] != b"\x7fELF" {
+        return Err("the kernel is not an ELF file".into());
+    }
+    if elf[4] != 2 || elf[5] != 1 {
+        return Err("only 64-bit little-endian kernels can be stripped".into());
+    }
+    let ph_offset = LittleEndian::read_u64(&elf[32..40]) as usize;
+    let sh_offset = LittleEndian::read_u64(&elf[40..48]) as usize;
+    let ph_entry_size = usize::from(LittleEndian::read_u16(&elf[54..56]));
+    let ph_count = usize::from(LittleEndian::read_u16(&elf[56..58]));
+    let sh_count = usize::from(LittleEndian::read_u16(&elf[60..62]));
+    let shstrndx = usize::from(LittleEndian::read_u16(&elf[62..64]));
+
+    if ph_count > 0 && ph_entry_size < 56 {
+        return Err("invalid program header size in the kernel ELF file".into());
+    }
+
+    let table = |offset: usize, count: usize, entry_size: usize| {
+        offset
+            .checked_add(count * entry_size)
+            .filter(|&end| end <= elf.len())
+            .map(|end| &elf[offset..end])
+            .ok_or_else(|| String::from("the kernel ELF file is truncated"))
+    };
+    let program_headers = table(ph_offset, ph_count, ph_entry_size)?;
+    let section_headers = table(sh_offset, sh_count, SECTION_HEADER_SIZE)?;
+    let section_names = match section_headers.chunks(SECTION_HEADER_SIZE).nth(shstrndx) {
+        Some(header) => {
+            let offset = LittleEndian::read_u64(&header[24..32]) as usize;
+            let size = LittleEndian::read_u64(&header[32..40]) as usize;
+            table(offset, size, 1)?
+        }
+        None => &[][..],
+    };
+
+    // the kept data ends with the last loaded segment or section
+    let mut end = ELF_HEADER_SIZE.max(ph_offset + ph_count * ph_entry_size);
+    for header in program_headers.chunks(ph_entry_size) {
+        let offset = LittleEndian::read_u64(&header[8..16]) as usize;
+        let file_size = LittleEndian::read_u64(&header[32..40]) as usize;
+        end = end.max(offset.saturating_add(file_size));
+    }
+    // the null section is always kept, the indices of the kept sections are remapped
+    let mut kept = vec![0];
+    for (index, header) in section_headers.chunks(SECTION_HEADER_SIZE).enumerate().skip(1) {
+        if LittleEndian::read_u64(&header[8..16]) & SHF_ALLOC == 0 {
+            continue;
+        }
+        if LittleEndian::read_u32(&header[4..8]) != SHT_NOBITS {
+            let offset = LittleEndian::read_u64(&header[24..32]) as usize;
+            let size = LittleEndian::read_u64(&header[32..40]) as usize;
+            end = end.max(offset.saturating_add(size));
+        }
+        kept.push(index);
+    }
+    if end > elf.len() {
+        return Err("the kernel ELF file is truncated".into());
+    }
+
+    let mut stripped = elf[..end].to_vec();
+    let mut names = vec![0u8];
+    let mut headers = Vec::new();
+    for &index in &kept {
+        let mut header = section_headers[index * SECTION_HEADER_SIZE..][..SECTION_HEADER_SIZE]
+            .to_vec();
+        if index != 0 {
+            let name = LittleEndian::read_u32(&header[0..4]) as usize;
+            let name = section_names
+                .get(name..)
+                .and_then(|name| name.split(|&byte| byte == 0).next())
+                .unwrap_or(&[]);
+            LittleEndian::write_u32(&mut header[0..4], names.len() as u32);
+            names.extend_from_slice(name);
+            names.push(0);
+            // links to removed sections, e.g. to `.symtab`, are cleared
+            let remap = |index| kept.iter().position(|&kept| kept == index).unwrap_or(0) as u32;
+            let link = LittleEndian::read_u32(&header[40..44]) as usize;
+            LittleEndian::write_u32(&mut header[40..44], remap(link));
+            if LittleEndian::read_u64(&header[8..16]) & SHF_INFO_LINK != 0 {
+                let info = LittleEndian::read_u32(&header[44..48]) as usize;
+                LittleEndian::write_u32(&mut header[44..48], remap(info));
+            }
+        }
+        headers.extend_from_slice(&header);
+    }
+
+    // the new section name table is the last section
+    let names_offset = stripped.len();
+    let name = names.len() as u32;
+    names.extend_from_slice(b".shstrtab\0");
+    let mut header = [0u8; SECTION_HEADER_SIZE];
+    LittleEndian::write_u32(&mut header[0..4], name);
+    LittleEndian::write_u32(&mut header[4..8], SHT_STRTAB);
+    LittleEndian::write_u64(&mut header[24..32], names_offset as u64);
+    LittleEndian::write_u64(&mut header[32..40], names.len() as u64);
+    LittleEndian::write_u64(&mut header[48..56], 1);
+    headers.extend_from_slice(&header);
+    stripped.extend_from_slice(&names);
+
+    let padding = (8 - stripped.len() % 8) % 8;
+    stripped.resize(stripped.len() + padding, 0);
+    let headers_offset = stripped.len();
+    stripped.extend_from_slice(&headers);
+    LittleEndian::write_u64(&mut stripped[40..48], headers_offset as u64);
+    LittleEndian::write_u16(&mut stripped[60..62], kept.len() as u16 + 1);
+    LittleEndian::write_u16(&mut stripped[62..64], kept.len() as u16);
+    Ok(stripped)
+}
@@ -0,0 +1,567 @@
+//! Resolves addresses of the kernel to functions and source lines.
+//!
+//! The functions are looked up in the ELF symbol table and the source lines in
+//! the line number programs of the DWARF `.debug_line` section (versions 2 to
+//! 5), so no binutils for the kernel target are required.
+
+use byteorder::{ByteOrder, LittleEndian};
+use rustc_demangle::demangle;
+use std::fmt;
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::{Entry, Type};
+use xmas_elf::ElfFile;
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_UDATA: u64 = 0x0f;
+
+/// The symbol table and line number information of a kernel executable.
+pub(crate) struct Symbolizer {
+    /// The function symbols as `(address, size, name)`, sorted by address.
+    functions: Vec<(u64, u64, String)>,
+    /// The source files referenced by the line rows.
+    files: Vec<String>,
+    /// The address ranges of the line number programs, sorted by start address.
+    sequences: Vec<Sequence>,
+}
+
+/// The rows of a line number program for a contiguous range of addresses. The last
+/// row marks the end address of the sequence.
+struct Sequence {
+    rows: Vec<Row>,
+}
+
+#[derive(Clone, Copy)]
+struct Row {
+    address: u64,
+    /// The index into `Symbolizer::files`.
+    file: usize,
+    line: u64,
+}
+
+/// The function and source location of an address.
+pub(crate) struct Location<'a> {
+    pub address: u64,
+    /// The demangled function name and the offset of the address in the function.
+    pub function: Option<(&'a str, u64)>,
+    pub file: Option<&'a str>,
+    pub line: Option<u64>,
+}
+
+impl Symbolizer {
+    /// Reads the symbols and line number programs of the ELF file `elf`.
+    pub fn new(elf: &[u8]) -> Result<Symbolizer, String> {
+        let elf_file = ElfFile::new(elf).map_err(|err| format!("invalid kernel ELF: {}", err))?;
+        let mut symbolizer = Symbolizer {
+            functions: Vec::new(),
+            files: Vec::new(),
+            sequences: Vec::new(),
+        };
+        symbolizer.read_symbols(&elf_file)?;
+        if let Some(debug_line) = elf_file.find_section_by_name(".debug_line") {
+            let strings = |name| {
+                elf_file
+                    .find_section_by_name(name)
+                    .map(|section| section.raw_data(&elf_file))
+                    .unwrap_or(&[])
+            };
+            let sections = Sections {
+                debug_line: debug_line.raw_data(&elf_file),
+                debug_str: strings(".debug_str"),
+                debug_line_str: strings(".debug_line_str"),
+            };
+            symbolizer.read_line_programs(&sections)?;
+        }
+        Ok(symbolizer)
+    }
+
+    /// Whether the kernel contains line number information.
+    pub fn has_line_info(&self) -> bool {
+        !self.sequences.is_empty()
+    }
+
+    /// Looks up the function and source location of `address`.
+    pub fn locate(&self, address: u64) -> Location<'_> {
+        // a function symbol that contains the address or, for symbols without a size,
+        // the closest preceding one
+        let index = self
+            .functions
+            .partition_point(|&(start, _, _)| start <= address);
+        let function = self.functions[..index]
+            .iter()
+            .rev()
+            .find(|&&(start, size, _)| address - start < size)
+            .or_else(|| self.functions[..index].last().filter(|&&(_, size, _)| size == 0))
+            .map(|&(start, _, ref name)| (name.as_str(), address - start));
+
+        let index = self
+            .sequences
+            .partition_point(|sequence| sequence.rows[0].address <= address);
+        let row = self.sequences[..index]
+            .iter()
+            .rev()
+            .find(|sequence| address < sequence.rows[sequence.rows.len() - 1].address)
+            .map(|sequence| {
+                let index = sequence.rows.partition_point(|row| row.address <= address);
+                sequence.rows[index - 1]
+            })
+            .filter(|row| row.line != 0);
+        Location {
+            address,
+            function,
+            file: row
+                .and_then(|row| self.files.get(row.file))
+                .map(String::as_str),
+            line: row.map(|row| row.line),
+        }
+    }
+
+    fn read_symbols(&mut self, elf_file: &ElfFile) -> Result<(), String> {
+        for section in elf_file.section_iter() {
+            let symbols = match section.get_data(elf_file) {
+                Ok(SectionData::SymbolTable64(symbols)) => symbols,
+                _ => continue,
+            };
+            for symbol in symbols {
+                if symbol.get_type() != Ok(Type::Func) || symbol.value() == 0 {
+                    continue;
+                }
+                let name = symbol.get_name(elf_file)?;
+                // the alternate format omits the hash suffix of legacy symbols
+                let name = format!("{:#}", demangle(name));
+                self.functions.push((symbol.value(), symbol.size(), name));
+            }
+        }
+        self.functions.sort_by_key(|&(start, _, _)| start);
+        Ok(())
+    }
+
+    fn read_line_programs(&mut self, sections: &Sections) -> Result<(), String> {
+        let mut reader = Reader::new(sections.debug_line);
+        while !reader.is_empty() {
+            let (length, offset_size) = reader.initial_length()?;
+            let unit = reader.bytes(length)?;
+            self.read_line_program(sections, unit, offset_size)
+                .map_err(|err| format!("invalid `.debug_line` section: {}", err))?;
+        }
+        self.sequences
+            .sort_by_key(|sequence| sequence.rows[0].address);
+        Ok(())
+    }
+
+    /// Runs the line number program of a unit and records its rows.
+    fn read_line_program(
+        &mut self,
+        sections: &Sections,
+        unit: &[u8],
+        offset_size: usize,
+    ) -> Result<(), String> {
+        let mut reader = Reader::new(unit);
+        let version = reader.u16()?;
+        if !(2..=5).contains(&version) {
+            return Err(format!("unsupported DWARF version {}", version));
+        }
+        if version >= 5 {
+            // the address and segment selector sizes
+            reader.bytes(2)?;
+        }
+        let header_length = reader.offset(offset_size)?;
+        let mut program = Reader::new(reader.rest());
+        program.bytes(header_length as usize)?;
+        let minimum_instruction_length = u64::from(reader.u8()?);
+        if version >= 4 {
+            // the maximum operations per instruction, which are only used for VLIW
+            reader.u8()?;
+        }
+        let _default_is_stmt = reader.u8()?;
+        let line_base = reader.u8()? as i8;
+        let line_range = reader.u8()?;
+        let opcode_base = reader.u8()?;
+        if line_range == 0 || opcode_base == 0 {
+            return Err("invalid line program header".into());
+        }
+        let opcode_lengths = reader.bytes(usize::from(opcode_base) - 1)?.to_vec();
+
+        // the paths of the files, indexed by the file register of the program
+        let mut files = Vec::new();
+        if version >= 5 {
+            let directories = read_entries(&mut reader, sections, offset_size)?;
+            let directories: Vec<String> = directories.into_iter().map(|(path, _)| path).collect();
+            for (path, directory) in read_entries(&mut reader, sections, offset_size)? {
+                files.push(join_path(directories.get(directory as usize), path));
+            }
+        } else {
+            let mut directories = Vec::new();
+            loop {
+                let directory = reader.string()?;
+                if directory.is_empty() {
+                    break;
+                }
+                directories.push(directory);
+            }
+            // file 0 doesn't exist before DWARF 5
+            files.push(String::new());
+            loop {
+                let path = reader.string()?;
+                if path.is_empty() {
+                    break;
+                }
+                let directory = reader.uleb128()?;
+                reader.uleb128()?;
+                reader.uleb128()?;
+                // directory 0 is the compilation directory, which is only known from
+                // `.debug_info`
+                let directory = (directory as usize).checked_sub(1);
+                files.push(join_path(directory.and_then(|d| directories.get(d)), path));
+            }
+        }
+        let file_base = self.files.len();
+        self.files.extend(files);
+
+        let mut rows = Vec::new();
+        let mut row = Row {
+            address: 0,
+            file: 1,
+            line: 1,
+        };
+        while !program.is_empty() {
+            let opcode = program.u8()?;
+            if opcode >= opcode_base {
+                let adjusted = opcode - opcode_base;
+                row.address = row
+                    .address
+                    .wrapping_add(minimum_instruction_length * u64::from(adjusted / line_range));
+                let advance = i64::from(line_base) + i64::from(adjusted % line_range);
+                row.line = (row.line as i64).wrapping_add(advance) as u64;
+                rows.push(row);
+                continue;
+            }
+            match opcode {
+                0 => {
+                    let length = program.uleb128()? as usize;
+                    let mut instruction = Reader::new(program.bytes(length)?);
+                    match instruction.u8()? {
+                        DW_LNE_END_SEQUENCE => {
+                            rows.push(row);
+                            let rows: Vec<_> = rows
+                                .drain(..)
+                                .map(|row| Row {
+                                    file: file_base + row.file,
+                                    ..row
+                                })
+                                .collect();
+                            // the code of functions that were removed by the linker is
+                            // located at address 0
+                            if rows.len() > 1 && rows[0].address != 0 {
+                                self.sequences.push(Sequence { rows });
+                            }
+                            row = Row {
+                                address: 0,
+                                file: 1,
+                                line: 1,
+                            };
+                        }
+                        DW_LNE_SET_ADDRESS => row.address = instruction.offset(length - 1)?,
+                        DW_LNE_DEFINE_FILE => {
+                            let path = instruction.string()?;
+                            self.files.push(path);
+                        }
+                        // e.g. the discriminator
+                        _ => {}
+                    }
+                }
+                DW_LNS_COPY => rows.push(row),
+                DW_LNS_ADVANCE_PC => {
+                    let advance = minimum_instruction_length.wrapping_mul(program.uleb128()?);
+                    row.address = row.address.wrapping_add(advance);
+                }
+                DW_LNS_ADVANCE_LINE => {
+                    row.line = (row.line as i64).wrapping_add(program.sleb128()?) as u64;
+                }
+                DW_LNS_SET_FILE => row.file = program.uleb128()? as usize,
+                DW_LNS_CONST_ADD_PC => {
+                    let adjusted = 255 - opcode_base;
+                    row.address = row
+                        .address
+                        .wrapping_add(minimum_instruction_length * u64::from(adjusted / line_range));
+                }
+                DW_LNS_FIXED_ADVANCE_PC => {
+                    row.address = row.address.wrapping_add(u64::from(program.u16()?))
+                }
+                // the remaining standard opcodes only change registers that aren't needed
+                _ => {
+                    for _ in 0..opcode_lengths[usize::from(opcode) - 1] {
+                        program.uleb128()?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Display for Location<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}: ", self.address)?;
+        match self.function {
+            Some((name, 0)) => write!(f, "{}", name)?,
+            Some((name, offset)) => write!(f, "{}+{:#x}", name, offset)?,
+            None => write!(f, "??")?,
+        }
+        match (self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, " at {}:{}", file, line),
+            _ => write!(f, " at ??"),
+        }
+    }
+}
+
+/// The sections that the line number programs refer to.
+struct Sections<'a> {
+    debug_line: &'a [u8],
+    debug_str: &'a [u8],
+    debug_line_str: &'a [u8],
+}
+
+/// Reads the directory or file name entries of a DWARF 5 line program header as
+/// `(path, directory index)` pairs.
+fn read_entries(
+    reader: &mut Reader,
+    sections: &Sections,
+    offset_size: usize,
+) -> Result<Vec<(String, u64)>, String> {
+    let format_count = reader.u8()?;
+    let mut formats = Vec::new();
+    for _ in 0..format_count {
+        formats.push((reader.uleb128()?, reader.uleb128()?));
+    }
+    let count = reader.uleb128()?;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let mut path = String::new();
+        let mut directory = 0;
+        for &(content, form) in &formats {
+            let value = match form {
+                DW_FORM_STRING => Value::String(reader.string()?),
+                DW_FORM_LINE_STRP => {
+                    Value::String(string_at(sections.debug_line_str, reader.offset(offset_size)?)?)
+                }
+                DW_FORM_STRP => {
+                    Value::String(string_at(sections.debug_str, reader.offset(offset_size)?)?)
+                }
+                DW_FORM_UDATA => Value::Number(reader.uleb128()?),
+                DW_FORM_DATA1 => Value::Number(reader.offset(1)?),
+                DW_FORM_DATA2 => Value::Number(reader.offset(2)?),
+                DW_FORM_DATA4 => Value::Number(reader.offset(4)?),
+                DW_FORM_DATA8 => Value::Number(reader.offset(8)?),
+                DW_FORM_DATA16 => Value::Bytes(reader.bytes(16)?),
+                DW_FORM_BLOCK => {
+                    let length = reader.uleb128()? as usize;
+                    Value::Bytes(reader.bytes(length)?)
+                }
+                form => return Err(format!("unsupported form {:#x} in file entry", form)),
+            };
+            match (content, value) {
+                (DW_LNCT_PATH, Value::String(value)) => path = value,
+                (DW_LNCT_DIRECTORY_INDEX, Value::Number(value)) => directory = value,
+                _ => {}
+            }
+        }
+        entries.push((path, directory));
+    }
+    Ok(entries)
+}
+
+enum Value<'a> {
+    String(String),
+    Number(u64),
+    #[allow(dead_code)]
+    Bytes(&'a [u8]),
+}
+
+fn join_path(directory: Option<&String>, path: String) -> String {
+    match directory {
+        Some(directory) if !path.starts_with('/') && !directory.is_empty() => {
+            format!("{}/{}", directory.trim_end_matches('/'), path)
+        }
+        _ => path,
+    }
+}
+
+fn string_at(section: &[u8], offset: u64) -> Result<String, String> {
+    let mut reader = Reader::new(section);
+    reader.bytes(offset as usize)?;
+    reader.string()
+}
+
+/// Reads the little-endian values of the DWARF sections.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        self.data
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if len > self.data.len() {
+            return Err("unexpected end of data".into());
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(LittleEndian::read_u16(self.bytes(2)?))
+    }
+
+    /// Reads an unsigned value of `size` bytes, e.g. an offset or an address.
+    fn offset(&mut self, size: usize) -> Result<u64, String> {
+        match size {
+            1 => self.u8().map(u64::from),
+            2 => self.u16().map(u64::from),
+            4 => Ok(u64::from(LittleEndian::read_u32(self.bytes(4)?))),
+            8 => Ok(LittleEndian::read_u64(self.bytes(8)?)),
+            size => Err(format!("unsupported value size {}", size)),
+        }
+    }
+
+    /// Reads the length of a unit and returns it with the size of the offsets in the
+    /// unit, which are 8 bytes in the 64-bit DWARF format.
+    fn initial_length(&mut self) -> Result<(usize, usize), String> {
+        match self.offset(4)? {
+            0xffff_ffff => Ok((self.offset(8)? as usize, 8)),
+            length => Ok((length as usize, 4)),
+        }
+    }
+
+    fn uleb128(&mut self) -> Result<u64, String> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= u64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn sleb128(&mut self) -> Result<i64, String> {
+        let mut value = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= i64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self
+            .data
+            .iter()
+            .position(|&byte| byte == 0)
+            .ok_or("unterminated string")?;
+        let string = String::from_utf8_lossy(&self.data[..len]).into_owned();
+        self.data = &self.data[len + 1..];
+        Ok(string)
+    }
+}
+
+/// Collects the backtrace addresses from the serial output of the kernel.
+///
+/// The hexadecimal numbers with a `0x` prefix that follow the marker on a line are
+/// taken as addresses, e.g. `BACKTRACE: 0xffff800000012345 0xffff800000016789`.
+pub(crate) struct BacktraceScanner<'a> {
+    marker: &'a str,
+    /// The incomplete last line of the output.
+    line: Vec<u8>,
+    addresses: Vec<u64>,
+}
+
+impl<'a> BacktraceScanner<'a> {
+    pub fn new(marker: &'a str) -> Self {
+        BacktraceScanner {
+            marker,
+            line: Vec::new(),
+            addresses: Vec::new(),
+        }
+    }
+
+    /// Scans the next chunk of the output, which doesn't need to end at a line break.
+    pub fn feed(&mut self, output: &[u8]) {
+        for &byte in output {
+            if byte == b'\n' {
+                self.scan_line();
+            } else {
+                self.line.push(byte);
+            }
+        }
+    }
+
+    /// Returns the addresses after scanning the last line.
+    pub fn finish(mut self) -> Vec<u64> {
+        self.scan_line();
+        self.addresses
+    }
+
+    fn scan_line(&mut self) {
+        let line = String::from_utf8_lossy(&self.line).into_owned();
+        self.line.clear();
+        let rest = match line.find(self.marker) {
+            Some(index) => &line[index + self.marker.len()..],
+            None => return,
+        };
+        let numbers = rest
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter_map(|token| token.strip_prefix("0x"))
+            .filter_map(|digits| u64::from_str_radix(digits, 16).ok());
+        self.addresses.extend(numbers);
+    }
+}
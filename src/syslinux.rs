@@ -0,0 +1,229 @@
+//! Wraps the raw disk image into images that boot it with SYSLINUX or ISOLINUX.
+//!
+//! The raw disk image is stored as a file next to `memdisk`, which the generated
+//! configuration boots with the disk image as its initrd. `memdisk` maps the
+//! disk image as the first hard disk and chainloads its first sector, so the
+//! bootloader and the kernel boot just like from the raw disk image, e.g. from
+//! an old USB stick or CD through the mature SYSLINUX bootloaders. With several
+//! kernels, each has its own disk image and an entry in the menu of `menu.c32`.
+
+use config::Config;
+use fat;
+use image::{self, BootEntry};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use Error;
+
+/// The directories in which the distributions install the SYSLINUX files, e.g.
+/// `/usr/lib/syslinux/modules/bios` on Debian and `/usr/share/syslinux` on Fedora.
+const SEARCH_DIRS: &[&str] = &[
+    "/usr/lib/syslinux/bios",
+    "/usr/lib/syslinux/modules/bios",
+    "/usr/lib/syslinux",
+    "/usr/lib/ISOLINUX",
+    "/usr/share/syslinux",
+];
+/// The tools that create ISO images from a directory with the `mkisofs` options.
+const MKISOFS: &[&[&str]] = &[&["xorriso", "-as", "mkisofs"], &["genisoimage"], &["mkisofs"]];
+/// The menu module and the libraries that it depends on.
+const MENU_MODULES: &[&str] = &["menu.c32", "libutil.c32", "libcom32.c32"];
+
+/// Writes a FAT image to `output` that boots the raw disk images of `entries`
+/// with SYSLINUX, like a USB stick without a partition table.
+///
+/// The boot sector and `ldlinux.sys` are installed with the `syslinux` tool.
+pub(crate) fn create_syslinux_image(
+    config: &Config,
+    entries: &[BootEntry],
+    output: &Path,
+) -> Result<(), Error> {
+    let invalid =
+        |err: String| Error::Image(format!("invalid file for the SYSLINUX image: {}", err));
+    let mut files = fat::Directory::default();
+    for name in syslinux_files(config, entries, &["memdisk"]) {
+        files
+            .add_file(name, read_syslinux_file(config, name)?)
+            .map_err(invalid)?;
+    }
+    files
+        .add_file("syslinux.cfg", syslinux_cfg(config, entries).into_bytes())
+        .map_err(invalid)?;
+    for (index, entry) in entries.iter().enumerate() {
+        files
+            .add_file(&disk_image_name(index), entry.data.clone())
+            .map_err(invalid)?;
+    }
+
+    // the FAT file system starts at the first sector, so it has no hidden sectors
+    let size = image::fat_size(&files, 0, image::BLOCK_SIZE as u64);
+    let mut out = fs::File::create(output)?;
+    out.set_len(size)?;
+    fat::write_fat(&mut out, 0, size, image::BLOCK_SIZE as u64, 0, "SYSLINUX", &files)?;
+    drop(out);
+
+    let result = Command::new("syslinux").arg("--install").arg(output).output();
+    let result = match result {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::Image(
+                "`syslinux` was not found, install SYSLINUX together with `mtools` (e.g. \
+                 `apt install syslinux syslinux-common mtools`) to create `syslinux` images"
+                    .into(),
+            ))
+        }
+        result => result?,
+    };
+    if !result.status.success() {
+        return Err(Error::Image(format!(
+            "`syslinux --install` failed ({}), it requires `mtools`:\n{}",
+            result.status,
+            String::from_utf8_lossy(&result.stderr).trim_end()
+        )));
+    }
+    Ok(())
+}
+
+/// Writes an ISO image to `output` that boots the raw disk images of `entries`
+/// with ISOLINUX.
+///
+/// The files of the image are staged in `staging_dir`, which is recreated.
+pub(crate) fn create_isolinux_image(
+    config: &Config,
+    entries: &[BootEntry],
+    staging_dir: &Path,
+    output: &Path,
+) -> Result<(), Error> {
+    if staging_dir.exists() {
+        fs::remove_dir_all(staging_dir)?;
+    }
+    let isolinux_dir = staging_dir.join("isolinux");
+    fs::create_dir_all(&isolinux_dir)?;
+    // ISOLINUX loads `ldlinux.c32` from its own directory, which must be of the same version
+    for name in syslinux_files(config, entries, &["isolinux.bin", "ldlinux.c32", "memdisk"]) {
+        fs::write(isolinux_dir.join(name), read_syslinux_file(config, name)?)?;
+    }
+    fs::write(isolinux_dir.join("isolinux.cfg"), syslinux_cfg(config, entries))?;
+    for (index, entry) in entries.iter().enumerate() {
+        fs::write(isolinux_dir.join(disk_image_name(index)), &entry.data)?;
+    }
+
+    for command in MKISOFS {
+        let result = Command::new(command[0])
+            .args(&command[1..])
+            .arg("-quiet")
+            .arg("-R")
+            .arg("-b")
+            .arg("isolinux/isolinux.bin")
+            .arg("-c")
+            .arg("isolinux/boot.cat")
+            .arg("-no-emul-boot")
+            .arg("-boot-load-size")
+            .arg("4")
+            .arg("-boot-info-table")
+            .arg("-o")
+            .arg(output)
+            .arg(staging_dir)
+            .output();
+        let result = match result {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+            result => result?,
+        };
+        if !result.status.success() {
+            return Err(Error::Image(format!(
+                "`{}` failed ({}):\n{}",
+                command.join(" "),
+                result.status,
+                String::from_utf8_lossy(&result.stderr).trim_end()
+            )));
+        }
+        return Ok(());
+    }
+    Err(Error::Image(
+        "neither `xorriso`, `genisoimage`, nor `mkisofs` was found, install one of them \
+         (e.g. `apt install xorriso`) to create `isolinux` images"
+            .into(),
+    ))
+}
+
+/// The SYSLINUX files that are copied into the image: the given ones and, if
+/// there is a menu, its modules.
+fn syslinux_files(
+    config: &Config,
+    entries: &[BootEntry],
+    files: &[&'static str],
+) -> Vec<&'static str> {
+    let mut files = files.to_vec();
+    if has_menu(config, entries) {
+        files.extend_from_slice(MENU_MODULES);
+    }
+    files
+}
+
+/// Whether the kernels are selected in a menu, which requires several kernels
+/// and a `menu-timeout` (without one, the first kernel is booted immediately).
+fn has_menu(config: &Config, entries: &[BootEntry]) -> bool {
+    entries.len() > 1 && config.menu_timeout > 0
+}
+
+/// The file name of the disk image of the boot menu entry `index`.
+fn disk_image_name(index: usize) -> String {
+    match index {
+        0 => "disk.img".into(),
+        index => format!("disk-{}.img", index),
+    }
+}
+
+/// A SYSLINUX configuration that boots the disk images with `memdisk`, the first
+/// one immediately or, if there is a menu, after the `menu-timeout`.
+fn syslinux_cfg(config: &Config, entries: &[BootEntry]) -> String {
+    let mut cfg = if has_menu(config, entries) {
+        // the timeout is given in tenths of a second
+        format!(
+            "UI menu.c32\nMENU TITLE bootimage\nPROMPT 0\nTIMEOUT {}\n",
+            config.menu_timeout * 10
+        )
+    } else {
+        String::from("PROMPT 0\nTIMEOUT 0\n")
+    };
+    cfg.push_str("DEFAULT entry0\n");
+    for (index, entry) in entries.iter().enumerate() {
+        cfg.push_str(&format!("\nLABEL entry{}\n", index));
+        cfg.push_str(&format!("    MENU LABEL {}\n", entry.title));
+        cfg.push_str("    KERNEL memdisk\n");
+        cfg.push_str(&format!("    INITRD {}\n", disk_image_name(index)));
+        cfg.push_str("    APPEND harddisk\n");
+    }
+    cfg
+}
+
+/// Reads the SYSLINUX file `name` from the `syslinux-directory` or, if it isn't
+/// configured, from the first of the default installation directories that
+/// contains it.
+fn read_syslinux_file(config: &Config, name: &str) -> Result<Vec<u8>, Error> {
+    let syslinux_dir = config.syslinux_directory.as_deref();
+    let dirs: Vec<PathBuf> = match syslinux_dir {
+        Some(dir) => vec![dir.to_path_buf()],
+        None => SEARCH_DIRS.iter().map(PathBuf::from).collect(),
+    };
+    for dir in &dirs {
+        let path = dir.join(name);
+        if path.is_file() {
+            return Ok(fs::read(path)?);
+        }
+    }
+    Err(Error::Image(match syslinux_dir {
+        Some(dir) => format!(
+            "the `syslinux-directory` {} doesn't contain `{}`",
+            dir.display(),
+            name
+        ),
+        None => format!(
+            "`{}` was not found in the SYSLINUX installation directories ({}), install \
+             SYSLINUX (e.g. `apt install syslinux-common isolinux`) or set \
+             `syslinux-directory`",
+            name,
+            SEARCH_DIRS.join(", ")
+        ),
+    }))
+}
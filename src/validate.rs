@@ -0,0 +1,204 @@
+//! Validates the kernel ELF file before it is placed in the disk image.
+//!
+//! A kernel that the bootloader can't load doesn't produce an error message, it
+//! triple faults at boot, e.g. after a broken change to the linker script. The
+//! common causes are detected here instead, with a hint on how to fix them.
+
+use xmas_elf::header::{Class, Machine};
+use xmas_elf::program::{ProgramHeader, Type as SegmentType};
+use xmas_elf::ElfFile;
+
+/// The lower half of the canonical x86_64 address space ends here, and the
+/// higher half starts at its two's complement.
+const LOWER_HALF_END: u64 = 1 << 47;
+/// The BIOS bootloader loads the kernel in 32-bit protected mode, so it can
+/// only place segments in the first 4 GiB of physical memory.
+const PHYSICAL_LIMIT: u64 = 1 << 32;
+/// The last entry of the level 4 page table maps this region, which the BIOS
+/// bootloader uses for the recursive mapping of the page tables.
+const RECURSIVE_MAPPING_START: u64 = 0xffff_ff80_0000_0000;
+
+/// Checks that `elf` is a 64-bit x86_64 ELF file with sane loadable segments,
+/// an entry point in an executable segment, and no thread-local storage.
+pub(crate) fn kernel(elf: &[u8]) -> Result<(), String> {
+    let elf_file =
+        ElfFile::new(elf).map_err(|err| format!("the kernel is not a valid ELF file: {}", err))?;
+    if elf_file.header.pt1.class() != Class::SixtyFour {
+        return Err("the kernel is a 32-bit ELF file, but the bootloader only loads 64-bit \
+                    kernels (build it for a 64-bit target, e.g. with `\"arch\": \"x86_64\"` \
+                    in the target specification)"
+            .into());
+    }
+    let machine = match elf_file.header.pt2.machine().as_machine() {
+        Machine::X86_64 => None,
+        Machine::X86 => Some("x86 (i386)".to_owned()),
+        Machine::AArch64 => Some("AArch64".to_owned()),
+        Machine::Arm => Some("ARM".to_owned()),
+        Machine::Other(machine) => Some(format!("the ELF machine type {}", machine)),
+        other => Some(format!("{:?}", other)),
+    };
+    if let Some(machine) = machine {
+        return Err(format!(
+            "the kernel is built for {}, but the bootloader only loads x86_64 kernels (check \
+             the `llvm-target` and `arch` of the target specification)",
+            machine
+        ));
+    }
+
+    let segments = loadable_segments(&elf_file);
+    if segments.is_empty() {
+        return Err("the kernel has no loadable segments (check that the linker script \
+                    doesn't discard the sections of the kernel)"
+            .into());
+    }
+    for segment in &segments {
+        let start = segment.virtual_addr();
+        let end = start.checked_add(segment.mem_size());
+        if !end.is_some_and(|end| is_canonical(start) && is_canonical(end.saturating_sub(1))) {
+            return Err(format!(
+                "the segment of the kernel at {:#x} ({} bytes) is not in the canonical x86_64 \
+                 address space (check the addresses in the linker script)",
+                start,
+                segment.mem_size()
+            ));
+        }
+        if segment.file_size() > segment.mem_size() {
+            return Err(format!(
+                "the segment of the kernel at {:#x} contains {} bytes of file data, but only \
+                 occupies {} bytes of memory",
+                start,
+                segment.file_size(),
+                segment.mem_size()
+            ));
+        }
+        if segment.offset().saturating_add(segment.file_size()) > elf.len() as u64 {
+            return Err(format!(
+                "the segment of the kernel at {:#x} extends beyond the end of the ELF file, \
+                 which is truncated",
+                start
+            ));
+        }
+    }
+    if elf_file
+        .program_iter()
+        .any(|segment| segment.get_type() == Ok(SegmentType::Tls))
+    {
+        return Err("the kernel has a thread-local storage (TLS) segment, which the bootloader \
+                    doesn't set up (remove the `#[thread_local]` statics and check that the \
+                    target specification doesn't enable TLS for the kernel)"
+            .into());
+    }
+
+    let entry = elf_file.header.pt2.entry_point();
+    if entry == 0 {
+        return Err("the entry point of the kernel is 0 (set it with `ENTRY(_start)` in the \
+                    linker script and make sure that `_start` is `#[no_mangle]`)"
+            .into());
+    }
+    let executable = segments.iter().any(|segment| {
+        segment.flags().is_execute()
+            && entry >= segment.virtual_addr()
+            && entry - segment.virtual_addr() < segment.mem_size()
+    });
+    if !executable {
+        return Err(format!(
+            "the entry point of the kernel ({:#x}) is not in an executable loadable segment \
+             (check the `ENTRY` of the linker script and the flags of the `.text` section)",
+            entry
+        ));
+    }
+    Ok(())
+}
+
+/// Returns warnings for the loadable segments of the kernel `elf` that the BIOS
+/// bootloader may not be able to load, depending on its version.
+///
+/// The kernel is expected to be validated with `kernel` already.
+pub(crate) fn bios_load_warnings(elf: &[u8]) -> Vec<String> {
+    let elf_file = match ElfFile::new(elf) {
+        Ok(elf_file) => elf_file,
+        Err(_) => return Vec::new(),
+    };
+    let segments = loadable_segments(&elf_file);
+    let mut warnings = Vec::new();
+    // the bootloader reads the whole kernel file into memory before it maps the segments
+    let footprint = segments
+        .iter()
+        .fold(elf.len() as u64, |size, segment| size.saturating_add(segment.mem_size()));
+    if footprint > PHYSICAL_LIMIT {
+        warnings.push(format!(
+            "the kernel needs {} bytes of memory to be loaded (the ELF file and its segments), \
+             but the BIOS bootloader can only use the first 4 GiB of physical memory",
+            footprint
+        ));
+    }
+    for segment in segments {
+        let start = segment.virtual_addr();
+        let physical_end = segment.physical_addr().saturating_add(segment.mem_size());
+        if physical_end > PHYSICAL_LIMIT {
+            warnings.push(format!(
+                "the segment of the kernel at {:#x} has the physical load address {:#x}, but \
+                 the BIOS bootloader can only load segments below 4 GiB (bootloaders that load \
+                 segments at their physical address will fail, check the `AT` addresses of the \
+                 linker script)",
+                start,
+                segment.physical_addr()
+            ));
+        }
+        if start.saturating_add(segment.mem_size()) > RECURSIVE_MAPPING_START {
+            warnings.push(format!(
+                "the segment of the kernel at {:#x} ({} bytes) reaches into the last 512 GiB of \
+                 the address space, where the BIOS bootloader maps the page tables recursively \
+                 (link the kernel below {:#x})",
+                start,
+                segment.mem_size(),
+                RECURSIVE_MAPPING_START
+            ));
+        }
+    }
+    warnings
+}
+
+/// Checks that the loadable segments of the kernel `elf` don't overlap the ones
+/// of `bootloader_elf`, which are still in use while the bootloader maps the
+/// kernel.
+pub(crate) fn load_region(elf: &[u8], bootloader_elf: &[u8]) -> Result<(), String> {
+    let elf_file = ElfFile::new(elf).map_err(|err| format!("invalid kernel ELF: {}", err))?;
+    let bootloader = ElfFile::new(bootloader_elf)
+        .map_err(|err| format!("invalid bootloader ELF file: {}", err))?;
+    let bootloader_segments = loadable_segments(&bootloader);
+    let bootloader_start = bootloader_segments.iter().map(|segment| segment.virtual_addr()).min();
+    let bootloader_end = bootloader_segments
+        .iter()
+        .map(|segment| segment.virtual_addr().saturating_add(segment.mem_size()))
+        .max();
+    let (bootloader_start, bootloader_end) = match (bootloader_start, bootloader_end) {
+        (Some(start), Some(end)) => (start, end),
+        _ => return Ok(()),
+    };
+    for segment in loadable_segments(&elf_file) {
+        let start = segment.virtual_addr();
+        let end = start.saturating_add(segment.mem_size());
+        if start < bootloader_end && bootloader_start < end {
+            return Err(format!(
+                "the segment of the kernel at {:#x}..{:#x} overlaps the bootloader at \
+                 {:#x}..{:#x} (link the kernel at higher addresses, e.g. with \
+                 `. = 0x200000;` in the linker script)",
+                start, end, bootloader_start, bootloader_end
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The segments of an ELF file that are loaded into memory, without empty ones.
+fn loadable_segments<'a>(elf_file: &ElfFile<'a>) -> Vec<ProgramHeader<'a>> {
+    elf_file
+        .program_iter()
+        .filter(|segment| segment.get_type() == Ok(SegmentType::Load) && segment.mem_size() > 0)
+        .collect()
+}
+
+fn is_canonical(address: u64) -> bool {
+    address < LOWER_HALF_END || address >= LOWER_HALF_END.wrapping_neg()
+}
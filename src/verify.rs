@@ -0,0 +1,332 @@
+//! Checks the structural invariants of an existing disk image for `bootimage verify`.
+//!
+//! The checks only rely on the image itself (and a checksum file next to it if
+//! there is one), so images that were built elsewhere can be verified as well.
+
+use crc32;
+use image::{self, BLOCK_SIZE};
+use inspect::{self, Layout};
+use sha256;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process;
+use xmas_elf::sections::ShType;
+use xmas_elf::ElfFile;
+
+/// The outcome of a single check.
+pub(crate) enum Outcome {
+    Passed(String),
+    Failed(String),
+    /// The check doesn't apply to the image, e.g. because there is no checksum.
+    Skipped(String),
+}
+
+pub(crate) struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+}
+
+/// Runs all checks on the disk image at `path`.
+///
+/// The detached signature of the image is only checked if a `public_key` is given.
+pub(crate) fn verify(path: &Path, public_key: Option<&Path>) -> Result<Vec<Check>, String> {
+    let io_err = |err: io::Error| format!("failed to read {}: {}", path.display(), err);
+    let mut checks = Vec::new();
+    let mut check = |name, outcome| checks.push(Check { name, outcome });
+
+    let layout = inspect::layout(path);
+    let total = fs::metadata(path).map_err(io_err)?.len();
+    let disk_offset = layout
+        .as_ref()
+        .map(|layout| layout.disk_offset)
+        .unwrap_or(0);
+
+    let mut image = File::open(path).map_err(io_err)?;
+    let first_sector = read_part(&mut image, disk_offset, BLOCK_SIZE as u64).map_err(io_err)?;
+    check(
+        "boot signature",
+        if first_sector.get(510..512) == Some(&[0x55, 0xaa][..]) {
+            Outcome::Passed("the first sector ends with 0x55 0xaa".into())
+        } else {
+            Outcome::Failed("the first sector doesn't end with 0x55 0xaa".into())
+        },
+    );
+
+    check(
+        "alignment",
+        if total % BLOCK_SIZE as u64 == 0 {
+            Outcome::Passed(format!(
+                "{} bytes are whole {}-byte blocks",
+                total, BLOCK_SIZE
+            ))
+        } else {
+            Outcome::Failed(format!(
+                "{} bytes are not a multiple of the block size ({} bytes)",
+                total, BLOCK_SIZE
+            ))
+        },
+    );
+
+    match layout {
+        Ok(ref layout) => {
+            check(
+                "kernel info block",
+                kernel_outcome(&mut image, layout).map_err(io_err)?,
+            );
+            check(
+                "kernel crc",
+                crc_outcome(&mut image, layout).map_err(io_err)?,
+            );
+            check(
+                "padding",
+                padding_outcome(&mut image, layout).map_err(io_err)?,
+            );
+        }
+        Err(err) => {
+            check("kernel info block", Outcome::Failed(err));
+            check(
+                "kernel crc",
+                Outcome::Skipped("the kernel wasn't found".into()),
+            );
+            check(
+                "padding",
+                Outcome::Skipped("the kernel wasn't found".into()),
+            );
+        }
+    }
+
+    let checksum_path = checksum_path(path);
+    check(
+        "checksum",
+        checksum_outcome(path, &checksum_path).map_err(io_err)?,
+    );
+    if let Some(public_key) = public_key {
+        check("signature", signature_outcome(path, public_key));
+    }
+    Ok(checks)
+}
+
+/// The path of the checksum file of the image at `path`, e.g. `bootimage-os.bin.sha256`.
+pub(crate) fn checksum_path(path: &Path) -> PathBuf {
+    let mut checksum_path = path.as_os_str().to_owned();
+    checksum_path.push(".sha256");
+    PathBuf::from(checksum_path)
+}
+
+/// Checks that the kernel size field covers exactly the kernel ELF file, or for a
+/// compressed kernel, that the uncompressed size field covers the decompressed one.
+fn kernel_outcome(image: &mut File, layout: &Layout) -> io::Result<Outcome> {
+    let mut size = u64::from(layout.kernel_size);
+    let mut kernel = read_part(image, layout.kernel_offset(), size)?;
+    let mut field = "kernel size field";
+    if let Some((compression, uncompressed_size)) = layout.compression {
+        kernel = match image::decompress_kernel(&kernel, compression) {
+            Ok(kernel) => kernel,
+            Err(err) => {
+                return Ok(Outcome::Failed(format!(
+                    "the {}-compressed kernel can't be decompressed: {}",
+                    compression, err
+                )))
+            }
+        };
+        size = u64::from(uncompressed_size);
+        field = "uncompressed size field";
+        if kernel.len() as u64 != size {
+            return Ok(Outcome::Failed(format!(
+                "the {} ({} bytes) doesn't match the decompressed kernel ({} bytes)",
+                field,
+                size,
+                kernel.len()
+            )));
+        }
+    }
+    let elf_file = match ElfFile::new(&kernel) {
+        Ok(elf_file) => elf_file,
+        Err(err) => {
+            return Ok(Outcome::Failed(format!(
+                "the {} ({} bytes) doesn't cover a valid ELF file: {}",
+                field, size, err
+            )))
+        }
+    };
+
+    // the end of the last header table, segment, or section in the file
+    let header = &elf_file.header.pt2;
+    let mut end = end_of(
+        header.ph_offset(),
+        u64::from(header.ph_count()) * u64::from(header.ph_entry_size()),
+    )
+    .max(end_of(
+        header.sh_offset(),
+        u64::from(header.sh_count()) * u64::from(header.sh_entry_size()),
+    ));
+    if end > size {
+        return Ok(too_small(field, size, end));
+    }
+    for segment in elf_file.program_iter() {
+        end = end.max(end_of(segment.offset(), segment.file_size()));
+    }
+    for section in elf_file.section_iter() {
+        if section.get_type() != Ok(ShType::NoBits) {
+            end = end.max(end_of(section.offset(), section.size()));
+        }
+    }
+
+    Ok(if end > size {
+        too_small(field, size, end)
+    } else {
+        Outcome::Passed(format!(
+            "the {} ({} bytes) matches the kernel at {:#x}",
+            field,
+            size,
+            layout.kernel_offset()
+        ))
+    })
+}
+
+/// Compares the CRC-32 of the kernel with the one in a version 2 kernel info block.
+fn crc_outcome(image: &mut File, layout: &Layout) -> io::Result<Outcome> {
+    let expected = match layout.kernel_crc {
+        Some(crc) => crc,
+        None => {
+            return Ok(Outcome::Skipped(
+                "version 1 kernel info blocks contain no CRC-32".into(),
+            ))
+        }
+    };
+    let kernel = read_part(image, layout.kernel_offset(), u64::from(layout.kernel_size))?;
+    let actual = crc32::crc32(&kernel);
+    Ok(if actual == expected {
+        Outcome::Passed(format!("the CRC-32 of the kernel is {:#010x}", actual))
+    } else {
+        Outcome::Failed(format!(
+            "the CRC-32 of the kernel is {:#010x}, but the kernel info block records {:#010x}",
+            actual, expected
+        ))
+    })
+}
+
+/// Checks that the kernel is padded to the next block with zeros or the `pad-byte`.
+fn padding_outcome(image: &mut File, layout: &Layout) -> io::Result<Outcome> {
+    let block_size = BLOCK_SIZE as u64;
+    let alignment = (block_size - u64::from(layout.kernel_size) % block_size) % block_size;
+    let padding = read_part(image, layout.padding_offset(), alignment)?;
+    let pad_byte = padding.first().cloned().unwrap_or(0);
+    Ok(if padding.len() as u64 != alignment {
+        Outcome::Failed("the image ends before the kernel is padded to a whole block".into())
+    } else if padding.iter().any(|&byte| byte != pad_byte) {
+        Outcome::Failed(format!(
+            "the {} bytes behind the kernel are not all the same padding byte, so the kernel \
+             size field is too small",
+            alignment
+        ))
+    } else if pad_byte == 0 {
+        Outcome::Passed(format!(
+            "the kernel is padded with {} zero bytes",
+            alignment
+        ))
+    } else {
+        Outcome::Passed(format!(
+            "the kernel is padded with {} bytes of {:#04x}",
+            alignment, pad_byte
+        ))
+    })
+}
+
+/// Compares the SHA-256 hash of the image with the one recorded in the checksum file.
+fn checksum_outcome(path: &Path, checksum_path: &Path) -> io::Result<Outcome> {
+    if !checksum_path.is_file() {
+        return Ok(Outcome::Skipped(format!(
+            "no checksum file at {}",
+            checksum_path.display()
+        )));
+    }
+    // `sha256sum` format: `<hash>  <file name>` per line
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned());
+    let checksums = fs::read_to_string(checksum_path)?;
+    let expected = checksums.lines().find_map(|line| {
+        let mut parts = line.splitn(2, char::is_whitespace);
+        let hash = parts.next()?;
+        let name = parts.next()?.trim_start_matches([' ', '*']);
+        Some(hash).filter(|_| Some(name) == file_name.as_deref())
+    });
+    let expected = match expected {
+        Some(expected) => expected.to_lowercase(),
+        None => {
+            return Ok(Outcome::Failed(format!(
+                "{} has no checksum for the image",
+                checksum_path.display()
+            )))
+        }
+    };
+    let actual = sha256::to_hex(&sha256::sha256(&fs::read(path)?));
+    Ok(if actual == expected {
+        Outcome::Passed(format!(
+            "the SHA-256 hash matches {}",
+            checksum_path.display()
+        ))
+    } else {
+        Outcome::Failed(format!(
+            "the SHA-256 hash is {}, but {} records {}",
+            actual,
+            checksum_path.display(),
+            expected
+        ))
+    })
+}
+
+/// Verifies the minisign signature `IMAGE.minisig` of the image with `public_key`.
+fn signature_outcome(path: &Path, public_key: &Path) -> Outcome {
+    let mut signature_path = path.as_os_str().to_owned();
+    signature_path.push(".minisig");
+    let signature_path = PathBuf::from(signature_path);
+    if !signature_path.is_file() {
+        return Outcome::Failed(format!("no signature at {}", signature_path.display()));
+    }
+    let output = process::Command::new("minisign")
+        .arg("-V")
+        .arg("-q")
+        .arg("-p")
+        .arg(public_key)
+        .arg("-m")
+        .arg(path)
+        .arg("-x")
+        .arg(&signature_path)
+        .output();
+    match output {
+        Ok(ref output) if output.status.success() => Outcome::Passed(format!(
+            "{} is valid for {}",
+            signature_path.display(),
+            public_key.display()
+        )),
+        Ok(output) => Outcome::Failed(format!(
+            "{} is not valid for {}: {}",
+            signature_path.display(),
+            public_key.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+        Err(err) => Outcome::Failed(format!("failed to run `minisign`: {}", err)),
+    }
+}
+
+fn too_small(field: &str, size: u64, end: u64) -> Outcome {
+    Outcome::Failed(format!(
+        "the {} is {} bytes, but the kernel ELF file extends to {} bytes",
+        field, size, end
+    ))
+}
+
+fn end_of(offset: u64, size: u64) -> u64 {
+    offset.saturating_add(size)
+}
+
+/// Reads up to `size` bytes at `offset`.
+fn read_part(image: &mut File, offset: u64, size: u64) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    image.seek(SeekFrom::Start(offset))?;
+    image.by_ref().take(size).read_to_end(&mut data)?;
+    Ok(data)
+}
@@ -0,0 +1,259 @@
+//! Boots disk images in VirtualBox VMs, for hosts on which QEMU or KVM isn't
+//! available.
+//!
+//! The VM is created on the first run and updated before every run through
+//! `VBoxManage`: the disk image is converted into a VDI image (ISO images are
+//! attached as a DVD instead) that replaces the previous one, and the first serial
+//! port writes to a file, which is read while the VM runs. Unlike the host pipes
+//! of VirtualBox, which are Unix sockets or named pipes depending on the host, the
+//! file works the same everywhere and doesn't lose the output before it is opened.
+
+use build::find_executable;
+use config::{Config, ExportFormat, Firmware, ImageFormat};
+use export;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use Error;
+
+/// The default installation directories of VirtualBox on Windows and macOS.
+const INSTALL_DIRS: &[&str] = &[
+    "C:\\Program Files\\Oracle\\VirtualBox",
+    "/Applications/VirtualBox.app/Contents/MacOS",
+];
+/// The name of the storage controller that the image is attached to.
+const STORAGE_CONTROLLER: &str = "bootimage";
+/// The interval in which the state of the VM is polled, which runs `VBoxManage`.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// The time that a running VM from a previous run gets to power off.
+const POWER_OFF_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A started VirtualBox VM.
+pub(crate) struct Vm {
+    vboxmanage: PathBuf,
+    name: String,
+    /// The file that the first serial port writes to.
+    serial: PathBuf,
+}
+
+/// Creates or updates the VM of the configuration with the image and starts it.
+///
+/// The VDI image and the serial output are written to `work_dir`.
+pub(crate) fn start(config: &Config, work_dir: &Path) -> Result<Vm, Error> {
+    let vboxmanage = find_executable("VBoxManage", INSTALL_DIRS).ok_or_else(|| {
+        Error::Run(
+            "`VBoxManage` was not found, install VirtualBox or use the `qemu` run backend".into(),
+        )
+    })?;
+    let name = config.virtualbox.vm_name.clone().unwrap_or_else(|| {
+        config
+            .output
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "bootimage".into())
+    });
+    // VirtualBox resolves relative paths against its own working directory
+    fs::create_dir_all(work_dir)?;
+    let work_dir = work_dir.canonicalize()?;
+    let (medium_type, raw_image) = match config.format {
+        ImageFormat::Multiboot2 => {
+            return Err(Error::Run(
+                "VirtualBox can't boot Multiboot2 kernels directly, use the `grub-iso` or \
+                 `limine` format to boot them"
+                    .into(),
+            ))
+        }
+        ImageFormat::GrubIso | ImageFormat::Isolinux => ("dvddrive", None),
+        _ => ("hdd", Some(config.raw_image_path())),
+    };
+    let medium = match raw_image {
+        Some(_) => work_dir.join("virtualbox.vdi"),
+        None => config.image_path().canonicalize()?,
+    };
+    // the kind of the medium, which is also the name of its boot device
+    let device = match medium_type {
+        "dvddrive" => "dvd",
+        _ => "disk",
+    };
+
+    let vm = Vm {
+        vboxmanage,
+        name,
+        serial: work_dir.join("virtualbox.serial"),
+    };
+    match vm.state()?.as_deref() {
+        None => {
+            println!("Creating VirtualBox VM `{}`", vm.name);
+            vm.manage(&["createvm", "--name", &vm.name, "--ostype", "Other_64", "--register"])?;
+            vm.manage(&["storagectl", &vm.name, "--name", STORAGE_CONTROLLER, "--add", "ide"])?;
+        }
+        Some("poweroff") | Some("aborted") => {}
+        Some("saved") => {
+            vm.manage(&["discardstate", &vm.name])?;
+        }
+        Some(_) => {
+            // e.g. the VM of a previous run that was interrupted
+            println!("Powering off the running VirtualBox VM `{}`", vm.name);
+            vm.manage(&["controlvm", &vm.name, "poweroff"])?;
+            vm.wait_until_stopped(POWER_OFF_TIMEOUT)?;
+        }
+    }
+
+    // VirtualBox identifies disk images by the UUID in their header, which changes
+    // with their content, so the previous image must be released before it is replaced
+    let attach = |medium: &Path| {
+        let medium = medium.to_string_lossy();
+        vm.manage(&[
+            "storageattach",
+            &vm.name,
+            "--storagectl",
+            STORAGE_CONTROLLER,
+            "--port",
+            "0",
+            "--device",
+            "0",
+            "--type",
+            medium_type,
+            "--medium",
+            &medium,
+        ])
+    };
+    // a new VM has nothing attached yet, and a new image isn't registered yet
+    let _ = attach(Path::new("none"));
+    let _ = vm.manage(&["closemedium", device, &medium.to_string_lossy()]);
+    if let Some(raw_image) = raw_image {
+        export::export(&raw_image, ExportFormat::Vdi, &medium)?;
+    }
+    attach(&medium)?;
+
+    let memory = (config.virtualbox.memory / (1024 * 1024)).to_string();
+    let firmware = match config.firmware {
+        Firmware::Uefi => "efi",
+        Firmware::Bios => "bios",
+    };
+    if vm.serial.exists() {
+        fs::remove_file(&vm.serial)?;
+    }
+    vm.manage(&[
+        "modifyvm",
+        &vm.name,
+        "--memory",
+        &memory,
+        "--firmware",
+        firmware,
+        "--boot1",
+        device,
+        "--boot2",
+        "none",
+        "--boot3",
+        "none",
+        "--boot4",
+        "none",
+        "--uart1",
+        "0x3F8",
+        "4",
+        "--uartmode1",
+        "file",
+        &vm.serial.to_string_lossy(),
+    ])?;
+
+    println!("Starting VirtualBox VM `{}`", vm.name);
+    let start_type = if config.virtualbox.headless {
+        "headless"
+    } else {
+        "gui"
+    };
+    vm.manage(&["startvm", &vm.name, "--type", start_type])?;
+    Ok(vm)
+}
+
+impl Vm {
+    /// The file that the serial output of the VM is written to, which VirtualBox
+    /// creates when the VM starts.
+    pub(crate) fn serial(&self) -> &Path {
+        &self.serial
+    }
+
+    /// Waits until the VM is powered off (e.g. by the kernel through ACPI or by
+    /// closing its window) and powers it off after `timeout`.
+    pub(crate) fn wait(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        let start = Instant::now();
+        loop {
+            match self.state()?.as_deref() {
+                Some("poweroff") | None => return Ok(()),
+                Some("aborted") => {
+                    return Err(Error::Run(format!(
+                        "the VirtualBox VM `{}` was aborted",
+                        self.name
+                    )))
+                }
+                Some("gurumeditation") => {
+                    self.manage(&["controlvm", &self.name, "poweroff"])?;
+                    return Err(Error::Run(format!(
+                        "the VirtualBox VM `{}` crashed with a guru meditation (e.g. a triple \
+                         fault), see its log in the VirtualBox manager",
+                        self.name
+                    )));
+                }
+                _ => {}
+            }
+            if let Some(timeout) = timeout {
+                if start.elapsed() >= timeout {
+                    // a hung kernel would otherwise block forever
+                    self.manage(&["controlvm", &self.name, "poweroff"])?;
+                    return Err(Error::Timeout(timeout));
+                }
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Waits until the VM doesn't run anymore.
+    fn wait_until_stopped(&self, timeout: Duration) -> Result<(), Error> {
+        let start = Instant::now();
+        loop {
+            match self.state()?.as_deref() {
+                Some("poweroff") | Some("aborted") | None => return Ok(()),
+                _ if start.elapsed() >= timeout => {
+                    return Err(Error::Run(format!(
+                        "the VirtualBox VM `{}` didn't power off",
+                        self.name
+                    )))
+                }
+                _ => thread::sleep(POLL_INTERVAL),
+            }
+        }
+    }
+
+    /// The state of the VM (e.g. `running` or `poweroff`), or `None` if there is
+    /// no VM with the name.
+    fn state(&self) -> Result<Option<String>, Error> {
+        let output = Command::new(&self.vboxmanage)
+            .args(["showvminfo", &self.name, "--machinereadable"])
+            .output()?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+        let info = String::from_utf8_lossy(&output.stdout);
+        Ok(info
+            .lines()
+            .find_map(|line| line.strip_prefix("VMState="))
+            .map(|state| state.trim_matches('"').to_owned()))
+    }
+
+    /// Runs `VBoxManage` with the given arguments.
+    fn manage(&self, args: &[&str]) -> Result<(), Error> {
+        let output = Command::new(&self.vboxmanage).args(args).output()?;
+        if !output.status.success() {
+            return Err(Error::Run(format!(
+                "`VBoxManage {}` failed ({}):\n{}",
+                args.join(" "),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            )));
+        }
+        Ok(())
+    }
+}
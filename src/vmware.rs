@@ -0,0 +1,235 @@
+//! Generates VMware VMs for the image and runs them with `vmrun`.
+//!
+//! The VM is written to the `vmware` subdirectory of the output directory: a
+//! `.vmx` file that defines the VM, the disk image as a VMDK image (or a copy of
+//! the ISO image, which is attached as a CD), and the serial output, which the
+//! first serial port writes to. The directory is self-contained, so it can also be
+//! copied to another machine and opened in VMware Workstation, Player, or Fusion.
+
+use build::find_executable;
+use config::{Config, ExportFormat, Firmware, ImageFormat};
+use export;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use Error;
+
+/// The default installation directories of VMware Workstation and Fusion.
+const INSTALL_DIRS: &[&str] = &[
+    "C:\\Program Files (x86)\\VMware\\VMware Workstation",
+    "C:\\Program Files\\VMware\\VMware Workstation",
+    "/Applications/VMware Fusion.app/Contents/Library",
+];
+/// The file that the first serial port writes to, relative to the VM directory.
+const SERIAL_FILE: &str = "serial.log";
+/// The interval in which `vmrun` is asked whether the VM is still running.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A started VMware VM.
+pub(crate) struct Vm {
+    vmrun: PathBuf,
+    vmx: PathBuf,
+    serial: PathBuf,
+}
+
+/// The directory that the VM of the image is written to.
+pub(crate) fn vm_dir(out_dir: &Path) -> PathBuf {
+    out_dir.join("vmware")
+}
+
+/// Writes the `.vmx` file and the disk of the VM for the image to `vm_dir`.
+///
+/// A VM that is still running from a previous run is stopped first, since its
+/// disk can't be replaced while it runs.
+pub(crate) fn write_vm(config: &Config, vm_dir: &Path) -> Result<(), Error> {
+    if config.format == ImageFormat::Multiboot2 {
+        return Err(Error::Config(
+            "VMware can't boot Multiboot2 kernels directly, use the `grub-iso` or `limine` \
+             format to boot them"
+                .into(),
+        ));
+    }
+    let vmx = vmx_path(config, vm_dir);
+    if let Some(vmrun) = find_executable("vmrun", INSTALL_DIRS) {
+        if is_running(&vmrun, &vmx)? {
+            println!("Stopping the running VMware VM {}", vmx.display());
+            run_vmrun(&vmrun, &["stop", &vmx.to_string_lossy(), "hard"])?;
+        }
+    }
+    fs::create_dir_all(vm_dir)?;
+
+    let name = vm_name(config);
+    let mut entries = vec![
+        (".encoding", "UTF-8".to_owned()),
+        ("config.version", "8".into()),
+        ("virtualHW.version", "14".into()),
+        ("displayName", name.clone()),
+        ("guestOS", "other-64".into()),
+        ("memsize", (config.vmware.memory / (1024 * 1024)).to_string()),
+        ("numvcpus", "1".into()),
+        (
+            "firmware",
+            match config.firmware {
+                Firmware::Uefi => "efi",
+                Firmware::Bios => "bios",
+            }
+            .into(),
+        ),
+    ];
+    match config.format {
+        ImageFormat::GrubIso | ImageFormat::Isolinux => {
+            let iso = format!("{}.iso", name);
+            fs::copy(config.image_path(), vm_dir.join(&iso))?;
+            entries.push(("ide1:0.present", "TRUE".into()));
+            entries.push(("ide1:0.deviceType", "cdrom-image".into()));
+            entries.push(("ide1:0.fileName", iso));
+            entries.push(("bios.bootOrder", "cdrom".into()));
+        }
+        _ => {
+            let vmdk = format!("{}.vmdk", name);
+            export::export(&config.raw_image_path(), ExportFormat::Vmdk, &vm_dir.join(&vmdk))?;
+            entries.push(("ide0:0.present", "TRUE".into()));
+            entries.push(("ide0:0.fileName", vmdk));
+            entries.push(("bios.bootOrder", "hdd".into()));
+        }
+    }
+    entries.push(("serial0.present", "TRUE".into()));
+    entries.push(("serial0.fileType", "file".into()));
+    entries.push(("serial0.fileName", SERIAL_FILE.into()));
+    // the VM is rewritten on every build, which VMware would ask about otherwise
+    entries.push(("uuid.action", "keep".into()));
+    entries.push(("msg.autoAnswer", "TRUE".into()));
+
+    let mut content = String::new();
+    for (key, value) in entries {
+        content.push_str(&format!("{} = \"{}\"\n", key, escape(&value)));
+    }
+    fs::write(&vmx, content)?;
+    Ok(())
+}
+
+/// Starts the VM that `write_vm` has written to `vm_dir`.
+pub(crate) fn start(config: &Config, vm_dir: &Path) -> Result<Vm, Error> {
+    let vmrun = find_executable("vmrun", INSTALL_DIRS).ok_or_else(|| {
+        Error::Run(
+            "`vmrun` was not found, install VMware Workstation or Fusion or use the `qemu` run \
+             backend"
+                .into(),
+        )
+    })?;
+    // `vmrun` resolves relative paths against its own working directory
+    let vmx = vmx_path(config, vm_dir).canonicalize()?;
+    let serial = vm_dir.join(SERIAL_FILE);
+    if serial.exists() {
+        fs::remove_file(&serial)?;
+    }
+
+    println!("Starting VMware VM {}", vmx.display());
+    let gui = if config.vmware.headless {
+        "nogui"
+    } else {
+        "gui"
+    };
+    run_vmrun(&vmrun, &["start", &vmx.to_string_lossy(), gui])?;
+    Ok(Vm { vmrun, vmx, serial })
+}
+
+impl Vm {
+    /// The file that the serial output of the VM is written to, which VMware
+    /// creates when the VM starts.
+    pub(crate) fn serial(&self) -> &Path {
+        &self.serial
+    }
+
+    /// Waits until the VM is powered off (e.g. by the kernel through ACPI or by
+    /// closing its window) and stops it after `timeout`.
+    pub(crate) fn wait(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        let start = Instant::now();
+        while is_running(&self.vmrun, &self.vmx)? {
+            if let Some(timeout) = timeout {
+                if start.elapsed() >= timeout {
+                    // a hung kernel would otherwise block forever
+                    run_vmrun(&self.vmrun, &["stop", &self.vmx.to_string_lossy(), "hard"])?;
+                    return Err(Error::Timeout(timeout));
+                }
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        Ok(())
+    }
+}
+
+/// The path of the `.vmx` file of the VM.
+fn vmx_path(config: &Config, vm_dir: &Path) -> PathBuf {
+    vm_dir.join(format!("{}.vmx", vm_name(config)))
+}
+
+/// The configured name of the VM, which defaults to the file stem of the output.
+fn vm_name(config: &Config) -> String {
+    config.vmware.vm_name.clone().unwrap_or_else(|| {
+        config
+            .output
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "bootimage".into())
+    })
+}
+
+/// Escapes a value of a `.vmx` file, in which `|` starts a hexadecimal escape.
+fn escape(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        match c {
+            '|' | '"' | '#' => escaped.push_str(&format!("|{:02X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Whether `vmrun list` lists the VM as running.
+fn is_running(vmrun: &Path, vmx: &Path) -> Result<bool, Error> {
+    let output = vmrun_command(vmrun).arg("list").output()?;
+    if !output.status.success() {
+        return Err(Error::Run(format!(
+            "`vmrun list` failed ({}):\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stdout).trim_end()
+        )));
+    }
+    // the first line is the number of running VMs, followed by their `.vmx` files
+    let vmx = vmx.canonicalize().unwrap_or_else(|_| vmx.to_path_buf());
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .skip(1)
+        .any(|line| Path::new(line.trim()).canonicalize().ok().as_ref() == Some(&vmx)))
+}
+
+/// Runs `vmrun` with the given arguments.
+fn run_vmrun(vmrun: &Path, args: &[&str]) -> Result<(), Error> {
+    let output = vmrun_command(vmrun).args(args).output()?;
+    if !output.status.success() {
+        // `vmrun` prints its errors to stdout
+        return Err(Error::Run(format!(
+            "`vmrun {}` failed ({}):\n{}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stdout).trim_end()
+        )));
+    }
+    Ok(())
+}
+
+/// A `vmrun` invocation for the VMware product of the host.
+fn vmrun_command(vmrun: &Path) -> Command {
+    let mut command = Command::new(vmrun);
+    let host_type = if cfg!(target_os = "macos") {
+        "fusion"
+    } else {
+        "ws"
+    };
+    command.args(["-T", host_type]);
+    command
+}
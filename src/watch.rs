@@ -0,0 +1,97 @@
+//! Detects changes of the sources of the kernel, for `bootimage watch` and the
+//! rebuilds of `bootimage serve`.
+//!
+//! The sources are polled instead of being watched through the notification API of
+//! the operating system: summarizing the files of a crate takes a few milliseconds
+//! and works the same on every platform and file system (including network and VM
+//! shares, which often don't deliver notifications), without a dependency on a
+//! watcher crate. A summary covers the path, size, and modification time of every
+//! file, so added, removed, renamed, and modified files are detected, but not a
+//! modification that keeps the size of a file within the resolution of its
+//! modification time. The callers decide whether a summary is taken before or after
+//! the build that it is compared with.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// The interval in which the sources are polled for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How long the sources have to stay unchanged before a change is reported, so that
+/// the files that an editor or `git checkout` writes one after another trigger a
+/// single rebuild.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// The files whose changes trigger a rebuild: everything in `dir` except hidden
+/// files and the `excluded` paths (like the target directory and the files that
+/// the build writes).
+pub(crate) struct Sources {
+    pub dir: PathBuf,
+    pub excluded: Vec<PathBuf>,
+}
+
+/// A summary of the sources that changes when a file is added, removed, renamed, or
+/// modified.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Fingerprint {
+    files: u64,
+    /// The sum of the hashes of the path, size, and modification time of every file,
+    /// which doesn't depend on the order of the directory entries.
+    hash: u64,
+}
+
+/// Summarizes the files in the directory of `sources` recursively.
+pub(crate) fn fingerprint(sources: &Sources) -> Fingerprint {
+    fn visit(dir: &Path, sources: &Sources, fingerprint: &mut Fingerprint) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let hidden = entry.file_name().to_string_lossy().starts_with('.');
+            if hidden || sources.excluded.contains(&path) {
+                continue;
+            }
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            if metadata.is_dir() {
+                visit(&path, sources, fingerprint);
+            } else {
+                let mut hasher = DefaultHasher::new();
+                path.strip_prefix(&sources.dir).unwrap_or(&path).hash(&mut hasher);
+                metadata.len().hash(&mut hasher);
+                metadata.modified().ok().hash(&mut hasher);
+                fingerprint.files += 1;
+                fingerprint.hash = fingerprint.hash.wrapping_add(hasher.finish());
+            }
+        }
+    }
+
+    let mut fingerprint = Fingerprint::default();
+    visit(&sources.dir, sources, &mut fingerprint);
+    fingerprint
+}
+
+/// Waits until the sources differ from `last` and have settled, and returns their
+/// new fingerprint.
+pub(crate) fn wait_for_change(sources: &Sources, last: Fingerprint) -> Fingerprint {
+    let mut current = last;
+    while current == last {
+        thread::sleep(POLL_INTERVAL);
+        current = fingerprint(sources);
+    }
+    loop {
+        thread::sleep(DEBOUNCE);
+        let settled = fingerprint(sources);
+        if settled == current {
+            return settled;
+        }
+        current = settled;
+    }
+}